@@ -0,0 +1,53 @@
+//! Cost of serving a cache hit through the router
+//!
+//! Run with `cargo bench --bench cache_hit`.
+
+use akkoproxy::build_router;
+use akkoproxy::cache::{CacheKey, CachedMeta, CachedResponse};
+use akkoproxy::config::Config;
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, Criterion};
+use tower::ServiceExt;
+
+fn upstream_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_static("\"5d8c72a5edda8d6a\""));
+    headers.insert(header::LAST_MODIFIED, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+    headers
+}
+
+fn bench_cache_hit(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("cache_hit");
+
+    for (name, preserved) in [("bare", None), ("upstream_headers", Some(upstream_headers()))] {
+        let mut config = Config::with_upstream("http://upstream.invalid".to_string());
+        config.server.preserve_upstream_headers = preserved.is_some();
+        let (router, state) = build_router(config);
+
+        let meta = CachedMeta::new("image/avif".to_string(), StatusCode::OK).with_headers(preserved);
+        let entry = CachedResponse::new(Bytes::from(vec![0u8; 16 * 1024]), meta);
+        runtime.block_on(state.cache.put(CacheKey::new("/media/a.jpg".to_string(), "Avif".to_string()), entry));
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let request = Request::builder()
+                    .uri("/media/a.jpg")
+                    .header(header::ACCEPT, "image/avif,image/webp,*/*")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = runtime.block_on(router.clone().oneshot(request)).unwrap();
+                assert_eq!(response.headers().get("x-cache-status").unwrap(), "HIT");
+                response
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_cache_hit);
+criterion_main!(benches);
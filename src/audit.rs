@@ -0,0 +1,212 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Tracing target every audit event is emitted under
+pub const AUDIT_TARGET: &str = "akkoproxy::audit";
+
+/// Events waiting for the file writer before new ones are dropped from the file
+const QUEUE_SIZE: usize = 1024;
+
+/// A single administrative action
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    pub action: &'static str,
+    pub params: serde_json::Value,
+    /// Address of the directly connected client, unset for actions not made over HTTP
+    pub client_ip: Option<IpAddr>,
+    /// Short hash of the bearer token presented, see [`token_fingerprint`]
+    pub token_fingerprint: Option<String>,
+    /// Whether the token was accepted
+    pub authorized: bool,
+}
+
+impl AuditEvent {
+    pub fn new(action: &'static str, params: serde_json::Value) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            action,
+            params,
+            client_ip: None,
+            token_fingerprint: None,
+            authorized: true,
+        }
+    }
+}
+
+/// Identify a token in the audit log without revealing it
+///
+/// The first 8 hex characters of its SHA-256 hash.
+pub fn token_fingerprint(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+enum Message {
+    Event(AuditEvent),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Audit trail of administrative actions
+///
+/// Events always go to the `akkoproxy::audit` tracing target. When a file is
+/// configured they are also appended to it as JSON lines by a background task,
+/// so recording never waits for disk I/O.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    sender: Option<mpsc::Sender<Message>>,
+}
+
+impl AuditLog {
+    /// Only emit events to the tracing target
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Also append events to `path`, moving it to `<path>.1` once it would exceed `max_size` bytes
+    ///
+    /// Spawns the writer task, so it must be called within a Tokio runtime.
+    pub fn to_file(path: PathBuf, max_size: u64) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_events(path, max_size, receiver));
+        Self { sender: Some(sender) }
+    }
+
+    /// Record an event without waiting for it to be written
+    pub fn record(&self, event: AuditEvent) {
+        info!(
+            target: AUDIT_TARGET,
+            action = event.action,
+            params = %event.params,
+            client_ip = event.client_ip.map(|ip| ip.to_string()).as_deref().unwrap_or("-"),
+            token_fingerprint = event.token_fingerprint.as_deref().unwrap_or("-"),
+            authorized = event.authorized,
+            "Admin action",
+        );
+
+        if let Some(sender) = &self.sender {
+            if sender.try_send(Message::Event(event)).is_err() {
+                warn!("Audit log writer is behind, event not written to the audit file");
+            }
+        }
+    }
+
+    /// Wait until every event recorded so far has been written
+    pub async fn flush(&self) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let (done_tx, done_rx) = oneshot::channel();
+        if sender.send(Message::Flush(done_tx)).await.is_ok() {
+            let _ = done_rx.await;
+        }
+    }
+}
+
+/// Audit file currently appended to
+struct AuditFile {
+    file: tokio::fs::File,
+    size: u64,
+}
+
+impl AuditFile {
+    async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self { file, size })
+    }
+}
+
+/// Writer task appending events to the audit file until every [`AuditLog`] is dropped
+async fn write_events(path: PathBuf, max_size: u64, mut receiver: mpsc::Receiver<Message>) {
+    let mut file = None;
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Event(event) => {
+                let mut line = serde_json::to_vec(&event).expect("Audit events serialize to JSON");
+                line.push(b'\n');
+                if let Err(e) = write_line(&path, max_size, &mut file, &line).await {
+                    warn!("Failed to write audit log {}: {}", path.display(), e);
+                }
+            }
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Append one line, rotating the file first if the line would not fit
+async fn write_line(path: &Path, max_size: u64, file: &mut Option<AuditFile>, line: &[u8]) -> std::io::Result<()> {
+    let mut current = match file.take() {
+        Some(current) => current,
+        None => AuditFile::open(path).await?,
+    };
+    if current.size > 0 && current.size + line.len() as u64 > max_size {
+        drop(current);
+        tokio::fs::rename(path, rotated_path(path)).await?;
+        current = AuditFile::open(path).await?;
+    }
+
+    current.file.write_all(line).await?;
+    current.file.flush().await?;
+    current.size += line.len() as u64;
+    *file = Some(current);
+    Ok(())
+}
+
+/// Where a full audit file is moved, replacing the previous one
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_fingerprint() {
+        let fingerprint = token_fingerprint("secret");
+        assert_eq!(fingerprint, "2bb80d53");
+        assert_ne!(token_fingerprint("secret2"), fingerprint);
+    }
+
+    #[tokio::test]
+    async fn test_audit_file_rotation() {
+        let path = std::env::temp_dir().join(format!("akkoproxy-audit-rotation-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+
+        let log = AuditLog::to_file(path.clone(), 200);
+        for i in 0..3 {
+            log.record(AuditEvent::new("test", serde_json::json!({ "i": i })));
+        }
+        log.flush().await;
+
+        // Each line is over 100 bytes, so every write after the first rotates
+        let current = std::fs::read_to_string(&path).unwrap();
+        let rotated = std::fs::read_to_string(rotated_path(&path)).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert_eq!(rotated.lines().count(), 1);
+        let event: serde_json::Value = serde_json::from_str(current.trim()).unwrap();
+        assert_eq!(event["params"]["i"], 2);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+    }
+}
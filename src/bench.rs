@@ -0,0 +1,371 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ImageConfig;
+use crate::convert::FormatArg;
+use crate::image::{DecodedImage, EncodeSettings, ImageConverter, OutputFormat, DEFAULT_AVIF_SPEED};
+use crate::proxy::build_converter;
+
+/// Arguments of the `bench` subcommand
+///
+/// Maximum dimension, decode limits and AVIF threads come from the `[image]`
+/// section of the configuration file, if one is given, so the numbers match
+/// what the server would see.
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Sample image, or directory of sample images
+    #[arg(short, long, value_name = "PATH")]
+    pub input: PathBuf,
+
+    /// Output formats to try
+    #[arg(short, long, value_enum, value_delimiter = ',', default_values = ["avif", "webp"])]
+    pub formats: Vec<FormatArg>,
+
+    /// Qualities to try (1-100), for AVIF and JPEG
+    #[arg(short, long, value_delimiter = ',', default_values = ["50", "65", "80"],
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub qualities: Vec<u8>,
+
+    /// AVIF encoder speeds to try (1-10, 10 is fastest)
+    #[arg(short, long, value_delimiter = ',', default_values = ["6", "8", "10"],
+          value_parser = clap::value_parser!(u8).range(1..=10))]
+    pub speeds: Vec<u8>,
+
+    /// Also write the report as CSV to this file
+    #[arg(long, value_name = "FILE")]
+    pub csv: Option<PathBuf>,
+
+    /// Also write the report as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    pub json: Option<PathBuf>,
+}
+
+/// One combination of encoder settings tried on every sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Combination {
+    format: OutputFormat,
+    /// `None` for formats the setting does not affect
+    quality: Option<u8>,
+    speed: Option<u8>,
+}
+
+impl BenchArgs {
+    /// Every combination of the requested settings that can change the output
+    ///
+    /// WebP is encoded losslessly and PNG has no settings, so they get a single
+    /// combination each; JPEG ignores the speed.
+    fn combinations(&self) -> Vec<Combination> {
+        let mut combinations = Vec::new();
+        for format in &self.formats {
+            let format = OutputFormat::from(*format);
+            let qualities: Vec<Option<u8>> = match format {
+                OutputFormat::Avif | OutputFormat::Jpeg => self.qualities.iter().copied().map(Some).collect(),
+                _ => vec![None],
+            };
+            let speeds: Vec<Option<u8>> = match format {
+                OutputFormat::Avif => self.speeds.iter().copied().map(Some).collect(),
+                _ => vec![None],
+            };
+            for &quality in &qualities {
+                for &speed in &speeds {
+                    let combination = Combination { format, quality, speed };
+                    if !combinations.contains(&combination) {
+                        combinations.push(combination);
+                    }
+                }
+            }
+        }
+        combinations
+    }
+}
+
+/// Results of one combination, summed over all samples
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchRow {
+    /// Media type produced
+    pub format: &'static str,
+    pub quality: Option<u8>,
+    pub speed: Option<u8>,
+    pub encode_ms: f64,
+    /// Size of the sample files
+    pub input_size: u64,
+    pub output_size: u64,
+    /// `output_size` over `input_size`
+    pub ratio: f64,
+}
+
+/// Outcome of a benchmark run
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    /// Samples benchmarked
+    pub samples: usize,
+    /// Files that could not be decoded, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Time spent decoding and resizing the samples, once each
+    pub decode_ms: f64,
+    pub rows: Vec<BenchRow>,
+}
+
+/// Encode every sample with every combination of settings
+///
+/// Each sample is decoded once and encoded on the blocking pool, one encode
+/// at a time so timings are not skewed by encodes competing for threads.
+pub async fn run(args: &BenchArgs, image: &ImageConfig) -> Result<BenchReport> {
+    let converter = Arc::new(build_converter(&ImageConfig {
+        enable_avif: true,
+        enable_webp: true,
+        ..image.clone()
+    }));
+    let combinations = args.combinations();
+    if combinations.is_empty() {
+        bail!("Nothing to benchmark: no formats, qualities or speeds given");
+    }
+    let files = sample_files(&args.input)?;
+
+    let mut report = BenchReport {
+        samples: 0,
+        skipped: Vec::new(),
+        decode_ms: 0.0,
+        rows: combinations
+            .iter()
+            .map(|combination| BenchRow {
+                format: mime_type(combination.format),
+                quality: combination.quality,
+                speed: combination.speed,
+                encode_ms: 0.0,
+                input_size: 0,
+                output_size: 0,
+                ratio: 0.0,
+            })
+            .collect(),
+    };
+    for file in files {
+        let data = fs::read(&file).with_context(|| format!("Failed to read {}", file.display()))?;
+        let input_size = data.len() as u64;
+        let started = Instant::now();
+        let decoded = tokio::task::spawn_blocking({
+            let converter = converter.clone();
+            move || converter.decode(&data, &CancellationToken::new())
+        })
+        .await?;
+        let decoded = match decoded {
+            Ok(decoded) => Arc::new(decoded),
+            Err(e) => {
+                report.skipped.push((file, e.to_string()));
+                continue;
+            }
+        };
+        report.decode_ms += millis(started.elapsed());
+        report.samples += 1;
+
+        for (combination, row) in combinations.iter().zip(&mut report.rows) {
+            let settings = EncodeSettings {
+                quality: combination.quality.unwrap_or(image.quality),
+                avif_speed: combination.speed.unwrap_or(DEFAULT_AVIF_SPEED),
+            };
+            let (encoded, elapsed) = encode(&converter, &decoded, combination.format, settings)
+                .await
+                .with_context(|| format!("Failed to encode {} as {}", file.display(), row.format))?;
+            row.encode_ms += millis(elapsed);
+            row.input_size += input_size;
+            row.output_size += encoded as u64;
+        }
+    }
+    for row in &mut report.rows {
+        row.ratio = if row.input_size == 0 { 0.0 } else { row.output_size as f64 / row.input_size as f64 };
+    }
+    Ok(report)
+}
+
+/// Size of `decoded` encoded as `format`, and how long the encode took
+async fn encode(
+    converter: &Arc<ImageConverter>,
+    decoded: &Arc<DecodedImage>,
+    format: OutputFormat,
+    settings: EncodeSettings,
+) -> Result<(usize, Duration)> {
+    let (converter, decoded) = (converter.clone(), decoded.clone());
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let converted = converter.encode(&decoded, format, settings)?;
+        Ok((converted.data.len(), started.elapsed()))
+    })
+    .await?
+}
+
+/// `input` itself, or the files directly inside it in name order
+fn sample_files(input: &Path) -> Result<Vec<PathBuf>> {
+    if input.is_file() {
+        return Ok(vec![input.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(input).with_context(|| format!("Failed to read {}", input.display()))? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        bail!("No sample images in {}", input.display());
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Media type [`ImageConverter::encode`] produces for `format` with every format enabled
+fn mime_type(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Avif => "image/avif",
+        OutputFormat::WebP => "image/webp",
+        OutputFormat::Jpeg => "image/jpeg",
+        OutputFormat::Png | OutputFormat::Original => "image/png",
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn setting(value: Option<u8>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// Write the report as CSV, one line per combination after a header line
+pub fn write_csv(report: &BenchReport, out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "format,quality,speed,encode_ms,input_size,output_size,ratio")?;
+    for row in &report.rows {
+        writeln!(
+            out,
+            "{},{},{},{:.1},{},{},{:.4}",
+            row.format,
+            row.quality.map_or(String::new(), |quality| quality.to_string()),
+            row.speed.map_or(String::new(), |speed| speed.to_string()),
+            row.encode_ms,
+            row.input_size,
+            row.output_size,
+            row.ratio,
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the report as a table for humans
+pub fn print_report(report: &BenchReport) {
+    for (file, reason) in &report.skipped {
+        eprintln!("Skipped {}: {}", file.display(), reason);
+    }
+    println!("{} sample(s), decoded and resized in {:.1} ms", report.samples, report.decode_ms);
+    println!("{:<12} {:>7} {:>5} {:>12} {:>12} {:>7}", "format", "quality", "speed", "encode ms", "output bytes", "ratio");
+    for row in &report.rows {
+        println!(
+            "{:<12} {:>7} {:>5} {:>12.1} {:>12} {:>7.4}",
+            row.format,
+            setting(row.quality),
+            setting(row.speed),
+            row.encode_ms,
+            row.output_size,
+            row.ratio,
+        );
+    }
+}
+
+/// Write the files requested with `--csv` and `--json`
+pub fn write_outputs(args: &BenchArgs, report: &BenchReport) -> Result<()> {
+    if let Some(path) = &args.csv {
+        let mut file = fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        write_csv(report, &mut file).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    if let Some(path) = &args.json {
+        fs::write(path, serde_json::to_vec_pretty(report)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("akkoproxy-bench-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn args(input: PathBuf, formats: Vec<FormatArg>) -> BenchArgs {
+        BenchArgs {
+            input,
+            formats,
+            qualities: vec![50, 80],
+            speeds: vec![8, 10],
+            csv: None,
+            json: None,
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_bench_reports_every_combination() {
+        let dir = temp_dir("grid");
+        image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([(x * 8) as u8, (y * 16) as u8, 90]))
+            .save(dir.join("sample.png"))
+            .unwrap();
+        let sample_size = fs::metadata(dir.join("sample.png")).unwrap().len();
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+
+        let args = args(dir.clone(), vec![FormatArg::Avif, FormatArg::Webp, FormatArg::Jpeg]);
+        let report = run(&args, &ImageConfig::default()).await.unwrap();
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.skipped.len(), 1);
+        assert!(report.skipped[0].0.ends_with("notes.txt"));
+
+        // AVIF over qualities and speeds, lossless WebP once, JPEG over qualities
+        let combinations: Vec<_> = report.rows.iter().map(|row| (row.format, row.quality, row.speed)).collect();
+        assert_eq!(
+            combinations,
+            vec![
+                ("image/avif", Some(50), Some(8)),
+                ("image/avif", Some(50), Some(10)),
+                ("image/avif", Some(80), Some(8)),
+                ("image/avif", Some(80), Some(10)),
+                ("image/webp", None, None),
+                ("image/jpeg", Some(50), None),
+                ("image/jpeg", Some(80), None),
+            ]
+        );
+        for row in &report.rows {
+            assert_eq!(row.input_size, sample_size);
+            assert!(row.output_size > 0, "{:?}", row);
+            assert!((row.ratio - row.output_size as f64 / row.input_size as f64).abs() < 1e-9);
+        }
+
+        let mut csv = Vec::new();
+        write_csv(&report, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines[0], "format,quality,speed,encode_ms,input_size,output_size,ratio");
+        assert_eq!(lines.len(), report.rows.len() + 1);
+        assert!(lines[5].starts_with("image/webp,,,"), "{}", lines[5]);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["samples"], 1);
+        assert_eq!(json["rows"].as_array().unwrap().len(), 7);
+        assert_eq!(json["rows"][0]["speed"], 8);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bench_without_samples() {
+        let dir = temp_dir("empty");
+        assert!(run(&args(dir.clone(), vec![FormatArg::Webp]), &ImageConfig::default()).await.is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,56 +1,691 @@
-use axum::http::HeaderMap;
+use axum::http::{HeaderMap, StatusCode};
 use bytes::Bytes;
 use moka::future::Cache;
-use std::sync::Arc;
-use std::time::Duration;
+use moka::notification::RemovalCause;
+use moka::Expiry;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::config::StatusPolicyConfig;
+use crate::digest::BodyDigest;
+use crate::headers::{add_validators, content_type_value, entry_headers};
 
 /// Cache key for storing responses
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct CacheKey {
-    pub path: String,
-    pub format: String,
+pub enum CacheKey {
+    /// A successful response, stored once per negotiated format
+    Variant { path: String, format: String },
+    /// A non-success response, shared by every format of the path
+    Negative { path: String },
 }
 
 impl CacheKey {
     pub fn new(path: String, format: String) -> Self {
-        Self { path, format }
+        Self::Variant { path, format }
+    }
+    
+    /// Key of the format-agnostic negative entry for an upstream path and query
+    pub fn negative(path: String) -> Self {
+        Self::Negative { path }
+    }
+    
+    /// Key of the same path in another format; negative keys have no format and stay as they are
+    pub fn with_format(&self, format: String) -> Self {
+        match self {
+            CacheKey::Variant { path, .. } => CacheKey::Variant { path: path.clone(), format },
+            CacheKey::Negative { .. } => self.clone(),
+        }
+    }
+    
+    /// Path the entry was stored for, without its query string
+    pub fn base_path(&self) -> &str {
+        let (CacheKey::Variant { path, .. } | CacheKey::Negative { path }) = self;
+        path.split_once('?').map_or(path.as_str(), |(path, _)| path)
     }
 }
 
+/// Version of the serialized [`CachedMeta`] layout
+///
+/// Bump whenever a field changes meaning or an incompatible field is added, so
+/// persistent tiers can discard entries written by older versions.
+pub const CACHE_META_VERSION: u32 = 1;
+
 /// Cached response data
 #[derive(Debug, Clone)]
 pub struct CachedResponse {
     pub data: Bytes,
+    pub meta: CachedMeta,
+    /// Response headers fixed by the entry, built once so hits only copy them
+    pub headers: HeaderMap,
+    /// How often the entry was served, kept in memory only
+    pub access: EntryAccess,
+}
+
+impl CachedResponse {
+    pub fn new(data: Bytes, meta: CachedMeta) -> Self {
+        // Only successful responses are replayed with their content type and validators
+        let content_type = meta.status.is_success().then(|| content_type_value(&meta.content_type));
+        let mut headers = entry_headers(content_type, meta.headers.as_ref());
+        if meta.status.is_success() {
+            let digest = meta.digest.clone().unwrap_or_else(|| BodyDigest::of(&data).to_hex());
+            add_validators(&mut headers, &digest, meta.last_modified.unwrap_or(meta.inserted_at));
+        }
+        Self {
+            data,
+            meta,
+            headers,
+            access: EntryAccess::default(),
+        }
+    }
+}
+
+/// Hits of a cache entry and the time of the last one
+///
+/// Clones copy the counts at the time of cloning and go on independently.
+#[derive(Debug, Default)]
+pub struct EntryAccess {
+    hits: AtomicU64,
+    /// Milliseconds since the Unix epoch, 0 before the first hit
+    last_access: AtomicU64,
+}
+
+impl EntryAccess {
+    /// Count one response served from the entry
+    pub fn record_hit(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.last_access.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+    
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    
+    /// Time of the last hit, `None` if the entry was never served from the cache
+    pub fn last_access(&self) -> Option<SystemTime> {
+        match self.last_access.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+}
+
+impl Clone for EntryAccess {
+    fn clone(&self) -> Self {
+        Self {
+            hits: AtomicU64::new(self.hits()),
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Everything about a cached response except its body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedMeta {
+    /// Layout version, see [`CACHE_META_VERSION`]
+    pub version: u32,
     pub content_type: String,
-    pub upstream_headers: Option<HeaderMap>,
+    /// Upstream ETag, sent as If-None-Match when the entry is revalidated
+    pub etag: Option<String>,
+    pub inserted_at: SystemTime,
+    /// Set when the entry lives shorter than the cache-wide TTL
+    pub expires_at: Option<SystemTime>,
+    /// Size of the upstream body before conversion
+    pub original_size: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Status code the response is replayed with
+    #[serde(with = "status_code_serde")]
+    pub status: StatusCode,
+    /// Upstream headers replayed with the response, if preserved
+    #[serde(with = "header_map_serde")]
+    pub headers: Option<HeaderMap>,
+    /// Tags the entry can be purged by, e.g. `domain:media.example.social`
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Hex SHA-256 of the cached body, taken while it was read or encoded
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Hex SHA-256 of the upstream body the entry was made from
+    #[serde(default)]
+    pub source_digest: Option<String>,
+    /// Date served as `Last-Modified` unless upstream sent one, kept when the entry is refreshed
+    #[serde(default)]
+    pub last_modified: Option<SystemTime>,
+}
+
+impl CachedMeta {
+    pub fn new(content_type: String, status: StatusCode) -> Self {
+        let inserted_at = SystemTime::now();
+        Self {
+            version: CACHE_META_VERSION,
+            content_type,
+            etag: None,
+            inserted_at,
+            expires_at: None,
+            original_size: None,
+            width: None,
+            height: None,
+            status,
+            headers: None,
+            tags: Vec::new(),
+            digest: None,
+            source_digest: None,
+            last_modified: Some(inserted_at),
+        }
+    }
+    
+    /// Expire the entry `ttl` after insertion instead of after the cache-wide TTL
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.expires_at = ttl.map(|ttl| self.inserted_at + ttl);
+        self
+    }
+    
+    pub fn with_headers(mut self, headers: Option<HeaderMap>) -> Self {
+        self.headers = headers;
+        self
+    }
+    
+    pub fn with_original_size(mut self, original_size: usize) -> Self {
+        self.original_size = Some(original_size as u64);
+        self
+    }
+    
+    pub fn with_etag(mut self, etag: Option<String>) -> Self {
+        self.etag = etag;
+        self
+    }
+    
+    pub fn with_digest(mut self, digest: BodyDigest) -> Self {
+        self.digest = Some(digest.to_hex());
+        self
+    }
+    
+    pub fn with_source_digest(mut self, digest: BodyDigest) -> Self {
+        self.source_digest = Some(digest.to_hex());
+        self
+    }
+    
+    /// Tag the entry, keeping at most [`MAX_TAGS_PER_ENTRY`] distinct tags
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags.clear();
+        for tag in tags {
+            if self.tags.len() < MAX_TAGS_PER_ENTRY && !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+        self
+    }
+    
+    /// Copy of the metadata for an entry upstream confirmed as unchanged just now
+    pub fn refreshed(&self) -> Self {
+        let ttl = self.ttl();
+        Self {
+            inserted_at: SystemTime::now(),
+            ..self.clone()
+        }
+        .with_ttl(ttl)
+    }
+    
+    /// Lifetime the entry was inserted with, if shorter than the cache-wide TTL
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| expires_at.duration_since(self.inserted_at).unwrap_or_default())
+    }
+    
+    /// Time left until the entry's own expiry, if it has one
+    fn remaining_ttl(&self) -> Option<Duration> {
+        self.expires_at
+            .map(|expires_at| expires_at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+}
+
+mod status_code_serde {
+    use axum::http::StatusCode;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    
+    pub fn serialize<S: Serializer>(status: &StatusCode, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(status.as_u16())
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StatusCode, D::Error> {
+        StatusCode::from_u16(u16::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+mod header_map_serde {
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    
+    /// Headers are stored as ordered (name, raw value) pairs to keep repeated
+    /// headers and non-UTF-8 values intact
+    pub fn serialize<S: Serializer>(headers: &Option<HeaderMap>, serializer: S) -> Result<S::Ok, S::Error> {
+        headers
+            .as_ref()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_bytes()))
+                    .collect::<Vec<_>>()
+            })
+            .serialize(serializer)
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<HeaderMap>, D::Error> {
+        let Some(pairs) = Option::<Vec<(String, Vec<u8>)>>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        
+        let mut headers = HeaderMap::with_capacity(pairs.len());
+        for (name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).map_err(D::Error::custom)?,
+                HeaderValue::from_bytes(&value).map_err(D::Error::custom)?,
+            );
+        }
+        Ok(Some(headers))
+    }
+}
+
+/// Expires entries after their own TTL when they carry one
+///
+/// The cache-wide TTL still applies, so the earlier of the two wins. Entries are
+/// kept for `stale_ttl` past either so they can be revalidated or served stale.
+struct ResponseExpiry {
+    stale_ttl: Duration,
+}
+
+impl Expiry<CacheKey, Arc<CachedResponse>> for ResponseExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &CacheKey,
+        value: &Arc<CachedResponse>,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        value.meta.remaining_ttl().map(|ttl| ttl + self.stale_ttl)
+    }
+    
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        value: &Arc<CachedResponse>,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        value.meta.remaining_ttl().map(|ttl| ttl + self.stale_ttl)
+    }
+}
+
+/// How upstream statuses are treated by the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusClass {
+    /// 2xx responses, cached for the cache-wide TTL
+    Success,
+    /// 301 and 308
+    PermanentRedirect,
+    /// 302, 303 and 307, never cached
+    TemporaryRedirect,
+    /// 404 and 410, negatively cached
+    NotFound,
+    /// 401 and 403
+    Denied,
+    /// 429 and 5xx
+    Error,
+    /// Everything else, never cached
+    Uncacheable,
+}
+
+impl StatusClass {
+    pub fn of(status: StatusCode) -> Self {
+        match status.as_u16() {
+            200..=299 => StatusClass::Success,
+            301 | 308 => StatusClass::PermanentRedirect,
+            302 | 303 | 307 => StatusClass::TemporaryRedirect,
+            404 | 410 => StatusClass::NotFound,
+            401 | 403 => StatusClass::Denied,
+            429 | 500..=599 => StatusClass::Error,
+            _ => StatusClass::Uncacheable,
+        }
+    }
 }
 
+/// How long a non-success upstream response may be cached, or `None` if never
+pub fn status_ttl(status: StatusCode, policy: &StatusPolicyConfig) -> Option<Duration> {
+    let seconds = match StatusClass::of(status) {
+        StatusClass::PermanentRedirect => policy.permanent_redirect_ttl,
+        StatusClass::NotFound => policy.not_found_ttl,
+        StatusClass::Denied => policy.denied_ttl,
+        StatusClass::Error => policy.error_ttl,
+        StatusClass::Success | StatusClass::TemporaryRedirect | StatusClass::Uncacheable => 0,
+    };
+    
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Cache-Control value sent downstream for a non-success response
+pub fn status_cache_control(ttl: Option<Duration>) -> String {
+    match ttl {
+        Some(ttl) => format!("public, max-age={}", ttl.as_secs()),
+        None => "no-store".to_string(),
+    }
+}
+
+/// Most tags kept per entry, which with the cache capacity bounds the tag index
+pub const MAX_TAGS_PER_ENTRY: usize = 8;
+
+/// Keys of the cached entries carrying each tag
+///
+/// Entries are added on insert and removed by the cache's eviction listener, so
+/// the index never holds more than the cache's entries times their tags.
+#[derive(Clone, Default)]
+struct TagIndex {
+    keys: Arc<Mutex<HashMap<String, HashSet<CacheKey>>>>,
+}
+
+impl TagIndex {
+    fn add(&self, key: &CacheKey, tags: &[String]) {
+        let mut index = self.keys.lock().unwrap();
+        for tag in tags {
+            index.entry(tag.clone()).or_default().insert(key.clone());
+        }
+    }
+    
+    fn remove(&self, key: &CacheKey, tags: &[String]) {
+        let mut index = self.keys.lock().unwrap();
+        for tag in tags {
+            if let Some(keys) = index.get_mut(tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    index.remove(tag);
+                }
+            }
+        }
+    }
+    
+    fn keys(&self, tag: &str) -> Vec<CacheKey> {
+        let index = self.keys.lock().unwrap();
+        index.get(tag).map(|keys| keys.iter().cloned().collect()).unwrap_or_default()
+    }
+    
+    /// Number of (tag, key) pairs in the index
+    fn len(&self) -> usize {
+        self.keys.lock().unwrap().values().map(HashSet::len).sum()
+    }
+}
+
+/// Labels of the hit count ranges entries are grouped in by [`ResponseCache::hit_distribution`]
+pub const HIT_BUCKETS: [&str; 4] = ["0", "1", "2-5", "6+"];
+
 /// Response cache manager
 #[derive(Clone)]
 pub struct ResponseCache {
     cache: Cache<CacheKey, Arc<CachedResponse>>,
+    ttl: Duration,
+    tags: TagIndex,
+    max_capacity: u64,
+    /// Entries the cache is held to, below `max_capacity` while memory is short
+    capacity: Arc<AtomicU64>,
+    /// Entries stored since the cache was created
+    inserts: Arc<AtomicU64>,
+    /// Entries removed since the cache was created to make room for others
+    evictions: Arc<AtomicU64>,
+}
+
+/// A cached response and whether it is still fresh
+pub struct CacheLookup {
+    pub response: Arc<CachedResponse>,
+    pub fresh: bool,
+    /// How long ago the response expired, zero while it is fresh
+    pub expired_for: Duration,
+}
+
+/// Result of [`ResponseCache::put_if_absent`]
+#[derive(Debug)]
+pub enum PutOutcome {
+    /// The response was stored
+    Inserted,
+    /// A fresh entry stored by someone else was kept; serve it instead, so every
+    /// client sees the same body for the entry's lifetime
+    Existing(Arc<CachedResponse>),
 }
 
 impl ResponseCache {
     /// Create a new response cache
-    pub fn new(max_capacity: u64, ttl: Duration, _max_item_size: u64) -> Self {
+    pub fn new(max_capacity: u64, ttl: Duration, max_item_size: u64) -> Self {
+        Self::with_stale_ttl(max_capacity, ttl, max_item_size, Duration::ZERO)
+    }
+    
+    /// Create a response cache that keeps expired entries around for `stale_ttl`
+    pub fn with_stale_ttl(max_capacity: u64, ttl: Duration, _max_item_size: u64, stale_ttl: Duration) -> Self {
+        let tags = TagIndex::default();
+        let listener_tags = tags.clone();
+        let evictions = Arc::new(AtomicU64::new(0));
+        let listener_evictions = evictions.clone();
         let cache = Cache::builder()
             .max_capacity(max_capacity)
-            .time_to_live(ttl)
+            .time_to_live(ttl + stale_ttl)
+            .expire_after(ResponseExpiry { stale_ttl })
             .initial_capacity(100)
+            // Replaced entries are handled by `put`, which knows the new tags
+            .eviction_listener(move |key: Arc<CacheKey>, value: Arc<CachedResponse>, cause| {
+                if cause != RemovalCause::Replaced {
+                    listener_tags.remove(&key, &value.meta.tags);
+                }
+                if cause == RemovalCause::Size {
+                    listener_evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            })
             .build();
         
-        Self { cache }
+        Self {
+            cache,
+            ttl,
+            tags,
+            max_capacity,
+            capacity: Arc::new(AtomicU64::new(max_capacity)),
+            inserts: Arc::new(AtomicU64::new(0)),
+            evictions,
+        }
     }
     
-    /// Get a cached response
+    /// Get a cached response, if it is still fresh
     pub async fn get(&self, key: &CacheKey) -> Option<Arc<CachedResponse>> {
-        self.cache.get(key).await
+        self.lookup(key).await.filter(|lookup| lookup.fresh).map(|lookup| lookup.response)
+    }
+    
+    /// Get a cached response, including one that expired but is still retained
+    pub async fn lookup(&self, key: &CacheKey) -> Option<CacheLookup> {
+        let response = self.cache.get(key).await?;
+        let now = SystemTime::now();
+        let expires_at = self.expires_at(&response);
+        Some(CacheLookup {
+            fresh: expires_at > now,
+            expired_for: now.duration_since(expires_at).unwrap_or_default(),
+            response,
+        })
+    }
+    
+    fn is_fresh(&self, response: &CachedResponse) -> bool {
+        self.expires_at(response) > SystemTime::now()
+    }
+    
+    /// When `response` stops being fresh, by its own TTL or the cache-wide one
+    fn expires_at(&self, response: &CachedResponse) -> SystemTime {
+        let lifetime = response.meta.ttl().map_or(self.ttl, |ttl| ttl.min(self.ttl));
+        response.meta.inserted_at + lifetime
     }
     
     /// Store a response in the cache
     pub async fn put(&self, key: CacheKey, response: CachedResponse) {
+        if let Some(previous) = self.cache.get(&key).await {
+            let dropped: Vec<_> = previous
+                .meta
+                .tags
+                .iter()
+                .filter(|tag| !response.meta.tags.contains(tag))
+                .cloned()
+                .collect();
+            self.tags.remove(&key, &dropped);
+        }
+        self.tags.add(&key, &response.meta.tags);
         self.cache.insert(key, Arc::new(response)).await;
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// Store a response unless a fresh entry already exists for `key`
+    ///
+    /// Concurrent misses for one key may each produce a response, and encoders
+    /// are not required to be deterministic. The first insert wins and later ones
+    /// get the stored entry back, so it is never silently replaced by different
+    /// bytes. Expired entries kept for revalidation are replaced.
+    pub async fn put_if_absent(&self, key: CacheKey, response: CachedResponse) -> PutOutcome {
+        let tags = response.meta.tags.clone();
+        let mut dropped = Vec::new();
+        let entry = self
+            .cache
+            .entry(key.clone())
+            .or_insert_with_if(async { Arc::new(response) }, |existing| {
+                let replace = !self.is_fresh(existing);
+                if replace {
+                    dropped = existing.meta.tags.iter().filter(|tag| !tags.contains(tag)).cloned().collect();
+                }
+                replace
+            })
+            .await;
+        if !entry.is_fresh() {
+            return PutOutcome::Existing(entry.into_value());
+        }
+        
+        self.tags.remove(&key, &dropped);
+        self.tags.add(&key, &tags);
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        PutOutcome::Inserted
+    }
+    
+    /// Remove the entry stored for `key`, fresh or expired
+    pub async fn invalidate(&self, key: &CacheKey) {
+        self.cache.invalidate(key).await;
+    }
+    
+    /// Remove every entry stored for `path`, whatever its format or query string
+    ///
+    /// Returns the number of entries removed.
+    pub async fn purge_path(&self, path: &str) -> usize {
+        let entries = self.entries_for_path(path);
+        for (key, _) in &entries {
+            self.cache.invalidate(key).await;
+        }
+        entries.len()
+    }
+    
+    /// Every entry stored for `path`, whatever its format or query string
+    pub fn entries_for_path(&self, path: &str) -> Vec<(CacheKey, Arc<CachedResponse>)> {
+        self.cache
+            .iter()
+            .filter(|(key, _)| key.base_path() == path)
+            .map(|(key, response)| (key.as_ref().clone(), response))
+            .collect()
+    }
+    
+    /// Remove the other formats of `key`'s path and query unless they were made from `source_digest`
+    ///
+    /// Returns the number of entries removed.
+    pub async fn invalidate_other_variants(&self, key: &CacheKey, source_digest: &str) -> usize {
+        let CacheKey::Variant { path, format } = key else {
+            return 0;
+        };
+        let stale: Vec<_> = self
+            .cache
+            .iter()
+            .filter(|(other, response)| {
+                matches!(other.as_ref(), CacheKey::Variant { path: p, format: f } if p == path && f != format)
+                    && response.meta.source_digest.as_deref() != Some(source_digest)
+            })
+            .map(|(other, _)| other)
+            .collect();
+        for other in &stale {
+            self.cache.invalidate(other.as_ref()).await;
+        }
+        stale.len()
+    }
+    
+    /// Number of entries by how often they were hit, see [`HIT_BUCKETS`]
+    ///
+    /// Walks the whole cache, so it is meant for scrapes rather than requests.
+    pub fn hit_distribution(&self) -> [u64; HIT_BUCKETS.len()] {
+        let mut buckets = [0; HIT_BUCKETS.len()];
+        for (_, response) in self.cache.iter() {
+            let bucket = match response.access.hits() {
+                0 => 0,
+                1 => 1,
+                2..=5 => 2,
+                _ => 3,
+            };
+            buckets[bucket] += 1;
+        }
+        buckets
+    }
+    
+    /// Remove every entry tagged with `tag`
+    ///
+    /// Returns the number of entries removed.
+    pub async fn purge_tag(&self, tag: &str) -> usize {
+        let keys = self.tags.keys(tag);
+        for key in &keys {
+            self.cache.invalidate(key).await;
+        }
+        keys.len()
+    }
+    
+    /// Number of (tag, entry) pairs in the tag index
+    pub fn tag_index_size(&self) -> usize {
+        self.tags.len()
+    }
+    
+    /// Entries the cache is currently held to
+    pub fn capacity(&self) -> u64 {
+        self.capacity.load(Ordering::Relaxed)
+    }
+    
+    /// Hold the cache to `capacity` entries, at most the configured maximum
+    ///
+    /// Lowering the capacity removes nothing by itself; [`Self::shrink`] does,
+    /// and entries stored meanwhile only count against the configured maximum.
+    pub fn set_capacity(&self, capacity: u64) {
+        self.capacity.store(capacity.min(self.max_capacity), Ordering::Relaxed);
+    }
+    
+    /// Remove the coldest entries until no more than [`Self::capacity`] remain
+    ///
+    /// Entries never served from the cache go first, then those served longest
+    /// ago, older inserts before newer ones. Walks the whole cache, so it is meant
+    /// for background tasks rather than requests. Returns the number of entries removed.
+    pub async fn shrink(&self) -> usize {
+        self.cache.run_pending_tasks().await;
+        let excess = self.cache.entry_count().saturating_sub(self.capacity()) as usize;
+        if excess == 0 {
+            return 0;
+        }
+        
+        let mut entries: Vec<_> = self
+            .cache
+            .iter()
+            .map(|(key, response)| (response.access.last_access(), response.meta.inserted_at, key))
+            .collect();
+        entries.sort_unstable_by_key(|(last_access, inserted_at, _)| (*last_access, *inserted_at));
+        let removed = excess.min(entries.len());
+        for (_, _, key) in &entries[..removed] {
+            self.cache.invalidate(key.as_ref()).await;
+        }
+        self.cache.run_pending_tasks().await;
+        removed
     }
     
     /// Get cache statistics
@@ -58,15 +693,254 @@ impl ResponseCache {
         CacheStats {
             entry_count: self.cache.entry_count(),
             weighted_size: self.cache.weighted_size(),
+            max_capacity: self.capacity(),
+            inserts: self.inserts.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+    
+    /// Apply pending evictions and expirations, so statistics are up to date
+    pub async fn run_pending_tasks(&self) {
+        self.cache.run_pending_tasks().await;
+    }
+}
+
+/// Refreshes of expired entries and fetches of missing ones in flight, at most one per key
+///
+/// The first request to find an entry expired or missing leads its refresh;
+/// requests arriving meanwhile follow and can wait for the leader to finish. A
+/// leader's guard removes its key however the leader ends, including by panic
+/// or cancellation, and a leader can hand its outcome `F` to the followers
+/// first. Keys older than the deadline are assumed stuck and swept as
+/// a safety net, which releases their followers as well.
+pub struct RefreshTracker<F = ()> {
+    inflight: Arc<Mutex<HashMap<CacheKey, InflightRefresh<F>>>>,
+    deadline: Duration,
+    next_id: Arc<AtomicU64>,
+    /// Keys removed by the sweep or by an admin rather than by their leader
+    swept: Arc<AtomicU64>,
+}
+
+struct InflightRefresh<F> {
+    /// Tells the leader's own entry apart from one that replaced it after a sweep
+    id: u64,
+    started: Instant,
+    /// Followers are released when this is dropped along with the entry, and
+    /// learn the leader's outcome if it set one
+    done: watch::Sender<Option<F>>,
+}
+
+/// Part a request plays in refreshing an expired entry or fetching a missing one
+pub enum RefreshRole<F = ()> {
+    /// Refresh the entry; followers are released when the guard is dropped
+    Leader(RefreshGuard<F>),
+    /// Another request is refreshing the entry; `changed()` fails once it is
+    /// done, and the value is the leader's outcome, if it shared one
+    Follower(watch::Receiver<Option<F>>),
+}
+
+/// Marks a refresh as in flight until dropped
+pub struct RefreshGuard<F = ()> {
+    tracker: RefreshTracker<F>,
+    key: CacheKey,
+    id: u64,
+}
+
+impl<F> RefreshGuard<F> {
+    /// Hand `outcome` to the followers, which are released once the guard is dropped
+    pub fn finish(&self, outcome: F) {
+        let inflight = self.tracker.inflight.lock().unwrap();
+        if let Some(refresh) = inflight.get(&self.key).filter(|refresh| refresh.id == self.id) {
+            refresh.done.send_replace(Some(outcome));
+        }
+    }
+}
+
+impl<F> Drop for RefreshGuard<F> {
+    fn drop(&mut self) {
+        let mut inflight = self.tracker.inflight.lock().unwrap();
+        if inflight.get(&self.key).is_some_and(|refresh| refresh.id == self.id) {
+            inflight.remove(&self.key);
         }
     }
 }
 
+// Derived, it would require `F: Clone` of a tracker that never clones a failure
+impl<F> Clone for RefreshTracker<F> {
+    fn clone(&self) -> Self {
+        Self {
+            inflight: self.inflight.clone(),
+            deadline: self.deadline,
+            next_id: self.next_id.clone(),
+            swept: self.swept.clone(),
+        }
+    }
+}
+
+impl<F> RefreshTracker<F> {
+    /// Track refreshes, sweeping those still in flight after `deadline`
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            inflight: Arc::default(),
+            deadline,
+            next_id: Arc::default(),
+            swept: Arc::default(),
+        }
+    }
+    
+    /// Lead the refresh of `key`, or follow the one already in flight
+    ///
+    /// A refresh past the deadline is replaced, so the caller leads a new one.
+    pub fn join(&self, key: &CacheKey) -> RefreshRole<F> {
+        let mut inflight = self.inflight.lock().unwrap();
+        match inflight.get(key) {
+            Some(refresh) if refresh.started.elapsed() < self.deadline => {
+                return RefreshRole::Follower(refresh.done.subscribe());
+            }
+            Some(_) => {
+                warn!("Refresh of {:?} still in flight after {:?}, starting over", key, self.deadline);
+                self.swept.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {}
+        }
+        
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (done, _) = watch::channel(None);
+        inflight.insert(key.clone(), InflightRefresh { id, started: Instant::now(), done });
+        RefreshRole::Leader(RefreshGuard {
+            tracker: self.clone(),
+            key: key.clone(),
+            id,
+        })
+    }
+    
+    /// Remove the refreshes in flight for longer than the deadline, returning how many
+    pub fn sweep(&self) -> usize {
+        self.remove_where(|_, refresh| refresh.started.elapsed() >= self.deadline)
+    }
+    
+    /// Remove the refreshes in flight for every key of `path`, returning how many
+    ///
+    /// Their followers stop waiting; the leaders carry on but no longer block anyone.
+    pub fn clear_path(&self, path: &str) -> usize {
+        self.remove_where(|key, _| key.base_path() == path)
+    }
+    
+    fn remove_where(&self, mut remove: impl FnMut(&CacheKey, &InflightRefresh<F>) -> bool) -> usize {
+        let mut inflight = self.inflight.lock().unwrap();
+        let before = inflight.len();
+        inflight.retain(|key, refresh| !remove(key, refresh));
+        let removed = before - inflight.len();
+        self.swept.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+    
+    /// Keys with a refresh in flight and how long ago each started
+    pub fn entries(&self) -> Vec<(CacheKey, Duration)> {
+        let inflight = self.inflight.lock().unwrap();
+        inflight.iter().map(|(key, refresh)| (key.clone(), refresh.started.elapsed())).collect()
+    }
+    
+    /// Number of keys with a refresh in flight
+    pub fn len(&self) -> usize {
+        self.inflight.lock().unwrap().len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    /// Refreshes removed so far by the sweep or by an admin
+    pub fn swept(&self) -> u64 {
+        self.swept.load(Ordering::Relaxed)
+    }
+}
+
+/// Sweep the stuck refreshes of `tracker` every `interval` until `cancel` fires
+///
+/// Keys nobody joins again are otherwise only dropped by an admin.
+pub fn spawn_sweeper<F: Send + Sync + 'static>(tracker: RefreshTracker<F>, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    let swept = tracker.sweep();
+                    if swept > 0 {
+                        warn!("Swept {} refreshes stuck for longer than {:?}", swept, tracker.deadline);
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Decides which missed responses are stored
+///
+/// Under the second-hit policy a key is admitted only when it misses again
+/// within the window. Between the two misses just a 64-bit fingerprint of the
+/// key is remembered, never the response.
+#[derive(Clone)]
+pub struct AdmissionFilter {
+    seen: Option<Cache<u64, ()>>,
+}
+
+impl AdmissionFilter {
+    /// Admit every response
+    pub fn always() -> Self {
+        Self { seen: None }
+    }
+    
+    /// Admit keys on their second miss within `window`, remembering up to `capacity` first misses
+    pub fn second_hit(window: Duration, capacity: u64) -> Self {
+        let seen = Cache::builder()
+            .max_capacity(capacity)
+            .time_to_live(window)
+            .build();
+        Self { seen: Some(seen) }
+    }
+    
+    /// Whether a response for `key` should be stored, remembering the miss if not
+    pub async fn admit(&self, key: &CacheKey) -> bool {
+        let Some(seen) = &self.seen else {
+            return true;
+        };
+        
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        // `remove` can hand back an expired entry, `get` never does
+        if seen.get(&fingerprint).await.is_some() {
+            seen.invalidate(&fingerprint).await;
+            return true;
+        }
+        seen.insert(fingerprint, ()).await;
+        false
+    }
+}
+
 /// Cache statistics
 #[derive(Debug, Clone)]
 pub struct CacheStats {
     pub entry_count: u64,
     pub weighted_size: u64,
+    pub max_capacity: u64,
+    /// Entries stored so far
+    pub inserts: u64,
+    /// Entries evicted so far for lack of capacity
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// Share of the capacity in use, in percent
+    pub fn utilization_percent(&self) -> f64 {
+        if self.max_capacity == 0 {
+            return 100.0;
+        }
+        self.weighted_size as f64 * 100.0 / self.max_capacity as f64
+    }
 }
 
 #[cfg(test)]
@@ -79,17 +953,66 @@ mod tests {
         let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
         
         let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
-        let response = CachedResponse {
-            data: Bytes::from("test data"),
-            content_type: "image/avif".to_string(),
-            upstream_headers: None,
-        };
+        let response = CachedResponse::new(
+            Bytes::from("test data"),
+            CachedMeta::new("image/avif".to_string(), StatusCode::OK),
+        );
         
         cache.put(key.clone(), response.clone()).await;
         
         let cached = cache.get(&key).await;
         assert!(cached.is_some());
-        assert_eq!(cached.unwrap().content_type, "image/avif");
+        assert_eq!(cached.unwrap().meta.content_type, "image/avif");
+    }
+
+    #[tokio::test]
+    async fn test_hit_distribution() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        let hits = [0, 1, 2, 5, 6, 9];
+        for (i, count) in hits.iter().enumerate() {
+            let response = CachedResponse::new(Bytes::from("x"), CachedMeta::new("text/plain".to_string(), StatusCode::OK));
+            for _ in 0..*count {
+                response.access.record_hit();
+            }
+            cache.put(CacheKey::new(format!("/media/{}.txt", i), "Original".to_string()), response).await;
+        }
+        cache.run_pending_tasks().await;
+        assert_eq!(cache.hit_distribution(), [1, 1, 2, 2]);
+        
+        let entries = cache.entries_for_path("/media/5.txt");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].1.access.hits(), 9);
+        assert!(entries[0].1.access.last_access().is_some());
+        assert!(cache.entries_for_path("/media/0.txt")[0].1.access.last_access().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shrink_removes_coldest_entries() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        for i in 0..6 {
+            let response = CachedResponse::new(Bytes::from("x"), CachedMeta::new("text/plain".to_string(), StatusCode::OK));
+            // Entries 0 and 1 are never served, the others in order
+            if i >= 2 {
+                response.access.record_hit();
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            cache.put(CacheKey::new(format!("/media/{}.txt", i), "Original".to_string()), response).await;
+        }
+
+        // Nothing is removed while the cache fits
+        assert_eq!(cache.shrink().await, 0);
+        cache.set_capacity(3);
+        assert_eq!(cache.stats().max_capacity, 3);
+        assert_eq!(cache.shrink().await, 3);
+        assert_eq!(cache.stats().entry_count, 3);
+        for i in 0..6 {
+            let kept = !cache.entries_for_path(&format!("/media/{}.txt", i)).is_empty();
+            assert_eq!(kept, i >= 3, "{}", i);
+        }
+
+        // The capacity never exceeds the configured maximum
+        cache.set_capacity(1000);
+        assert_eq!(cache.capacity(), 100);
     }
 
     #[tokio::test]
@@ -114,21 +1037,20 @@ mod tests {
         );
         
         let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
-        let response = CachedResponse {
-            data: Bytes::from("test data"),
-            content_type: "image/avif".to_string(),
-            upstream_headers: Some(headers.clone()),
-        };
+        let response = CachedResponse::new(
+            Bytes::from("test data"),
+            CachedMeta::new("image/avif".to_string(), StatusCode::OK).with_headers(Some(headers.clone())),
+        );
         
         cache.put(key.clone(), response.clone()).await;
         
         let cached = cache.get(&key).await;
         assert!(cached.is_some());
         let cached = cached.unwrap();
-        assert_eq!(cached.content_type, "image/avif");
-        assert!(cached.upstream_headers.is_some());
+        assert_eq!(cached.meta.content_type, "image/avif");
+        assert!(cached.meta.headers.is_some());
         
-        let cached_headers = cached.upstream_headers.as_ref().unwrap();
+        let cached_headers = cached.meta.headers.as_ref().unwrap();
         assert_eq!(
             cached_headers.get("x-custom-header").unwrap(),
             "test-value"
@@ -141,11 +1063,10 @@ mod tests {
         let cache = ResponseCache::new(100, Duration::from_secs(1), 1024 * 1024);
         
         let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
-        let response = CachedResponse {
-            data: Bytes::from("test data"),
-            content_type: "image/avif".to_string(),
-            upstream_headers: None,
-        };
+        let response = CachedResponse::new(
+            Bytes::from("test data"),
+            CachedMeta::new("image/avif".to_string(), StatusCode::OK),
+        );
         
         cache.put(key.clone(), response.clone()).await;
         
@@ -160,4 +1081,344 @@ mod tests {
         let cached = cache.get(&key).await;
         assert!(cached.is_none());
     }
+    
+    #[tokio::test]
+    async fn test_cache_per_entry_ttl() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        
+        let key = CacheKey::new("/media/missing.jpg".to_string(), "avif".to_string());
+        let response = CachedResponse::new(
+            Bytes::from("not found"),
+            CachedMeta::new("text/plain".to_string(), StatusCode::NOT_FOUND)
+                .with_ttl(Some(Duration::from_secs(1))),
+        );
+        
+        cache.put(key.clone(), response).await;
+        assert!(cache.get(&key).await.is_some());
+        
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(cache.get(&key).await.is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_cache_keeps_stale_entries() {
+        let cache = ResponseCache::with_stale_ttl(100, Duration::from_secs(1), 1024 * 1024, Duration::from_secs(60));
+        
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        let meta = CachedMeta::new("image/avif".to_string(), StatusCode::OK);
+        cache.put(key.clone(), CachedResponse::new(Bytes::from("test data"), meta)).await;
+        assert!(cache.lookup(&key).await.unwrap().fresh);
+        
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get(&key).await.is_none());
+        let stale = cache.lookup(&key).await.unwrap();
+        assert!(!stale.fresh);
+        
+        // Refreshing restarts the entry's lifetime
+        let refreshed = CachedResponse::new(stale.response.data.clone(), stale.response.meta.refreshed());
+        cache.put(key.clone(), refreshed).await;
+        assert!(cache.get(&key).await.is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_racing_inserts_keep_the_first_body() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        let key = CacheKey::new("/media/race.jpg".to_string(), "Avif".to_string());
+        let barrier = Arc::new(tokio::sync::Barrier::new(8));
+        
+        // Each worker produced different bytes for the same key, as nondeterministic encoders may
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let (cache, key, barrier) = (cache.clone(), key.clone(), barrier.clone());
+                tokio::spawn(async move {
+                    let meta = CachedMeta::new("image/avif".to_string(), StatusCode::OK).with_tags(vec![format!("worker:{}", i)]);
+                    let body = Bytes::from(format!("body {}", i));
+                    barrier.wait().await;
+                    match cache.put_if_absent(key, CachedResponse::new(body.clone(), meta)).await {
+                        PutOutcome::Inserted => (true, body),
+                        PutOutcome::Existing(entry) => (false, entry.data.clone()),
+                    }
+                })
+            })
+            .collect();
+        let mut winners = 0;
+        let mut bodies = HashSet::new();
+        for worker in workers {
+            let (won, served) = worker.await.unwrap();
+            winners += usize::from(won);
+            bodies.insert(served);
+        }
+        
+        // Every worker serves the winner's body, which stays in the cache
+        assert_eq!(winners, 1);
+        assert_eq!(bodies.len(), 1, "{:?}", bodies);
+        let stored = cache.get(&key).await.unwrap();
+        assert!(bodies.contains(&stored.data));
+        assert_eq!(cache.stats().inserts, 1);
+        assert_eq!(cache.tag_index_size(), 1);
+        assert_eq!(cache.purge_tag(&stored.meta.tags[0]).await, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_put_if_absent_replaces_stale_entries() {
+        let cache = ResponseCache::with_stale_ttl(100, Duration::from_secs(60), 1024 * 1024, Duration::from_secs(60));
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        let entry = |body: &'static str, tag: &str, ttl| {
+            let meta = CachedMeta::new("image/avif".to_string(), StatusCode::OK)
+                .with_ttl(Some(ttl))
+                .with_tags(vec![tag.to_string()]);
+            CachedResponse::new(Bytes::from(body), meta)
+        };
+        
+        assert!(matches!(cache.put_if_absent(key.clone(), entry("old", "old", Duration::from_millis(50))).await, PutOutcome::Inserted));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!cache.lookup(&key).await.unwrap().fresh);
+        
+        assert!(matches!(cache.put_if_absent(key.clone(), entry("new", "new", Duration::from_secs(60))).await, PutOutcome::Inserted));
+        assert_eq!(cache.get(&key).await.unwrap().data, "new");
+        assert_eq!(cache.purge_tag("old").await, 0);
+        
+        match cache.put_if_absent(key.clone(), entry("newer", "new", Duration::from_secs(60))).await {
+            PutOutcome::Existing(existing) => assert_eq!(existing.data, "new"),
+            PutOutcome::Inserted => panic!("Fresh entry was replaced"),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_purge_path_removes_every_variant() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        let entry = || CachedResponse::new(Bytes::from("x"), CachedMeta::new("image/avif".to_string(), StatusCode::OK));
+        let keys = [
+            CacheKey::new("/media/a.jpg".to_string(), "Avif".to_string()),
+            CacheKey::new("/media/a.jpg?frame=first".to_string(), "WebP:static".to_string()),
+            CacheKey::negative("/media/a.jpg".to_string()),
+        ];
+        let other = CacheKey::new("/media/a.jpg2".to_string(), "Avif".to_string());
+        for key in keys.iter().chain([&other]) {
+            cache.put(key.clone(), entry()).await;
+        }
+
+        assert_eq!(cache.purge_path("/media/a.jpg").await, 3);
+        for key in &keys {
+            assert!(cache.get(key).await.is_none());
+        }
+        assert!(cache.get(&other).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_purge_tag() {
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024 * 1024);
+        let tagged = |tags: &[&str]| CachedResponse::new(
+            Bytes::from("data"),
+            CachedMeta::new("image/avif".to_string(), StatusCode::OK)
+                .with_tags(tags.iter().map(|tag| tag.to_string()).collect()),
+        );
+        let key = |path: &str| CacheKey::new(path.to_string(), "avif".to_string());
+        
+        cache.put(key("/media/a.jpg"), tagged(&["domain:a.example", "type:image", "converted"])).await;
+        cache.put(key("/media/b.jpg"), tagged(&["domain:a.example", "type:image", "original"])).await;
+        cache.put(key("/media/c.jpg"), tagged(&["domain:b.example", "type:image", "converted"])).await;
+        cache.put(CacheKey::negative("/media/d.jpg".to_string()), tagged(&["domain:b.example"])).await;
+        assert_eq!(cache.tag_index_size(), 10);
+        
+        assert_eq!(cache.purge_tag("domain:a.example").await, 2);
+        cache.cache.run_pending_tasks().await;
+        assert!(cache.get(&key("/media/a.jpg")).await.is_none());
+        assert!(cache.get(&key("/media/b.jpg")).await.is_none());
+        assert!(cache.get(&key("/media/c.jpg")).await.is_some());
+        assert!(cache.get(&CacheKey::negative("/media/d.jpg".to_string())).await.is_some());
+        assert_eq!(cache.tag_index_size(), 4);
+        assert_eq!(cache.purge_tag("domain:a.example").await, 0);
+        
+        // Replacing an entry drops the tags it no longer carries
+        cache.put(key("/media/c.jpg"), tagged(&["domain:b.example", "type:image", "original"])).await;
+        cache.cache.run_pending_tasks().await;
+        assert_eq!(cache.purge_tag("converted").await, 0);
+        assert_eq!(cache.tag_index_size(), 4);
+        
+        assert_eq!(cache.purge_tag("domain:b.example").await, 2);
+        cache.cache.run_pending_tasks().await;
+        assert_eq!(cache.tag_index_size(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_second_hit_admission() {
+        let filter = AdmissionFilter::second_hit(Duration::from_secs(1), 100);
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        let other_format = CacheKey::new("/media/test.jpg".to_string(), "webp".to_string());
+        
+        assert!(!filter.admit(&key).await);
+        assert!(!filter.admit(&other_format).await);
+        assert!(filter.admit(&key).await);
+        
+        // First misses are forgotten after the window
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(!filter.admit(&other_format).await);
+        
+        assert!(AdmissionFilter::always().admit(&key).await);
+    }
+    
+    #[tokio::test]
+    async fn test_refresh_tracker_single_leader() {
+        let tracker = RefreshTracker::<()>::new(Duration::from_secs(60));
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        
+        let RefreshRole::Leader(guard) = tracker.join(&key) else {
+            panic!("first request must lead");
+        };
+        let RefreshRole::Follower(mut done) = tracker.join(&key) else {
+            panic!("second request must follow");
+        };
+        let other = CacheKey::new("/media/other.jpg".to_string(), "avif".to_string());
+        assert!(matches!(tracker.join(&other), RefreshRole::Leader(_)));
+        
+        drop(guard);
+        assert!(done.changed().await.is_err());
+        assert!(matches!(tracker.join(&key), RefreshRole::Leader(_)));
+    }
+    
+    #[tokio::test]
+    async fn test_refresh_tracker_shares_outcome() {
+        let tracker = RefreshTracker::new(Duration::from_secs(60));
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        
+        let RefreshRole::Leader(guard) = tracker.join(&key) else {
+            panic!("first request must lead");
+        };
+        let RefreshRole::Follower(mut done) = tracker.join(&key) else {
+            panic!("second request must follow");
+        };
+        guard.finish("upstream down");
+        let RefreshRole::Follower(late) = tracker.join(&key) else {
+            panic!("the key stays in flight until the guard is dropped");
+        };
+        drop(guard);
+        assert!(done.changed().await.is_ok());
+        assert_eq!(*done.borrow(), Some("upstream down"));
+        assert_eq!(*late.borrow(), Some("upstream down"));
+        
+        // The next leader starts without a failure
+        let RefreshRole::Leader(_guard) = tracker.join(&key) else {
+            panic!("a finished refresh must be replaced");
+        };
+        let RefreshRole::Follower(done) = tracker.join(&key) else {
+            panic!("second request must follow");
+        };
+        assert_eq!(*done.borrow(), None);
+    }
+    
+    #[tokio::test]
+    async fn test_refresh_tracker_cleans_up() {
+        let tracker = RefreshTracker::<()>::new(Duration::from_millis(100));
+        let key = CacheKey::new("/media/test.jpg".to_string(), "avif".to_string());
+        
+        // A panicking leader still removes its key
+        let panicking = tracker.clone();
+        let panicked_key = key.clone();
+        assert!(std::panic::catch_unwind(move || {
+            let _role = panicking.join(&panicked_key);
+            panic!("leader failed");
+        })
+        .is_err());
+        assert!(tracker.is_empty());
+        
+        // A stuck leader is replaced once past the deadline, releasing its followers
+        let RefreshRole::Leader(stuck) = tracker.join(&key) else {
+            panic!("first request must lead");
+        };
+        let RefreshRole::Follower(mut done) = tracker.join(&key) else {
+            panic!("second request must follow");
+        };
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let RefreshRole::Leader(replacement) = tracker.join(&key) else {
+            panic!("a stuck refresh must be replaced");
+        };
+        assert!(done.changed().await.is_err());
+        assert_eq!(tracker.swept(), 1);
+        
+        // The stuck leader finishing late leaves its replacement alone
+        drop(stuck);
+        assert_eq!(tracker.len(), 1);
+        drop(replacement);
+        assert!(tracker.is_empty());
+        
+        // The sweep removes what nobody joins again, an admin anything of a path
+        let _forgotten = tracker.join(&key);
+        let _other = tracker.join(&CacheKey::negative("/media/other.jpg".to_string()));
+        assert_eq!(tracker.clear_path("/media/other.jpg"), 1);
+        assert_eq!(tracker.sweep(), 0);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(tracker.sweep(), 1);
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.swept(), 3);
+    }
+    
+    #[test]
+    fn test_cached_meta_serialization_round_trip() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-multi", HeaderValue::from_static("a"));
+        headers.append("x-multi", HeaderValue::from_static("b"));
+        headers.insert("x-binary", HeaderValue::from_bytes(b"\xff\xfe").unwrap());
+        let meta = CachedMeta::new("image/webp".to_string(), StatusCode::GONE)
+            .with_ttl(Some(Duration::from_secs(60)))
+            .with_headers(Some(headers))
+            .with_original_size(1234);
+        
+        let json = serde_json::to_string(&meta).unwrap();
+        let decoded: CachedMeta = serde_json::from_str(&json).unwrap();
+        
+        assert_eq!(decoded.version, CACHE_META_VERSION);
+        assert_eq!(decoded.content_type, "image/webp");
+        assert_eq!(decoded.status, StatusCode::GONE);
+        assert_eq!(decoded.ttl(), Some(Duration::from_secs(60)));
+        assert_eq!(decoded.original_size, Some(1234));
+        let headers = decoded.headers.unwrap();
+        let multi: Vec<_> = headers.get_all("x-multi").iter().collect();
+        assert_eq!(multi, vec!["a", "b"]);
+        assert_eq!(headers.get("x-binary").unwrap().as_bytes(), b"\xff\xfe");
+    }
+    
+    #[test]
+    fn test_status_policy_table() {
+        let policy = StatusPolicyConfig {
+            permanent_redirect_ttl: 300,
+            not_found_ttl: 60,
+            denied_ttl: 30,
+            error_ttl: 5,
+        };
+        let ttl = |code: u16| status_ttl(StatusCode::from_u16(code).unwrap(), &policy);
+        
+        for code in [301, 308] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::PermanentRedirect);
+            assert_eq!(ttl(code), Some(Duration::from_secs(300)));
+        }
+        for code in [302, 303, 307] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::TemporaryRedirect);
+            assert_eq!(ttl(code), None);
+        }
+        for code in [404, 410] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::NotFound);
+            assert_eq!(ttl(code), Some(Duration::from_secs(60)));
+        }
+        for code in [401, 403] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::Denied);
+            assert_eq!(ttl(code), Some(Duration::from_secs(30)));
+        }
+        for code in [429, 500, 502, 503, 504] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::Error);
+            assert_eq!(ttl(code), Some(Duration::from_secs(5)));
+        }
+        for code in [300, 304, 400, 405, 418] {
+            assert_eq!(StatusClass::of(StatusCode::from_u16(code).unwrap()), StatusClass::Uncacheable);
+            assert_eq!(ttl(code), None);
+        }
+        assert_eq!(StatusClass::of(StatusCode::OK), StatusClass::Success);
+        
+        // A zero TTL disables caching for that class
+        let policy = StatusPolicyConfig { error_ttl: 0, ..policy };
+        assert_eq!(status_ttl(StatusCode::SERVICE_UNAVAILABLE, &policy), None);
+        
+        assert_eq!(status_cache_control(Some(Duration::from_secs(60))), "public, max-age=60");
+        assert_eq!(status_cache_control(None), "no-store");
+    }
 }
@@ -0,0 +1,82 @@
+//! Conditional requests, answered with 304 when the client's copy is current
+//!
+//! Browsers revisiting media send back the `ETag` and `Last-Modified` they were
+//! served. `If-None-Match` compares ETags weakly, as HTTP requires for GET, and
+//! takes precedence over `If-Modified-Since`, which is only looked at without it.
+
+use axum::http::{header, HeaderMap, HeaderValue};
+
+/// Whether a response with `response_headers` is unchanged for a client sending `request_headers`
+///
+/// Responses without the validator the client asks about are always sent in full.
+pub fn is_not_modified(request_headers: &HeaderMap, response_headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = request_headers.get(header::IF_NONE_MATCH) {
+        let Some(etag) = response_headers.get(header::ETAG).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        return if_none_match
+            .to_str()
+            .is_ok_and(|tags| tags.split(',').map(str::trim).any(|tag| tag == "*" || opaque_tag(tag) == opaque_tag(etag)));
+    }
+
+    let date = |headers: &HeaderMap, name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+    };
+    match (date(request_headers, header::IF_MODIFIED_SINCE), date(response_headers, header::LAST_MODIFIED)) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+/// An entity tag without its weakness indicator
+fn opaque_tag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.clone(), HeaderValue::from_static(value))).collect()
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let response = headers(&[(header::ETAG, "\"v1\""), (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"v1\"")]), &response));
+        assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"v0\", \"v1\"")]), &response));
+        assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "*")]), &response));
+        assert!(!is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"v2\"")]), &response));
+
+        // Weak tags match strong ones of the same value, either way round
+        assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "W/\"v1\"")]), &response));
+        let weak = headers(&[(header::ETAG, "W/\"v1\"")]);
+        assert!(is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"v1\"")]), &weak));
+
+        // A failing If-None-Match is not overruled by a matching date
+        let both = headers(&[(header::IF_NONE_MATCH, "\"v2\""), (header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert!(!is_not_modified(&both, &response));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let response = headers(&[(header::ETAG, "\"v1\""), (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert!(is_not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]), &response));
+        assert!(is_not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "Thu, 22 Oct 2015 07:28:00 GMT")]), &response));
+        assert!(!is_not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "Tue, 20 Oct 2015 07:28:00 GMT")]), &response));
+        assert!(!is_not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "yesterday")]), &response));
+    }
+
+    #[test]
+    fn test_missing_validators() {
+        let response = HeaderMap::new();
+        assert!(!is_not_modified(&HeaderMap::new(), &headers(&[(header::ETAG, "\"v1\"")])));
+        assert!(!is_not_modified(&headers(&[(header::IF_NONE_MATCH, "\"v1\"")]), &response));
+        assert!(!is_not_modified(&headers(&[(header::IF_NONE_MATCH, "*")]), &response));
+        assert!(!is_not_modified(&headers(&[(header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")]), &response));
+    }
+}
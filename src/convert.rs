@@ -0,0 +1,444 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ImageConfig;
+use crate::image::{ImageConverter, ImageError, OutputFormat};
+use crate::proxy::build_converter;
+
+/// Arguments of the `convert` subcommand
+#[derive(Args, Debug)]
+pub struct ConvertArgs {
+    /// Image file to convert
+    #[arg(short, long, value_name = "FILE")]
+    pub input: PathBuf,
+
+    /// Output format
+    #[arg(short, long, value_enum)]
+    pub format: FormatArg,
+
+    /// Quality for conversions (1-100)
+    #[arg(short, long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: u8,
+
+    /// Maximum image dimension, larger images are scaled down
+    #[arg(long, default_value_t = 4096)]
+    pub max_dimension: u32,
+
+    /// Where to write the converted image
+    #[arg(short, long, value_name = "FILE")]
+    pub output: PathBuf,
+}
+
+/// Arguments of the `pipe` subcommand
+///
+/// Unset settings are taken from the `[image]` section of the configuration file,
+/// if one is given, so the output matches what the server would produce.
+#[derive(Args, Debug)]
+pub struct PipeArgs {
+    /// Output format
+    #[arg(short, long, value_enum)]
+    pub format: FormatArg,
+
+    /// Quality for conversions (1-100) [default: image.quality, or 85]
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: Option<u8>,
+
+    /// Maximum image dimension, larger images are scaled down [default: image.max_dimension, or 4096]
+    #[arg(long)]
+    pub max_dimension: Option<u32>,
+
+    /// Write the metadata JSON to this file descriptor instead of stderr (Unix only)
+    #[arg(long, value_name = "FD")]
+    pub meta_fd: Option<i32>,
+}
+
+impl PipeArgs {
+    /// Converter applying `image` with the settings given on the command line replacing its own
+    ///
+    /// The requested format is always produced, even one `image` leaves disabled.
+    pub fn converter(&self, image: &ImageConfig) -> ImageConverter {
+        let image = ImageConfig {
+            quality: self.quality.unwrap_or(image.quality),
+            max_dimension: self.max_dimension.unwrap_or(image.max_dimension),
+            enable_avif: true,
+            enable_webp: true,
+            ..image.clone()
+        };
+        build_converter(&image)
+    }
+}
+
+/// Output formats selectable on the command line
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum FormatArg {
+    Avif,
+    Webp,
+    Jpeg,
+    Png,
+}
+
+impl From<FormatArg> for OutputFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Avif => OutputFormat::Avif,
+            FormatArg::Webp => OutputFormat::WebP,
+            FormatArg::Jpeg => OutputFormat::Jpeg,
+            FormatArg::Png => OutputFormat::Png,
+        }
+    }
+}
+
+/// Outcome of a single-file conversion
+#[derive(Debug)]
+pub struct ConvertReport {
+    pub source_format: Option<image::ImageFormat>,
+    pub input_size: usize,
+    pub output_size: usize,
+    pub content_type: &'static str,
+    pub elapsed: Duration,
+}
+
+/// Convert a single file with the same pipeline the server uses
+pub fn run(args: &ConvertArgs) -> Result<ConvertReport> {
+    let data = Bytes::from(
+        fs::read(&args.input)
+            .with_context(|| format!("Failed to read {}", args.input.display()))?,
+    );
+
+    let converter = ImageConverter::new(args.quality, args.max_dimension, true, true);
+    let started = Instant::now();
+    let (converted, content_type) = converter
+        .convert(&data, args.format.into(), &CancellationToken::new())
+        .with_context(|| format!("Failed to convert {}", args.input.display()))?;
+    let elapsed = started.elapsed();
+
+    fs::write(&args.output, &converted)
+        .with_context(|| format!("Failed to write {}", args.output.display()))?;
+
+    Ok(ConvertReport {
+        source_format: image::guess_format(&data).ok(),
+        input_size: data.len(),
+        output_size: converted.len(),
+        content_type,
+        elapsed,
+    })
+}
+
+/// Failure of a piped conversion, each kind with its own exit code
+#[derive(Debug, thiserror::Error)]
+pub enum PipeError {
+    #[error("unsupported input: {0}")]
+    Unsupported(String),
+    #[error("failed to decode input: {0}")]
+    Decode(String),
+    #[error("failed to convert: {0}")]
+    Encode(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl PipeError {
+    /// Process exit code; 2 is left to usage errors
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            PipeError::Io(_) => 1,
+            PipeError::Unsupported(_) => 3,
+            PipeError::Decode(_) => 4,
+            PipeError::Encode(_) => 5,
+        }
+    }
+
+    /// Name of the failure in the metadata JSON
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PipeError::Io(_) => "io",
+            PipeError::Unsupported(_) => "unsupported",
+            PipeError::Decode(_) => "decode",
+            PipeError::Encode(_) => "encode",
+        }
+    }
+}
+
+impl From<ImageError> for PipeError {
+    fn from(error: ImageError) -> Self {
+        match error {
+            ImageError::DecodeUnsupported(_) => PipeError::Unsupported(error.to_string()),
+            ImageError::DecodeCorrupt(_) | ImageError::DecodeLimitsExceeded(_) => PipeError::Decode(error.to_string()),
+            ImageError::EncodeFailed { .. } | ImageError::Resize(_) | ImageError::Cancelled(_) => {
+                PipeError::Encode(error.to_string())
+            }
+        }
+    }
+}
+
+/// Metadata of a piped conversion, written as JSON next to the image
+#[derive(Debug, Serialize)]
+pub struct PipeMeta {
+    /// Media type of the input, `null` when it was not recognized
+    pub source_format: Option<&'static str>,
+    /// Media type of the output
+    pub output_format: &'static str,
+    /// Whether the input was converted; sources in a tier without conversion are copied
+    pub converted: bool,
+    pub source_width: u32,
+    pub source_height: u32,
+    pub width: u32,
+    pub height: u32,
+    pub input_size: usize,
+    pub output_size: usize,
+    /// Time spent resizing, part of `convert_ms`
+    pub resize_ms: f64,
+    pub convert_ms: f64,
+}
+
+/// Convert the image read from `input` to `format`, writing the result to `output`
+///
+/// Nothing is written unless the conversion succeeds.
+pub fn pipe(
+    converter: &ImageConverter,
+    format: OutputFormat,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<PipeMeta, PipeError> {
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    let data = Bytes::from(data);
+
+    let source_format = image::guess_format(&data).ok();
+    let (source_width, source_height) = image::ImageReader::new(std::io::Cursor::new(&data))
+        .with_guessed_format()?
+        .into_dimensions()
+        .unwrap_or((0, 0));
+
+    // Sources in a tier without conversion are copied
+    let started = Instant::now();
+    let (encoded, output_format, (width, height), resize) = match converter.encode_settings(data.len()) {
+        Some(settings) => {
+            let decoded = converter.decode(&data, &CancellationToken::new())?;
+            let converted = converter.encode(&decoded, format, settings)?;
+            (converted.data, converted.mime_type, decoded.dimensions(), Some(decoded.resize.1))
+        }
+        None => {
+            let mime_type = source_format.map_or("application/octet-stream", |format| format.to_mime_type());
+            (data.clone(), mime_type, (source_width, source_height), None)
+        }
+    };
+    let convert_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    output.write_all(&encoded)?;
+    output.flush()?;
+
+    Ok(PipeMeta {
+        source_format: source_format.map(|format| format.to_mime_type()),
+        output_format,
+        converted: resize.is_some(),
+        source_width,
+        source_height,
+        width,
+        height,
+        input_size: data.len(),
+        output_size: encoded.len(),
+        resize_ms: resize.map_or(0.0, |duration| duration.as_secs_f64() * 1000.0),
+        convert_ms,
+    })
+}
+
+/// Run the `pipe` subcommand, returning the process exit code
+///
+/// The metadata, or the kind and message of the failure, is written to `meta`
+/// as one line of JSON.
+pub fn run_pipe(
+    args: &PipeArgs,
+    image: &ImageConfig,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    meta: &mut impl Write,
+) -> std::io::Result<u8> {
+    let (line, code) = match pipe(&args.converter(image), args.format.into(), input, output) {
+        Ok(report) => (serde_json::to_value(&report).map_err(std::io::Error::other)?, 0),
+        Err(e) => (serde_json::json!({ "error": e.kind(), "message": e.to_string() }), e.exit_code()),
+    };
+    writeln!(meta, "{}", line)?;
+    Ok(code)
+}
+
+/// Where the metadata of a piped conversion goes: `meta_fd`, or stderr
+pub fn meta_writer(meta_fd: Option<i32>) -> Result<Box<dyn Write>> {
+    match meta_fd {
+        None => Ok(Box::new(std::io::stderr())),
+        Some(fd) => open_fd(fd),
+    }
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<Box<dyn Write>> {
+    use std::os::fd::FromRawFd;
+
+    if fd <= 2 {
+        anyhow::bail!("--meta-fd {} is a standard stream", fd);
+    }
+    // SAFETY: the descriptor was opened for this process by its parent, which
+    // handed it over on the command line; nothing else in the process uses it.
+    Ok(Box::new(unsafe { fs::File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<Box<dyn Write>> {
+    anyhow::bail!("--meta-fd is only supported on Unix")
+}
+
+/// Print a conversion report for humans
+pub fn print_report(args: &ConvertArgs, report: &ConvertReport) {
+    let source_format = report
+        .source_format
+        .map(|format| format!("{:?}", format))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("Source format: {}", source_format);
+    println!("Input:  {} ({} bytes)", args.input.display(), report.input_size);
+    println!("Output: {} ({} bytes, {})", args.output.display(), report.output_size, report.content_type);
+    println!("Time:   {:.1?}", report.elapsed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("akkoproxy-convert-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_convert_round_trip() {
+        let input = temp_path("input.png");
+        image::RgbImage::from_pixel(64, 32, image::Rgb([10, 200, 30]))
+            .save(&input)
+            .unwrap();
+
+        for (format, expected) in [
+            (FormatArg::Avif, image::ImageFormat::Avif),
+            (FormatArg::Webp, image::ImageFormat::WebP),
+            (FormatArg::Jpeg, image::ImageFormat::Jpeg),
+        ] {
+            let output = temp_path(&format!("output-{:?}", format));
+            let args = ConvertArgs {
+                input: input.clone(),
+                format,
+                quality: 60,
+                max_dimension: 16,
+                output: output.clone(),
+            };
+
+            let report = run(&args).unwrap();
+            assert_eq!(report.source_format, Some(image::ImageFormat::Png));
+
+            let written = fs::read(&output).unwrap();
+            assert_eq!(written.len(), report.output_size);
+            assert_eq!(image::guess_format(&written).unwrap(), expected);
+            if expected != image::ImageFormat::Avif {
+                let decoded = image::load_from_memory(&written).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (16, 8));
+            }
+            fs::remove_file(output).unwrap();
+        }
+
+        fs::remove_file(input).unwrap();
+    }
+
+    fn pipe_args(format: FormatArg, max_dimension: Option<u32>) -> PipeArgs {
+        PipeArgs {
+            format,
+            quality: None,
+            max_dimension,
+            meta_fd: None,
+        }
+    }
+
+    fn encode_png(image: image::DynamicImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        image.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        png
+    }
+
+    /// Exit code, output and metadata of piping `input` through `args`
+    fn run_piped(args: &PipeArgs, image: &ImageConfig, input: &[u8]) -> (u8, Vec<u8>, serde_json::Value) {
+        let (mut output, mut meta) = (Vec::new(), Vec::new());
+        let code = run_pipe(args, image, &mut &input[..], &mut output, &mut meta).unwrap();
+        assert!(meta.ends_with(b"\n"));
+        (code, output, serde_json::from_slice(&meta).unwrap())
+    }
+
+    #[test]
+    fn test_pipe_converts_and_reports() {
+        let png = encode_png(image::DynamicImage::ImageRgb8(image::RgbImage::from_pixel(64, 32, image::Rgb([10, 200, 30]))));
+        let image = ImageConfig {
+            max_dimension: 16,
+            enable_webp: false,
+            ..ImageConfig::default()
+        };
+
+        // The configuration supplies the defaults, the requested format is produced even if disabled there
+        let (code, output, meta) = run_piped(&pipe_args(FormatArg::Webp, None), &image, &png);
+        assert_eq!(code, 0);
+        assert_eq!(image::guess_format(&output).unwrap(), image::ImageFormat::WebP);
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+        assert_eq!(meta["source_format"], "image/png");
+        assert_eq!(meta["output_format"], "image/webp");
+        assert_eq!(meta["converted"], true);
+        assert_eq!((meta["source_width"].as_u64(), meta["source_height"].as_u64()), (Some(64), Some(32)));
+        assert_eq!((meta["width"].as_u64(), meta["height"].as_u64()), (Some(16), Some(8)));
+        assert_eq!(meta["input_size"].as_u64(), Some(png.len() as u64));
+        assert_eq!(meta["output_size"].as_u64(), Some(output.len() as u64));
+        assert!(meta["convert_ms"].as_f64().unwrap() >= meta["resize_ms"].as_f64().unwrap());
+
+        // Flags win over the configuration
+        let (code, output, meta) = run_piped(&pipe_args(FormatArg::Jpeg, Some(32)), &image, &png);
+        assert_eq!(code, 0);
+        assert_eq!(image::guess_format(&output).unwrap(), image::ImageFormat::Jpeg);
+        assert_eq!((meta["width"].as_u64(), meta["height"].as_u64()), (Some(32), Some(16)));
+    }
+
+    #[test]
+    fn test_pipe_exit_codes() {
+        let image = ImageConfig::default();
+        let args = pipe_args(FormatArg::Jpeg, Some(100_000));
+
+        let (code, output, meta) = run_piped(&args, &image, b"definitely not an image");
+        assert_eq!((code, meta["error"].as_str()), (3, Some("unsupported")));
+        assert!(output.is_empty());
+
+        let mut truncated = encode_png(image::DynamicImage::ImageRgb8(image::RgbImage::new(64, 64)));
+        truncated.truncate(60);
+        let (code, output, meta) = run_piped(&args, &image, &truncated);
+        assert_eq!((code, meta["error"].as_str()), (4, Some("decode")));
+        assert!(output.is_empty());
+
+        // The JPEG encoder refuses images wider than 65535 pixels
+        let wide = encode_png(image::DynamicImage::ImageLuma8(image::GrayImage::new(70_000, 1)));
+        let (code, output, meta) = run_piped(&args, &image, &wide);
+        assert_eq!((code, meta["error"].as_str()), (5, Some("encode")));
+        assert!(output.is_empty());
+        assert!(meta["message"].as_str().unwrap().contains("JPEG"), "{}", meta);
+    }
+
+    #[test]
+    fn test_convert_missing_input() {
+        let args = ConvertArgs {
+            input: temp_path("missing.png"),
+            format: FormatArg::Png,
+            quality: 85,
+            max_dimension: 4096,
+            output: temp_path("never-written.png"),
+        };
+
+        assert!(run(&args).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+
+/// SHA-256 of a response body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyDigest([u8; 32]);
+
+impl BodyDigest {
+    /// Hash a complete body in one pass, for bodies not hashed while they were produced
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// Lowercase hex encoding
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl fmt::Display for BodyDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Hash of a body fed in chunks as they arrive
+#[derive(Default)]
+pub struct BodyHasher(Sha256);
+
+impl BodyHasher {
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.0.update(chunk);
+    }
+
+    pub fn finish(self) -> BodyDigest {
+        BodyDigest(self.0.finalize().into())
+    }
+}
+
+/// Writer hashing everything written through it
+///
+/// Encoders write their output through it, so the digest is ready as soon as
+/// the output is, without a second pass over the buffer.
+pub struct DigestingWriter<W> {
+    inner: W,
+    hasher: BodyHasher,
+}
+
+impl<W: Write> DigestingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: BodyHasher::default(),
+        }
+    }
+
+    /// The wrapped writer and the digest of everything written to it
+    pub fn finish(self) -> (W, BodyDigest) {
+        (self.inner, self.hasher.finish())
+    }
+}
+
+impl<W: Write> Write for DigestingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Only what the inner writer accepted is hashed
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_digests_match_one_pass() {
+        let data = b"hello world, hashed in pieces".repeat(100);
+        let reference = BodyDigest::of(&data);
+
+        let mut hasher = BodyHasher::default();
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finish(), reference);
+
+        let mut writer = DigestingWriter::new(Vec::new());
+        for chunk in data.chunks(13) {
+            writer.write_all(chunk).unwrap();
+        }
+        let (written, digest) = writer.finish();
+        assert_eq!(written, data);
+        assert_eq!(digest, reference);
+
+        assert_eq!(
+            BodyDigest::of(b"").to_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+    }
+}
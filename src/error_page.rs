@@ -0,0 +1,214 @@
+//! Error responses in the format the client asked for
+//!
+//! Browsers navigating to a failing URL get a small HTML page, API clients JSON
+//! and everyone else, image requests included, a line of plain text. The HTML
+//! template is built in and can be replaced with `server.error_template_path`.
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+
+use crate::image::explicit_quality;
+
+/// Template used unless `server.error_template_path` is set
+const DEFAULT_TEMPLATE: &str = include_str!("error_page.html");
+
+/// Response header carrying the id error pages show
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+/// Longest request id taken over from the client
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Body format of an error response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    /// Format an Accept header explicitly asks for, HTML winning ties
+    ///
+    /// Wildcards do not count, so image requests and bare clients get text.
+    pub fn negotiate(accept: &str) -> Self {
+        let html = explicit_quality(accept, "text/html");
+        let json = explicit_quality(accept, "application/json");
+        if html > 0.0 && html >= json {
+            ErrorFormat::Html
+        } else if json > 0.0 {
+            ErrorFormat::Json
+        } else {
+            ErrorFormat::Text
+        }
+    }
+}
+
+/// What an error response reports
+#[derive(Debug)]
+pub struct ErrorBody<'a> {
+    pub status: StatusCode,
+    /// Stable machine-readable name of the error, such as `upstream_timeout`
+    pub code: &'static str,
+    pub message: &'a str,
+    pub request_id: Option<&'a str>,
+}
+
+/// HTML template of error pages
+///
+/// `{{status}}`, `{{reason}}`, `{{code}}` and `{{request_id}}` are replaced,
+/// HTML-escaped. The message is never put into the page, as it can quote
+/// upstream URLs and with them whatever the client put into the path.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    template: Arc<str>,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self { template: Arc::from(DEFAULT_TEMPLATE) }
+    }
+}
+
+impl ErrorPages {
+    /// Pages from the template at `path`, or the built-in one
+    pub fn load(path: Option<&Path>) -> std::io::Result<Self> {
+        match path {
+            Some(path) => Ok(Self { template: Arc::from(std::fs::read_to_string(path)?) }),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Response reporting `body` in `format`
+    ///
+    /// JSON bodies carry `status`, `code`, `error` and `request_id`; `details` adds fields to them.
+    pub fn render(&self, format: ErrorFormat, body: &ErrorBody, details: Option<serde_json::Value>) -> Response {
+        let mut response = match format {
+            ErrorFormat::Html => (
+                [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+                self.html(body),
+            )
+                .into_response(),
+            ErrorFormat::Json => {
+                let mut json = serde_json::json!({
+                    "status": body.status.as_u16(),
+                    "code": body.code,
+                    "error": body.message,
+                    "request_id": body.request_id,
+                });
+                if let (Some(serde_json::Value::Object(details)), Some(json)) = (details, json.as_object_mut()) {
+                    json.extend(details);
+                }
+                axum::Json(json).into_response()
+            }
+            ErrorFormat::Text => body.message.to_string().into_response(),
+        };
+        *response.status_mut() = body.status;
+        if let Some(request_id) = body.request_id.and_then(|id| HeaderValue::from_str(id).ok()) {
+            response.headers_mut().insert(X_REQUEST_ID, request_id);
+        }
+        response
+    }
+
+    fn html(&self, body: &ErrorBody) -> String {
+        let fields = [
+            ("{{status}}", body.status.as_str().to_string()),
+            ("{{reason}}", body.status.canonical_reason().unwrap_or_default().to_string()),
+            ("{{code}}", body.code.to_string()),
+            ("{{request_id}}", body.request_id.unwrap_or_default().to_string()),
+        ];
+        fields
+            .iter()
+            .fold(self.template.to_string(), |page, (placeholder, value)| page.replace(placeholder, &escape_html(value)))
+    }
+}
+
+/// Escape the characters that are special in HTML text and attribute values
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Id of a request for error pages and logs
+///
+/// An `X-Request-Id` set by a proxy in front is kept when it is short and made of
+/// letters, digits, `-`, `_` and `.`; otherwise a new id is generated.
+pub fn request_id(headers: &HeaderMap) -> String {
+    static PREFIX: OnceLock<u32> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let given = headers.get(X_REQUEST_ID).and_then(|v| v.to_str().ok()).filter(|id| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    });
+    if let Some(id) = given {
+        return id.to_string();
+    }
+    // Ids of one process share a prefix from its start time, so restarts do not repeat them
+    let prefix = PREFIX.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos()
+    });
+    format!("{:08x}{:08x}", prefix, NEXT.fetch_add(1, Ordering::Relaxed) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let browser = "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,*/*;q=0.8";
+        assert_eq!(ErrorFormat::negotiate(browser), ErrorFormat::Html);
+        assert_eq!(ErrorFormat::negotiate("application/json"), ErrorFormat::Json);
+        assert_eq!(ErrorFormat::negotiate("application/json, text/html;q=0.5"), ErrorFormat::Json);
+        assert_eq!(ErrorFormat::negotiate("image/avif,image/webp,*/*"), ErrorFormat::Text);
+        assert_eq!(ErrorFormat::negotiate(""), ErrorFormat::Text);
+    }
+
+    #[test]
+    fn test_html_fields_escaped() {
+        let pages = ErrorPages {
+            template: Arc::from("<p>{{status}} {{reason}} {{code}} {{request_id}}</p>"),
+        };
+        let body = ErrorBody {
+            status: StatusCode::BAD_GATEWAY,
+            code: "upstream_error",
+            message: "<script>",
+            request_id: Some("<a href=\"x\">"),
+        };
+        assert_eq!(
+            pages.html(&body),
+            "<p>502 Bad Gateway upstream_error &lt;a href=&quot;x&quot;&gt;</p>"
+        );
+    }
+
+    #[test]
+    fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        let generated = request_id(&headers);
+        assert_eq!(generated.len(), 16);
+        assert_ne!(request_id(&headers), generated);
+
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("edge-1234.abc"));
+        assert_eq!(request_id(&headers), "edge-1234.abc");
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("<script>"));
+        assert_eq!(request_id(&headers).len(), 16);
+    }
+}
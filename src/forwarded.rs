@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use axum::http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// An IP address or CIDR range of a proxy allowed to set X-Forwarded-* headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    /// Parse `"10.0.0.1"` or `"10.0.0.0/8"` style entries
+    pub fn parse(value: &str) -> Result<Self> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid trusted proxy address: {}", value))?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_prefix_len)
+                .with_context(|| format!("Invalid trusted proxy prefix length: {}", value))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self { network, prefix_len })
+    }
+
+    /// Check whether `addr` lies within this address range
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Work out the externally visible base URL (scheme and host, no trailing slash)
+///
+/// An explicit `external_base_url` always wins. Otherwise X-Forwarded-Proto and
+/// X-Forwarded-Host are honored only when the peer is a trusted proxy, with the
+/// bind address as the fallback. The client-controlled Host header is never used.
+pub fn external_base_url(
+    external_base_url: Option<&str>,
+    trusted_proxies: &[TrustedProxy],
+    bind: SocketAddr,
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+) -> String {
+    if let Some(base) = external_base_url {
+        return base.trim_end_matches('/').to_string();
+    }
+
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            // Only the first (client-most) value of a comma-separated list counts
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    };
+
+    let trusted = peer.is_some_and(|peer| trusted_proxies.iter().any(|proxy| proxy.contains(peer)));
+    let forwarded_proto = header("x-forwarded-proto")
+        .filter(|_| trusted)
+        .filter(|proto| proto.eq_ignore_ascii_case("http") || proto.eq_ignore_ascii_case("https"));
+    let forwarded_host = header("x-forwarded-host").filter(|_| trusted);
+
+    let scheme = forwarded_proto.map(str::to_ascii_lowercase).unwrap_or_else(|| "http".to_string());
+    let host = forwarded_host
+        .map(str::to_string)
+        .unwrap_or_else(|| bind.to_string());
+
+    format!("{}://{}", scheme, host)
+}
+
+/// Work out the address of the client behind Cloudflare
+///
+/// CF-Connecting-IP is honored when no trusted proxies are configured, or when
+/// the peer is one of them; otherwise the peer itself is the client.
+pub fn cloudflare_client_ip(
+    trusted_proxies: &[TrustedProxy],
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+) -> Option<IpAddr> {
+    let trusted = trusted_proxies.is_empty()
+        || peer.is_some_and(|peer| trusted_proxies.iter().any(|proxy| proxy.contains(peer)));
+    headers
+        .get("cf-connecting-ip")
+        .filter(|_| trusted)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn forwarded_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert("x-forwarded-host", HeaderValue::from_static("media.example.com"));
+        headers
+    }
+
+    fn bind() -> SocketAddr {
+        "0.0.0.0:3000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_trusted_proxy_parse_and_contains() {
+        let proxy = TrustedProxy::parse("10.0.0.0/8").unwrap();
+        assert!(proxy.contains("10.1.2.3".parse().unwrap()));
+        assert!(!proxy.contains("11.0.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 peers match IPv4 ranges
+        assert!(proxy.contains("::ffff:10.0.0.1".parse().unwrap()));
+
+        let proxy = TrustedProxy::parse("127.0.0.1").unwrap();
+        assert!(proxy.contains("127.0.0.1".parse().unwrap()));
+        assert!(!proxy.contains("127.0.0.2".parse().unwrap()));
+
+        let proxy = TrustedProxy::parse("fd00::/8").unwrap();
+        assert!(proxy.contains("fd12::1".parse().unwrap()));
+
+        assert!(TrustedProxy::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(TrustedProxy::parse("10.0.0.0/33").is_err());
+        assert!(TrustedProxy::parse("not-an-ip").is_err());
+    }
+
+    #[test]
+    fn test_explicit_external_base_url() {
+        let base = external_base_url(
+            Some("https://cdn.example.com/"),
+            &[],
+            bind(),
+            &forwarded_headers(),
+            Some("10.0.0.1".parse().unwrap()),
+        );
+        assert_eq!(base, "https://cdn.example.com");
+    }
+
+    #[test]
+    fn test_trusted_proxy_derivation() {
+        let trusted = [TrustedProxy::parse("10.0.0.0/8").unwrap()];
+        let base = external_base_url(
+            None,
+            &trusted,
+            bind(),
+            &forwarded_headers(),
+            Some("10.0.0.1".parse().unwrap()),
+        );
+        assert_eq!(base, "https://media.example.com");
+    }
+
+    #[test]
+    fn test_untrusted_peer_falls_back_to_bind_address() {
+        let trusted = [TrustedProxy::parse("10.0.0.0/8").unwrap()];
+        let base = external_base_url(
+            None,
+            &trusted,
+            bind(),
+            &forwarded_headers(),
+            Some("203.0.113.9".parse().unwrap()),
+        );
+        assert_eq!(base, "http://0.0.0.0:3000");
+
+        // Unknown peers are never trusted
+        let base = external_base_url(None, &trusted, bind(), &forwarded_headers(), None);
+        assert_eq!(base, "http://0.0.0.0:3000");
+
+        // Neither is the Host header
+        let mut headers = forwarded_headers();
+        headers.insert("host", HeaderValue::from_static("attacker.example"));
+        let base = external_base_url(None, &trusted, bind(), &headers, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(base, "http://0.0.0.0:3000");
+    }
+
+    #[test]
+    fn test_cloudflare_client_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("198.51.100.4"));
+        let client = Some("198.51.100.4".parse().unwrap());
+        let edge = Some("172.64.0.1".parse().unwrap());
+
+        assert_eq!(cloudflare_client_ip(&[], &headers, edge), client);
+        let trusted = [TrustedProxy::parse("172.64.0.0/13").unwrap()];
+        assert_eq!(cloudflare_client_ip(&trusted, &headers, edge), client);
+
+        // Peers outside the trusted ranges cannot claim another address
+        let other = Some("203.0.113.9".parse().unwrap());
+        assert_eq!(cloudflare_client_ip(&trusted, &headers, other), other);
+        assert_eq!(cloudflare_client_ip(&trusted, &HeaderMap::new(), edge), edge);
+    }
+}
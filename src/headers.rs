@@ -0,0 +1,386 @@
+use axum::http::header::InvalidHeaderName;
+use axum::http::{header, HeaderMap, HeaderValue};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+/// Custom header name for cache status
+pub const X_CACHE_STATUS: &str = "x-cache-status";
+
+/// Headers that should not be copied from upstream responses
+/// These are either automatically set by the proxy or should not be forwarded
+/// Note: ACCESS_CONTROL_ALLOW_ORIGIN is NOT excluded - it will be preserved from upstream
+/// if present, otherwise the proxy will set it to "*"
+fn excluded_headers() -> &'static HeaderNameSet {
+    static EXCLUDED: OnceLock<HeaderNameSet> = OnceLock::new();
+    EXCLUDED.get_or_init(|| {
+        HeaderNameSet::from_iter([
+            header::CONTENT_LENGTH,
+            header::CONTENT_ENCODING,
+            header::CONTENT_TYPE,
+            header::TRANSFER_ENCODING,
+            header::CONNECTION,
+            header::VIA,
+            header::CACHE_CONTROL,
+            // Upstream credentials must never reach clients, even if echoed back
+            header::AUTHORIZATION,
+            header::HeaderName::from_static(X_CACHE_STATUS),
+        ])
+    })
+}
+
+/// Headers describing a single connection, never passed on to the next hop
+///
+/// Besides these, `Proxy-*` headers and those the `Connection` header lists are hop-by-hop.
+fn hop_by_hop_headers() -> &'static HeaderNameSet {
+    static HOP_BY_HOP: OnceLock<HeaderNameSet> = OnceLock::new();
+    HOP_BY_HOP.get_or_init(|| {
+        HeaderNameSet::from_iter([
+            header::CONNECTION,
+            header::HeaderName::from_static("keep-alive"),
+            header::TE,
+            header::TRAILER,
+            header::TRANSFER_ENCODING,
+            header::UPGRADE,
+        ])
+    })
+}
+
+/// Set of header names, matched regardless of the case they were written in
+///
+/// Names are parsed into `HeaderName`s once, which are always lowercase, so
+/// lookups never compare strings. Response exclusions, the upstream headers
+/// forwarded without `preserve_upstream_headers` and the hop-by-hop headers
+/// kept from upstream requests all match through one.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderNameSet {
+    names: Vec<header::HeaderName>,
+}
+
+impl HeaderNameSet {
+    pub fn contains(&self, name: &header::HeaderName) -> bool {
+        self.names.contains(name)
+    }
+
+    pub fn insert(&mut self, name: header::HeaderName) {
+        if !self.contains(&name) {
+            self.names.push(name);
+        }
+    }
+}
+
+impl FromIterator<header::HeaderName> for HeaderNameSet {
+    fn from_iter<I: IntoIterator<Item = header::HeaderName>>(names: I) -> Self {
+        let mut set = Self::default();
+        set.extend(names);
+        set
+    }
+}
+
+impl Extend<header::HeaderName> for HeaderNameSet {
+    fn extend<I: IntoIterator<Item = header::HeaderName>>(&mut self, names: I) {
+        for name in names {
+            self.insert(name);
+        }
+    }
+}
+
+/// Header name written in configuration, in any case and with surrounding whitespace
+pub fn parse_header_name(name: &str) -> Result<header::HeaderName, InvalidHeaderName> {
+    header::HeaderName::from_bytes(name.trim().to_ascii_lowercase().as_bytes())
+}
+
+/// Headers the proxy sets itself, in the order responses emit them
+///
+/// Each one keeps its place whether the proxy or upstream supplied it.
+const PROXY_HEADER_ORDER: &[&str] = &[
+    "content-type",
+    "cache-control",
+    "cdn-cache-control",
+    "vary",
+    "access-control-allow-origin",
+    "via",
+    X_CACHE_STATUS,
+    "content-disposition",
+    "cache-tag",
+    "warning",
+    "x-akkoproxy-truncated",
+];
+
+/// Upstream headers media players rely on for seeking and saving, forwarded on
+/// unconverted responses even when upstream headers are not preserved
+pub const MEDIA_PASSTHROUGH_HEADERS: &[header::HeaderName] = &[
+    header::ACCEPT_RANGES,
+    header::CONTENT_RANGE,
+    header::LAST_MODIFIED,
+    header::CONTENT_DISPOSITION,
+];
+
+/// The upstream headers named in `names`, in upstream order
+pub fn select_headers(upstream_headers: &HeaderMap, names: &HeaderNameSet) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (key, value) in upstream_headers.iter() {
+        if names.contains(key) {
+            headers.append(key.clone(), value.clone());
+        }
+    }
+    headers
+}
+
+/// Check if a header should be excluded from upstream response
+pub fn should_exclude_header(key: &header::HeaderName) -> bool {
+    excluded_headers().contains(key)
+}
+
+/// Headers listed by the `Connection` headers of `headers`, any case
+fn connection_tokens(headers: &HeaderMap) -> HeaderNameSet {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|token| parse_header_name(token).ok())
+        .collect()
+}
+
+/// Whether `name` only concerns one connection, given the headers its `Connection` header `listed`
+fn is_hop_by_hop(name: &header::HeaderName, listed: &HeaderNameSet) -> bool {
+    hop_by_hop_headers().contains(name) || name.as_str().starts_with("proxy-") || listed.contains(name)
+}
+
+/// Remove the hop-by-hop headers from a request about to be sent upstream
+///
+/// These are the fixed set, every `Proxy-*` header, and the headers the request's
+/// own `Connection` header lists.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = connection_tokens(headers);
+    let hop_by_hop: Vec<header::HeaderName> = headers.keys().filter(|name| is_hop_by_hop(name, &listed)).cloned().collect();
+    for name in hop_by_hop {
+        headers.remove(name);
+    }
+}
+
+/// The client headers named in `names` that may be passed upstream
+///
+/// A client's `Connection` header can claim any header as hop-by-hop, those are
+/// left out along with the ones that always are.
+pub fn forward_request_headers(request_headers: &HeaderMap, names: &HeaderNameSet) -> HeaderMap {
+    let listed = connection_tokens(request_headers);
+    let mut headers = select_headers(request_headers, names);
+    let hop_by_hop: Vec<header::HeaderName> = headers.keys().filter(|name| is_hop_by_hop(name, &listed)).cloned().collect();
+    for name in hop_by_hop {
+        headers.remove(name);
+    }
+    headers
+}
+
+/// Build Vary header value, prepending "Accept" to upstream value if present
+pub fn build_vary_header(upstream_vary: Option<&str>) -> String {
+    if let Some(upstream_value) = upstream_vary {
+        // Check if "Accept" is already in the upstream Vary header (case-insensitive)
+        let has_accept = upstream_value
+            .split(',')
+            .any(|v| v.trim().eq_ignore_ascii_case("accept"));
+
+        if has_accept {
+            // If upstream already has "Accept", just use upstream value
+            upstream_value.to_string()
+        } else {
+            // Prepend "Accept" to upstream value
+            format!("Accept, {}", upstream_value)
+        }
+    } else {
+        // No upstream Vary header, just use "Accept"
+        "Accept".to_string()
+    }
+}
+
+/// Remove `Cookie` from every Vary header, dropping Vary headers left empty
+pub fn strip_vary_cookie(headers: &mut HeaderMap) {
+    let values: Vec<HeaderValue> = headers.get_all(header::VARY).iter().cloned().collect();
+    if !values.iter().any(|value| value.to_str().is_ok_and(|v| v.split(',').any(is_cookie))) {
+        return;
+    }
+
+    headers.remove(header::VARY);
+    for value in values {
+        let Ok(value) = value.to_str() else {
+            headers.append(header::VARY, value);
+            continue;
+        };
+        let kept: Vec<&str> = value.split(',').filter(|v| !is_cookie(v)).map(str::trim).collect();
+        if !kept.is_empty() {
+            let kept = HeaderValue::from_str(&kept.join(", ")).expect("Parts of a header value are valid");
+            headers.append(header::VARY, kept);
+        }
+    }
+}
+
+fn is_cookie(field: &str) -> bool {
+    field.trim().eq_ignore_ascii_case("cookie")
+}
+
+/// Hex digits of a body digest making up the ETags derived from it
+const ETAG_DIGEST_LEN: usize = 32;
+
+/// Strong ETag of a body with the hex SHA-256 `digest`
+pub fn digest_etag(digest: &str) -> HeaderValue {
+    let digest = digest.get(..ETAG_DIGEST_LEN).unwrap_or(digest);
+    HeaderValue::from_str(&format!("\"{}\"", digest)).expect("Hex digests are valid header values")
+}
+
+/// Replace upstream's `ETag` on the headers of a converted body with one derived from its `digest`
+///
+/// Upstream's tag identifies the source, not the bytes served in its place.
+pub fn replace_etag(headers: Option<&mut HeaderMap>, digest: &str) {
+    if let Some(headers) = headers {
+        headers.insert(header::ETAG, digest_etag(digest));
+    }
+}
+
+/// Add the `ETag` and `Last-Modified` validators conditional requests are answered from
+///
+/// Validators taken over from upstream are kept; otherwise the ETag is derived
+/// from the body's hex SHA-256 `digest` and the date is `last_modified`.
+pub fn add_validators(headers: &mut HeaderMap, digest: &str, last_modified: SystemTime) {
+    if !headers.contains_key(header::ETAG) {
+        headers.insert(header::ETAG, digest_etag(digest));
+    }
+    if !headers.contains_key(header::LAST_MODIFIED) {
+        let date = httpdate::fmt_http_date(last_modified);
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&date).expect("HTTP dates are valid header values"));
+    }
+}
+
+/// Content-Type header value, falling back to `application/octet-stream` if invalid
+pub fn content_type_value(content_type: &str) -> HeaderValue {
+    HeaderValue::from_str(content_type).unwrap_or(HeaderValue::from_static("application/octet-stream"))
+}
+
+/// Response headers that depend only on the response itself, not on the request serving it
+///
+/// Cache entries build these once, so a hit only copies them and adds Via,
+/// Cache-Control and X-Cache-Status, without parsing or validating anything.
+pub fn entry_headers(content_type: Option<HeaderValue>, upstream_headers: Option<&HeaderMap>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+
+    // Add upstream headers if configured
+    if let Some(upstream_headers) = upstream_headers {
+        for (key, value) in upstream_headers.iter() {
+            // Skip headers that shouldn't be copied (those set by the proxy)
+            // Also skip Vary header as we'll handle it specially
+            if !should_exclude_header(key) && key != header::VARY {
+                headers.append(key.clone(), value.clone());
+            }
+        }
+    }
+
+    if let Some(content_type) = content_type {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+
+    // Always add Vary header with Accept
+    // If upstream has Vary header, prepend "Accept" to it
+    let upstream_vary = upstream_headers
+        .and_then(|h| h.get(header::VARY))
+        .and_then(|v| v.to_str().ok());
+    let vary = HeaderValue::from_str(&build_vary_header(upstream_vary)).unwrap_or(HeaderValue::from_static("Accept"));
+    headers.insert(header::VARY, vary);
+
+    // Only set CORS header if upstream didn't provide one
+    if !headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    }
+
+    headers
+}
+
+/// Rewrite `headers` in the order every proxied response is emitted in
+///
+/// Proxy headers come first in [`PROXY_HEADER_ORDER`], then the remaining upstream
+/// headers sorted by name, each name keeping the order of its values. Hits, misses and
+/// passed-through errors thus send identical header blocks for identical inputs, which
+/// CDN cache keys and validators sensitive to ordering rely on. Names are always
+/// lowercase, as `HeaderName` stores them.
+pub fn canonical_order(headers: &mut HeaderMap) {
+    let mut fields: Vec<(header::HeaderName, Vec<HeaderValue>)> = Vec::with_capacity(headers.keys_len());
+    for (name, value) in std::mem::take(headers) {
+        match name {
+            Some(name) => fields.push((name, vec![value])),
+            None => fields.last_mut().expect("Values without a name follow a named one").1.push(value),
+        }
+    }
+    fields.sort_by(|(a, _), (b, _)| {
+        let rank = |name: &header::HeaderName| {
+            PROXY_HEADER_ORDER.iter().position(|known| *known == name.as_str()).unwrap_or(PROXY_HEADER_ORDER.len())
+        };
+        rank(a).cmp(&rank(b)).then_with(|| a.as_str().cmp(b.as_str()))
+    });
+
+    headers.reserve(fields.len());
+    for (name, values) in fields {
+        for value in values {
+            headers.append(name.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (header::HeaderName::from_static(name), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_header_name_set_ignores_case() {
+        let set: HeaderNameSet = ["X-Custom", " x-other ", "X-CUSTOM"]
+            .into_iter()
+            .map(|name| parse_header_name(name).unwrap())
+            .collect();
+        assert_eq!(set.names.len(), 2);
+        assert!(set.contains(&header::HeaderName::from_static("x-custom")));
+        assert!(set.contains(&parse_header_name("X-Other").unwrap()));
+        assert!(!set.contains(&parse_header_name("x-missing").unwrap()));
+        assert!(parse_header_name("bad header").is_err());
+    }
+
+    #[test]
+    fn test_excluded_headers() {
+        assert!(should_exclude_header(&header::CONTENT_LENGTH));
+        assert!(should_exclude_header(&header::HeaderName::from_static(X_CACHE_STATUS)));
+        assert!(!should_exclude_header(&header::ETAG));
+    }
+
+    #[test]
+    fn test_connection_listed_headers_stripped() {
+        let mut request = headers(&[
+            ("connection", "keep-alive, X-Custom-Token"),
+            ("x-custom-token", "secret"),
+            ("keep-alive", "timeout=5"),
+            ("te", "trailers"),
+            ("upgrade", "websocket"),
+            ("proxy-authorization", "Basic Zm9v"),
+            ("range", "bytes=0-99"),
+        ]);
+        strip_hop_by_hop(&mut request);
+        assert_eq!(request.keys().collect::<Vec<_>>(), [header::RANGE]);
+    }
+
+    #[test]
+    fn test_forward_request_headers() {
+        let names = HeaderNameSet::from_iter([header::RANGE, header::IF_RANGE]);
+        let request = headers(&[("range", "bytes=0-99"), ("if-range", "\"v1\""), ("accept", "image/avif")]);
+        let forwarded = forward_request_headers(&request, &names);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded[header::RANGE], "bytes=0-99");
+
+        // A client can mark a forwarded header as its own connection's
+        let request = headers(&[("connection", "If-Range"), ("range", "bytes=0-99"), ("if-range", "\"v1\"")]);
+        let forwarded = forward_request_headers(&request, &names);
+        assert_eq!(forwarded.keys().collect::<Vec<_>>(), [header::RANGE]);
+    }
+}
@@ -0,0 +1,168 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::metrics::Metrics;
+use crate::upstream::{FetchError, UpstreamFetcher, UpstreamResponse};
+
+/// Fetcher decorator sending a second identical GET when the first is slow
+///
+/// If response headers have not arrived `hedge_after` into a fetch, the request
+/// is sent again and whichever answers first is used. The other request is
+/// dropped, which aborts its transfer. At most `max_hedged` hedges are in flight
+/// at once; past that, slow fetches just wait. Each call to [`fetch`] hedges at
+/// most once, so a retrying decorator wrapped around this one hedges every
+/// attempt separately instead of multiplying requests within one.
+///
+/// [`fetch`]: UpstreamFetcher::fetch
+pub struct HedgedFetcher {
+    inner: Arc<dyn UpstreamFetcher>,
+    hedge_after: Duration,
+    permits: Arc<Semaphore>,
+    metrics: Arc<Metrics>,
+}
+
+impl HedgedFetcher {
+    pub fn new(inner: Arc<dyn UpstreamFetcher>, hedge_after: Duration, max_hedged: usize, metrics: Arc<Metrics>) -> Self {
+        Self {
+            inner,
+            hedge_after,
+            permits: Arc::new(Semaphore::new(max_hedged)),
+            metrics,
+        }
+    }
+}
+
+#[async_trait]
+impl UpstreamFetcher for HedgedFetcher {
+    async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+        let primary = self.inner.fetch(url, headers.clone());
+        tokio::pin!(primary);
+        tokio::select! {
+            result = &mut primary => return result,
+            _ = tokio::time::sleep(self.hedge_after) => {}
+        }
+
+        let Ok(_permit) = self.permits.clone().try_acquire_owned() else {
+            debug!("Not hedging slow request for {}: too many hedges in flight", url);
+            return primary.await;
+        };
+        debug!("No response from upstream after {:?}, hedging request for {}", self.hedge_after, url);
+        Metrics::incr(&self.metrics.hedged_requests);
+
+        let hedge = self.inner.fetch(url, headers);
+        tokio::pin!(hedge);
+        // A failure only decides the race when the other request fails too
+        tokio::select! {
+            result = &mut primary => match result {
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    debug!("First request for {} failed, waiting for its hedge: {}", url, e);
+                    let response = hedge.await?;
+                    Metrics::incr(&self.metrics.hedge_wins);
+                    Ok(response)
+                }
+            },
+            result = &mut hedge => match result {
+                Ok(response) => {
+                    Metrics::incr(&self.metrics.hedge_wins);
+                    Ok(response)
+                }
+                Err(e) => {
+                    debug!("Hedged request for {} failed, waiting for the first: {}", url, e);
+                    primary.await
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    /// Upstream whose first request takes `first_delay` and every later one answers at once
+    #[derive(Default)]
+    struct SlowFirst {
+        first_delay: Duration,
+        calls: AtomicUsize,
+        first_completed: AtomicBool,
+    }
+
+    impl SlowFirst {
+        fn new(first_delay: Duration) -> Arc<Self> {
+            Arc::new(Self {
+                first_delay,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl UpstreamFetcher for SlowFirst {
+        async fn fetch(&self, _url: &str, _headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+            let body = if self.calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                tokio::time::sleep(self.first_delay).await;
+                self.first_completed.store(true, Ordering::SeqCst);
+                "slow"
+            } else {
+                "fast"
+            };
+            Ok(UpstreamResponse {
+                status: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: futures::stream::once(async move { Ok(Bytes::from(body)) }).boxed(),
+            })
+        }
+    }
+
+    async fn body(mut response: UpstreamResponse) -> Bytes {
+        response.body.next().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hedge_wins_over_slow_request() {
+        let upstream = SlowFirst::new(Duration::from_secs(5));
+        let metrics = Metrics::new();
+        let fetcher = HedgedFetcher::new(upstream.clone(), Duration::from_millis(50), 4, metrics.clone());
+
+        let started = Instant::now();
+        let response = fetcher.fetch("http://upstream.test/media/a.jpg", HeaderMap::new()).await.unwrap();
+        assert_eq!(body(response).await.as_ref(), b"fast");
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(Metrics::get(&metrics.hedged_requests), 1);
+        assert_eq!(Metrics::get(&metrics.hedge_wins), 1);
+
+        // The losing request was dropped rather than left running
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!upstream.first_completed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_no_hedge_for_fast_or_capped_requests() {
+        let upstream = SlowFirst::new(Duration::from_millis(10));
+        let metrics = Metrics::new();
+        let fetcher = HedgedFetcher::new(upstream.clone(), Duration::from_secs(1), 4, metrics.clone());
+        let response = fetcher.fetch("http://upstream.test/media/a.jpg", HeaderMap::new()).await.unwrap();
+        assert_eq!(body(response).await.as_ref(), b"slow");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 1);
+
+        // Without hedge permits slow requests simply wait
+        let upstream = SlowFirst::new(Duration::from_millis(200));
+        let fetcher = HedgedFetcher::new(upstream.clone(), Duration::from_millis(10), 0, metrics.clone());
+        let response = fetcher.fetch("http://upstream.test/media/a.jpg", HeaderMap::new()).await.unwrap();
+        assert_eq!(body(response).await.as_ref(), b"slow");
+        assert_eq!(upstream.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(Metrics::get(&metrics.hedged_requests), 0);
+    }
+}
@@ -0,0 +1,214 @@
+//! Limit on concurrent upstream connections per host
+//!
+//! Remote servers ban clients that open too many connections at once, so each
+//! fetch holds a permit of its target host until its body has been read. Excess
+//! fetches queue for a permit up to a timeout and then fail.
+
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use futures::StreamExt;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+use crate::metrics::Metrics;
+use crate::upstream::{FetchError, UpstreamFetcher, UpstreamResponse};
+
+/// Hosts tracked before idle ones are dropped from the map
+const MAX_HOSTS: usize = 1024;
+
+/// Permits and counts of one host
+struct Host {
+    permits: Arc<Semaphore>,
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+/// Fetches in flight and waiting for one host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostUsage {
+    pub host: String,
+    pub in_flight: usize,
+    pub queued: usize,
+}
+
+/// Per-host semaphores, created on first use
+///
+/// Hosts with nothing in flight or queued are dropped once the map holds
+/// [`MAX_HOSTS`] entries; hosts still busy then are kept regardless.
+pub struct HostLimiter {
+    per_host: usize,
+    queue_timeout: Duration,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+/// A connection slot of one host, given back when dropped
+struct HostPermit {
+    host: Arc<Host>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        self.host.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl HostLimiter {
+    pub fn new(per_host: usize, queue_timeout: Duration) -> Self {
+        Self {
+            per_host,
+            queue_timeout,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn host(&self, name: &str) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(host) = hosts.get(name) {
+            return host.clone();
+        }
+        if hosts.len() >= MAX_HOSTS {
+            // Busy hosts are referenced by their fetches as well as the map
+            hosts.retain(|_, host| Arc::strong_count(host) > 1);
+        }
+        let host = Arc::new(Host {
+            permits: Arc::new(Semaphore::new(self.per_host)),
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+        });
+        hosts.insert(name.to_string(), host.clone());
+        host
+    }
+
+    /// Wait for a connection slot of `name`, `None` once the queue timeout passes
+    async fn acquire(&self, name: &str) -> Option<HostPermit> {
+        let host = self.host(name);
+        host.queued.fetch_add(1, Ordering::Relaxed);
+        let permit = tokio::time::timeout(self.queue_timeout, host.permits.clone().acquire_owned()).await;
+        host.queued.fetch_sub(1, Ordering::Relaxed);
+        let permit = permit.ok()?.expect("Host semaphores are never closed");
+        host.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(HostPermit { host, _permit: permit })
+    }
+
+    /// Hosts with fetches in flight or queued, busiest first
+    pub fn usage(&self) -> Vec<HostUsage> {
+        let mut usage: Vec<HostUsage> = self
+            .hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, host)| HostUsage {
+                host: name.clone(),
+                in_flight: host.in_flight.load(Ordering::Relaxed),
+                queued: host.queued.load(Ordering::Relaxed),
+            })
+            .filter(|usage| usage.in_flight + usage.queued > 0)
+            .collect();
+        usage.sort_by(|a, b| (b.in_flight + b.queued).cmp(&(a.in_flight + a.queued)).then_with(|| a.host.cmp(&b.host)));
+        usage
+    }
+}
+
+/// Fetcher decorator holding a permit of the target host for each connection
+///
+/// The permit is kept until the body has been read to its end or failed, so a
+/// body requested again after a failure takes the place of the first transfer
+/// instead of waiting next to it. Wrapped directly around the fetcher that opens
+/// connections, every hedged request or retry holds exactly one permit.
+pub struct HostLimitedFetcher {
+    inner: Arc<dyn UpstreamFetcher>,
+    limiter: Arc<HostLimiter>,
+    metrics: Arc<Metrics>,
+}
+
+impl HostLimitedFetcher {
+    pub fn new(inner: Arc<dyn UpstreamFetcher>, limiter: Arc<HostLimiter>, metrics: Arc<Metrics>) -> Self {
+        Self { inner, limiter, metrics }
+    }
+}
+
+#[async_trait]
+impl UpstreamFetcher for HostLimitedFetcher {
+    async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            }))
+            .unwrap_or_default();
+        let Some(permit) = self.limiter.acquire(&host).await else {
+            debug!("No connection to {} free within {:?}", host, self.limiter.queue_timeout);
+            Metrics::incr(&self.metrics.upstream_host_queue_timeouts);
+            return Err(FetchError::HostBusy(host));
+        };
+
+        let response = self.inner.fetch(url, headers).await?;
+        // Ending or failing gives the permit back, the transfer is over either way
+        let body = futures::stream::unfold((response.body, Some(permit)), |(mut body, mut permit)| async move {
+            let chunk = body.next().await;
+            if !matches!(chunk, Some(Ok(_))) {
+                permit.take();
+            }
+            chunk.map(|chunk| (chunk, (body, permit)))
+        })
+        .boxed();
+
+        Ok(UpstreamResponse { body, ..response })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::mock::{MockFetcher, MockResponse};
+
+    #[tokio::test]
+    async fn test_permits_held_until_body_read() {
+        let limiter = Arc::new(HostLimiter::new(1, Duration::from_millis(50)));
+        let metrics = Metrics::new();
+        let fetcher = HostLimitedFetcher::new(
+            Arc::new(MockFetcher::always(MockResponse::ok("text/plain", "hello"))),
+            limiter.clone(),
+            metrics.clone(),
+        );
+
+        let first = fetcher.fetch("http://a.test/1", HeaderMap::new()).await.unwrap();
+        assert_eq!(limiter.usage(), vec![HostUsage { host: "a.test".to_string(), in_flight: 1, queued: 0 }]);
+        // Other hosts have their own permits, the same host waits and gives up
+        let _other = fetcher.fetch("http://b.test:8080/1", HeaderMap::new()).await.unwrap();
+        assert_eq!(limiter.usage()[1].host, "b.test:8080");
+        assert!(matches!(fetcher.fetch("http://a.test/2", HeaderMap::new()).await, Err(FetchError::HostBusy(host)) if host == "a.test"));
+        assert_eq!(Metrics::get(&metrics.upstream_host_queue_timeouts), 1);
+
+        let chunks: Vec<_> = first.body.collect().await;
+        assert_eq!(chunks.len(), 1);
+        assert!(fetcher.fetch("http://a.test/2", HeaderMap::new()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_failed_body_releases_permit() {
+        let limiter = Arc::new(HostLimiter::new(1, Duration::from_millis(50)));
+        let fetcher = HostLimitedFetcher::new(
+            Arc::new(MockFetcher::always(MockResponse::ok("text/plain", "hello").drop_after(2, 1))),
+            limiter.clone(),
+            Metrics::new(),
+        );
+
+        let mut failing = fetcher.fetch("http://a.test/1", HeaderMap::new()).await.unwrap();
+        assert!(failing.body.next().await.unwrap().is_ok());
+        assert!(failing.body.next().await.unwrap().is_err());
+        // The failed transfer is still around while its body is fetched again
+        let again = fetcher.fetch("http://a.test/1", HeaderMap::new()).await.unwrap();
+        drop(failing);
+        assert_eq!(limiter.usage()[0].in_flight, 1);
+        drop(again);
+        assert!(limiter.usage().is_empty());
+    }
+}
@@ -1,10 +1,23 @@
-use anyhow::{Context, Result};
 use bytes::Bytes;
-use image::{DynamicImage, GenericImageView, ImageFormat};
+use image::metadata::Orientation;
+use image::{AnimationDecoder, DynamicImage, GenericImageView, ImageDecoder, ImageFormat};
 use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::config::{ImageConfig, ImageTierConfig};
+use crate::digest::{BodyDigest, DigestingWriter};
+
+/// AVIF encoder speed used outside of tiers (1-10, 10 is fastest)
+pub const DEFAULT_AVIF_SPEED: u8 = 10;
+
+/// Whether this build can encode AVIF at all, see the `avif` cargo feature
+pub const AVIF_COMPILED: bool = cfg!(feature = "avif");
 
 /// Supported image output formats
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OutputFormat {
     Avif,
     WebP,
@@ -13,12 +26,214 @@ pub enum OutputFormat {
     Original,
 }
 
+impl OutputFormat {
+    /// Whether the encoder keeps every pixel, so its output is rarely smaller than a lossy source
+    pub fn is_lossless(self) -> bool {
+        matches!(self, OutputFormat::WebP | OutputFormat::Png)
+    }
+}
+
+/// Reason an image response was served without conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    NotImage,
+    FormatSatisfied,
+    TooLarge,
+    BelowMinSize,
+    TierDisabled,
+    /// Conversions are shed while the host is overloaded
+    Shed,
+    /// The source recently failed to decode as any supported format
+    Undecodable,
+    /// The request or the upstream response carried `Cache-Control: no-transform`
+    NoTransform,
+    /// The path's extension names another format, see `server.extension_consistency`
+    ExtensionMismatch,
+    /// The converted image would likely be, or already was, too large for `cache.max_item_size`
+    Uncacheable,
+}
+
+impl SkipReason {
+    pub const ALL: [SkipReason; 10] = [
+        SkipReason::NotImage,
+        SkipReason::FormatSatisfied,
+        SkipReason::TooLarge,
+        SkipReason::BelowMinSize,
+        SkipReason::TierDisabled,
+        SkipReason::Shed,
+        SkipReason::Undecodable,
+        SkipReason::NoTransform,
+        SkipReason::ExtensionMismatch,
+        SkipReason::Uncacheable,
+    ];
+    
+    /// Label used for this reason on the metrics endpoint
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::NotImage => "not_image",
+            SkipReason::FormatSatisfied => "format_satisfied",
+            SkipReason::TooLarge => "too_large",
+            SkipReason::BelowMinSize => "below_min_size",
+            SkipReason::TierDisabled => "tier_disabled",
+            SkipReason::Shed => "shed",
+            SkipReason::Undecodable => "undecodable",
+            SkipReason::NoTransform => "no_transform",
+            SkipReason::ExtensionMismatch => "extension_mismatch",
+            SkipReason::Uncacheable => "uncacheable",
+        }
+    }
+}
+
+/// How a decoded image was resized before encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeKind {
+    /// Already within the maximum dimensions
+    Skipped,
+    /// One Lanczos3 pass
+    SingleStep,
+    /// A cheap Triangle downscale, finished with Lanczos3 unless it already reached the target
+    TwoStep,
+}
+
+impl ResizeKind {
+    pub const ALL: [ResizeKind; 3] = [ResizeKind::Skipped, ResizeKind::SingleStep, ResizeKind::TwoStep];
+    
+    /// Label used for this outcome on the metrics endpoint
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ResizeKind::Skipped => "skipped",
+            ResizeKind::SingleStep => "single_step",
+            ResizeKind::TwoStep => "two_step",
+        }
+    }
+}
+
+/// A requested variant that cannot be produced within the configured limits
+#[derive(Debug, thiserror::Error)]
+pub enum VariantError {
+    #[error("Source image has {actual} pixels, exceeding the limit of {limit}")]
+    TooManyPixels { actual: u64, limit: u64 },
+    #[error("Source image exceeds the decoder's limits: {0}")]
+    DecoderLimits(String),
+}
+
+/// Error returned when a conversion is abandoned because its request went away
+#[derive(Debug, thiserror::Error)]
+#[error("Image conversion cancelled")]
+pub struct ConversionCancelled;
+
+/// Why an image could not be converted
+///
+/// The variants call for different handling: an unsupported source will never
+/// convert, a corrupt one may have been damaged in transit, and an encoder failure
+/// says nothing about the source, so another format may still work.
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    /// The source is in a format, or a flavor of one, that cannot be decoded
+    #[error("Unsupported image format: {0}")]
+    DecodeUnsupported(String),
+    /// The source claims a supported format but its data is broken or truncated
+    #[error("Corrupt image: {0}")]
+    DecodeCorrupt(String),
+    /// The source exceeds `image.max_pixels` or the decoder's own limits
+    #[error(transparent)]
+    DecodeLimitsExceeded(#[from] VariantError),
+    #[error("Failed to encode {format:?}: {reason}")]
+    EncodeFailed { format: OutputFormat, reason: String },
+    #[error("Failed to resize image: {0}")]
+    Resize(String),
+    #[error(transparent)]
+    Cancelled(#[from] ConversionCancelled),
+}
+
+impl ImageError {
+    /// Labels of the failures counted on the metrics endpoint, cancellations are counted apart
+    pub const KINDS: [&'static str; 5] = ["decode_unsupported", "decode_corrupt", "decode_limits", "encode_failed", "resize"];
+    
+    /// Label of this failure on the metrics endpoint, `None` for cancellations
+    pub fn kind(&self) -> Option<&'static str> {
+        match self {
+            ImageError::DecodeUnsupported(_) => Some("decode_unsupported"),
+            ImageError::DecodeCorrupt(_) => Some("decode_corrupt"),
+            ImageError::DecodeLimitsExceeded(_) => Some("decode_limits"),
+            ImageError::EncodeFailed { .. } => Some("encode_failed"),
+            ImageError::Resize(_) => Some("resize"),
+            ImageError::Cancelled(_) => None,
+        }
+    }
+    
+    /// Classify an error of the image crate raised while decoding
+    fn decoding(error: image::ImageError) -> Self {
+        match error {
+            image::ImageError::Unsupported(e) => ImageError::DecodeUnsupported(e.to_string()),
+            image::ImageError::Limits(e) => VariantError::DecoderLimits(e.to_string()).into(),
+            e => ImageError::DecodeCorrupt(e.to_string()),
+        }
+    }
+    
+    fn encoding(format: OutputFormat) -> impl FnOnce(image::ImageError) -> Self {
+        move |e| ImageError::EncodeFailed { format, reason: e.to_string() }
+    }
+}
+
+type Result<T, E = ImageError> = std::result::Result<T, E>;
+
+/// Error returned when a conversion is abandoned for running over its time budget
+#[derive(Debug, thiserror::Error)]
+#[error("Image conversion exceeded its time budget of {0:?}")]
+pub struct ConversionOverBudget(pub std::time::Duration);
+
 /// Image converter for format transformations
 pub struct ImageConverter {
     quality: u8,
     max_dimension: u32,
     enable_avif: bool,
     enable_webp: bool,
+    max_pixels: Option<u64>,
+    fast_resize_threshold: u64,
+    tiers: Vec<ImageTierConfig>,
+    avif_threads: Option<usize>,
+    deterministic: bool,
+    /// Sources decoded so far
+    decodes: AtomicU64,
+}
+
+/// Source decoded by [`ImageConverter::decode`], ready to be encoded to any number of formats
+#[derive(Debug)]
+pub struct DecodedImage {
+    /// Upright and scaled down to `max_dimension`
+    image: DynamicImage,
+    /// Format the source was detected as
+    pub source_format: Option<ImageFormat>,
+    /// Orientation from the source's metadata, applied to the image
+    pub orientation: Orientation,
+    /// How the source was resized and how long it took
+    pub resize: (ResizeKind, std::time::Duration),
+}
+
+impl DecodedImage {
+    /// Dimensions of the upright, resized image
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.image.dimensions()
+    }
+}
+
+/// Output of [`ImageConverter::convert_digested`]
+#[derive(Debug, Clone)]
+pub struct Converted {
+    pub data: Bytes,
+    pub mime_type: &'static str,
+    /// SHA-256 of `data` taken while it was encoded, `None` when the source was returned as is
+    pub digest: Option<BodyDigest>,
+    /// How the source was resized and how long it took, `None` when it was returned as is
+    pub resize: Option<(ResizeKind, std::time::Duration)>,
+}
+
+/// Encoder settings selected for a single conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeSettings {
+    pub quality: u8,
+    pub avif_speed: u8,
 }
 
 impl ImageConverter {
@@ -28,47 +243,236 @@ impl ImageConverter {
             max_dimension,
             enable_avif,
             enable_webp,
+            max_pixels: None,
+            fast_resize_threshold: 0,
+            tiers: Vec::new(),
+            avif_threads: None,
+            deterministic: false,
+            decodes: AtomicU64::new(0),
+        }
+    }
+    
+    /// Limit the threads of a single AVIF encode, `None` uses the whole rayon pool
+    pub fn with_avif_threads(mut self, threads: Option<usize>) -> Self {
+        self.avif_threads = threads;
+        self
+    }
+    
+    /// Produce byte-identical output for identical sources and settings
+    ///
+    /// The JPEG, PNG and lossless WebP encoders already are deterministic and none
+    /// of the encoders write timestamps or software tags. AVIF encodes are tiled by
+    /// thread count, so they are pinned to one thread, overriding `with_avif_threads`.
+    pub fn with_deterministic_encoding(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+    
+    /// Use per-size encoding rules, sizes outside every tier keep the global settings
+    pub fn with_tiers(mut self, tiers: Vec<ImageTierConfig>) -> Self {
+        self.tiers = tiers;
+        self
+    }
+    
+    /// Encoder settings for a source of `size` bytes, or `None` if its tier disables conversion
+    pub fn encode_settings(&self, size: usize) -> Option<EncodeSettings> {
+        let global = EncodeSettings {
+            quality: self.quality,
+            avif_speed: DEFAULT_AVIF_SPEED,
+        };
+        
+        match self.tiers.iter().find(|tier| tier.contains(size as u64)) {
+            Some(tier) if !tier.convert => None,
+            Some(tier) => Some(EncodeSettings {
+                quality: tier.quality.unwrap_or(global.quality),
+                avif_speed: tier.avif_speed.unwrap_or(global.avif_speed),
+            }),
+            None => Some(global),
+        }
+    }
+    
+    /// Refuse to decode sources with more than `max_pixels` pixels
+    pub fn with_max_pixels(mut self, max_pixels: Option<u64>) -> Self {
+        self.max_pixels = max_pixels;
+        self
+    }
+    
+    /// Resize sources with more than `pixels` pixels in two steps, 0 never does
+    pub fn with_fast_resize_threshold(mut self, pixels: u64) -> Self {
+        self.fast_resize_threshold = pixels;
+        self
+    }
+    
+    /// Check the source against the decode limits using only its header
+    ///
+    /// Sources whose dimensions cannot be read are left for the decoder to reject.
+    pub fn check_limits(&self, data: &[u8]) -> std::result::Result<(), VariantError> {
+        let Some(limit) = self.max_pixels else {
+            return Ok(());
+        };
+        
+        let dimensions = image::ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        match dimensions {
+            Some((width, height)) if u64::from(width) * u64::from(height) > limit => {
+                Err(VariantError::TooManyPixels {
+                    actual: u64::from(width) * u64::from(height),
+                    limit,
+                })
+            }
+            _ => Ok(()),
         }
     }
     
     /// Convert image to the requested format
-    pub fn convert(&self, data: &Bytes, target_format: OutputFormat) -> Result<(Bytes, &'static str)> {
-        // Try to detect and decode the image
-        let img = image::load_from_memory(data)
-            .context("Failed to decode image")?;
+    ///
+    /// Stops between the decode, resize and encode stages with
+    /// [`ConversionCancelled`] once `cancel` is triggered.
+    pub fn convert(
+        &self,
+        data: &Bytes,
+        target_format: OutputFormat,
+        cancel: &CancellationToken,
+    ) -> Result<(Bytes, &'static str)> {
+        self.convert_digested(data, target_format, cancel)
+            .map(|converted| (converted.data, converted.mime_type))
+    }
+    
+    /// Like [`convert`](Self::convert), also returning the digest of the encoded output
+    pub fn convert_digested(
+        &self,
+        data: &Bytes,
+        target_format: OutputFormat,
+        cancel: &CancellationToken,
+    ) -> Result<Converted> {
+        let (converted, _) = self.convert_inner(data, None, target_format, cancel)?;
+        Ok(converted)
+    }
+    
+    /// Like [`convert_digested`](Self::convert_digested), also handing back the
+    /// decoded source for encoding it to further formats
+    pub fn convert_keeping_decoded(
+        &self,
+        data: &Bytes,
+        target_format: OutputFormat,
+        cancel: &CancellationToken,
+    ) -> Result<(Converted, Option<Arc<DecodedImage>>)> {
+        self.convert_inner(data, None, target_format, cancel)
+    }
+    
+    /// Like [`convert_digested`](Self::convert_digested), encoding `decoded`, the
+    /// already decoded `data`, instead of decoding it again
+    pub fn convert_decoded(
+        &self,
+        data: &Bytes,
+        decoded: Arc<DecodedImage>,
+        target_format: OutputFormat,
+        cancel: &CancellationToken,
+    ) -> Result<Converted> {
+        let (converted, _) = self.convert_inner(data, Some(decoded), target_format, cancel)?;
+        Ok(converted)
+    }
+    
+    fn convert_inner(
+        &self,
+        data: &Bytes,
+        decoded: Option<Arc<DecodedImage>>,
+        target_format: OutputFormat,
+        cancel: &CancellationToken,
+    ) -> Result<(Converted, Option<Arc<DecodedImage>>)> {
+        let untouched = || Converted {
+            data: data.clone(),
+            mime_type: "application/octet-stream",
+            digest: None,
+            resize: None,
+        };
         
-        // Check dimensions and resize if necessary
-        let img = self.resize_if_needed(img);
+        // Sources in a tier without conversion are returned untouched
+        let Some(settings) = self.encode_settings(data.len()) else {
+            return Ok((untouched(), None));
+        };
+        
+        let decoded = match decoded {
+            Some(decoded) => decoded,
+            None => Arc::new(self.decode(data, cancel)?),
+        };
+        if target_format == OutputFormat::Original {
+            return Ok((untouched(), Some(decoded)));
+        }
         
-        // Convert to target format
-        let (converted, mime_type) = match target_format {
+        check_cancelled(cancel)?;
+        let converted = self.encode(&decoded, target_format, settings)?;
+        Ok((converted, Some(decoded)))
+    }
+    
+    /// Decode a source and scale it down to `max_dimension`, turned upright
+    ///
+    /// Stops before decoding and before resizing with [`ConversionCancelled`] once
+    /// `cancel` is triggered. The result can be encoded to any number of formats.
+    pub fn decode(&self, data: &[u8], cancel: &CancellationToken) -> Result<DecodedImage> {
+        check_cancelled(cancel)?;
+        self.check_limits(data)?;
+        let (img, source_format, orientation) = decode(data)?;
+        self.decodes.fetch_add(1, Ordering::Relaxed);
+        
+        // Check dimensions and resize if necessary
+        check_cancelled(cancel)?;
+        let started = std::time::Instant::now();
+        let (img, resize_kind) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.resize_if_needed(img)))
+            .map_err(|_| ImageError::Resize("resizer panicked".to_string()))?;
+        Ok(DecodedImage {
+            image: img,
+            source_format,
+            orientation,
+            resize: (resize_kind, started.elapsed()),
+        })
+    }
+    
+    /// Encode a decoded source to `target_format`; disabled formats fall back to JPEG, the original to PNG
+    pub fn encode(&self, decoded: &DecodedImage, target_format: OutputFormat, settings: EncodeSettings) -> Result<Converted> {
+        let img = &decoded.image;
+        let ((converted, digest), mime_type) = match target_format {
             OutputFormat::Avif if self.enable_avif => {
-                (self.to_avif(&img)?, "image/avif")
+                (self.to_avif(img, settings)?, "image/avif")
             }
             OutputFormat::WebP if self.enable_webp => {
-                (self.to_webp(&img)?, "image/webp")
+                (self.to_webp(img)?, "image/webp")
             }
             OutputFormat::Jpeg => {
-                (self.to_jpeg(&img)?, "image/jpeg")
+                (self.to_jpeg(img, settings)?, "image/jpeg")
             }
-            OutputFormat::Png => {
-                (self.to_png(&img)?, "image/png")
-            }
-            OutputFormat::Original => {
-                // Return original data
-                return Ok((data.clone(), "application/octet-stream"));
+            OutputFormat::Png | OutputFormat::Original => {
+                (self.to_png(img)?, "image/png")
             }
             _ => {
                 // Fallback to JPEG if format is disabled
-                (self.to_jpeg(&img)?, "image/jpeg")
+                (self.to_jpeg(img, settings)?, "image/jpeg")
             }
         };
         
-        Ok((converted, mime_type))
+        Ok(Converted {
+            data: converted,
+            mime_type,
+            digest: Some(digest),
+            resize: Some(decoded.resize),
+        })
+    }
+    
+    /// Sources decoded so far by this converter
+    pub fn decode_count(&self) -> u64 {
+        self.decodes.load(Ordering::Relaxed)
     }
     
     /// Resize image if it exceeds maximum dimensions
-    fn resize_if_needed(&self, img: DynamicImage) -> DynamicImage {
+    ///
+    /// Sources over the fast resize threshold are first downscaled with the much
+    /// cheaper Triangle filter to twice the target size, so the final Lanczos3 pass
+    /// works on a fraction of the pixels. The output dimensions do not depend on the path.
+    fn resize_if_needed(&self, img: DynamicImage) -> (DynamicImage, ResizeKind) {
+        use image::imageops::FilterType;
+        
         let (width, height) = img.dimensions();
         
         if width > self.max_dimension || height > self.max_dimension {
@@ -81,59 +485,215 @@ impl ImageConverter {
             let new_width = (width as f32 * scale) as u32;
             let new_height = (height as f32 * scale) as u32;
             
-            img.resize(new_width, new_height, image::imageops::FilterType::Lanczos3)
+            let pixels = u64::from(width) * u64::from(height);
+            if self.fast_resize_threshold == 0 || pixels <= self.fast_resize_threshold {
+                return (img.resize(new_width, new_height, FilterType::Lanczos3), ResizeKind::SingleStep);
+            }
+            
+            // Match the dimensions a single resize would pick for the same bounds
+            let (target_width, target_height) = fit_within(width, height, new_width, new_height);
+            let (step_width, step_height) = (target_width.saturating_mul(2), target_height.saturating_mul(2));
+            let img = if step_width < width && step_height < height {
+                img.resize_exact(step_width, step_height, FilterType::Triangle)
+                    .resize_exact(target_width, target_height, FilterType::Lanczos3)
+            } else {
+                img.resize_exact(target_width, target_height, FilterType::Triangle)
+            };
+            (img, ResizeKind::TwoStep)
         } else {
-            img
+            (img, ResizeKind::Skipped)
         }
     }
     
     /// Convert image to AVIF format
-    fn to_avif(&self, img: &DynamicImage) -> Result<Bytes> {
-        let mut buffer = Vec::new();
+    #[cfg(feature = "avif")]
+    fn to_avif(&self, img: &DynamicImage, settings: EncodeSettings) -> Result<(Bytes, BodyDigest)> {
+        let mut writer = DigestingWriter::new(Vec::new());
         let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
-            &mut buffer,
-            10, // Speed (1-10, 10 is fastest)
-            self.quality,
-        );
+            &mut writer,
+            settings.avif_speed,
+            settings.quality,
+        )
+        .with_num_threads(if self.deterministic { Some(1) } else { self.avif_threads });
         
         img.write_with_encoder(encoder)
-            .context("Failed to encode AVIF")?;
+            .map_err(ImageError::encoding(OutputFormat::Avif))?;
         
-        Ok(Bytes::from(buffer))
+        Ok(finish(writer))
+    }
+    
+    #[cfg(not(feature = "avif"))]
+    fn to_avif(&self, _img: &DynamicImage, _settings: EncodeSettings) -> Result<(Bytes, BodyDigest)> {
+        Err(ImageError::EncodeFailed {
+            format: OutputFormat::Avif,
+            reason: "AVIF support was not compiled in".to_string(),
+        })
     }
     
     /// Convert image to WebP format
-    fn to_webp(&self, img: &DynamicImage) -> Result<Bytes> {
-        let mut buffer = Vec::new();
-        let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut buffer);
+    fn to_webp(&self, img: &DynamicImage) -> Result<(Bytes, BodyDigest)> {
+        let mut writer = DigestingWriter::new(Vec::new());
+        let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut writer);
         
         img.write_with_encoder(encoder)
-            .context("Failed to encode WebP")?;
+            .map_err(ImageError::encoding(OutputFormat::WebP))?;
         
-        Ok(Bytes::from(buffer))
+        Ok(finish(writer))
     }
     
     /// Convert image to JPEG format
-    fn to_jpeg(&self, img: &DynamicImage) -> Result<Bytes> {
-        let mut buffer = Vec::new();
-        let mut cursor = Cursor::new(&mut buffer);
+    fn to_jpeg(&self, img: &DynamicImage, settings: EncodeSettings) -> Result<(Bytes, BodyDigest)> {
+        let mut writer = DigestingWriter::new(Vec::new());
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut writer, settings.quality);
         
-        img.write_to(&mut cursor, ImageFormat::Jpeg)
-            .context("Failed to encode JPEG")?;
+        img.write_with_encoder(encoder)
+            .map_err(ImageError::encoding(OutputFormat::Jpeg))?;
         
-        Ok(Bytes::from(buffer))
+        Ok(finish(writer))
     }
     
     /// Convert image to PNG format
-    fn to_png(&self, img: &DynamicImage) -> Result<Bytes> {
-        let mut buffer = Vec::new();
-        let mut cursor = Cursor::new(&mut buffer);
+    fn to_png(&self, img: &DynamicImage) -> Result<(Bytes, BodyDigest)> {
+        let mut writer = DigestingWriter::new(Vec::new());
+        let encoder = image::codecs::png::PngEncoder::new(&mut writer);
         
-        img.write_to(&mut cursor, ImageFormat::Png)
-            .context("Failed to encode PNG")?;
+        img.write_with_encoder(encoder)
+            .map_err(ImageError::encoding(OutputFormat::Png))?;
+        
+        Ok(finish(writer))
+    }
+}
+
+/// Outcome of the startup self-test of one encoder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncoderStatus {
+    /// A 1×1 pixel image was encoded
+    Ok,
+    /// The format is disabled in the configuration and was not tried
+    Disabled,
+    /// Encoding failed, so the format stays disabled for this run
+    Failed(String),
+}
+
+impl std::fmt::Display for EncoderStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EncoderStatus::Ok => f.write_str("ok"),
+            EncoderStatus::Disabled => f.write_str("disabled"),
+            EncoderStatus::Failed(reason) => write!(f, "failed ({})", reason),
+        }
+    }
+}
+
+/// Availability of the encoders that can be turned off, found at startup
+///
+/// Distro builds may lack AVIF support or ship a broken AV1 encoder. Finding out
+/// once at startup turns that into a warning instead of an error on every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderSelfTest {
+    pub avif: EncoderStatus,
+    pub webp: EncoderStatus,
+}
+
+impl EncoderSelfTest {
+    /// Encode a 1×1 pixel image with every enabled encoder
+    ///
+    /// Encoders behave the same for the whole process, so each is only tried once.
+    pub fn run(image: &ImageConfig) -> Self {
+        static AVIF: OnceLock<Result<(), String>> = OnceLock::new();
+        static WEBP: OnceLock<Result<(), String>> = OnceLock::new();
         
-        Ok(Bytes::from(buffer))
+        Self::run_with(image, |format| match format {
+            OutputFormat::Avif => AVIF.get_or_init(|| probe(format)).clone(),
+            _ => WEBP.get_or_init(|| probe(format)).clone(),
+        })
+    }
+    
+    /// Same as [`EncoderSelfTest::run`], trying encoders with `probe`
+    pub fn run_with(image: &ImageConfig, probe: impl Fn(OutputFormat) -> Result<(), String>) -> Self {
+        let status = |enabled: bool, format: OutputFormat| {
+            if !enabled {
+                return EncoderStatus::Disabled;
+            }
+            match probe(format) {
+                Ok(()) => EncoderStatus::Ok,
+                Err(reason) => EncoderStatus::Failed(reason),
+            }
+        };
+        Self {
+            avif: status(image.enable_avif, OutputFormat::Avif),
+            webp: status(image.enable_webp, OutputFormat::WebP),
+        }
     }
+    
+    /// Turn off the formats whose encoder failed, warning about each
+    pub fn apply(&self, image: &mut ImageConfig) {
+        if let EncoderStatus::Failed(reason) = &self.avif {
+            warn!("AVIF encoder self-test failed, disabling AVIF conversion: {}", reason);
+        }
+        if let EncoderStatus::Failed(reason) = &self.webp {
+            warn!("WebP encoder self-test failed, disabling WebP conversion: {}", reason);
+        }
+        self.disable_failed(image);
+    }
+    
+    /// Turn off the formats whose encoder failed, without warning again
+    pub fn disable_failed(&self, image: &mut ImageConfig) {
+        image.enable_avif &= !matches!(self.avif, EncoderStatus::Failed(_));
+        image.enable_webp &= !matches!(self.webp, EncoderStatus::Failed(_));
+    }
+}
+
+/// Encode a 1×1 pixel image in `format`, catching encoder panics
+fn probe(format: OutputFormat) -> Result<(), String> {
+    let converter = ImageConverter::new(80, 1, true, true);
+    let img = DynamicImage::ImageRgb8(image::RgbImage::new(1, 1));
+    let settings = EncodeSettings {
+        quality: 80,
+        avif_speed: DEFAULT_AVIF_SPEED,
+    };
+    let encode = || match format {
+        OutputFormat::Avif => converter.to_avif(&img, settings),
+        OutputFormat::WebP => converter.to_webp(&img),
+        OutputFormat::Jpeg => converter.to_jpeg(&img, settings),
+        OutputFormat::Png | OutputFormat::Original => converter.to_png(&img),
+    };
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(encode)) {
+        Ok(Ok(_)) => Ok(()),
+        // The format is already named where the status is reported
+        Ok(Err(ImageError::EncodeFailed { reason, .. })) => Err(reason),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("encoder panicked".to_string()),
+    }
+}
+
+/// Decode an image turned upright by its metadata, classifying the failure
+fn decode(data: &[u8]) -> Result<(DynamicImage, Option<ImageFormat>, Orientation)> {
+    let reader = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| ImageError::decoding(e.into()))?;
+    let source_format = reader.format();
+    let mut decoder = reader.into_decoder().map_err(ImageError::decoding)?;
+    // Unreadable metadata is no reason to reject the pixels
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(ImageError::decoding)?;
+    img.apply_orientation(orientation);
+    Ok((img, source_format, orientation))
+}
+
+/// Fail with [`ConversionCancelled`] once `cancel` is triggered
+fn check_cancelled(cancel: &CancellationToken) -> std::result::Result<(), ConversionCancelled> {
+    if cancel.is_cancelled() {
+        Err(ConversionCancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Encoded bytes and their digest
+fn finish(writer: DigestingWriter<Vec<u8>>) -> (Bytes, BodyDigest) {
+    let (buffer, digest) = writer.finish();
+    (Bytes::from(buffer), digest)
 }
 
 /// Parse Accept header to determine preferred image format
@@ -150,29 +710,22 @@ impl ImageConverter {
 /// - */* has quality 0.5
 /// 
 /// Result: Returns Avif (first format with highest quality 1.0)
-pub fn parse_accept_header(accept: &str, enable_avif: bool, enable_webp: bool) -> OutputFormat {
+///
+/// `*/*` expresses no preference and keeps the original. With `image_wildcard_auto`,
+/// `image/*` winning means any image will do, which gets the best enabled format;
+/// otherwise it keeps the original too.
+pub fn parse_accept_header(accept: &str, enable_avif: bool, enable_webp: bool, image_wildcard_auto: bool) -> OutputFormat {
     // Parse media types and their quality values
     let mut formats: Vec<(OutputFormat, f32)> = Vec::new();
     
-    for part in accept.split(',') {
-        let part = part.trim();
-        let mut segments = part.split(';');
-        let media_type = segments.next().unwrap_or("").trim();
-        
-        // Extract quality value (default to 1.0)
-        let quality = segments
-            .find_map(|s| {
-                let s = s.trim();
-                s.strip_prefix("q=")?.parse::<f32>().ok()
-            })
-            .unwrap_or(1.0);
-        
+    for (media_type, quality) in accept_entries(accept) {
         // Map media type to output format
         let format = match media_type {
-            "image/avif" if enable_avif => Some(OutputFormat::Avif),
+            "image/avif" if enable_avif && AVIF_COMPILED => Some(OutputFormat::Avif),
             "image/webp" if enable_webp => Some(OutputFormat::WebP),
             "image/jpeg" => Some(OutputFormat::Jpeg),
             "image/png" => Some(OutputFormat::Png),
+            "image/*" if image_wildcard_auto => Some(preferred_format(enable_avif, enable_webp)),
             "image/*" => Some(OutputFormat::Original),
             "*/*" => Some(OutputFormat::Original),
             _ => None,
@@ -193,14 +746,246 @@ pub fn parse_accept_header(accept: &str, enable_avif: bool, enable_webp: bool) -
         .unwrap_or(OutputFormat::Original)
 }
 
+/// Best enabled output format, the original when none is
+fn preferred_format(enable_avif: bool, enable_webp: bool) -> OutputFormat {
+    if enable_avif && AVIF_COMPILED {
+        OutputFormat::Avif
+    } else if enable_webp {
+        OutputFormat::WebP
+    } else {
+        OutputFormat::Original
+    }
+}
+
+/// Largest dimensions with the aspect ratio of `width` x `height` that fit the bounds
+///
+/// Rounds like [`DynamicImage::resize`], so both resize paths agree on the output size.
+fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let ratio = f64::min(
+        f64::from(max_width) / f64::from(width),
+        f64::from(max_height) / f64::from(height),
+    );
+    let fit = |side: u32| ((f64::from(side) * ratio).round() as u32).max(1);
+    (fit(width), fit(height))
+}
+
+/// Media types listed in an Accept header with their quality values, in order
+fn accept_entries(accept: &str) -> impl Iterator<Item = (&str, f32)> {
+    accept.split(',').map(|part| {
+        let mut segments = part.trim().split(';');
+        let media_type = segments.next().unwrap_or("").trim();
+        
+        // Extract quality value (default to 1.0)
+        let quality = segments
+            .find_map(|s| {
+                let s = s.trim();
+                s.strip_prefix("q=")?.parse::<f32>().ok()
+            })
+            .unwrap_or(1.0);
+        (media_type, quality)
+    })
+}
+
+/// Quality value of a media type listed explicitly in an Accept header
+///
+/// Wildcards do not count; types that are not listed get `0.0`.
+pub fn explicit_quality(accept: &str, media_type: &str) -> f32 {
+    accept_entries(accept)
+        .filter(|(listed, _)| listed.eq_ignore_ascii_case(media_type))
+        .map(|(_, quality)| quality)
+        .fold(0.0, f32::max)
+}
+
+/// The other legacy format a client demanding JPEG or PNG accepts just as much
+///
+/// Only explicitly listed media types count, wildcards do not. A source already in
+/// the returned format can be served as is instead of being re-encoded.
+pub fn legacy_alternative_format(accept: &str, demanded: OutputFormat) -> Option<OutputFormat> {
+    let (demanded_type, alternative, alternative_type) = match demanded {
+        OutputFormat::Jpeg => ("image/jpeg", OutputFormat::Png, "image/png"),
+        OutputFormat::Png => ("image/png", OutputFormat::Jpeg, "image/jpeg"),
+        _ => return None,
+    };
+    let quality_of = |wanted: &str| {
+        accept_entries(accept)
+            .filter(|(media_type, _)| *media_type == wanted)
+            .map(|(_, quality)| quality)
+            .reduce(f32::max)
+    };
+    match (quality_of(demanded_type), quality_of(alternative_type)) {
+        (Some(demanded), Some(other)) if other > 0.0 && other >= demanded => Some(alternative),
+        _ => None,
+    }
+}
+
+/// Accept values longer than this are negotiated without being memoized
+const MAX_MEMOIZED_ACCEPT_LEN: usize = 512;
+
+/// Memo of negotiated formats by Accept header
+///
+/// Real traffic carries only a handful of distinct Accept values, one per browser
+/// family. The enable flags are part of the key, so results stay correct when
+/// they change.
+#[derive(Clone)]
+pub struct AcceptCache {
+    formats: moka::sync::Cache<(String, bool, bool, bool), OutputFormat>,
+}
+
+impl AcceptCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            formats: moka::sync::Cache::new(capacity),
+        }
+    }
+    
+    /// Same result as [`parse_accept_header`], parsing each distinct value once
+    pub fn negotiate(&self, accept: &str, enable_avif: bool, enable_webp: bool, image_wildcard_auto: bool) -> OutputFormat {
+        if accept.len() > MAX_MEMOIZED_ACCEPT_LEN {
+            return parse_accept_header(accept, enable_avif, enable_webp, image_wildcard_auto);
+        }
+        
+        self.formats.get_with((accept.to_string(), enable_avif, enable_webp, image_wildcard_auto), || {
+            parse_accept_header(accept, enable_avif, enable_webp, image_wildcard_auto)
+        })
+    }
+}
+
+/// Check if the image data contains more than one frame
+///
+/// Only GIF and WebP are inspected; every other format is treated as static.
+/// For GIF, decoding stops after the second frame.
+pub fn is_animated(data: &[u8]) -> bool {
+    match image::guess_format(data) {
+        Ok(ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.into_frames().take(2).count() > 1)
+            .unwrap_or(false),
+        Ok(ImageFormat::WebP) => image::codecs::webp::WebPDecoder::new(Cursor::new(data))
+            .map(|decoder| decoder.has_animation())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Check whether the image has an alpha channel, reading only its header
+///
+/// Sources with an alpha channel count even when every pixel is opaque.
+pub fn has_alpha(data: &[u8]) -> bool {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok())
+        .is_some_and(|decoder| decoder.color_type().has_alpha())
+}
+
+/// Check whether an image body plausibly matches its declared content type
+///
+/// Empty bodies never match. Raster types the sniffer can recognize must start
+/// with a known image signature; anything else (SVG, HEIC, TGA, ...) is trusted.
+pub fn body_matches_image_type(content_type: &str, data: &[u8]) -> bool {
+    if data.is_empty() {
+        return false;
+    }
+    
+    let mime_type = content_type_essence(content_type);
+    let mime_type = if mime_type == "image/jpg" { "image/jpeg" } else { &mime_type };
+    match ImageFormat::from_mime_type(mime_type) {
+        Some(ImageFormat::Tga) | None => true,
+        Some(_) => image::guess_format(data).is_ok(),
+    }
+}
+
+/// Score given to a conversion whose output matches the source exactly, in dB
+pub const MAX_QUALITY_SCORE: f64 = 100.0;
+
+/// Longest side of the copies compared by [`quality_score`]
+const QUALITY_AUDIT_DIMENSION: u32 = 256;
+
+/// PSNR in dB of the luma of a converted image against its source
+///
+/// Both are decoded and scaled to the converted image's aspect ratio, at most
+/// 256 pixels on the longer side, which keeps the cost independent of the image
+/// size and absorbs any resize done during conversion. Higher is better; around
+/// 30 dB differences start to be visible. Fails for outputs that cannot be
+/// decoded, such as AVIF.
+pub fn quality_score(source: &[u8], converted: &[u8]) -> Result<f64> {
+    let (source, ..) = decode(source)?;
+    let (converted, ..) = decode(converted)?;
+    
+    let (width, height) = converted.dimensions();
+    let scale = (QUALITY_AUDIT_DIMENSION as f64 / width.max(height) as f64).min(1.0);
+    let width = ((width as f64 * scale) as u32).max(1);
+    let height = ((height as f64 * scale) as u32).max(1);
+    let filter = image::imageops::FilterType::Triangle;
+    let source = source.resize_exact(width, height, filter).to_luma8();
+    let converted = converted.resize_exact(width, height, filter).to_luma8();
+    
+    let squared_error: f64 = source
+        .pixels()
+        .zip(converted.pixels())
+        .map(|(a, b)| (a[0] as f64 - b[0] as f64).powi(2))
+        .sum();
+    let mse = squared_error / (width as f64 * height as f64);
+    if mse == 0.0 {
+        return Ok(MAX_QUALITY_SCORE);
+    }
+    Ok((10.0 * (255.0 * 255.0 / mse).log10()).min(MAX_QUALITY_SCORE))
+}
+
+/// Whether the signature of `content_type` can be recognized in a body
+pub fn is_sniffable_image_type(content_type: &str) -> bool {
+    sniffable_format(content_type).is_some()
+}
+
+/// Whether `data` starts with the signature of `content_type`
+///
+/// Unlike [`body_matches_image_type`] this never trusts a type it cannot recognize.
+pub fn body_is_image_type(content_type: &str, data: &[u8]) -> bool {
+    sniffable_format(content_type).is_some_and(|format| image::guess_format(data).is_ok_and(|guessed| guessed == format))
+}
+
+/// Content type of an image recognized by its signature, for bodies sent without one
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    let mime_type = image::guess_format(data).ok()?.to_mime_type();
+    is_sniffable_image_type(mime_type).then_some(mime_type)
+}
+
+fn sniffable_format(content_type: &str) -> Option<ImageFormat> {
+    let mime_type = content_type_essence(content_type);
+    let mime_type = if mime_type == "image/jpg" { "image/jpeg" } else { &mime_type };
+    ImageFormat::from_mime_type(mime_type).filter(|format| *format != ImageFormat::Tga)
+}
+
+/// Media type of a content type without parameters, lowercased
+fn content_type_essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase()
+}
+
+/// Normalize an upstream content type for matching and storage
+///
+/// The media type is lowercased and trimmed. Parameters are dropped for images,
+/// where they carry no meaning, and kept for everything else since a charset matters there.
+pub fn normalize_content_type(content_type: &str) -> String {
+    let essence = content_type_essence(content_type);
+    if essence.starts_with("image/") {
+        return essence;
+    }
+    
+    let mut normalized = essence;
+    for param in content_type.split(';').skip(1).map(str::trim).filter(|p| !p.is_empty()) {
+        normalized.push_str("; ");
+        normalized.push_str(param);
+    }
+    normalized
+}
+
 /// Check if content type is an image
 pub fn is_image_content_type(content_type: &str) -> bool {
-    content_type.starts_with("image/")
+    content_type_essence(content_type).starts_with("image/")
 }
 
 /// Get OutputFormat from content-type string
 pub fn format_from_content_type(content_type: &str) -> Option<OutputFormat> {
-    match content_type {
+    match content_type_essence(content_type).as_str() {
         "image/avif" => Some(OutputFormat::Avif),
         "image/webp" => Some(OutputFormat::WebP),
         "image/jpeg" | "image/jpg" => Some(OutputFormat::Jpeg),
@@ -209,37 +994,497 @@ pub fn format_from_content_type(content_type: &str) -> Option<OutputFormat> {
     }
 }
 
+/// Format a file name extension stands for, ignoring case
+pub fn format_from_extension(extension: &str) -> Option<OutputFormat> {
+    match extension.to_ascii_lowercase().as_str() {
+        "avif" => Some(OutputFormat::Avif),
+        "webp" => Some(OutputFormat::WebP),
+        "jpg" | "jpeg" | "jpe" | "jfif" => Some(OutputFormat::Jpeg),
+        "png" => Some(OutputFormat::Png),
+        _ => None,
+    }
+}
+
+/// Usual file name extension of a format, `None` for the original
+pub fn format_extension(format: OutputFormat) -> Option<&'static str> {
+    match format {
+        OutputFormat::Avif => Some("avif"),
+        OutputFormat::WebP => Some("webp"),
+        OutputFormat::Jpeg => Some("jpg"),
+        OutputFormat::Png => Some("png"),
+        OutputFormat::Original => None,
+    }
+}
+
+/// Media type of a format, `None` for the original
+pub fn format_media_type(format: OutputFormat) -> Option<&'static str> {
+    match format {
+        OutputFormat::Avif => Some("image/avif"),
+        OutputFormat::WebP => Some("image/webp"),
+        OutputFormat::Jpeg => Some("image/jpeg"),
+        OutputFormat::Png => Some("image/png"),
+        OutputFormat::Original => None,
+    }
+}
+
+/// Whether an Accept header lists `media_type` explicitly with `q=0`
+pub fn accept_refuses(accept: &str, media_type: &str) -> bool {
+    accept_entries(accept).any(|(listed, quality)| listed.eq_ignore_ascii_case(media_type) && quality <= 0.0)
+}
+
 /// Check if the upstream format satisfies the desired format
 /// Returns true if no conversion is needed
 pub fn format_satisfies(upstream_format: OutputFormat, desired_format: OutputFormat) -> bool {
     upstream_format == desired_format || desired_format == OutputFormat::Original
 }
 
+/// GIF fixture with `frame_count` 4x4 frames of distinct colours
+#[cfg(test)]
+pub(crate) fn encode_gif(frame_count: usize) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut buffer);
+        for i in 0..frame_count {
+            let pixel = image::Rgba([(i * 80) as u8, 0, 0, 255]);
+            let frame = image::Frame::new(image::RgbaImage::from_pixel(4, 4, pixel));
+            encoder.encode_frame(frame).unwrap();
+        }
+    }
+    buffer
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[cfg(feature = "avif")]
     fn test_parse_accept_avif_preferred() {
         let accept = "image/avif,image/webp,image/jpeg";
-        let format = parse_accept_header(accept, true, true);
+        let format = parse_accept_header(accept, true, true, true);
         assert_eq!(format, OutputFormat::Avif);
     }
 
     #[test]
     fn test_parse_accept_webp_preferred() {
         let accept = "image/webp;q=1.0,image/avif;q=0.8,image/jpeg;q=0.5";
-        let format = parse_accept_header(accept, true, true);
+        let format = parse_accept_header(accept, true, true, true);
         assert_eq!(format, OutputFormat::WebP);
     }
 
     #[test]
     fn test_parse_accept_avif_disabled() {
         let accept = "image/avif,image/webp,image/jpeg";
-        let format = parse_accept_header(accept, false, true);
+        let format = parse_accept_header(accept, false, true, true);
         assert_eq!(format, OutputFormat::WebP);
     }
 
+    #[test]
+    fn test_parse_accept_wildcards() {
+        // Any image will do, so the best enabled format is served
+        assert_eq!(parse_accept_header("image/*", false, true, true), OutputFormat::WebP);
+        assert_eq!(parse_accept_header("image/*,*/*;q=0.8", false, true, true), OutputFormat::WebP);
+        assert_eq!(parse_accept_header("image/*", false, false, true), OutputFormat::Original);
+        // Only when the wildcard ranks first
+        assert_eq!(parse_accept_header("image/png,image/*;q=0.8", false, true, true), OutputFormat::Png);
+        assert_eq!(parse_accept_header("image/*;q=0.5,*/*", false, true, true), OutputFormat::Original);
+        // No preference at all keeps the original
+        assert_eq!(parse_accept_header("*/*", true, true, true), OutputFormat::Original);
+        assert_eq!(parse_accept_header("", true, true, true), OutputFormat::Original);
+        
+        // Without the setting, image/* is as unspecific as */*
+        assert_eq!(parse_accept_header("image/*", true, true, false), OutputFormat::Original);
+        assert_eq!(parse_accept_header("image/*,*/*;q=0.8", false, true, false), OutputFormat::Original);
+    }
+    
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_parse_accept_image_wildcard_prefers_avif() {
+        assert_eq!(parse_accept_header("image/*", true, true, true), OutputFormat::Avif);
+    }
+
+    #[test]
+    fn test_encode_from_shared_decode_matches_convert() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(96, 48, |x, y| image::Rgb([x as u8, y as u8, (x ^ y) as u8])))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let png = Bytes::from(png);
+        let converter = ImageConverter::new(70, 32, true, true).with_deterministic_encoding(true);
+        let cancel = CancellationToken::new();
+        
+        let decoded = converter.decode(&png, &cancel).unwrap();
+        assert_eq!(decoded.dimensions(), (32, 16));
+        assert_eq!(decoded.source_format, Some(ImageFormat::Png));
+        assert_eq!(decoded.orientation, Orientation::NoTransforms);
+        let settings = converter.encode_settings(png.len()).unwrap();
+        let mut formats = vec![OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png];
+        if AVIF_COMPILED {
+            formats.push(OutputFormat::Avif);
+        }
+        for format in formats {
+            let shared = converter.encode(&decoded, format, settings).unwrap();
+            let one_shot = converter.convert_digested(&png, format, &cancel).unwrap();
+            assert_eq!(shared.data, one_shot.data, "{:?}", format);
+            assert_eq!(shared.mime_type, one_shot.mime_type);
+        }
+        // One decode for the shared image, one per one-shot conversion
+        assert_eq!(converter.decode_count(), 1 + if AVIF_COMPILED { 4 } else { 3 });
+        
+        // The decoded source can be kept from one conversion and reused for the next
+        let (webp, kept) = converter.convert_keeping_decoded(&png, OutputFormat::WebP, &cancel).unwrap();
+        let decodes = converter.decode_count();
+        let jpeg = converter.convert_decoded(&png, kept.unwrap(), OutputFormat::Jpeg, &cancel).unwrap();
+        assert_eq!(converter.decode_count(), decodes);
+        assert_eq!(webp.mime_type, "image/webp");
+        assert_eq!(jpeg.data, converter.convert(&png, OutputFormat::Jpeg, &cancel).unwrap().0);
+    }
+
+    #[test]
+    fn test_legacy_alternative() {
+        assert_eq!(legacy_alternative_format("image/png, image/jpeg", OutputFormat::Png), Some(OutputFormat::Jpeg));
+        assert_eq!(legacy_alternative_format("image/jpeg,image/png;q=1.0", OutputFormat::Jpeg), Some(OutputFormat::Png));
+        assert_eq!(legacy_alternative_format("image/png,image/jpeg;q=0.9", OutputFormat::Png), None);
+        assert_eq!(legacy_alternative_format("image/png,image/jpeg;q=0", OutputFormat::Png), None);
+        // Wildcards do not name a format
+        assert_eq!(legacy_alternative_format("image/png,image/*,*/*", OutputFormat::Png), None);
+        assert_eq!(legacy_alternative_format("image/webp,image/png", OutputFormat::WebP), None);
+    }
+
+    #[test]
+    fn test_has_alpha() {
+        let mut opaque = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::new(4, 4))
+            .write_to(&mut Cursor::new(&mut opaque), ImageFormat::Png)
+            .unwrap();
+        let mut transparent = Vec::new();
+        DynamicImage::ImageRgba8(image::RgbaImage::new(4, 4))
+            .write_to(&mut Cursor::new(&mut transparent), ImageFormat::Png)
+            .unwrap();
+        assert!(!has_alpha(&opaque));
+        assert!(has_alpha(&transparent));
+        assert!(!has_alpha(b"not an image"));
+    }
+
+    #[test]
+    fn test_accept_cache_matches_parser() {
+        let corpus = [
+            // Chrome, Edge and Opera
+            "image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8",
+            // Firefox
+            "image/avif,image/webp,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
+            "image/avif,image/webp,*/*",
+            // Safari
+            "image/webp,image/avif,image/jxl,image/heic,image/heic-sequence,video/*;q=0.8,image/png,image/svg+xml,image/*;q=0.8,*/*;q=0.5",
+            "image/webp,image/png,image/svg+xml,image/*;q=0.8,video/*;q=0.8,*/*;q=0.5",
+            // Fediverse servers and command line clients
+            "image/png,image/*;q=0.9,*/*;q=0.5",
+            "image/jpeg",
+            "*/*",
+            "",
+            "image/webp;q=0.5, image/avif;q=0.9",
+            "image/avif;q=abc,image/webp",
+        ];
+        let cache = AcceptCache::new(128);
+        
+        for _ in 0..2 {
+            for accept in corpus {
+                for (avif, webp, auto) in (0..8).map(|bits| (bits & 1 != 0, bits & 2 != 0, bits & 4 != 0)) {
+                    assert_eq!(
+                        cache.negotiate(accept, avif, webp, auto),
+                        parse_accept_header(accept, avif, webp, auto),
+                        "{:?} with avif={} webp={} auto={}", accept, avif, webp, auto,
+                    );
+                }
+            }
+        }
+        
+        let long = format!("{},image/webp", "x".repeat(MAX_MEMOIZED_ACCEPT_LEN));
+        assert_eq!(cache.negotiate(&long, true, true, true), OutputFormat::WebP);
+        cache.formats.run_pending_tasks();
+        assert_eq!(cache.formats.entry_count(), corpus.len() as u64 * 8);
+    }
+    
+    #[test]
+    fn test_is_animated() {
+        assert!(is_animated(&encode_gif(3)));
+        assert!(!is_animated(&encode_gif(1)));
+        assert!(!is_animated(b"not an image"));
+    }
+
+    #[test]
+    fn test_convert_animated_gif_keeps_first_frame() {
+        let converter = ImageConverter::new(85, 4096, true, true);
+        let gif = Bytes::from(encode_gif(3));
+
+        let (webp, mime_type) = converter
+            .convert(&gif, OutputFormat::WebP, &CancellationToken::new())
+            .unwrap();
+        assert_eq!(mime_type, "image/webp");
+        assert!(!is_animated(&webp));
+
+        let decoded = image::load_from_memory(&webp).unwrap().to_rgba8();
+        assert_eq!(decoded.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_body_matches_image_type() {
+        let gif = encode_gif(1);
+        assert!(body_matches_image_type("image/gif", &gif));
+        // A different image format than declared is still an image
+        assert!(body_matches_image_type("image/jpeg; charset=binary", &gif));
+        assert!(!body_matches_image_type("image/jpeg", b"<html>oops</html>"));
+        assert!(!body_matches_image_type("image/jpg", b"<html>oops</html>"));
+        assert!(!body_matches_image_type("image/png", b""));
+        assert!(body_matches_image_type("image/svg+xml", b"<svg></svg>"));
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let gif = encode_gif(1);
+        let converter = ImageConverter::new(85, 4096, true, true);
+        assert!(converter.check_limits(&gif).is_ok());
+        
+        let converter = converter.with_max_pixels(Some(16));
+        assert!(converter.check_limits(&gif).is_ok());
+        
+        let converter = ImageConverter::new(85, 4096, true, true).with_max_pixels(Some(15));
+        assert!(matches!(
+            converter.check_limits(&gif),
+            Err(VariantError::TooManyPixels { actual: 16, limit: 15 })
+        ));
+        let err = converter
+            .convert(&Bytes::from(gif), OutputFormat::WebP, &CancellationToken::new())
+            .unwrap_err();
+        assert!(matches!(err, ImageError::DecodeLimitsExceeded(VariantError::TooManyPixels { .. })));
+    }
+
+    #[test]
+    fn test_convert_cancelled() {
+        let converter = ImageConverter::new(85, 4096, true, true);
+        let gif = Bytes::from(encode_gif(1));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        
+        let err = converter
+            .convert(&gif, OutputFormat::Png, &cancel)
+            .unwrap_err();
+        assert!(matches!(err, ImageError::Cancelled(_)));
+    }
+
+    #[test]
+    fn test_conversion_error_variants() {
+        let converter = ImageConverter::new(85, 4096, true, true);
+        let convert = |data: Vec<u8>| {
+            converter
+                .convert(&Bytes::from(data), OutputFormat::Png, &CancellationToken::new())
+                .unwrap_err()
+        };
+        
+        // A GIF cut short after its header
+        let mut truncated = encode_gif(1);
+        truncated.truncate(20);
+        let err = convert(truncated);
+        assert!(matches!(err, ImageError::DecodeCorrupt(_)), "{:?}", err);
+        assert_eq!(err.kind(), Some("decode_corrupt"));
+        
+        // AVIF is recognized by its container but cannot be decoded
+        let mut avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf".to_vec();
+        avif.resize(64, 0);
+        let err = convert(avif);
+        assert!(matches!(err, ImageError::DecodeUnsupported(_)), "{:?}", err);
+        assert!(matches!(convert(b"not an image".to_vec()), ImageError::DecodeUnsupported(_)));
+        
+        // JPEG dimensions are limited to 65535 pixels, so the encoder refuses a wider image
+        let wide = DynamicImage::new_luma8(70_000, 1);
+        let err = converter.to_jpeg(&wide, converter.encode_settings(0).unwrap()).unwrap_err();
+        assert!(matches!(err, ImageError::EncodeFailed { format: OutputFormat::Jpeg, .. }), "{:?}", err);
+        assert_eq!(err.kind(), Some("encode_failed"));
+        assert_eq!(ImageError::Cancelled(ConversionCancelled).kind(), None);
+    }
+
+    fn tier(min_size: u64, max_size: Option<u64>, quality: u8, avif_speed: u8, convert: bool) -> ImageTierConfig {
+        ImageTierConfig {
+            min_size,
+            max_size,
+            avif_speed: Some(avif_speed),
+            quality: Some(quality),
+            convert,
+        }
+    }
+
+    /// PNG of smooth gradients with some detail, as photos have
+    fn encode_gradient_png(size: u32) -> Bytes {
+        let img = image::RgbImage::from_fn(size, size, |x, y| {
+            let detail = if (x / 4 + y / 4) % 2 == 0 { 24 } else { 0 };
+            image::Rgb([(x * 255 / size) as u8, (y * 255 / size) as u8, ((x + y) * 100 / size) as u8 + detail])
+        });
+        let mut buffer = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png).unwrap();
+        Bytes::from(buffer)
+    }
+
+    /// PNG of noisy pixels, so the encoded size depends on quality
+    #[cfg(feature = "avif")]
+    fn encode_noise_png(size: u32) -> Bytes {
+        let img = image::RgbImage::from_fn(size, size, |x, y| {
+            let v = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) as u8;
+            image::Rgb([v, v.wrapping_mul(3), v.wrapping_add(x as u8)])
+        });
+        let mut buffer = Vec::new();
+        img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png).unwrap();
+        Bytes::from(buffer)
+    }
+
+    #[test]
+    fn test_encode_settings_by_tier() {
+        let converter = ImageConverter::new(85, 4096, true, true).with_tiers(vec![
+            tier(0, Some(1_000), 85, 10, false),
+            tier(1_000, Some(100_000), 90, 4, true),
+            tier(1_000_000, None, 60, 10, true),
+        ]);
+        
+        assert_eq!(converter.encode_settings(500), None);
+        assert_eq!(converter.encode_settings(1_000), Some(EncodeSettings { quality: 90, avif_speed: 4 }));
+        // Gaps between tiers use the global settings
+        assert_eq!(converter.encode_settings(500_000), Some(EncodeSettings { quality: 85, avif_speed: 10 }));
+        assert_eq!(converter.encode_settings(5_000_000), Some(EncodeSettings { quality: 60, avif_speed: 10 }));
+        
+        // Without tiers every size uses the global settings
+        let converter = ImageConverter::new(70, 4096, true, true);
+        assert_eq!(converter.encode_settings(0), Some(EncodeSettings { quality: 70, avif_speed: 10 }));
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_convert_applies_tier_settings() {
+        let small = encode_noise_png(8);
+        let large = encode_noise_png(48);
+        let boundary = large.len() as u64;
+        assert!((small.len() as u64) < boundary);
+        
+        // Small sources are passed through, large ones encoded with their tier's quality
+        let tiers = |large_quality| vec![
+            tier(0, Some(boundary), 85, 10, false),
+            tier(boundary, None, large_quality, 10, true),
+        ];
+        let low = ImageConverter::new(85, 4096, true, true).with_tiers(tiers(20));
+        let high = ImageConverter::new(85, 4096, true, true).with_tiers(tiers(95));
+        let cancel = CancellationToken::new();
+        
+        let (passed, _) = low.convert(&small, OutputFormat::Avif, &cancel).unwrap();
+        assert_eq!(passed, small);
+        
+        let (low_output, content_type) = low.convert(&large, OutputFormat::Avif, &cancel).unwrap();
+        assert_eq!(content_type, "image/avif");
+        let (high_output, _) = high.convert(&large, OutputFormat::Avif, &cancel).unwrap();
+        assert!(low_output.len() < high_output.len(),
+                "quality 20 gave {} bytes, quality 95 gave {}", low_output.len(), high_output.len());
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_avif_thread_counts() {
+        let png = encode_noise_png(32);
+        for threads in [1, 4] {
+            let converter = ImageConverter::new(85, 4096, true, true).with_avif_threads(Some(threads));
+            let (avif, content_type) = converter
+                .convert(&png, OutputFormat::Avif, &CancellationToken::new())
+                .unwrap();
+            assert_eq!(content_type, "image/avif");
+            // The image crate is built without an AVIF decoder, so check the container only
+            assert_eq!(image::guess_format(&avif).unwrap(), ImageFormat::Avif);
+            assert_eq!(&avif[4..12], b"ftypavif");
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_deterministic_encoding_across_converters() {
+        let png = encode_noise_png(160);
+        // Thread settings differ between the two, as they may between replicas
+        let first = ImageConverter::new(85, 4096, true, true)
+            .with_avif_threads(Some(1))
+            .with_deterministic_encoding(true);
+        let second = ImageConverter::new(85, 4096, true, true)
+            .with_avif_threads(Some(4))
+            .with_deterministic_encoding(true);
+        let cancel = CancellationToken::new();
+        for format in [OutputFormat::Avif, OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png] {
+            let (once, _) = first.convert(&png, format, &cancel).unwrap();
+            let (again, _) = first.convert(&png, format, &cancel).unwrap();
+            let (elsewhere, _) = second.convert(&png, format, &cancel).unwrap();
+            assert_eq!(once, again, "{:?}", format);
+            assert_eq!(once, elsewhere, "{:?}", format);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "avif")]
+    fn test_convert_digest_matches_output() {
+        let png = encode_noise_png(16);
+        let converter = ImageConverter::new(85, 4096, true, true);
+        let cancel = CancellationToken::new();
+        for format in [OutputFormat::Avif, OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png] {
+            let converted = converter.convert_digested(&png, format, &cancel).unwrap();
+            assert_eq!(converted.digest, Some(BodyDigest::of(&converted.data)), "{:?}", format);
+        }
+        
+        // Untouched sources are not hashed again
+        let converted = converter.convert_digested(&png, OutputFormat::Original, &cancel).unwrap();
+        assert_eq!(converted.data, png);
+        assert_eq!(converted.digest, None);
+    }
+
+    #[test]
+    fn test_quality_score_orders_jpeg_qualities() {
+        let png = encode_gradient_png(128);
+        let cancel = CancellationToken::new();
+        let score = |quality| {
+            let converter = ImageConverter::new(quality, 4096, false, false);
+            let (jpeg, _) = converter.convert(&png, OutputFormat::Jpeg, &cancel).unwrap();
+            quality_score(&png, &jpeg).unwrap()
+        };
+        let low = score(10);
+        let high = score(90);
+        assert!(low < high, "quality 10 scored {:.1} dB, quality 90 scored {:.1} dB", low, high);
+        assert!(high > 30.0, "{}", high);
+        
+        // Lossless output matches its source, downscaled outputs are compared at their size
+        let (lossless, _) = ImageConverter::new(85, 4096, false, true)
+            .convert(&png, OutputFormat::WebP, &cancel)
+            .unwrap();
+        assert_eq!(quality_score(&png, &lossless).unwrap(), MAX_QUALITY_SCORE);
+        let (resized, _) = ImageConverter::new(85, 64, false, false)
+            .convert(&png, OutputFormat::Png, &cancel)
+            .unwrap();
+        assert!(quality_score(&png, &resized).unwrap() > 30.0);
+        
+        assert!(quality_score(&png, b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_two_step_resize_keeps_dimensions() {
+        let single = ImageConverter::new(85, 64, false, false);
+        let two_step = ImageConverter::new(85, 64, false, false).with_fast_resize_threshold(1000);
+        for (width, height) in [(1000, 10), (300, 200), (199, 301), (97, 95), (100, 80), (64, 64), (30, 20)] {
+            let img = DynamicImage::ImageRgb8(image::RgbImage::new(width, height));
+            let (expected, single_kind) = single.resize_if_needed(img.clone());
+            let (actual, kind) = two_step.resize_if_needed(img);
+            assert_eq!(actual.dimensions(), expected.dimensions(), "{}x{}", width, height);
+
+            let expected_kind = match (width, height) {
+                (..=64, ..=64) => ResizeKind::Skipped,
+                _ if width * height > 1000 => ResizeKind::TwoStep,
+                _ => ResizeKind::SingleStep,
+            };
+            assert_eq!(kind, expected_kind, "{}x{}", width, height);
+            if kind != ResizeKind::Skipped {
+                assert_eq!(single_kind, ResizeKind::SingleStep);
+            }
+        }
+    }
+
     #[test]
     fn test_is_image_content_type() {
         assert!(is_image_content_type("image/jpeg"));
@@ -259,6 +1504,62 @@ mod tests {
         assert_eq!(format_from_content_type("text/plain"), None);
     }
 
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(format_from_extension("avif"), Some(OutputFormat::Avif));
+        assert_eq!(format_from_extension("webp"), Some(OutputFormat::WebP));
+        assert_eq!(format_from_extension("JPG"), Some(OutputFormat::Jpeg));
+        assert_eq!(format_from_extension("jpeg"), Some(OutputFormat::Jpeg));
+        assert_eq!(format_from_extension("Png"), Some(OutputFormat::Png));
+        assert_eq!(format_from_extension("gif"), None);
+        assert_eq!(format_from_extension("mp4"), None);
+        assert_eq!(format_from_extension(""), None);
+        
+        // Every format with an extension maps back to itself
+        for format in [OutputFormat::Avif, OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png] {
+            assert_eq!(format_extension(format).and_then(format_from_extension), Some(format));
+            assert_eq!(format_media_type(format).and_then(format_from_content_type), Some(format));
+        }
+        assert_eq!(format_extension(OutputFormat::Original), None);
+    }
+
+    #[test]
+    fn test_accept_refuses() {
+        assert!(accept_refuses("image/avif,image/png;q=0", "image/png"));
+        assert!(accept_refuses("image/avif, IMAGE/PNG ; q=0.0", "image/png"));
+        assert!(!accept_refuses("image/avif", "image/png"));
+        assert!(!accept_refuses("image/png;q=0.1,*/*;q=0", "image/png"));
+        assert!(!accept_refuses("*/*;q=0", "image/png"));
+    }
+
+    #[test]
+    fn test_content_type_normalization() {
+        let cases = [
+            ("image/avif", OutputFormat::Avif),
+            ("image/webp", OutputFormat::WebP),
+            ("image/jpeg", OutputFormat::Jpeg),
+            ("image/jpg", OutputFormat::Jpeg),
+            ("image/png", OutputFormat::Png),
+        ];
+        for (essence, format) in cases {
+            for variant in [
+                essence.to_string(),
+                essence.to_uppercase(),
+                format!("{}; charset=utf-8", essence),
+                format!("  {} ;q=1", essence.to_uppercase()),
+            ] {
+                assert_eq!(format_from_content_type(&variant), Some(format), "{:?}", variant);
+                assert!(is_image_content_type(&variant), "{:?}", variant);
+                assert_eq!(normalize_content_type(&variant), essence, "{:?}", variant);
+            }
+        }
+        
+        // Parameters of non-image types are kept
+        assert_eq!(normalize_content_type(" Text/HTML ;charset=UTF-8"), "text/html; charset=UTF-8");
+        assert_eq!(normalize_content_type("application/json"), "application/json");
+        assert!(!is_image_content_type("text/html; charset=utf-8"));
+    }
+
     #[test]
     fn test_format_satisfies() {
         // Same format satisfies
@@ -273,4 +1574,51 @@ mod tests {
         assert!(!format_satisfies(OutputFormat::Jpeg, OutputFormat::Avif));
         assert!(!format_satisfies(OutputFormat::Png, OutputFormat::WebP));
     }
+
+    #[test]
+    fn test_encoder_self_test_passes() {
+        let mut image = ImageConfig::default();
+        let encoders = EncoderSelfTest::run(&image);
+        assert_eq!(encoders.webp, EncoderStatus::Ok);
+        if AVIF_COMPILED {
+            assert_eq!(encoders.avif, EncoderStatus::Ok);
+        } else {
+            assert!(matches!(&encoders.avif, EncoderStatus::Failed(reason) if reason.contains("not compiled in")));
+        }
+        
+        encoders.apply(&mut image);
+        assert_eq!(image.enable_avif, AVIF_COMPILED);
+        assert!(image.enable_webp);
+        
+        // Disabled formats are not tried at all
+        image.enable_webp = false;
+        let encoders = EncoderSelfTest::run_with(&image, |format| {
+            assert_ne!(format, OutputFormat::WebP);
+            Ok(())
+        });
+        assert_eq!(encoders.webp, EncoderStatus::Disabled);
+    }
+    
+    #[test]
+    fn test_failed_encoder_self_test_disables_format() {
+        let mut image = ImageConfig::default();
+        let encoders = EncoderSelfTest::run_with(&image, |format| match format {
+            OutputFormat::Avif => Err("AV1 encoder missing".to_string()),
+            _ => Ok(()),
+        });
+        assert_eq!(encoders.avif, EncoderStatus::Failed("AV1 encoder missing".to_string()));
+        assert_eq!(encoders.avif.to_string(), "failed (AV1 encoder missing)");
+        
+        encoders.apply(&mut image);
+        assert!(!image.enable_avif);
+        assert!(image.enable_webp);
+        assert_eq!(parse_accept_header("image/avif,image/webp", image.enable_avif, image.enable_webp, true), OutputFormat::WebP);
+    }
+    
+    #[test]
+    #[cfg(not(feature = "avif"))]
+    fn test_avif_never_negotiated_without_feature() {
+        assert_eq!(parse_accept_header("image/avif", true, true, true), OutputFormat::Original);
+        assert_eq!(parse_accept_header("image/*", true, true, true), OutputFormat::WebP);
+    }
 }
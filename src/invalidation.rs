@@ -0,0 +1,337 @@
+//! Purges shared between replicas
+//!
+//! Every replica keeps its own in-memory cache, so a purge made on one of them
+//! would leave the others serving the purged entries until they expire. With
+//! `[cache.invalidation]` configured, each purge is applied locally and then
+//! published on an [`InvalidationBus`]; replicas apply the messages they
+//! receive without publishing them again. The bus built from the configuration
+//! posts each message to the `/admin/cache/invalidation` endpoint of every peer.
+
+use async_trait::async_trait;
+use axum::http::header;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::metrics::Metrics;
+
+/// Path of the endpoint replicas receive invalidations on
+pub const INVALIDATION_ENDPOINT: &str = "/admin/cache/invalidation";
+
+/// What a purge removes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidationTarget {
+    /// Every format and query variant of a path
+    Path(String),
+    /// Every entry with a tag
+    Tag(String),
+}
+
+/// A purge made on one replica, to be applied by the others
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invalidation {
+    /// Replica that made the purge
+    pub node: String,
+    /// Increases with every message of `node`
+    ///
+    /// Starts at the replica's start time in milliseconds, so it keeps increasing
+    /// across restarts and replayed or reordered messages can be told apart.
+    pub seq: u64,
+    pub target: InvalidationTarget,
+}
+
+/// Channel purges are published on
+#[async_trait]
+pub trait InvalidationBus: Send + Sync {
+    /// Deliver `message` to the other replicas, describing the failure if it did not reach all of them
+    async fn publish(&self, message: &Invalidation) -> Result<(), String>;
+}
+
+/// Bus posting each message to the invalidation endpoint of a fixed list of peers
+pub struct HttpPeers {
+    client: reqwest::Client,
+    peers: Vec<String>,
+    token: Option<String>,
+}
+
+impl HttpPeers {
+    pub fn new(peers: Vec<String>, token: Option<String>, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(format!("akkoproxy/{}", env!("CARGO_PKG_VERSION")))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to create HTTP client");
+        Self { client, peers, token }
+    }
+
+    async fn post(&self, peer: &str, body: Vec<u8>) -> Result<(), String> {
+        let mut request = self
+            .client
+            .post(format!("{}{}", peer.trim_end_matches('/'), INVALIDATION_ENDPOINT))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("{} answered {}", peer, response.status())),
+            Err(e) => Err(format!("{}: {}", peer, e)),
+        }
+    }
+}
+
+#[async_trait]
+impl InvalidationBus for HttpPeers {
+    async fn publish(&self, message: &Invalidation) -> Result<(), String> {
+        let body = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+        let results = futures::future::join_all(self.peers.iter().map(|peer| self.post(peer, body.clone()))).await;
+        let failures: Vec<String> = results.into_iter().filter_map(Result::err).collect();
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join(", "))
+        }
+    }
+}
+
+/// Applies purges locally and shares them with the other replicas
+pub struct Invalidator {
+    node_id: String,
+    bus: Option<Arc<dyn InvalidationBus>>,
+    /// Sequence number of the next message
+    ///
+    /// Purges publish concurrently, so a peer may get them out of order and ignore
+    /// the older one; a bulk purge is never held up by one slow peer.
+    next_seq: AtomicU64,
+    /// Highest sequence number applied per replica
+    last_seen: Mutex<HashMap<String, u64>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Invalidator {
+    /// Publish purges on `bus`, if any, as `node_id`
+    pub fn new(node_id: String, bus: Option<Arc<dyn InvalidationBus>>, metrics: Arc<Metrics>) -> Self {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            node_id,
+            bus,
+            next_seq: AtomicU64::new(started.as_millis() as u64),
+            last_seen: Mutex::new(HashMap::new()),
+            metrics,
+        }
+    }
+
+    /// Invalidator for `cache.invalidation`, purging locally only when it is unset
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let Some(invalidation) = &config.cache.invalidation else {
+            return Self::new(random_node_id(), None, metrics);
+        };
+        let token = invalidation.token.clone().or_else(|| config.server.admin_token.clone());
+        let bus = HttpPeers::new(invalidation.peers.clone(), token, Duration::from_secs(invalidation.timeout));
+        let node_id = invalidation.node_id.clone().unwrap_or_else(random_node_id);
+        debug!("Purges shared with {} peer(s) as {}", invalidation.peers.len(), node_id);
+        Self::new(node_id, Some(Arc::new(bus)), metrics)
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Remove `target` from `cache` and publish the purge, returning the number of entries removed
+    ///
+    /// A purge the bus fails to deliver still applies here; the failure is logged and counted.
+    pub async fn purge(&self, cache: &ResponseCache, target: InvalidationTarget) -> usize {
+        let purged = apply(cache, &target).await;
+        let Some(bus) = &self.bus else {
+            return purged;
+        };
+        let message = Invalidation {
+            node: self.node_id.clone(),
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            target,
+        };
+        match bus.publish(&message).await {
+            Ok(()) => Metrics::incr(&self.metrics.invalidations_published),
+            Err(e) => {
+                warn!("Purge of {:?} applied on this replica only, publishing failed: {}", message.target, e);
+                Metrics::incr(&self.metrics.invalidation_publish_failures);
+            }
+        }
+        purged
+    }
+
+    /// Apply a purge published by another replica, `None` when it is ignored
+    ///
+    /// Messages of this replica itself and messages not newer than the last one
+    /// applied from their sender are ignored.
+    pub async fn receive(&self, cache: &ResponseCache, message: &Invalidation) -> Option<usize> {
+        let fresh = message.node != self.node_id && {
+            let mut last_seen = self.last_seen.lock().expect("invalidation sequence map poisoned");
+            let seen = last_seen.entry(message.node.clone()).or_insert(0);
+            let fresh = message.seq > *seen;
+            *seen = (*seen).max(message.seq);
+            fresh
+        };
+        if !fresh {
+            debug!("Ignoring purge {} of {}", message.seq, message.node);
+            Metrics::incr(&self.metrics.invalidations_ignored);
+            return None;
+        }
+        Metrics::incr(&self.metrics.invalidations_received);
+        Some(apply(cache, &message.target).await)
+    }
+}
+
+async fn apply(cache: &ResponseCache, target: &InvalidationTarget) -> usize {
+    match target {
+        InvalidationTarget::Path(path) => cache.purge_path(path).await,
+        InvalidationTarget::Tag(tag) => cache.purge_tag(tag).await,
+    }
+}
+
+/// Name for a replica configured without one, different on every start
+fn random_node_id() -> String {
+    let seed = (std::process::id(), SystemTime::now());
+    format!("{:016x}", std::hash::RandomState::new().hash_one(seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheKey, CachedMeta, CachedResponse};
+    use axum::http::StatusCode;
+    use bytes::Bytes;
+
+    /// Bus delivering every message to each subscribed replica, the sender included
+    #[derive(Default)]
+    struct MemoryBus {
+        replicas: Mutex<Vec<(Arc<Invalidator>, ResponseCache)>>,
+        fail: std::sync::atomic::AtomicBool,
+        /// How long each delivery takes, like a peer that is slow to answer
+        delay: Mutex<Duration>,
+        /// Sequence numbers published, in order of publishing
+        published: Mutex<Vec<u64>>,
+    }
+
+    #[async_trait]
+    impl InvalidationBus for MemoryBus {
+        async fn publish(&self, message: &Invalidation) -> Result<(), String> {
+            self.published.lock().unwrap().push(message.seq);
+            let delay = *self.delay.lock().unwrap();
+            tokio::time::sleep(delay).await;
+            if self.fail.load(std::sync::atomic::Ordering::Relaxed) {
+                return Err("bus down".to_string());
+            }
+            let replicas = self.replicas.lock().unwrap().clone();
+            for (invalidator, cache) in replicas {
+                invalidator.receive(&cache, message).await;
+            }
+            Ok(())
+        }
+    }
+
+    fn replica(bus: &Arc<MemoryBus>, node_id: &str) -> (Arc<Invalidator>, ResponseCache, Arc<Metrics>) {
+        let metrics = Metrics::new();
+        let invalidator = Arc::new(Invalidator::new(node_id.to_string(), Some(bus.clone()), metrics.clone()));
+        let cache = ResponseCache::new(100, Duration::from_secs(60), 1024);
+        bus.replicas.lock().unwrap().push((invalidator.clone(), cache.clone()));
+        (invalidator, cache, metrics)
+    }
+
+    async fn store(cache: &ResponseCache, path: &str, format: &str) -> CacheKey {
+        let key = CacheKey::new(path.to_string(), format.to_string());
+        let meta = CachedMeta::new("image/webp".to_string(), StatusCode::OK).with_tags(vec!["avatar".to_string()]);
+        cache.put(key.clone(), CachedResponse::new(Bytes::from_static(b"data"), meta)).await;
+        key
+    }
+
+    #[tokio::test]
+    async fn test_purge_reaches_other_replicas() {
+        let bus = Arc::new(MemoryBus::default());
+        let (a, cache_a, metrics_a) = replica(&bus, "a");
+        let (_, cache_b, metrics_b) = replica(&bus, "b");
+
+        let on_a = store(&cache_a, "/media/a.jpg", "WebP").await;
+        let on_b = store(&cache_b, "/media/a.jpg", "Avif").await;
+        let other = store(&cache_b, "/media/b.jpg", "WebP").await;
+
+        assert_eq!(a.purge(&cache_a, InvalidationTarget::Path("/media/a.jpg".to_string())).await, 1);
+        assert!(cache_a.get(&on_a).await.is_none());
+        assert!(cache_b.get(&on_b).await.is_none());
+        assert!(cache_b.get(&other).await.is_some());
+        assert_eq!(Metrics::get(&metrics_a.invalidations_published), 1);
+        assert_eq!(Metrics::get(&metrics_b.invalidations_received), 1);
+        // The sender got its own message back and ignored it
+        assert_eq!(Metrics::get(&metrics_a.invalidations_ignored), 1);
+
+        // Tags are shared the same way
+        a.purge(&cache_a, InvalidationTarget::Tag("avatar".to_string())).await;
+        assert!(cache_b.get(&other).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replayed_messages_ignored() {
+        let bus = Arc::new(MemoryBus::default());
+        let (receiver, cache, metrics) = replica(&bus, "b");
+        let message = |seq| Invalidation {
+            node: "a".to_string(),
+            seq,
+            target: InvalidationTarget::Path("/media/a.jpg".to_string()),
+        };
+
+        store(&cache, "/media/a.jpg", "WebP").await;
+        assert_eq!(receiver.receive(&cache, &message(10)).await, Some(1));
+        // A message replayed after a reconnect must not purge entries stored since
+        let key = store(&cache, "/media/a.jpg", "WebP").await;
+        assert_eq!(receiver.receive(&cache, &message(10)).await, None);
+        assert_eq!(receiver.receive(&cache, &message(9)).await, None);
+        assert!(cache.get(&key).await.is_some());
+        assert_eq!(receiver.receive(&cache, &message(11)).await, Some(1));
+        assert_eq!(Metrics::get(&metrics.invalidations_received), 2);
+        assert_eq!(Metrics::get(&metrics.invalidations_ignored), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_publish_purges_locally() {
+        let bus = Arc::new(MemoryBus::default());
+        let (a, cache_a, metrics) = replica(&bus, "a");
+        let (_, cache_b, _) = replica(&bus, "b");
+        let on_a = store(&cache_a, "/media/a.jpg", "WebP").await;
+        let on_b = store(&cache_b, "/media/a.jpg", "WebP").await;
+
+        bus.fail.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(a.purge(&cache_a, InvalidationTarget::Path("/media/a.jpg".to_string())).await, 1);
+        assert!(cache_a.get(&on_a).await.is_none());
+        assert!(cache_b.get(&on_b).await.is_some());
+        assert_eq!(Metrics::get(&metrics.invalidation_publish_failures), 1);
+        assert_eq!(Metrics::get(&metrics.invalidations_published), 0);
+    }
+
+    #[tokio::test]
+    async fn test_purges_publish_concurrently() {
+        let bus = Arc::new(MemoryBus::default());
+        let (a, cache_a, metrics) = replica(&bus, "a");
+        *bus.delay.lock().unwrap() = Duration::from_millis(200);
+
+        // A slow peer holds up each purge by its own delivery only
+        let started = std::time::Instant::now();
+        let purges = (0..5).map(|i| a.purge(&cache_a, InvalidationTarget::Path(format!("/media/{}.jpg", i))));
+        futures::future::join_all(purges).await;
+        assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+        assert_eq!(Metrics::get(&metrics.invalidations_published), 5);
+
+        let mut published = bus.published.lock().unwrap().clone();
+        published.sort_unstable();
+        published.dedup();
+        assert_eq!(published.len(), 5);
+    }
+}
@@ -0,0 +1,73 @@
+//! A caching and converting media proxy for Akkoma/Pleroma
+//!
+//! The `akkoproxy` binary is a thin CLI around this crate. The proxy can also be
+//! embedded into an existing axum application, for example one that already
+//! terminates TLS and handles authentication:
+//!
+//! ```no_run
+//! use akkoproxy::{build_router, config::Config};
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! let config = Config::with_upstream("https://akkoma.example.com".to_string());
+//! config.validate()?;
+//! let (proxy, _state) = build_router(config);
+//!
+//! let app = axum::Router::new().nest("/mediaproxy", proxy);
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+//! axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod audit;
+pub mod bench;
+pub mod cache;
+pub mod conditional;
+pub mod config;
+pub mod convert;
+pub mod digest;
+pub mod error_page;
+mod forwarded;
+mod headers;
+pub mod hedge;
+pub mod host_limit;
+pub mod image;
+pub mod invalidation;
+pub mod listener;
+pub mod logging;
+pub mod memo;
+pub mod memory;
+pub mod metrics;
+pub mod migration;
+pub mod ndjson;
+pub mod prefetch;
+pub mod preflight;
+pub mod pressure;
+pub mod proxy;
+pub mod range;
+pub mod resume;
+pub mod runtime_state;
+pub mod serve;
+pub mod shed;
+pub mod slow;
+pub mod stats;
+pub mod telemetry;
+pub mod throttle;
+pub mod upstream;
+pub mod url_template;
+
+use axum::Router;
+
+use crate::config::Config;
+use crate::proxy::{router, AppState};
+
+/// Build the proxy router and the state behind it
+///
+/// The router only relies on paths relative to where it is mounted, so it can
+/// be nested under a prefix of a parent router. Parents with their own state
+/// can mount it with `Router::nest_service`. The configuration should have been
+/// checked with [`Config::validate`] first.
+pub fn build_router(config: Config) -> (Router, AppState) {
+    let state = AppState::new(config);
+    (router(state.clone()), state)
+}
@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{SocketAddr, TcpListener};
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+
+/// First descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Where the listening socket comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerSource {
+    /// A descriptor named with `--inherit-fd`
+    InheritedFd(i32),
+    /// The first descriptor of systemd socket activation
+    Systemd,
+    /// A new socket bound to `server.bind`
+    Bind,
+}
+
+impl ListenerSource {
+    /// Source for this process: `--inherit-fd`, then `LISTEN_FDS`, then binding
+    pub fn detect(inherit_fd: Option<i32>) -> Self {
+        if let Some(fd) = inherit_fd {
+            return ListenerSource::InheritedFd(fd);
+        }
+
+        // LISTEN_PID guards against variables leaked to a child process
+        let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+        match std::env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<u32>().ok()) {
+            Some(count) if count > 0 && for_us => {
+                if count > 1 {
+                    warn!("Socket activation passed {} sockets, only the first is used", count);
+                }
+                ListenerSource::Systemd
+            }
+            _ => ListenerSource::Bind,
+        }
+    }
+}
+
+/// Listening sockets for the server, non-blocking so they can be handed to tokio
+///
+/// An inherited socket is used as is and `server.bind` is ignored. Otherwise a
+/// socket is bound to each address, with SO_REUSEPORT when `server.reuse_port`
+/// is set so a new process can bind the same addresses while the old one still
+/// drains. Any address failing to bind fails the whole startup.
+pub fn listen(server: &ServerConfig, source: ListenerSource) -> Result<Vec<TcpListener>> {
+    let listeners = match source {
+        ListenerSource::InheritedFd(fd) => {
+            vec![from_fd(fd).with_context(|| format!("Failed to use inherited descriptor {}", fd))?]
+        }
+        ListenerSource::Systemd => vec![from_fd(SD_LISTEN_FDS_START).context("Failed to use the socket-activated descriptor")?],
+        ListenerSource::Bind => {
+            let addrs = server.bind.addrs();
+            // IPv6 wildcards also take the port for IPv4 unless told not to, which would fail an IPv4 bind next to them
+            let only_v6 = addrs.iter().any(SocketAddr::is_ipv4);
+            addrs
+                .iter()
+                .map(|addr| bind(*addr, server.reuse_port, only_v6).with_context(|| format!("Failed to bind to {}", addr)))
+                .collect::<Result<_>>()?
+        }
+    };
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+        if source != ListenerSource::Bind {
+            info!("Listening on inherited socket {}", listener.local_addr()?);
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr, reuse_port: bool, only_v6: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    if addr.is_ipv6() && only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+fn set_reuse_port(socket: &Socket) -> Result<()> {
+    Ok(socket.set_reuse_port(true)?)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))))]
+fn set_reuse_port(_socket: &Socket) -> Result<()> {
+    anyhow::bail!("server.reuse_port is not supported on this platform")
+}
+
+/// Take ownership of a listening TCP socket passed by the parent process
+#[cfg(unix)]
+fn from_fd(fd: i32) -> Result<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    if fd < SD_LISTEN_FDS_START {
+        anyhow::bail!("descriptor {} is a standard stream", fd);
+    }
+    // SAFETY: the descriptor was passed to this process to be owned by it, and
+    // nothing else in the process refers to it. A descriptor that is not open
+    // fails the checks below and is closed again, which is harmless.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    if socket.r#type().context("not a socket")? != Type::STREAM {
+        anyhow::bail!("not a stream socket");
+    }
+    socket.local_addr()?.as_socket().context("not a TCP socket")?;
+    Ok(socket.into())
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32) -> Result<TcpListener> {
+    anyhow::bail!("inheriting sockets is only supported on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+    #[tokio::test]
+    async fn test_reuse_port_listeners_share_an_address() {
+        let mut config = Config::with_upstream("http://upstream.test".to_string());
+        config.server.bind = "127.0.0.1:0".parse().unwrap();
+        config.server.reuse_port = true;
+        let first = listen(&config.server, ListenerSource::Bind).unwrap().remove(0);
+        config.server.bind = first.local_addr().unwrap().into();
+        let second = listen(&config.server, ListenerSource::Bind).unwrap().remove(0);
+        assert_eq!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+        // Each serves on its own; the kernel spreads connections between them
+        for (listener, name) in [(first, "first"), (second, "second")] {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let app = axum::Router::new().route("/", axum::routing::get(move || async move { name }));
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        }
+        // A new connection per request, since the kernel picks a listener per connection
+        let client = reqwest::Client::builder().pool_max_idle_per_host(0).build().unwrap();
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..64 {
+            let response = client.get(format!("http://{}/", config.server.bind)).send().await.unwrap();
+            seen.insert(response.text().await.unwrap());
+            if seen.len() == 2 {
+                break;
+            }
+        }
+        assert_eq!(seen.len(), 2, "{:?}", seen);
+
+        // Without SO_REUSEPORT the address stays taken
+        config.server.reuse_port = false;
+        assert!(listen(&config.server, ListenerSource::Bind).is_err());
+    }
+
+    #[test]
+    fn test_every_address_gets_a_listener() {
+        let mut server = Config::with_upstream("http://upstream.test".to_string()).server;
+        server.bind = "127.0.0.1:0, 127.0.0.1:0".parse().unwrap();
+        let listeners = listen(&server, ListenerSource::Bind).unwrap();
+        assert_eq!(listeners.len(), 2);
+        let taken = listeners[0].local_addr().unwrap();
+        assert_ne!(taken, listeners[1].local_addr().unwrap());
+
+        // One address failing fails them all, naming it
+        server.bind = format!("127.0.0.1:0, {}", taken).parse().unwrap();
+        let error = format!("{:#}", listen(&server, ListenerSource::Bind).unwrap_err());
+        assert!(error.contains(&taken.to_string()), "{}", error);
+
+        // IPv4 and IPv6 wildcards share a port, where the system supports IPv6
+        if TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let port = TcpListener::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        server.bind = format!("0.0.0.0:{}, [::]:{}", port, port).parse().unwrap();
+        let listeners = listen(&server, ListenerSource::Bind).unwrap();
+        assert!(listeners[1].local_addr().unwrap().is_ipv6());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_inherited_descriptor_is_checked() {
+        use std::os::fd::IntoRawFd;
+
+        let server = Config::with_upstream("http://upstream.test".to_string()).server;
+        let bound = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = bound.local_addr().unwrap();
+        let listeners = listen(&server, ListenerSource::InheritedFd(bound.into_raw_fd())).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap(), addr);
+
+        assert!(listen(&server, ListenerSource::InheritedFd(1)).is_err());
+        let file = std::fs::File::open("Cargo.toml").unwrap();
+        assert!(listen(&server, ListenerSource::InheritedFd(file.into_raw_fd())).is_err());
+    }
+
+    #[test]
+    fn test_listener_source_detection() {
+        assert_eq!(ListenerSource::detect(Some(7)), ListenerSource::InheritedFd(7));
+        // The test runner is not socket-activated
+        assert_eq!(ListenerSource::detect(None), ListenerSource::Bind);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Fraction of high-frequency debug events that are emitted, stored as f64 bits
+static DEBUG_SAMPLE_RATE: AtomicU64 = AtomicU64::new(0x3FF0_0000_0000_0000); // 1.0
+
+/// Set the fraction (0.0 to 1.0) of sampled debug events that are emitted
+///
+/// Takes effect immediately for every call site, so it can be changed at runtime.
+pub fn set_debug_sample_rate(rate: f64) {
+    DEBUG_SAMPLE_RATE.store(rate.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+}
+
+/// Current debug sample rate
+pub fn debug_sample_rate() -> f64 {
+    f64::from_bits(DEBUG_SAMPLE_RATE.load(Ordering::Relaxed))
+}
+
+/// Decide whether the next event of a call site should be emitted
+///
+/// `counter` counts events seen by the call site. An event is emitted whenever
+/// the running total multiplied by the rate crosses an integer, which spreads
+/// emitted events evenly instead of relying on randomness.
+pub fn should_sample(counter: &AtomicU64) -> bool {
+    sample_at(debug_sample_rate(), counter)
+}
+
+/// Decide whether the next of the events counted by `counter` is sampled at `rate`
+///
+/// Spreads sampled events evenly like [`should_sample`], for rates other than the debug one.
+pub fn sample_at(rate: f64, counter: &AtomicU64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    if rate <= 0.0 {
+        return false;
+    }
+
+    let seen = counter.fetch_add(1, Ordering::Relaxed) as f64;
+    ((seen + 1.0) * rate).floor() > (seen * rate).floor()
+}
+
+/// Emit a debug event subject to the configured debug sample rate
+///
+/// Use this for events that fire on every request (cache hits and misses,
+/// conversion decisions). Warnings, errors and the access log are never sampled.
+macro_rules! sampled_debug {
+    ($($arg:tt)+) => {
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+            if $crate::logging::should_sample(&COUNTER) {
+                tracing::debug!($($arg)+);
+            }
+        }
+    };
+}
+
+pub(crate) use sampled_debug;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    /// Layer counting every event it sees
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_sampled_debug_rate() {
+        let captured = Arc::new(AtomicUsize::new(0));
+        let subscriber = tracing_subscriber::registry()
+            .with(CountingLayer(captured.clone()))
+            .with(tracing_subscriber::filter::LevelFilter::DEBUG);
+
+        tracing::subscriber::with_default(subscriber, || {
+            set_debug_sample_rate(0.1);
+            for i in 0..1000 {
+                sampled_debug!("sampled event {}", i);
+            }
+            set_debug_sample_rate(1.0);
+        });
+
+        let count = captured.load(Ordering::SeqCst);
+        assert!((90..=110).contains(&count), "captured {} events", count);
+    }
+}
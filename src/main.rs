@@ -1,22 +1,12 @@
-mod cache;
-mod config;
-mod image;
-mod proxy;
-
 use anyhow::{Context, Result};
-use axum::{
-    routing::get,
-    Router,
-};
-use clap::Parser;
-use std::net::SocketAddr;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
-use tower_http::trace::TraceLayer;
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::{info, warn};
 
-use crate::config::Config;
-use crate::proxy::{health_handler, metrics_handler, proxy_handler, AppState};
+use akkoproxy::config::{BindAddresses, Config, ConfigNote, ConfigWarning, ImageConfig};
+use akkoproxy::proxy::{router, AppState};
+use akkoproxy::listener::{self, ListenerSource};
+use akkoproxy::{bench, convert, logging, preflight, serve, telemetry};
 
 #[derive(Parser, Debug)]
 #[command(name = "akkoproxy")]
@@ -31,9 +21,9 @@ struct Cli {
     #[arg(short, long, value_name = "URL")]
     upstream: Option<String>,
 
-    /// Address to bind the server to (e.g., 0.0.0.0:3000)
+    /// Addresses to bind the server to, comma-separated (e.g., 0.0.0.0:3000,[::]:3000)
     #[arg(short, long, value_name = "ADDR")]
-    bind: Option<SocketAddr>,
+    bind: Option<BindAddresses>,
 
     /// Enable AVIF conversion
     #[arg(long)]
@@ -54,6 +44,34 @@ struct Cli {
     /// Preserve all headers from upstream when responding
     #[arg(long)]
     preserve_headers: bool,
+
+    /// Validate the configuration, print any warnings and exit
+    #[arg(long)]
+    check_config: bool,
+
+    /// Accept connections on this already listening socket instead of binding (Unix only)
+    #[arg(long, value_name = "FD")]
+    inherit_fd: Option<i32>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the proxy server (default)
+    Serve,
+
+    /// Convert a single image file with the server's conversion pipeline
+    Convert(convert::ConvertArgs),
+
+    /// Convert an image read from stdin to stdout, with metadata as JSON on stderr
+    ///
+    /// Exits with 3 for unsupported input, 4 when decoding and 5 when converting fails.
+    Pipe(convert::PipeArgs),
+
+    /// Encode sample images over a grid of formats, qualities and AVIF speeds, reporting time and size
+    Bench(bench::BenchArgs),
 }
 
 #[tokio::main]
@@ -61,57 +79,177 @@ async fn main() -> Result<()> {
     // Parse command-line arguments
     let cli = Cli::parse();
 
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "akkoproxy=info,tower_http=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    if let Some(Command::Convert(args)) = &cli.command {
+        let report = convert::run(args)?;
+        convert::print_report(args, &report);
+        return Ok(());
+    }
+
+    if let Some(Command::Bench(args)) = &cli.command {
+        let image = match &cli.config {
+            Some(config_path) => Config::from_file(config_path)?.0.image,
+            None => ImageConfig::default(),
+        };
+        let report = bench::run(args, &image).await?;
+        bench::print_report(&report);
+        bench::write_outputs(args, &report)?;
+        return Ok(());
+    }
+
+    // Nothing may be logged, stdout carries the converted image
+    if let Some(Command::Pipe(args)) = &cli.command {
+        let image = match &cli.config {
+            Some(config_path) => Config::from_file(config_path)?.0.image,
+            None => ImageConfig::default(),
+        };
+        let mut meta = convert::meta_writer(args.meta_fd)?;
+        let code = convert::run_pipe(args, &image, &mut std::io::stdin().lock(), &mut std::io::stdout().lock(), &mut meta)?;
+        std::process::exit(code.into());
+    }
+
+    // Initialize tracing, falling back to defaults rather than failing
+    telemetry::init_logging();
 
     info!("Starting Akkoproxy v{}", env!("CARGO_PKG_VERSION"));
 
-    // Load configuration
-    let config = load_config(&cli)?;
+    // Load configuration and check the paths it refers to before anything uses them
+    let (config, notes, warnings) = load_config(&cli)?;
+    preflight::check(&config)?;
+    
+    if cli.check_config {
+        for note in &notes {
+            println!("note: {}", note);
+        }
+        for warning in &warnings {
+            println!("warning: {}", warning);
+        }
+        println!("Configuration OK ({} warning(s))", warnings.len());
+        return Ok(());
+    }
+    for note in &notes {
+        warn!("Configuration: {}", note);
+    }
+    for warning in &warnings {
+        warn!("Configuration: {}", warning);
+    }
     
     info!("Configuration loaded:");
-    info!("  Bind address: {}", config.server.bind);
-    info!("  Upstream URL: {}", config.upstream.url);
+    info!("  Bind addresses: {}", config.server.bind);
+    info!("  Upstream URL: {}", config.upstream.base_url());
+    if let Some(auth) = &config.upstream.auth {
+        info!("  Upstream auth: {}", auth.redacted());
+    }
     info!("  Cache max capacity: {}", config.cache.max_capacity);
     info!("  AVIF conversion: {}", config.image.enable_avif);
     info!("  WebP conversion: {}", config.image.enable_webp);
+    info!("  Conversion parallelism: {} threads total, {} per AVIF encode",
+          config.conversion_thread_budget(), config.effective_avif_threads().min(config.conversion_thread_budget()));
     info!("  Preserve upstream headers: {}", config.server.preserve_upstream_headers);
+    info!("  Debug log sample rate: {}", config.server.debug_log_sample_rate);
+    
+    logging::set_debug_sample_rate(config.server.debug_log_sample_rate);
 
     // Create application state
     let state = AppState::new(config.clone());
+    info!("Encoder self-test: AVIF {}, WebP {}", state.encoders.avif, state.encoders.webp);
+    if let Some(config_path) = config_file_path(&cli) {
+        spawn_reload_handler(config_path, state.clone());
+    }
 
     // Build router
-    let app = Router::new()
-        .route("/health", get(health_handler))
-        .route("/metrics", get(metrics_handler))
-        .fallback(proxy_handler)
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
-
-    // Start server
-    let listener = tokio::net::TcpListener::bind(&config.server.bind)
-        .await
-        .with_context(|| format!("Failed to bind to {}", config.server.bind))?;
+    let metrics = state.metrics.clone();
+    let app = router(state.clone());
+
+    // Start server, on a socket passed by a supervisor or systemd if there is one
+    let source = ListenerSource::detect(cli.inherit_fd);
+    let listeners = listener::listen(&config.server, source)?
+        .into_iter()
+        .map(tokio::net::TcpListener::from_std)
+        .collect::<std::io::Result<Vec<_>>>()?;
 
-    info!("Server listening on {}", config.server.bind);
+    for listener in &listeners {
+        info!("Server listening on {}", listener.local_addr()?);
+    }
     
-    axum::serve(listener, app)
+    serve::serve_all(listeners, app, config.server.write_timeout(), metrics, shutdown_signal())
         .await
         .context("Server error")?;
 
+    // Whatever was logged or counted last reaches the sinks still up
+    state.flush_telemetry().await;
+    std::io::Write::flush(&mut std::io::stdout()).ok();
+
     Ok(())
 }
 
+/// Resolves on SIGTERM or Ctrl-C, after which in-flight requests are drained
+async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down, draining in-flight requests");
+}
+
+/// Path of the configuration file in use, if any
+fn config_file_path(cli: &Cli) -> Option<PathBuf> {
+    cli.config
+        .clone()
+        .or_else(|| Some(PathBuf::from("config.toml")).filter(|path| path.exists()))
+}
+
+/// Re-read the configuration file on SIGHUP and apply the settings that can change at runtime
+fn spawn_reload_handler(config_path: PathBuf, state: AppState) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            warn!("Failed to install SIGHUP handler, configuration reload disabled");
+            return;
+        };
+        
+        while hangup.recv().await.is_some() {
+            match state.reload_config(&config_path) {
+                Ok(config) => {
+                    info!("Reloaded configuration from {}: debug log sample rate {}",
+                          config_path.display(), config.server.debug_log_sample_rate);
+                }
+                Err(e) => warn!("Failed to reload configuration from {}: {:#}", config_path.display(), e),
+            }
+        }
+    });
+    
+    #[cfg(not(unix))]
+    let _ = (config_path, state);
+}
+
 /// Load configuration with priority: env > cmdline options > config file
-fn load_config(cli: &Cli) -> Result<Config> {
+///
+/// Returns the validated configuration together with the notes on migrating its
+/// file and its warnings.
+fn load_config(cli: &Cli) -> Result<(Config, Vec<ConfigNote>, Vec<ConfigWarning>)> {
     // Priority 3 (lowest): Load from config file if it exists
-    let mut config = if let Some(config_path) = &cli.config {
+    let (mut config, notes) = if let Some(config_path) = &cli.config {
         // Use specified config file
         info!("Loading configuration from: {}", config_path.display());
         Config::from_file(config_path)?
@@ -123,7 +261,7 @@ fn load_config(cli: &Cli) -> Result<Config> {
             Config::from_file(&config_path)?
         } else {
             // No config file, start with defaults (upstream will be set from env or CLI)
-            Config::default_without_upstream()
+            (Config::default_without_upstream(), Vec::new())
         }
     };
 
@@ -133,8 +271,8 @@ fn load_config(cli: &Cli) -> Result<Config> {
         config.upstream.url = upstream_url.clone();
     }
     
-    if let Some(bind) = cli.bind {
-        config.server.bind = bind;
+    if let Some(bind) = &cli.bind {
+        config.server.bind = bind.clone();
     }
 
     if cli.enable_avif {
@@ -182,6 +320,6 @@ fn load_config(cli: &Cli) -> Result<Config> {
         )
     }
 
-    config.validate()?;
-    Ok(config)
+    let warnings = config.validate()?;
+    Ok((config, notes, warnings))
 }
@@ -0,0 +1,187 @@
+use moka::sync::Cache;
+use moka::Expiry;
+use std::time::{Duration, Instant};
+
+use crate::image::OutputFormat;
+
+/// Most memos kept across all paths; the least recently used go first
+pub const MEMO_CAPACITY: u64 = 20_000;
+
+/// Decision about one path remembered for a while
+///
+/// New per-path decisions belong here rather than in a map of their own, so they
+/// are bounded, expire, and are forgotten when the path is purged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DecisionMemo {
+    /// Skipping conversion for exceeding `image.max_convert_input_size` was logged
+    TooLargeLogged,
+    /// Converting to the format ran over `image.conversion_time_budget_ms`
+    OverBudget(OutputFormat),
+    /// The source could not be decoded as any supported format
+    Undecodable,
+    /// Converting to the format gave a body over `cache.max_item_size`
+    Uncacheable(OutputFormat),
+}
+
+impl DecisionMemo {
+    /// How long the decision is remembered
+    pub fn ttl(self) -> Duration {
+        match self {
+            DecisionMemo::TooLargeLogged => Duration::from_secs(3600),
+            DecisionMemo::OverBudget(_) => Duration::from_secs(3600),
+            DecisionMemo::Undecodable => Duration::from_secs(3600),
+            DecisionMemo::Uncacheable(_) => Duration::from_secs(3600),
+        }
+    }
+}
+
+type MemoKey = (String, DecisionMemo);
+
+/// Expires each memo after the TTL of its variant
+struct MemoExpiry {
+    ttl: fn(DecisionMemo) -> Duration,
+}
+
+impl Expiry<MemoKey, ()> for MemoExpiry {
+    fn expire_after_create(&self, key: &MemoKey, _value: &(), _created_at: Instant) -> Option<Duration> {
+        Some((self.ttl)(key.1))
+    }
+
+    fn expire_after_update(
+        &self,
+        key: &MemoKey,
+        _value: &(),
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some((self.ttl)(key.1))
+    }
+}
+
+/// Bounded store of per-path decisions, shared by everything that memoizes them
+#[derive(Clone)]
+pub struct MemoStore {
+    memos: Cache<MemoKey, ()>,
+}
+
+impl Default for MemoStore {
+    fn default() -> Self {
+        Self::new(MEMO_CAPACITY)
+    }
+}
+
+impl MemoStore {
+    pub fn new(capacity: u64) -> Self {
+        Self::with_ttls(capacity, DecisionMemo::ttl)
+    }
+
+    /// Store whose memos live for `ttl` of their variant instead of the default
+    pub fn with_ttls(capacity: u64, ttl: fn(DecisionMemo) -> Duration) -> Self {
+        Self {
+            memos: Cache::builder()
+                .max_capacity(capacity)
+                .expire_after(MemoExpiry { ttl })
+                .build(),
+        }
+    }
+
+    /// Whether `memo` is currently remembered for `path`
+    pub fn contains(&self, path: &str, memo: DecisionMemo) -> bool {
+        self.memos.contains_key(&(path.to_string(), memo))
+    }
+
+    /// Remember `memo` for `path`, restarting its TTL
+    pub fn remember(&self, path: &str, memo: DecisionMemo) {
+        self.memos.insert((path.to_string(), memo), ());
+    }
+
+    /// Remember `memo` for `path` unless it already is, returning whether it was new
+    pub fn remember_once(&self, path: &str, memo: DecisionMemo) -> bool {
+        self.memos.entry((path.to_string(), memo)).or_insert(()).is_fresh()
+    }
+
+    /// Forget every memo of `path`, returning how many there were
+    pub fn forget_path(&self, path: &str) -> usize {
+        self.forget(|memo_path, _| memo_path == path)
+    }
+
+    /// Forget every memo `matches`, for example after the setting it depends on changed
+    pub fn forget_where(&self, matches: impl Fn(DecisionMemo) -> bool) -> usize {
+        self.forget(|_, memo| matches(memo))
+    }
+
+    fn forget(&self, matches: impl Fn(&str, DecisionMemo) -> bool) -> usize {
+        let keys: Vec<_> = self
+            .memos
+            .iter()
+            .filter(|(key, _)| matches(&key.0, key.1))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            self.memos.invalidate(key.as_ref());
+        }
+        keys.len()
+    }
+
+    /// Number of memos held
+    ///
+    /// Pending evictions are applied first. The memos are then walked, since the
+    /// cheap entry count includes expired memos until their removal is processed.
+    pub fn len(&self) -> u64 {
+        self.memos.run_pending_tasks();
+        self.memos.iter().count() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memos_expire_after_their_variant_ttl() {
+        let memos = MemoStore::with_ttls(100, |memo| match memo {
+            DecisionMemo::TooLargeLogged => Duration::from_millis(50),
+            DecisionMemo::OverBudget(_) | DecisionMemo::Undecodable | DecisionMemo::Uncacheable(_) => Duration::from_secs(60),
+        });
+        memos.remember("/media/a.jpg", DecisionMemo::TooLargeLogged);
+        memos.remember("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::Avif));
+        assert!(memos.contains("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        assert!(!memos.contains("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::WebP)));
+        assert!(!memos.contains("/media/b.jpg", DecisionMemo::TooLargeLogged));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!memos.contains("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        assert!(memos.contains("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::Avif)));
+        assert_eq!(memos.len(), 1);
+    }
+
+    #[test]
+    fn test_memos_are_bounded() {
+        let memos = MemoStore::new(16);
+        for i in 0..1000 {
+            memos.remember(&format!("/media/{}.jpg", i), DecisionMemo::TooLargeLogged);
+        }
+        assert!(memos.len() <= 16, "{}", memos.len());
+    }
+
+    #[test]
+    fn test_remember_once_and_forget() {
+        let memos = MemoStore::default();
+        assert!(memos.remember_once("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        assert!(!memos.remember_once("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        memos.remember("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::Avif));
+        memos.remember("/media/a.jpg2", DecisionMemo::OverBudget(OutputFormat::Avif));
+        memos.remember("/media/b.jpg", DecisionMemo::OverBudget(OutputFormat::WebP));
+
+        assert_eq!(memos.forget_path("/media/a.jpg"), 2);
+        assert!(!memos.contains("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        assert!(memos.contains("/media/a.jpg2", DecisionMemo::OverBudget(OutputFormat::Avif)));
+
+        assert_eq!(memos.forget_where(|memo| matches!(memo, DecisionMemo::OverBudget(_))), 2);
+        assert!(memos.is_empty());
+    }
+}
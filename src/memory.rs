@@ -0,0 +1,307 @@
+//! Watchdog keeping the response cache from pushing the process out of memory
+//!
+//! Resident memory is sampled in the background and compared with the memory
+//! limit. Above the shrink threshold the cache is held to a fraction of its
+//! capacity, its coldest entries are removed and background conversions pause;
+//! below the lower restore threshold the configured capacity comes back.
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::cache::ResponseCache;
+use crate::config::ServerConfig;
+use crate::metrics::Metrics;
+
+/// Source of resident memory samples, in bytes
+pub trait MemoryReader: Send + 'static {
+    /// Current resident memory, `None` while unknown
+    fn rss(&mut self) -> Option<u64>;
+}
+
+/// Resident memory of this process, read from `/proc/self/status`
+///
+/// Reports nothing on systems without procfs, so the cache is never shrunk there.
+#[derive(Debug, Default)]
+pub struct ProcessMemoryReader;
+
+impl MemoryReader for ProcessMemoryReader {
+    fn rss(&mut self) -> Option<u64> {
+        parse_vm_rss(&std::fs::read_to_string("/proc/self/status").ok()?)
+    }
+}
+
+/// `VmRSS` of a `/proc/<pid>/status` file, in bytes
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// A cgroup v2 `memory.max` value, `None` when unlimited
+fn parse_memory_max(max: &str) -> Option<u64> {
+    max.trim().parse().ok().filter(|limit| *limit > 0)
+}
+
+/// `memory.max` of the cgroup v2 this process runs in, `None` when unlimited or unknown
+///
+/// Inside a container the cgroup namespace usually makes its own cgroup the root
+/// of `/sys/fs/cgroup`, so the root file is tried when the process's path has none.
+pub fn cgroup_memory_limit() -> Option<u64> {
+    let root = Path::new("/sys/fs/cgroup");
+    let own = std::fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|cgroups| cgroups.lines().find_map(|line| line.strip_prefix("0::").map(str::to_string)));
+    own.iter()
+        .map(|path| root.join(path.trim_start_matches('/')))
+        .chain([root.to_path_buf()])
+        .find_map(|dir| std::fs::read_to_string(dir.join("memory.max")).ok())
+        .and_then(|max| parse_memory_max(&max))
+}
+
+/// Switch that shrinks the cache while resident memory is close to the limit
+///
+/// Shrinking starts when a sample crosses the shrink threshold and stops once
+/// one falls below the lower restore threshold. While it lasts, every sample
+/// removes the entries stored since the previous one beyond the shrunk capacity.
+pub struct MemoryWatchdog {
+    /// Memory limit in bytes, `None` never shrinks
+    limit: Option<u64>,
+    /// Shrink and restore thresholds in percent of the limit, `None` never shrinks
+    thresholds: Option<(f64, f64)>,
+    /// Entries the cache is held to while memory is short
+    shrunk_capacity: u64,
+    cache: ResponseCache,
+    metrics: Arc<Metrics>,
+    under_pressure: AtomicBool,
+    /// Last sampled resident memory in bytes
+    rss: AtomicU64,
+}
+
+impl MemoryWatchdog {
+    /// Watchdog of `cache` against `limit`, with the thresholds of `server`
+    pub fn new(server: &ServerConfig, limit: Option<u64>, cache: ResponseCache, metrics: Arc<Metrics>) -> Self {
+        let max_capacity = cache.capacity();
+        Self {
+            limit,
+            thresholds: server.memory_shrink_percent.zip(server.memory_restore_percent()),
+            shrunk_capacity: (max_capacity as f64 * server.memory_shrunk_capacity_percent / 100.0) as u64,
+            cache,
+            metrics,
+            under_pressure: AtomicBool::new(false),
+            rss: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether memory is sampled at all
+    pub fn is_enabled(&self) -> bool {
+        self.limit.is_some() && self.thresholds.is_some()
+    }
+
+    /// Whether the cache is currently shrunk
+    pub fn is_under_pressure(&self) -> bool {
+        self.under_pressure.load(Ordering::Relaxed)
+    }
+
+    /// Memory limit the watchdog compares with, if known
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Last sampled resident memory in bytes
+    pub fn rss(&self) -> u64 {
+        self.rss.load(Ordering::Relaxed)
+    }
+
+    /// Record a resident memory sample, returning whether the cache is shrunk afterwards
+    ///
+    /// A warning is logged when shrinking starts and a note when the capacity is restored.
+    pub async fn observe(&self, rss: u64) -> bool {
+        self.rss.store(rss, Ordering::Relaxed);
+        let (Some(limit), Some((shrink, restore))) = (self.limit, self.thresholds) else {
+            return false;
+        };
+
+        let percent = rss as f64 * 100.0 / limit as f64;
+        let under_pressure = self.is_under_pressure();
+        if under_pressure && percent < restore {
+            self.under_pressure.store(false, Ordering::Relaxed);
+            self.cache.set_capacity(u64::MAX);
+            info!("Resident memory down to {:.1}% of the limit, restoring the cache capacity of {} entries",
+                  percent, self.cache.capacity());
+            return false;
+        }
+        if !under_pressure && percent <= shrink {
+            return false;
+        }
+
+        if !under_pressure {
+            self.under_pressure.store(true, Ordering::Relaxed);
+            self.cache.set_capacity(self.shrunk_capacity);
+            Metrics::incr(&self.metrics.memory_pressure_shrinks);
+        }
+        let removed = self.cache.shrink().await;
+        self.metrics.memory_pressure_evictions.fetch_add(removed as u64, Ordering::Relaxed);
+        if !under_pressure {
+            warn!("Resident memory at {:.1}% of the {} byte limit, removed {} cache entries to hold the cache to {} \
+                   and paused background conversions until it drops below {:.1}%",
+                  percent, limit, removed, self.shrunk_capacity, restore);
+        }
+        true
+    }
+}
+
+/// Feed `reader` into `watchdog` every `interval` until `cancel` fires
+pub fn spawn_monitor(watchdog: Arc<MemoryWatchdog>, mut reader: impl MemoryReader, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    if let Some(rss) = reader.rss() {
+                        watchdog.observe(rss).await;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheKey, CachedMeta, CachedResponse};
+    use crate::config::Config;
+    use axum::http::StatusCode;
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    const MIB: u64 = 1024 * 1024;
+
+    async fn filled_cache(entries: u64) -> ResponseCache {
+        let cache = ResponseCache::new(10, Duration::from_secs(60), 1024);
+        for i in 0..entries {
+            let response = CachedResponse::new(Bytes::from_static(b"jpeg"), CachedMeta::new("image/jpeg".to_string(), StatusCode::OK));
+            cache.put(CacheKey::new(format!("/media/{}.jpg", i), "Original".to_string()), response).await;
+        }
+        cache.run_pending_tasks().await;
+        cache
+    }
+
+    fn watchdog(cache: ResponseCache, metrics: Arc<Metrics>) -> MemoryWatchdog {
+        let mut server = Config::with_upstream("http://upstream.test".to_string()).server;
+        server.memory_shrink_percent = Some(90.0);
+        server.memory_restore_percent = Some(70.0);
+        server.memory_shrunk_capacity_percent = 40.0;
+        MemoryWatchdog::new(&server, Some(100 * MIB), cache, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_shrinks_and_restores_cache() {
+        let cache = filled_cache(10).await;
+        let metrics = Metrics::new();
+        let watchdog = watchdog(cache.clone(), metrics.clone());
+        assert!(watchdog.is_enabled());
+
+        assert!(!watchdog.observe(80 * MIB).await);
+        assert_eq!(cache.stats().entry_count, 10);
+
+        assert!(watchdog.observe(95 * MIB).await);
+        assert_eq!(watchdog.rss(), 95 * MIB);
+        assert_eq!(cache.capacity(), 4);
+        assert_eq!(cache.stats().entry_count, 4);
+        assert_eq!(Metrics::get(&metrics.memory_pressure_shrinks), 1);
+        assert_eq!(Metrics::get(&metrics.memory_pressure_evictions), 6);
+
+        // Entries stored meanwhile are removed with the next sample, between the thresholds as well
+        for i in 10..13 {
+            let response = CachedResponse::new(Bytes::from_static(b"jpeg"), CachedMeta::new("image/jpeg".to_string(), StatusCode::OK));
+            cache.put(CacheKey::new(format!("/media/{}.jpg", i), "Original".to_string()), response).await;
+        }
+        assert!(watchdog.observe(80 * MIB).await);
+        assert_eq!(cache.stats().entry_count, 4);
+        assert_eq!(Metrics::get(&metrics.memory_pressure_shrinks), 1);
+        assert_eq!(Metrics::get(&metrics.memory_pressure_evictions), 9);
+
+        assert!(!watchdog.observe(60 * MIB).await);
+        assert!(!watchdog.is_under_pressure());
+        assert_eq!(cache.capacity(), 10);
+        assert_eq!(cache.stats().max_capacity, 10);
+    }
+
+    #[tokio::test]
+    async fn test_without_limit_never_shrinks() {
+        let cache = filled_cache(10).await;
+        let mut server = Config::with_upstream("http://upstream.test".to_string()).server;
+        server.memory_shrink_percent = Some(90.0);
+        let watchdog = MemoryWatchdog::new(&server, None, cache.clone(), Metrics::new());
+        assert!(!watchdog.is_enabled());
+        assert!(!watchdog.observe(u64::MAX).await);
+        assert_eq!(cache.stats().entry_count, 10);
+
+        let server = Config::with_upstream("http://upstream.test".to_string()).server;
+        let watchdog = MemoryWatchdog::new(&server, Some(MIB), cache.clone(), Metrics::new());
+        assert!(!watchdog.is_enabled());
+        assert!(!watchdog.observe(2 * MIB).await);
+    }
+
+    /// Reader replaying fixed readings, then reporting nothing
+    struct FakeReader(Arc<Mutex<VecDeque<u64>>>);
+
+    impl MemoryReader for FakeReader {
+        fn rss(&mut self) -> Option<u64> {
+            self.0.lock().unwrap().pop_front()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_feeds_samples() {
+        let cache = filled_cache(10).await;
+        let watchdog = Arc::new(watchdog(cache.clone(), Metrics::new()));
+        let readings = Arc::new(Mutex::new(VecDeque::from([50 * MIB, 99 * MIB])));
+        let cancel = CancellationToken::new();
+        spawn_monitor(watchdog.clone(), FakeReader(readings.clone()), Duration::from_millis(5), cancel.clone());
+
+        let wait_for = |under_pressure: bool| {
+            let watchdog = watchdog.clone();
+            async move {
+                for _ in 0..200 {
+                    if watchdog.is_under_pressure() == under_pressure {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                panic!("Memory pressure did not become {}", under_pressure);
+            }
+        };
+        wait_for(true).await;
+        assert_eq!(cache.stats().entry_count, 4);
+
+        readings.lock().unwrap().push_back(10 * MIB);
+        wait_for(false).await;
+        assert_eq!(cache.capacity(), 10);
+        cancel.cancel();
+    }
+
+    #[test]
+    fn test_parse_procfs_and_cgroup() {
+        let status = "Name:\takkoproxy\nVmPeak:\t  204800 kB\nVmRSS:\t   51200 kB\nThreads:\t8\n";
+        assert_eq!(parse_vm_rss(status), Some(50 * MIB));
+        assert_eq!(parse_vm_rss("Name:\tkthreadd\n"), None);
+
+        assert_eq!(parse_memory_max("536870912\n"), Some(512 * MIB));
+        assert_eq!(parse_memory_max("max\n"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_reader_reads_procfs() {
+        assert!(ProcessMemoryReader.rss().is_some_and(|rss| rss > 0));
+    }
+}
@@ -0,0 +1,524 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::image::{ImageError, ResizeKind, SkipReason};
+use crate::stats::FormatStats;
+
+/// Default upper bounds in seconds of the request duration buckets
+pub const DEFAULT_LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Path a proxied request took, the label of its duration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFlow {
+    /// Served from the cache, including stale and negative entries
+    Hit,
+    /// Fetched from upstream and served as received
+    Miss,
+    /// Fetched from upstream and converted
+    MissConvert,
+    /// Failed, or answered with a server error
+    Error,
+}
+
+impl RequestFlow {
+    pub const ALL: [RequestFlow; 4] = [RequestFlow::Hit, RequestFlow::Miss, RequestFlow::MissConvert, RequestFlow::Error];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RequestFlow::Hit => "hit",
+            RequestFlow::Miss => "miss",
+            RequestFlow::MissConvert => "miss_convert",
+            RequestFlow::Error => "error",
+        }
+    }
+}
+
+/// Histogram with one series per label value, updated with atomic increments only
+///
+/// Sums are kept as whole multiples of `1 / scale` of the observed unit, so they
+/// can be added to atomically; `scale` also sets the decimals they are rendered with.
+#[derive(Debug)]
+pub struct Histogram {
+    name: &'static str,
+    /// Label telling the series apart, unset for a single unlabeled series
+    label: Option<&'static str>,
+    /// Upper bounds, followed by an implicit +Inf bucket
+    bounds: Vec<f64>,
+    scale: u32,
+    series: Vec<(&'static str, HistogramSeries)>,
+}
+
+#[derive(Debug)]
+struct HistogramSeries {
+    /// Per-bucket (not cumulative) counts, the last entry is the +Inf bucket
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    /// Histogram `name` with a series per value of `label`, bounds must be increasing
+    pub fn new(name: &'static str, label: Option<&'static str>, values: &[&'static str], bounds: &[f64], scale: u32) -> Self {
+        Self {
+            name,
+            label,
+            bounds: bounds.to_vec(),
+            scale,
+            series: values
+                .iter()
+                .map(|value| {
+                    let series = HistogramSeries {
+                        buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+                        count: AtomicU64::new(0),
+                        sum: AtomicU64::new(0),
+                    };
+                    (*value, series)
+                })
+                .collect(),
+        }
+    }
+
+    /// Record `sample` in the series at `index`, negative samples add nothing to the sum
+    pub fn observe(&self, index: usize, sample: f64) {
+        let series = &self.series[index].1;
+        let bucket = self.bounds.iter().position(|bound| sample <= *bound).unwrap_or(self.bounds.len());
+        series.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        series.count.fetch_add(1, Ordering::Relaxed);
+        series.sum.fetch_add((sample.max(0.0) * self.scale as f64) as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self, index: usize) -> u64 {
+        self.series[index].1.count.load(Ordering::Relaxed)
+    }
+
+    pub fn render(&self, body: &mut String) {
+        let name = self.name;
+        let decimals = self.scale.checked_ilog10().unwrap_or(0) as usize;
+        let _ = writeln!(body, "# TYPE {} histogram", name);
+        for (value, series) in &self.series {
+            let labels = |extra: Option<String>| {
+                let pairs: Vec<String> =
+                    self.label.map(|label| format!("{}=\"{}\"", label, value)).into_iter().chain(extra).collect();
+                if pairs.is_empty() {
+                    String::new()
+                } else {
+                    format!("{{{}}}", pairs.join(","))
+                }
+            };
+            let mut cumulative = 0;
+            for (index, bucket) in series.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = self.bounds.get(index).map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(body, "{}_bucket{} {}", name, labels(Some(format!("le=\"{}\"", le))), cumulative);
+            }
+            let sum = series.sum.load(Ordering::Relaxed) as f64 / self.scale as f64;
+            let _ = writeln!(body, "{}_sum{} {:.*}", name, labels(None), decimals, sum);
+            let _ = writeln!(body, "{}_count{} {}", name, labels(None), series.count.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Request durations by [`RequestFlow`]
+#[derive(Debug)]
+pub struct LatencyHistogram(Histogram);
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new(&DEFAULT_LATENCY_BUCKETS)
+    }
+}
+
+impl LatencyHistogram {
+    /// Histogram with the given bucket bounds in seconds, which must be increasing
+    pub fn new(bounds: &[f64]) -> Self {
+        let flows = RequestFlow::ALL.map(RequestFlow::as_str);
+        Self(Histogram::new("request_duration_seconds", Some("flow"), &flows, bounds, 1_000_000))
+    }
+
+    pub fn observe(&self, flow: RequestFlow, duration: Duration) {
+        let index = RequestFlow::ALL.iter().position(|f| *f == flow).expect("ALL lists every flow");
+        self.0.observe(index, duration.as_secs_f64());
+    }
+
+    /// Render as the `request_duration_seconds` histogram
+    pub fn render(&self, body: &mut String) {
+        self.0.render(body);
+    }
+}
+
+/// Upper bounds in seconds of the resize duration buckets
+pub const RESIZE_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Durations of the resizes performed before encoding
+#[derive(Debug)]
+pub struct ResizeHistogram(Histogram);
+
+impl Default for ResizeHistogram {
+    fn default() -> Self {
+        Self(Histogram::new("image_resize_duration_seconds", None, &[""], &RESIZE_BUCKETS, 1_000_000))
+    }
+}
+
+impl ResizeHistogram {
+    pub fn observe(&self, duration: Duration) {
+        self.0.observe(0, duration.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.count(0)
+    }
+
+    /// Render as the `image_resize_duration_seconds` histogram
+    pub fn render(&self, body: &mut String) {
+        self.0.render(body);
+    }
+}
+
+/// Upper bounds in dB of the conversion quality buckets
+pub const QUALITY_BUCKETS: [f64; 7] = [20.0, 25.0, 30.0, 35.0, 40.0, 45.0, 50.0];
+
+/// Output formats the quality audit can decode, the label of its scores
+pub const AUDITED_FORMATS: [(&str, &str); 3] = [("image/webp", "webp"), ("image/jpeg", "jpeg"), ("image/png", "png")];
+
+/// Quality audit scores by output format
+#[derive(Debug)]
+pub struct QualityHistogram(Histogram);
+
+impl Default for QualityHistogram {
+    fn default() -> Self {
+        let formats = AUDITED_FORMATS.map(|(_, format)| format);
+        Self(Histogram::new("conversion_quality_psnr_db", Some("format"), &formats, &QUALITY_BUCKETS, 1000))
+    }
+}
+
+impl QualityHistogram {
+    /// Record the PSNR of a conversion to `mime_type`, ignoring formats that are not audited
+    pub fn observe(&self, mime_type: &str, score: f64) {
+        if let Some(index) = AUDITED_FORMATS.iter().position(|(mime, _)| *mime == mime_type) {
+            self.0.observe(index, score);
+        }
+    }
+
+    /// Number of scores recorded for `mime_type`
+    pub fn count(&self, mime_type: &str) -> u64 {
+        AUDITED_FORMATS
+            .iter()
+            .position(|(mime, _)| *mime == mime_type)
+            .map_or(0, |index| self.0.count(index))
+    }
+
+    /// Render as the `conversion_quality_psnr_db` histogram
+    pub fn render(&self, body: &mut String) {
+        self.0.render(body);
+    }
+}
+
+/// Process-wide counters exposed on the metrics endpoint
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Requests abandoned by the client before a response was produced
+    pub cancelled_requests: AtomicU64,
+
+    /// Connections closed because the client accepted no response data for `server.client_write_timeout`
+    pub client_write_timeouts: AtomicU64,
+
+    /// Image conversions stopped early because their request was abandoned
+    pub cancelled_conversions: AtomicU64,
+
+    /// Image responses whose body was empty or not actually an image
+    pub mislabeled_upstream: AtomicU64,
+
+    /// Non-success responses whose upstream body or headers exceeded the caps
+    pub truncated_error_responses: AtomicU64,
+
+    /// Responses passed on as they arrived, being too large to cache or convert
+    pub streamed_responses: AtomicU64,
+
+    /// Responses the proxy cut to a client's byte range, 206 and 416 alike
+    pub range_responses: AtomicU64,
+
+    /// Conditional requests answered with 304 because the client's copy was current
+    pub not_modified_responses: AtomicU64,
+
+    /// Requests that refreshed an expired cache entry
+    pub refresh_leaders: AtomicU64,
+
+    /// Requests that found a refresh of their expired entry already in flight
+    pub refresh_followers: AtomicU64,
+
+    /// Expired entries served stale at once while refreshed in the background
+    pub background_refreshes: AtomicU64,
+
+    /// Requests that missed while another request was fetching the same key and waited for it
+    pub coalesced_misses: AtomicU64,
+
+    /// Variants removed because a refresh found their upstream source had changed
+    pub variant_invalidations: AtomicU64,
+
+    /// Purges sent to the other replicas
+    pub invalidations_published: AtomicU64,
+
+    /// Purges that could not be sent to every replica and were applied locally only
+    pub invalidation_publish_failures: AtomicU64,
+
+    /// Purges received from other replicas and applied
+    pub invalidations_received: AtomicU64,
+
+    /// Purges received from this replica itself or older than one already applied
+    pub invalidations_ignored: AtomicU64,
+
+    /// Missed responses stored under the cache admission policy
+    pub cache_admitted: AtomicU64,
+
+    /// Missed responses not stored because the admission policy turned them away
+    pub cache_admission_rejected: AtomicU64,
+
+    /// Upstream requests sent again because the first was slow to answer
+    pub hedged_requests: AtomicU64,
+
+    /// Hedged requests that answered before the request they duplicated
+    pub hedge_wins: AtomicU64,
+
+    /// Upstream fetches that gave up waiting for a connection to their host
+    pub upstream_host_queue_timeouts: AtomicU64,
+
+    /// Upstream bodies resumed with a range request after failing partway
+    pub resumed_transfers: AtomicU64,
+
+    /// Body bytes resumed transfers did not download again
+    pub resume_bytes_saved: AtomicU64,
+
+    /// Sibling format variants converted and cached in the background
+    pub prewarmed_variants: AtomicU64,
+
+    /// Sibling format conversions dropped because the prewarm queue was full
+    pub prewarm_dropped: AtomicU64,
+
+    /// Paths fetched into the cache through `POST /prefetch`
+    pub prefetched_paths: AtomicU64,
+
+    /// Prefetched paths rejected because the prefetch queue was full
+    pub prefetch_dropped: AtomicU64,
+
+    /// Prefetch requests refused for exceeding a client's rate limit
+    pub prefetch_rate_limited: AtomicU64,
+
+    /// Conversion attempts abandoned for running over the time budget
+    pub conversions_over_budget: AtomicU64,
+
+    /// Images served in a format further down the fallback ladder than negotiated
+    pub conversion_fallbacks: AtomicU64,
+
+    /// Upstream content types replaced by a matching override rule
+    pub content_type_overrides: AtomicU64,
+
+    /// Override rules that matched but were refused because the body disagreed
+    pub content_type_overrides_refused: AtomicU64,
+
+    /// Audited conversions that scored below `image.quality_audit_threshold`
+    pub quality_audit_below_threshold: AtomicU64,
+
+    /// Times the cache was shrunk because resident memory crossed the shrink threshold
+    pub memory_pressure_shrinks: AtomicU64,
+
+    /// Cache entries removed to bring the cache down to its shrunk capacity
+    pub memory_pressure_evictions: AtomicU64,
+
+    /// Images served without conversion, indexed like [`SkipReason::ALL`]
+    conversion_skipped: [AtomicU64; SkipReason::ALL.len()],
+
+    /// Failed conversions by what went wrong, indexed like [`ImageError::KINDS`]
+    conversion_errors: [AtomicU64; ImageError::KINDS.len()],
+
+    /// Decoded images by how they were resized, indexed like [`ResizeKind::ALL`]
+    resizes: [AtomicU64; ResizeKind::ALL.len()],
+
+    /// Durations of the resizes actually performed
+    pub resize_duration: ResizeHistogram,
+
+    /// Body sizes by source and served content type
+    pub formats: FormatStats,
+
+    /// Durations of proxied requests by the path they took
+    pub request_duration: LatencyHistogram,
+
+    /// Scores of the sampled conversion quality audits
+    pub conversion_quality: QualityHistogram,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Metrics whose request duration histogram uses `bounds`, in seconds
+    pub fn with_latency_buckets(bounds: &[f64]) -> Arc<Self> {
+        Arc::new(Self {
+            request_duration: LatencyHistogram::new(bounds),
+            ..Default::default()
+        })
+    }
+
+    /// Increment a counter by one
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read the current value of a counter
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Count an image served without conversion
+    pub fn record_conversion_skipped(&self, reason: SkipReason) {
+        Self::incr(self.conversion_skipped_counter(reason));
+    }
+
+    fn conversion_skipped_counter(&self, reason: SkipReason) -> &AtomicU64 {
+        let index = SkipReason::ALL
+            .iter()
+            .position(|r| *r == reason)
+            .expect("SkipReason::ALL lists every reason");
+        &self.conversion_skipped[index]
+    }
+
+    /// Number of images served without conversion for `reason`
+    pub fn conversion_skipped(&self, reason: SkipReason) -> u64 {
+        Self::get(self.conversion_skipped_counter(reason))
+    }
+
+    /// Count a failed conversion, cancellations excepted
+    pub fn record_conversion_error(&self, error: &ImageError) {
+        if let Some(index) = error.kind().and_then(|kind| ImageError::KINDS.iter().position(|k| *k == kind)) {
+            Self::incr(&self.conversion_errors[index]);
+        }
+    }
+
+    /// Number of conversions that failed with the error labeled `kind`
+    pub fn conversion_errors(&self, kind: &str) -> u64 {
+        ImageError::KINDS
+            .iter()
+            .position(|k| *k == kind)
+            .map_or(0, |index| Self::get(&self.conversion_errors[index]))
+    }
+
+    /// Count a resize, timing it unless it was skipped
+    pub fn record_resize(&self, kind: ResizeKind, duration: Duration) {
+        Self::incr(&self.resizes[Self::resize_index(kind)]);
+        if kind != ResizeKind::Skipped {
+            self.resize_duration.observe(duration);
+        }
+    }
+
+    /// Number of decoded images resized as `kind`
+    pub fn resizes(&self, kind: ResizeKind) -> u64 {
+        Self::get(&self.resizes[Self::resize_index(kind)])
+    }
+
+    fn resize_index(kind: ResizeKind) -> usize {
+        ResizeKind::ALL
+            .iter()
+            .position(|k| *k == kind)
+            .expect("ResizeKind::ALL lists every outcome")
+    }
+
+    /// Render all counters in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut body = format!(
+            "# Request Statistics\n\
+             cancelled_requests_total {}\n\
+             client_write_timeouts_total {}\n\
+             cancelled_conversions_total {}\n\
+             mislabeled_upstream_total {}\n\
+             truncated_error_responses_total {}\n\
+             streamed_responses_total {}\n\
+             range_responses_total {}\n\
+             not_modified_responses_total {}\n\
+             refresh_leaders_total {}\n\
+             refresh_followers_total {}\n\
+             background_refreshes_total {}\n\
+             coalesced_misses_total {}\n\
+             variant_invalidations_total {}\n\
+             invalidations_published_total {}\n\
+             invalidation_publish_failures_total {}\n\
+             invalidations_received_total {}\n\
+             invalidations_ignored_total {}\n\
+             cache_admitted_total {}\n\
+             cache_admission_rejected_total {}\n\
+             hedged_requests_total {}\n\
+             hedge_wins_total {}\n\
+             upstream_host_queue_timeouts_total {}\n\
+             resumed_transfers_total {}\n\
+             resume_bytes_saved_total {}\n\
+             prewarmed_variants_total {}\n\
+             prewarm_dropped_total {}\n\
+             prefetched_paths_total {}\n\
+             prefetch_dropped_total {}\n\
+             prefetch_rate_limited_total {}\n\
+             conversions_over_budget_total {}\n\
+             conversion_fallbacks_total {}\n\
+             content_type_overrides_total {}\n\
+             content_type_overrides_refused_total {}\n\
+             quality_audit_below_threshold_total {}\n\
+             memory_pressure_shrinks_total {}\n\
+             memory_pressure_evictions_total {}\n",
+            Self::get(&self.cancelled_requests),
+            Self::get(&self.client_write_timeouts),
+            Self::get(&self.cancelled_conversions),
+            Self::get(&self.mislabeled_upstream),
+            Self::get(&self.truncated_error_responses),
+            Self::get(&self.streamed_responses),
+            Self::get(&self.range_responses),
+            Self::get(&self.not_modified_responses),
+            Self::get(&self.refresh_leaders),
+            Self::get(&self.refresh_followers),
+            Self::get(&self.background_refreshes),
+            Self::get(&self.coalesced_misses),
+            Self::get(&self.variant_invalidations),
+            Self::get(&self.invalidations_published),
+            Self::get(&self.invalidation_publish_failures),
+            Self::get(&self.invalidations_received),
+            Self::get(&self.invalidations_ignored),
+            Self::get(&self.cache_admitted),
+            Self::get(&self.cache_admission_rejected),
+            Self::get(&self.hedged_requests),
+            Self::get(&self.hedge_wins),
+            Self::get(&self.upstream_host_queue_timeouts),
+            Self::get(&self.resumed_transfers),
+            Self::get(&self.resume_bytes_saved),
+            Self::get(&self.prewarmed_variants),
+            Self::get(&self.prewarm_dropped),
+            Self::get(&self.prefetched_paths),
+            Self::get(&self.prefetch_dropped),
+            Self::get(&self.prefetch_rate_limited),
+            Self::get(&self.conversions_over_budget),
+            Self::get(&self.conversion_fallbacks),
+            Self::get(&self.content_type_overrides),
+            Self::get(&self.content_type_overrides_refused),
+            Self::get(&self.quality_audit_below_threshold),
+            Self::get(&self.memory_pressure_shrinks),
+            Self::get(&self.memory_pressure_evictions),
+        );
+        for reason in SkipReason::ALL {
+            let _ = writeln!(
+                body,
+                "conversion_skipped_total{{reason=\"{}\"}} {}",
+                reason.as_str(),
+                self.conversion_skipped(reason),
+            );
+        }
+        for kind in ImageError::KINDS {
+            let _ = writeln!(body, "conversion_errors_total{{kind=\"{}\"}} {}", kind, self.conversion_errors(kind));
+        }
+        for kind in ResizeKind::ALL {
+            let _ = writeln!(body, "image_resizes_total{{outcome=\"{}\"}} {}", kind.as_str(), self.resizes(kind));
+        }
+        self.resize_duration.render(&mut body);
+        self.request_duration.render(&mut body);
+        self.conversion_quality.render(&mut body);
+        self.formats.render(&mut body);
+        body
+    }
+}
@@ -0,0 +1,242 @@
+use toml::Table;
+
+use crate::config::{Config, ConfigNote};
+
+/// Version of the configuration layout; files declare theirs with a top-level `config_version`
+///
+/// Files without it are assumed to predate every migration.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Key at the top of a file naming the layout version it was written for
+const VERSION_KEY: &str = "config_version";
+
+/// A rewrite of settings that moved or changed meaning, applied to files older than `version`
+struct Migration {
+    version: u32,
+    apply: fn(&mut Table, &mut Vec<ConfigNote>),
+}
+
+/// Every migration, oldest first
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        apply: cdn_mode_from_cloudflare_flag,
+    },
+    Migration {
+        version: 2,
+        apply: max_convert_input_size_renamed,
+    },
+];
+
+/// `server.behind_cloudflare_free = true` became `server.cdn.mode = "cloudflare_free"`
+fn cdn_mode_from_cloudflare_flag(config: &mut Table, notes: &mut Vec<ConfigNote>) {
+    let Some(server) = config.get_mut("server").and_then(|v| v.as_table_mut()) else {
+        return;
+    };
+    let Some(flag) = server.remove("behind_cloudflare_free") else {
+        return;
+    };
+    if flag.as_bool() != Some(true) {
+        notes.push(note("server.behind_cloudflare_free", "is deprecated and was ignored"));
+        return;
+    }
+
+    let cdn = server
+        .entry("cdn")
+        .or_insert_with(|| toml::Value::Table(Table::new()));
+    let Some(cdn) = cdn.as_table_mut() else {
+        return;
+    };
+    if cdn.contains_key("mode") {
+        notes.push(note("server.behind_cloudflare_free", "is deprecated and was ignored in favor of server.cdn.mode"));
+    } else {
+        cdn.insert("mode".to_string(), toml::Value::String("cloudflare_free".to_string()));
+        notes.push(note("server.behind_cloudflare_free", "is deprecated, read as server.cdn.mode = \"cloudflare_free\""));
+    }
+}
+
+/// `image.max_convert_size` became `image.max_convert_input_size`, to tell it from `cache.max_item_size`
+fn max_convert_input_size_renamed(config: &mut Table, notes: &mut Vec<ConfigNote>) {
+    let Some(image) = config.get_mut("image").and_then(|v| v.as_table_mut()) else {
+        return;
+    };
+    let Some(size) = image.remove("max_convert_size") else {
+        return;
+    };
+    if image.contains_key("max_convert_input_size") {
+        notes.push(note("image.max_convert_size", "is deprecated and was ignored in favor of image.max_convert_input_size"));
+    } else {
+        image.insert("max_convert_input_size".to_string(), size);
+        notes.push(note("image.max_convert_size", "is deprecated, read as image.max_convert_input_size"));
+    }
+}
+
+fn note(key: &str, message: &str) -> ConfigNote {
+    ConfigNote {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Bring a parsed configuration file up to [`CONFIG_VERSION`] and check it for unknown keys
+///
+/// Returns a note for every rewritten setting and every key no setting matches.
+pub fn migrate(config: &mut Table) -> Vec<ConfigNote> {
+    let mut notes = Vec::new();
+    let version = match config.remove(VERSION_KEY) {
+        None => 0,
+        Some(toml::Value::Integer(version)) => u32::try_from(version).unwrap_or(u32::MAX),
+        Some(_) => {
+            notes.push(note(VERSION_KEY, "is not a number and was ignored"));
+            0
+        }
+    };
+    if version > CONFIG_VERSION {
+        notes.push(note(
+            VERSION_KEY,
+            &format!("is {}, newer than the {} this version understands", version, CONFIG_VERSION),
+        ));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > version) {
+        (migration.apply)(config, &mut notes);
+    }
+
+    // Serialized as JSON, unset optional settings keep their keys as nulls
+    let known = serde_json::to_value(Config::default_without_upstream()).expect("Config serializes to JSON");
+    if let serde_json::Value::Object(known) = known {
+        unknown_keys(config, &known, "", &mut notes);
+    }
+    notes
+}
+
+/// Note the keys of `table` that `known` does not have, suggesting the closest one
+///
+/// Settings that are unset by default, lists and maps of arbitrary keys are not
+/// looked into, since their keys are not known up front.
+fn unknown_keys(table: &Table, known: &serde_json::Map<String, serde_json::Value>, prefix: &str, notes: &mut Vec<ConfigNote>) {
+    for (key, value) in table {
+        let path = format!("{}{}", prefix, key);
+        match (known.get(key), value) {
+            (Some(serde_json::Value::Object(known)), toml::Value::Table(table)) if !known.is_empty() => {
+                unknown_keys(table, known, &format!("{}.", path), notes);
+            }
+            (Some(_), _) => {}
+            (None, _) => {
+                let message = match closest_key(key, known.keys()) {
+                    Some(suggestion) => format!("is not a known setting and was ignored, did you mean {}{}?", prefix, suggestion),
+                    None => "is not a known setting and was ignored".to_string(),
+                };
+                notes.push(ConfigNote { key: path, message });
+            }
+        }
+    }
+}
+
+/// Candidate within a few edits of `key`, the nearest one if there are several
+fn closest_key<'a>(key: &str, candidates: impl Iterator<Item = &'a String>) -> Option<&'a str> {
+    let max_distance = (key.len() / 3).max(2);
+    candidates
+        .map(|candidate| (edit_distance(key, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+/// Levenshtein distance between two keys
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CdnMode;
+
+    fn load(contents: &str) -> (Config, Vec<String>) {
+        let (config, notes) = Config::parse(contents).unwrap();
+        (config, notes.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn test_legacy_cloudflare_flag_is_migrated() {
+        let (config, notes) = load("[upstream]\nurl = \"https://example.com\"\n[server]\nbehind_cloudflare_free = true\n");
+        assert_eq!(config.server.cdn_mode(), CdnMode::CloudflareFree);
+        assert_eq!(config.server.cdn.mode, CdnMode::CloudflareFree);
+        assert!(!config.server.behind_cloudflare_free);
+        assert_eq!(notes, vec!["server.behind_cloudflare_free is deprecated, read as server.cdn.mode = \"cloudflare_free\""]);
+
+        // An explicit mode wins, as it did before the migration
+        let (config, notes) = load(
+            "[upstream]\nurl = \"https://example.com\"\n[server]\nbehind_cloudflare_free = true\n[server.cdn]\nmode = \"generic\"\n",
+        );
+        assert_eq!(config.server.cdn_mode(), CdnMode::Generic);
+        assert_eq!(notes, vec!["server.behind_cloudflare_free is deprecated and was ignored in favor of server.cdn.mode"]);
+
+        // Files written for the current layout are not migrated
+        let (config, notes) = load("config_version = 2\n[upstream]\nurl = \"https://example.com\"\n[server.cdn]\nmode = \"cloudflare\"\n");
+        assert_eq!(config.server.cdn_mode(), CdnMode::Cloudflare);
+        assert!(notes.is_empty(), "{:?}", notes);
+    }
+
+    #[test]
+    fn test_max_convert_size_is_renamed() {
+        let (config, notes) = load("[upstream]\nurl = \"https://example.com\"\n[image]\nmax_convert_size = 1000\n");
+        assert_eq!(config.image.max_convert_input_size, Some(1000));
+        assert_eq!(notes, vec!["image.max_convert_size is deprecated, read as image.max_convert_input_size"]);
+
+        let (config, notes) = load(
+            "config_version = 1\n[upstream]\nurl = \"https://example.com\"\n[image]\nmax_convert_size = 1000\nmax_convert_input_size = 2000\n",
+        );
+        assert_eq!(config.image.max_convert_input_size, Some(2000));
+        assert_eq!(notes, vec!["image.max_convert_size is deprecated and was ignored in favor of image.max_convert_input_size"]);
+    }
+
+    #[test]
+    fn test_unknown_keys_suggest_the_nearest_setting() {
+        let (config, notes) = load(
+            "[upstream]\nurl = \"https://example.com\"\ntimeuot = 5\n\
+             [cache]\nmax_capacity = 5\nmax_capacty = 10\n\
+             [image]\nqualty = 70\nfrobnicate = true\n\
+             [[image.tiers]]\nmin_size = 0\nquality = 50\n\
+             [upstream.auth]\ntype = \"bearer\"\ntoken = \"secret\"\n\
+             [nonsense]\nkey = 1\n",
+        );
+        assert_eq!(config.cache.max_capacity, 5);
+        assert_eq!(config.image.quality, 85);
+        assert_eq!(
+            notes,
+            vec![
+                "cache.max_capacty is not a known setting and was ignored, did you mean cache.max_capacity?",
+                "image.frobnicate is not a known setting and was ignored",
+                "image.qualty is not a known setting and was ignored, did you mean image.quality?",
+                "nonsense is not a known setting and was ignored",
+                "upstream.timeuot is not a known setting and was ignored, did you mean upstream.timeout?",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_example_config_needs_no_migration() {
+        let (_, notes) = load(include_str!("../config.example.toml"));
+        assert!(notes.is_empty(), "{:?}", notes);
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("quality", "quality"), 0);
+        assert_eq!(edit_distance("qualty", "quality"), 1);
+        assert_eq!(edit_distance("timeuot", "timeout"), 2);
+        assert_eq!(edit_distance("", "ttl"), 3);
+    }
+}
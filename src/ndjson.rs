@@ -0,0 +1,195 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::convert::Infallible;
+use std::fmt::Display;
+
+/// Content type of newline-delimited JSON, one value per line
+pub const NDJSON: &str = "application/x-ndjson";
+
+/// Check whether a request body is sent as newline-delimited JSON
+pub fn is_ndjson(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case(NDJSON))
+}
+
+/// Problem with a single input line
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LineError {
+    #[error("line longer than {0} bytes")]
+    TooLong(usize),
+    #[error("line is not valid UTF-8")]
+    NotUtf8,
+    #[error("failed to read request body: {0}")]
+    Read(String),
+}
+
+/// Non-empty line of a body with its 1-based line number
+pub type Line = (usize, Result<String, LineError>);
+
+struct Splitter<S> {
+    body: S,
+    buffer: Vec<u8>,
+    max_line: usize,
+    line: usize,
+    /// Rest of an over-long line is dropped up to its newline
+    discarding: bool,
+    done: bool,
+}
+
+impl<S> Splitter<S> {
+    /// Next complete line in the buffer, skipping blank ones
+    fn next_buffered(&mut self) -> Option<Line> {
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let raw: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.line += 1;
+            if std::mem::take(&mut self.discarding) {
+                continue;
+            }
+            if end > self.max_line {
+                return Some((self.line, Err(LineError::TooLong(self.max_line))));
+            }
+            if let Some(line) = self.decode(&raw[..end]) {
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    fn decode(&self, raw: &[u8]) -> Option<Line> {
+        let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+        if raw.iter().all(u8::is_ascii_whitespace) {
+            return None;
+        }
+        let content = std::str::from_utf8(raw)
+            .map(str::to_string)
+            .map_err(|_| LineError::NotUtf8);
+        Some((self.line, content))
+    }
+}
+
+/// Split a streamed body into lines as its chunks arrive
+///
+/// Only one line is buffered at a time: a line growing past `max_line` bytes is
+/// reported as [`LineError::TooLong`] and skipped, and the body is only read
+/// further when the next line is polled. A read error ends the stream.
+pub fn lines<S, E>(body: S, max_line: usize) -> impl Stream<Item = Line>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    let splitter = Splitter {
+        body,
+        buffer: Vec::new(),
+        max_line,
+        line: 0,
+        discarding: false,
+        done: false,
+    };
+    futures::stream::unfold(splitter, |mut splitter| async move {
+        loop {
+            if let Some(line) = splitter.next_buffered() {
+                return Some((line, splitter));
+            }
+            if splitter.done {
+                // A last line without a trailing newline
+                let rest = std::mem::take(&mut splitter.buffer);
+                if splitter.discarding || rest.is_empty() {
+                    return None;
+                }
+                splitter.line += 1;
+                let line = splitter.decode(&rest)?;
+                return Some((line, splitter));
+            }
+            if splitter.buffer.len() > splitter.max_line {
+                splitter.buffer.clear();
+                splitter.discarding = true;
+                let line = (splitter.line + 1, Err(LineError::TooLong(splitter.max_line)));
+                return Some((line, splitter));
+            }
+
+            match splitter.body.next().await {
+                Some(Ok(chunk)) if splitter.discarding => {
+                    if let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+                        splitter.buffer.extend_from_slice(&chunk[end..]);
+                    }
+                }
+                Some(Ok(chunk)) => splitter.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    splitter.done = true;
+                    splitter.buffer.clear();
+                    splitter.discarding = true;
+                    let line = (splitter.line + 1, Err(LineError::Read(e.to_string())));
+                    return Some((line, splitter));
+                }
+                None => splitter.done = true,
+            }
+        }
+    })
+}
+
+/// Stream `values` back to the client, one JSON value per line
+pub fn response(values: impl Stream<Item = serde_json::Value> + Send + 'static) -> Response {
+    let body = values.map(|value| {
+        let mut line = serde_json::to_vec(&value).expect("JSON values always serialize");
+        line.push(b'\n');
+        Ok::<_, Infallible>(Bytes::from(line))
+    });
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON))],
+        Body::from_stream(body),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn split(chunks: &[&str], max_line: usize) -> Vec<Line> {
+        let chunks: Vec<Result<Bytes, Infallible>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk.as_bytes())))
+            .collect();
+        lines(futures::stream::iter(chunks), max_line).collect().await
+    }
+
+    #[tokio::test]
+    async fn test_lines_across_chunks() {
+        let lines = split(&["{\"a\":", "1}\r\n\n  \n{\"b\"", ":2}\n{\"c\":3}"], 64).await;
+        assert_eq!(lines, vec![
+            (1, Ok("{\"a\":1}".to_string())),
+            (4, Ok("{\"b\":2}".to_string())),
+            (5, Ok("{\"c\":3}".to_string())),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_long_lines_are_skipped() {
+        let long = "x".repeat(40);
+        let lines = split(&["short\n", &long, &long, "\nafter\n", &format!("{}\n", long), &long], 16).await;
+        assert_eq!(lines, vec![
+            (1, Ok("short".to_string())),
+            (2, Err(LineError::TooLong(16))),
+            (3, Ok("after".to_string())),
+            (4, Err(LineError::TooLong(16))),
+            (5, Err(LineError::TooLong(16))),
+        ]);
+    }
+
+    #[test]
+    fn test_is_ndjson() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_ndjson(&headers));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("Application/X-NDJSON; charset=utf-8"));
+        assert!(is_ndjson(&headers));
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(!is_ndjson(&headers));
+    }
+}
@@ -0,0 +1,127 @@
+use moka::sync::Cache;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::ServerConfig;
+
+/// Most clients whose prefetch allowance is tracked at once; the least recently seen go first
+const TRACKED_CLIENTS: u64 = 10_000;
+
+/// Requests a client may make per minute, refilled continuously with a burst of one minute's worth
+pub struct ClientRateLimiter {
+    per_minute: u32,
+    buckets: Cache<IpAddr, Arc<Mutex<Bucket>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl ClientRateLimiter {
+    /// Allow `per_minute` requests per client; 0 allows any number
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            // An idle minute refills a bucket completely, so it can be dropped
+            buckets: Cache::builder()
+                .max_capacity(TRACKED_CLIENTS)
+                .time_to_idle(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    /// Take one request from the client's allowance, or the time until one is available
+    ///
+    /// Clients without a known address share one allowance.
+    pub fn acquire(&self, client: Option<IpAddr>) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let burst = f64::from(self.per_minute);
+        let bucket = self
+            .buckets
+            .get_with(client.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), || {
+                Arc::new(Mutex::new(Bucket {
+                    tokens: burst,
+                    refilled_at: Instant::now(),
+                }))
+            });
+        let mut bucket = bucket.lock().expect("rate limit bucket poisoned");
+
+        let now = Instant::now();
+        let rate = burst / 60.0;
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.refilled_at).as_secs_f64() * rate).min(burst);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Limits on background work requested through the prefetch endpoint
+///
+/// Prefetched paths wait in a bounded queue and at most `workers` of them are
+/// fetched at once. Their conversions run on a pool of their own, so however
+/// much is queued, requests never wait behind a prefetch for conversion threads.
+pub struct Prefetcher {
+    pub limiter: ClientRateLimiter,
+    queue: Arc<Semaphore>,
+    workers: Arc<Semaphore>,
+    /// One permit per thread prefetch conversions may occupy
+    pub conversion_permits: Arc<Semaphore>,
+    pub conversion_threads: usize,
+}
+
+impl Prefetcher {
+    pub fn new(server: &ServerConfig, workers: usize) -> Self {
+        Self {
+            limiter: ClientRateLimiter::new(server.prefetch_rate_limit),
+            queue: Arc::new(Semaphore::new(server.prefetch_queue_size)),
+            workers: Arc::new(Semaphore::new(workers)),
+            conversion_permits: Arc::new(Semaphore::new(server.prefetch_threads)),
+            conversion_threads: server.prefetch_threads,
+        }
+    }
+
+    /// Reserve a place in the queue, `None` when it is full
+    pub fn enqueue(&self) -> Option<OwnedSemaphorePermit> {
+        self.queue.clone().try_acquire_owned().ok()
+    }
+
+    /// Wait until a queued path may be fetched
+    pub async fn worker(&self) -> OwnedSemaphorePermit {
+        self.workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Prefetch worker semaphore is never closed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_is_per_client() {
+        let limiter = ClientRateLimiter::new(2);
+        let a = Some(IpAddr::from([192, 0, 2, 1]));
+        let b = Some(IpAddr::from([192, 0, 2, 2]));
+        assert!(limiter.acquire(a).is_ok());
+        assert!(limiter.acquire(a).is_ok());
+        let retry_after = limiter.acquire(a).unwrap_err();
+        assert!(retry_after > Duration::from_secs(25) && retry_after <= Duration::from_secs(30), "{:?}", retry_after);
+        assert!(limiter.acquire(b).is_ok());
+
+        let unlimited = ClientRateLimiter::new(0);
+        for _ in 0..100 {
+            assert!(unlimited.acquire(a).is_ok());
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// Checks of the filesystem paths a configuration refers to, run before the server binds
+///
+/// Every check is run and the problems are reported together, so a bad deployment
+/// can be fixed in one go instead of one path per restart.
+#[derive(Debug, Default)]
+pub struct Preflight {
+    problems: Vec<String>,
+}
+
+impl Preflight {
+    /// A file the proxy appends to, created if missing
+    pub fn writable_file(&mut self, key: &str, path: &Path) {
+        if path.is_dir() {
+            self.problem(key, path, "is a directory");
+            return;
+        }
+        if let Err(e) = std::fs::OpenOptions::new().create(true).append(true).open(path) {
+            self.problem(key, path, &format!("cannot be opened for writing: {}", e));
+        }
+    }
+
+    fn problem(&mut self, key: &str, path: &Path, message: &str) {
+        self.problems.push(format!("{} ({}) {}", key, path.display(), message));
+    }
+
+    /// Fail with every problem found, if there were any
+    pub fn finish(self) -> anyhow::Result<()> {
+        if self.problems.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!("{} configured path(s) failed the startup check:", self.problems.len());
+        for problem in &self.problems {
+            let _ = write!(message, "\n  {}", problem);
+        }
+        anyhow::bail!(message)
+    }
+}
+
+/// Check every filesystem path in `config`
+pub fn check(config: &Config) -> anyhow::Result<()> {
+    let mut preflight = Preflight::default();
+    if let Some(path) = &config.server.audit_log_path {
+        preflight.writable_file("server.audit_log_path", path);
+    }
+    preflight.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_problems_reported_together() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("akkoproxy-preflight-{}/missing/audit.log", std::process::id()));
+
+        let mut preflight = Preflight::default();
+        preflight.writable_file("server.audit_log_path", &missing);
+        preflight.writable_file("other.path", &dir);
+        let message = preflight.finish().unwrap_err().to_string();
+
+        assert!(message.starts_with("2 configured path(s)"), "{}", message);
+        assert!(message.contains(&format!("server.audit_log_path ({})", missing.display())), "{}", message);
+        assert!(message.contains(&format!("other.path ({}) is a directory", dir.display())), "{}", message);
+    }
+
+    #[test]
+    fn test_writable_paths_pass() {
+        let path = std::env::temp_dir().join(format!("akkoproxy-preflight-{}.log", std::process::id()));
+        let mut config = Config::with_upstream("https://example.com".to_string());
+        assert!(check(&config).is_ok());
+
+        config.server.audit_log_path = Some(path.clone());
+        assert!(check(&config).is_ok());
+        assert!(path.exists());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::cache::{CacheStats, ResponseCache};
+
+/// Samples making up the window eviction pressure is judged over
+pub const PRESSURE_WINDOW: usize = 10;
+
+/// Eviction churn of the response cache, sampled in the background
+///
+/// Capacity counts entries, so an insert evicts at most one other entry. Evictions
+/// keeping up with inserts over the whole window means nearly every new entry
+/// pushes out an older one and the hit rate is about to collapse.
+#[derive(Default)]
+pub struct CachePressure {
+    window: Mutex<Window>,
+    /// Evictions per insert over the window, as `f64` bits
+    ratio: AtomicU64,
+    under_pressure: AtomicBool,
+}
+
+#[derive(Default)]
+struct Window {
+    /// Insert and eviction totals at the last sample
+    last: Option<(u64, u64)>,
+    /// Inserts and evictions between consecutive samples, oldest first
+    deltas: VecDeque<(u64, u64)>,
+}
+
+impl CachePressure {
+    /// Record a sample of the cache statistics, returning whether the cache is under pressure
+    ///
+    /// A warning is logged when the cache comes under pressure and a note when it recovers.
+    pub fn sample(&self, stats: &CacheStats) -> bool {
+        let (inserts, evictions) = {
+            let mut window = self.window.lock().unwrap();
+            if let Some((inserts, evictions)) = window.last {
+                window.deltas.push_back((stats.inserts - inserts, stats.evictions - evictions));
+                if window.deltas.len() > PRESSURE_WINDOW {
+                    window.deltas.pop_front();
+                }
+            }
+            window.last = Some((stats.inserts, stats.evictions));
+            if window.deltas.len() < PRESSURE_WINDOW {
+                (0, 0)
+            } else {
+                window.deltas.iter().fold((0, 0), |(i, e), (di, de)| (i + di, e + de))
+            }
+        };
+
+        let ratio = if inserts == 0 { 0.0 } else { evictions as f64 / inserts as f64 };
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+
+        let under_pressure = inserts > 0 && evictions >= inserts;
+        let was_under_pressure = self.under_pressure.swap(under_pressure, Ordering::Relaxed);
+        if under_pressure && !was_under_pressure {
+            warn!("Cache under eviction pressure: {} evictions for {} inserts over the last {} samples, {} of {} entries in use",
+                  evictions, inserts, PRESSURE_WINDOW, stats.entry_count, stats.max_capacity);
+        } else if was_under_pressure && !under_pressure {
+            info!("Cache eviction pressure receded: {} evictions for {} inserts", evictions, inserts);
+        }
+        under_pressure
+    }
+
+    /// Evictions per insert over the last window, 0 until the window is full
+    pub fn ratio(&self) -> f64 {
+        f64::from_bits(self.ratio.load(Ordering::Relaxed))
+    }
+}
+
+/// Sample `cache` into `pressure` every `interval` until `cancel` fires
+pub fn spawn_monitor(cache: ResponseCache, pressure: Arc<CachePressure>, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    cache.run_pending_tasks().await;
+                    pressure.sample(&cache.stats());
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheKey, CachedMeta, CachedResponse};
+    use axum::http::StatusCode;
+    use bytes::Bytes;
+
+    fn entry() -> CachedResponse {
+        CachedResponse::new(
+            Bytes::from_static(b"jpeg"),
+            CachedMeta::new("image/jpeg".to_string(), StatusCode::OK),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_churn_in_tiny_cache_reports_pressure() {
+        let cache = ResponseCache::new(4, Duration::from_secs(60), 1024);
+        let pressure = CachePressure::default();
+
+        // Filling the cache is not churn
+        for i in 0..4 {
+            cache.put(CacheKey::new(format!("/media/{}.jpg", i), "Original".to_string()), entry()).await;
+        }
+        cache.run_pending_tasks().await;
+        assert!(!pressure.sample(&cache.stats()));
+        assert_eq!(cache.stats().utilization_percent(), 100.0);
+
+        // Every later insert displaces an entry, sample after sample
+        let mut next = 4;
+        let mut under_pressure = false;
+        for _ in 0..PRESSURE_WINDOW {
+            for _ in 0..8 {
+                cache.put(CacheKey::new(format!("/media/{}.jpg", next), "Original".to_string()), entry()).await;
+                next += 1;
+            }
+            cache.run_pending_tasks().await;
+            under_pressure = pressure.sample(&cache.stats());
+        }
+        assert!(under_pressure);
+        assert!(pressure.ratio() >= 1.0, "{}", pressure.ratio());
+        assert_eq!(cache.stats().utilization_percent(), 100.0);
+
+        // Without new inserts the pressure recedes once they leave the window
+        for _ in 0..PRESSURE_WINDOW {
+            under_pressure = pressure.sample(&cache.stats());
+        }
+        assert!(!under_pressure);
+        assert_eq!(pressure.ratio(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_monitor_stops_when_cancelled() {
+        let cache = ResponseCache::new(4, Duration::from_secs(60), 1024);
+        let pressure = Arc::new(CachePressure::default());
+        let cancel = CancellationToken::new();
+        spawn_monitor(cache.clone(), pressure.clone(), Duration::from_millis(10), cancel.clone());
+
+        cache.put(CacheKey::new("/media/a.jpg".to_string(), "Original".to_string()), entry()).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pressure.window.lock().unwrap().last, Some((1, 0)));
+
+        // The task drops its pressure handle as it exits
+        cancel.cancel();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(Arc::strong_count(&pressure), 1);
+    }
+}
@@ -1,56 +1,137 @@
-use crate::cache::{CacheKey, CachedResponse, ResponseCache};
-use crate::config::Config;
-use crate::image::{is_image_content_type, parse_accept_header, format_from_content_type, format_satisfies, ImageConverter, OutputFormat};
+use crate::audit::{token_fingerprint, AuditEvent, AuditLog};
+use crate::cache::{
+    self, status_cache_control, status_ttl, AdmissionFilter, CacheKey, CacheLookup, CachedMeta, CachedResponse, PutOutcome, RefreshGuard, RefreshRole,
+    RefreshTracker, ResponseCache, HIT_BUCKETS,
+};
+use crate::conditional;
+use crate::config::{
+    AdmissionPolicy, CdnMode, Config, ExtensionConsistency, FallbackFormat, ForcedFormat, FormatMismatch, ImageConfig,
+    NoAcceptBehavior, UaOverride,
+};
+use crate::digest::{BodyDigest, BodyHasher};
+use crate::error_page::{self, ErrorBody, ErrorFormat, ErrorPages};
+use crate::forwarded::{self, TrustedProxy};
+use crate::headers::{add_validators, canonical_order, replace_etag, content_type_value, entry_headers, select_headers, forward_request_headers, parse_header_name, strip_hop_by_hop, strip_vary_cookie, HeaderNameSet, MEDIA_PASSTHROUGH_HEADERS, X_CACHE_STATUS};
+use crate::hedge::HedgedFetcher;
+use crate::host_limit::{HostLimitedFetcher, HostLimiter};
+use crate::logging::{self, sampled_debug};
+use crate::invalidation::{Invalidation, InvalidationTarget, Invalidator, INVALIDATION_ENDPOINT};
+use crate::memo::{DecisionMemo, MemoStore};
+use crate::metrics::{Metrics, RequestFlow, AUDITED_FORMATS};
+use crate::ndjson;
+use crate::prefetch::Prefetcher;
+use crate::memory::{self, MemoryWatchdog, ProcessMemoryReader};
+use crate::pressure::{self, CachePressure};
+use crate::range::{self, Ranged};
+use crate::resume::{BodyRetry, Refetched};
+use crate::runtime_state::{self, Actor, RuntimeState, RuntimeStates};
+use crate::shed::{self, ConversionShed, ProcessCpuSampler};
+use crate::slow::{self, RequestTimings, SlowRequest, SlowRequestLog};
+use crate::stats::PublicStats;
+use crate::telemetry::{self, OtlpExporter, SinkState};
+use crate::throttle::{BandwidthLimiter, ThrottledFetcher};
+use crate::upstream::{self, FetchError, ReqwestFetcher, UpstreamFetcher, UpstreamResponse};
+use crate::url_template::{path_hash, UrlTemplate};
+use crate::image::{body_is_image_type, quality_score, sniff_content_type, body_matches_image_type, AcceptCache, is_animated, normalize_content_type, ConversionOverBudget, Converted, DecodedImage, EncoderSelfTest, ImageError, SkipReason, VariantError, is_image_content_type, format_from_content_type, format_satisfies, explicit_quality, has_alpha, legacy_alternative_format, ImageConverter, OutputFormat, accept_refuses, format_extension, format_from_extension, format_media_type};
 use axum::{
     body::Body,
-    extract::{Request, State},
+    extract::{ConnectInfo, FromRequest, Query, Request, State},
     http::{header, HeaderMap, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
 };
 use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
-use tracing::{debug, error, info, warn};
-
-/// Custom header name for cache status
-const X_CACHE_STATUS: &str = "x-cache-status";
-
-/// Headers that should not be copied from upstream responses
-/// These are either automatically set by the proxy or should not be forwarded
-/// Note: ACCESS_CONTROL_ALLOW_ORIGIN is NOT excluded - it will be preserved from upstream
-/// if present, otherwise the proxy will set it to "*"
-const EXCLUDED_HEADERS: &[header::HeaderName] = &[
-    header::CONTENT_LENGTH,
-    header::CONTENT_TYPE,
-    header::TRANSFER_ENCODING,
-    header::CONNECTION,
-    header::VIA,
-    header::CACHE_CONTROL,
-];
-
-/// Check if a header should be excluded from upstream response
-fn should_exclude_header(key: &header::HeaderName) -> bool {
-    EXCLUDED_HEADERS.contains(key) || key.as_str() == X_CACHE_STATUS
-}
-
-/// Build Vary header value, prepending "Accept" to upstream value if present
-fn build_vary_header(upstream_vary: Option<&str>) -> String {
-    if let Some(upstream_value) = upstream_vary {
-        // Check if "Accept" is already in the upstream Vary header (case-insensitive)
-        let has_accept = upstream_value
-            .split(',')
-            .any(|v| v.trim().eq_ignore_ascii_case("accept"));
-        
-        if has_accept {
-            // If upstream already has "Accept", just use upstream value
-            upstream_value.to_string()
-        } else {
-            // Prepend "Accept" to upstream value
-            format!("Accept, {}", upstream_value)
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::Semaphore;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tower_http::trace::TraceLayer;
+use tracing::{debug, error, info, warn, Instrument};
+
+/// How often slow requests older than an hour are dropped
+const SLOW_REQUEST_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Response header listing what was cut from an oversized upstream error response
+const X_AKKOPROXY_TRUNCATED: &str = "x-akkoproxy-truncated";
+
+/// Body sent in place of an oversized upstream error body
+const ERROR_BODY_TOO_LARGE: &str = "Upstream error response too large";
+
+/// Warning sent with a transparent source served as PNG although JPEG was demanded
+const ALPHA_KEPT_WARNING: &str = "299 akkoproxy \"Served image/png to keep transparency\"";
+
+/// Request header that forces the unconverted original (admin only)
+const X_AKKOPROXY_NO_CONVERT: &str = "x-akkoproxy-no-convert";
+
+/// Cache lifetime for the CDN, separate from the browser's Cache-Control (RFC 9213)
+const CDN_CACHE_CONTROL: &str = "cdn-cache-control";
+
+/// Comma-separated tags Cloudflare can purge cached responses by
+const CACHE_TAG: &str = "cache-tag";
+
+/// X-Cache-Status detail of images left unconverted for `Cache-Control: no-transform`
+const NO_TRANSFORM_DETAIL: &str = "no-transform";
+
+/// X-Cache-Status detail of a response passed on as it arrived
+const STREAMED_DETAIL: &str = "streamed";
+
+/// Longest line accepted in an NDJSON admin request body
+const MAX_BULK_LINE_BYTES: usize = 8 * 1024;
+
+/// Lines of an NDJSON admin request body processed at once
+const BULK_CONCURRENCY: usize = 8;
+
+/// Busiest upstream hosts exported on `/metrics`, the rest are listed at `/admin/stats/hosts`
+const TOP_HOSTS: usize = 10;
+
+/// Accept header requests without one are negotiated with under `no_accept_behavior = "treat_as_avif_capable"`
+const AVIF_CAPABLE_ACCEPT: &str = "image/avif,image/webp,*/*";
+
+/// Cache outcome reported in the X-Cache-Status header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CacheStatus {
+    Hit,
+    /// An expired entry served while another request refreshes it
+    Stale,
+    Miss,
+    Bypass,
+    /// Served unconverted because conversions are shed
+    Shed,
+}
+
+impl CacheStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+            CacheStatus::Shed => "SHED",
+        }
+    }
+    
+    fn header_value(self) -> header::HeaderValue {
+        header::HeaderValue::from_static(self.as_str())
+    }
+    
+    /// Cache-Control of a successful response served with this status
+    fn cache_control(self) -> header::HeaderValue {
+        match self {
+            // The original must not be cached by others in place of the variant either
+            CacheStatus::Bypass | CacheStatus::Shed => header::HeaderValue::from_static("no-store"),
+            CacheStatus::Hit | CacheStatus::Stale | CacheStatus::Miss => {
+                header::HeaderValue::from_static("public, max-age=31536000, immutable")
+            }
         }
-    } else {
-        // No upstream Vary header, just use "Accept"
-        "Accept".to_string()
     }
 }
 
@@ -59,581 +140,7059 @@ fn build_vary_header(upstream_vary: Option<&str>) -> String {
 pub struct AppState {
     pub config: Arc<Config>,
     pub cache: ResponseCache,
-    pub client: reqwest::Client,
+    /// Decides which missed responses are stored in `cache`
+    admission: AdmissionFilter,
+    pub fetcher: Arc<dyn UpstreamFetcher>,
     pub image_converter: Arc<ImageConverter>,
+    /// Settings and converter of each `upstream.routes` entry with its own image settings
+    route_images: Arc<[Option<RouteImage>]>,
+    /// Encoders found working at startup; failed ones are disabled in `config`
+    pub encoders: EncoderSelfTest,
+    pub metrics: Arc<Metrics>,
+    pub audit: AuditLog,
+    /// `server.via_header`, validated once
+    via_header: header::HeaderValue,
+    /// Refreshes of expired cache entries and fetches of missing ones in flight
+    refreshes: RefreshTracker<FetchOutcome>,
+    /// One permit per thread conversions may occupy
+    conversion_permits: Arc<Semaphore>,
+    /// Permits in `conversion_permits`
+    conversion_threads: usize,
+    /// One permit per background prewarm conversion waiting or running
+    prewarm_slots: Arc<Semaphore>,
+    trusted_proxies: Arc<[TrustedProxy]>,
+    /// Parsed `upstream.url_template`, if configured
+    url_template: Option<Arc<UrlTemplate>>,
+    /// `Authorization` value of `upstream.auth`, sent to `url` and `url_template` only
+    upstream_auth: Option<header::HeaderValue>,
+    /// Formats already negotiated for recently seen Accept headers
+    accept_formats: AcceptCache,
+    /// Limiter shared by all upstream body reads, if a bandwidth cap is configured
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    /// Connections per upstream host, if limited
+    host_limiter: Option<Arc<HostLimiter>>,
+    /// Template of the HTML error pages shown to browsers
+    error_pages: ErrorPages,
+    /// Per-path decisions remembered for a while, such as slow conversions
+    pub memos: MemoStore,
+    /// Eviction churn of `cache`, sampled in the background
+    pub cache_pressure: Arc<CachePressure>,
+    /// Upstream headers kept on passed-through responses without `preserve_upstream_headers`
+    forwarded_headers: Arc<HeaderNameSet>,
+    /// Coarse totals and the last hour's hit rate, published at `/stats`
+    pub public_stats: Arc<PublicStats>,
+    /// Skips conversions while CPU usage is high, sampled in the background
+    pub conversion_shed: Arc<ConversionShed>,
+    /// Shrinks `cache` while resident memory is close to the limit, sampled in the background
+    pub memory_watchdog: Arc<MemoryWatchdog>,
+    /// Slowest requests of the last hour, listed at `/admin/slow`
+    pub slow_requests: Arc<SlowRequestLog>,
+    /// Applies purges here and shares them with the other replicas
+    pub invalidator: Arc<Invalidator>,
+    /// Maintenance and other states switched on through `/admin/state`
+    pub runtime_states: Arc<RuntimeStates>,
+    /// Queue, workers and conversion threads of `POST /prefetch`
+    prefetcher: Arc<Prefetcher>,
+    /// Pushes metrics to an OTLP collector, if one is configured
+    pub otlp: Option<Arc<OtlpExporter>>,
+    /// Stops background tasks once the last clone of the state is dropped
+    _background: Arc<DropGuard>,
+}
+
+/// Image settings of one upstream route, merged over the global ones
+struct RouteImage {
+    image: ImageConfig,
+    converter: Arc<ImageConverter>,
 }
 
 impl AppState {
     pub fn new(config: Config) -> Self {
+        let fetcher = Arc::new(ReqwestFetcher::new(&config));
+        Self::with_fetcher(config, fetcher)
+    }
+    
+    /// Application state for tests that compare output across runs
+    ///
+    /// Upstream is `fetcher`, encoder output is reproducible and no background
+    /// tasks are started.
+    pub fn deterministic(mut config: Config, fetcher: Arc<dyn UpstreamFetcher>) -> Self {
+        config.image.deterministic_encoding = true;
+        config.cache.pressure_sample_interval = 0;
+        Self::with_fetcher(config, fetcher)
+    }
+    
+    /// Create application state that talks to upstream through `fetcher`
+    pub fn with_fetcher(mut config: Config, fetcher: Arc<dyn UpstreamFetcher>) -> Self {
         debug!("Initializing AppState with config: bind={}, upstream={}", 
-               config.server.bind, config.upstream.url);
+               config.server.bind, config.upstream.base_url());
         
-        let cache = ResponseCache::new(
+        // Routes may enable formats the global settings leave off, so those are tried too
+        let mut probed = config.image.clone();
+        for image in config.upstream.routes.iter().filter_map(|route| route.image.as_ref()) {
+            probed.enable_avif |= image.enable_avif == Some(true);
+            probed.enable_webp |= image.enable_webp == Some(true);
+        }
+        let encoders = EncoderSelfTest::run(&probed);
+        encoders.apply(&mut config.image);
+        debug!("Encoder self-test: avif {}, webp {}", encoders.avif, encoders.webp);
+        
+        let cache = ResponseCache::with_stale_ttl(
             config.cache.max_capacity,
             Duration::from_secs(config.cache.ttl),
             config.cache.max_item_size,
+            // Entries served stale while revalidated are kept at least that long
+            Duration::from_secs(config.cache.stale_ttl.max(config.cache.stale_while_revalidate)),
         );
         debug!("Cache initialized: max_capacity={}, ttl={}s, max_item_size={} bytes",
                config.cache.max_capacity, config.cache.ttl, config.cache.max_item_size);
+        let admission = match config.cache.admission_policy {
+            AdmissionPolicy::Always => AdmissionFilter::always(),
+            AdmissionPolicy::SecondHit => AdmissionFilter::second_hit(
+                Duration::from_secs(config.cache.admission_window),
+                config.cache.max_capacity,
+            ),
+        };
         
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(config.upstream.timeout))
-            .user_agent(format!("akkoproxy/{}", env!("CARGO_PKG_VERSION")))
-            .pool_max_idle_per_host(10)
-            .pool_idle_timeout(Duration::from_secs(90))
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .expect("Failed to create HTTP client");
-        debug!("HTTP client configured: timeout={}s, user_agent=akkoproxy/{}, redirect_policy=none",
-               config.upstream.timeout, env!("CARGO_PKG_VERSION"));
-        
-        let image_converter = Arc::new(ImageConverter::new(
-            config.image.quality,
-            config.image.max_dimension,
-            config.image.enable_avif,
-            config.image.enable_webp,
-        ));
+        let image_converter = Arc::new(build_converter(&config.image));
         debug!("Image converter initialized: quality={}, max_dimension={}, avif={}, webp={}",
                config.image.quality, config.image.max_dimension, 
                config.image.enable_avif, config.image.enable_webp);
+        let route_images = config
+            .upstream
+            .routes
+            .iter()
+            .map(|route| {
+                let mut image = route.image.as_ref()?.merged(&config.image);
+                encoders.disable_failed(&mut image);
+                debug!("Image settings for {}: quality={}, max_dimension={}, avif={}, webp={}",
+                       route.prefix, image.quality, image.max_dimension, image.enable_avif, image.enable_webp);
+                let converter = Arc::new(build_converter(&image));
+                Some(RouteImage { image, converter })
+            })
+            .collect();
+        
+        let metrics = Metrics::with_latency_buckets(&config.telemetry.latency_buckets);
+        let invalidator = Arc::new(Invalidator::from_config(&config, metrics.clone()));
+        // Innermost, so each connection holds one permit whether it is a hedge, a retry or neither
+        let (fetcher, host_limiter) = match config.upstream.max_concurrent_per_host {
+            Some(per_host) => {
+                debug!("Upstream connections limited to {} per host", per_host);
+                let limiter = Arc::new(HostLimiter::new(per_host, Duration::from_secs(config.upstream.host_queue_timeout)));
+                let fetcher: Arc<dyn UpstreamFetcher> = Arc::new(HostLimitedFetcher::new(fetcher, limiter.clone(), metrics.clone()));
+                (fetcher, Some(limiter))
+            }
+            None => (fetcher, None),
+        };
+        let fetcher: Arc<dyn UpstreamFetcher> = match config.upstream.hedge_after_ms {
+            Some(hedge_after_ms) => {
+                debug!("Upstream requests hedged after {}ms, at most {} at once",
+                       hedge_after_ms, config.upstream.max_hedged_requests);
+                Arc::new(HedgedFetcher::new(
+                    fetcher,
+                    Duration::from_millis(hedge_after_ms),
+                    config.upstream.max_hedged_requests,
+                    metrics.clone(),
+                ))
+            }
+            None => fetcher,
+        };
+        
+        let (fetcher, bandwidth) = match config.upstream.max_bandwidth_bytes_per_sec {
+            Some(bytes_per_sec) => {
+                debug!("Upstream bandwidth limited to {} bytes/s", bytes_per_sec);
+                let limiter = Arc::new(BandwidthLimiter::new(bytes_per_sec));
+                let fetcher: Arc<dyn UpstreamFetcher> = Arc::new(ThrottledFetcher::new(fetcher, limiter.clone()));
+                (fetcher, Some(limiter))
+            }
+            None => (fetcher, None),
+        };
+        
+        // Entries were validated with the rest of the configuration
+        let trusted_proxies = config
+            .server
+            .trusted_proxies
+            .iter()
+            .filter_map(|proxy| TrustedProxy::parse(proxy).ok())
+            .collect();
+        
+        let audit = match &config.server.audit_log_path {
+            Some(path) => {
+                debug!("Audit log written to {}", path.display());
+                AuditLog::to_file(path.clone(), config.server.audit_log_max_size)
+            }
+            None => AuditLog::disabled(),
+        };
+        
+        // Names were validated with the rest of the configuration
+        let forwarded_headers: HeaderNameSet = MEDIA_PASSTHROUGH_HEADERS
+            .iter()
+            .cloned()
+            .chain(
+                config
+                    .server
+                    .always_forward_headers
+                    .iter()
+                    .filter_map(|name| parse_header_name(name).ok()),
+            )
+            .collect();
+        
+        // Validated with the rest of the configuration as well
+        let url_template = config
+            .upstream
+            .url_template
+            .as_deref()
+            .and_then(|template| UrlTemplate::parse(template).ok())
+            .map(Arc::new);
+        let upstream_auth = config
+            .upstream
+            .auth
+            .as_ref()
+            .map(|auth| auth.header_value().expect("Upstream credentials are checked by Config::validate"));
+        
+        let conversion_threads = config.conversion_thread_budget();
+        let conversion_permits = Arc::new(Semaphore::new(conversion_threads));
+        let prefetcher = Arc::new(Prefetcher::new(&config.server, BULK_CONCURRENCY));
+        let prewarm_slots = Arc::new(Semaphore::new(config.image.prewarm_queue_size));
+        debug!("Conversion thread budget: {}, AVIF threads per encode: {}",
+               config.conversion_thread_budget(), config.effective_avif_threads());
+        
+        let via_header = header::HeaderValue::from_str(&config.server.via_header)
+            .unwrap_or_else(|_| header::HeaderValue::from_static("akkoproxy"));
+        
+        // Background tasks need a runtime, which states built outside one go without
+        let background = CancellationToken::new();
+        let cache_pressure = Arc::new(CachePressure::default());
+        if config.cache.pressure_sample_interval > 0 && tokio::runtime::Handle::try_current().is_ok() {
+            pressure::spawn_monitor(
+                cache.clone(),
+                cache_pressure.clone(),
+                Duration::from_secs(config.cache.pressure_sample_interval),
+                background.clone(),
+            );
+        }
+        let refreshes = RefreshTracker::new(config.refresh_deadline());
+        if tokio::runtime::Handle::try_current().is_ok() {
+            // Sweeping twice per deadline drops a stuck key within one and a half deadlines
+            let interval = (config.refresh_deadline() / 2).max(Duration::from_secs(1));
+            cache::spawn_sweeper(refreshes.clone(), interval, background.clone());
+        }
+        let conversion_shed = Arc::new(ConversionShed::new(&config.server));
+        if conversion_shed.is_enabled() && tokio::runtime::Handle::try_current().is_ok() {
+            shed::spawn_monitor(
+                conversion_shed.clone(),
+                ProcessCpuSampler::default(),
+                Duration::from_secs(config.server.conversion_shed_sample_interval),
+                background.clone(),
+            );
+        }
+        let memory_limit = config.server.memory_shrink_percent
+            .and(config.server.memory_limit_bytes.or_else(memory::cgroup_memory_limit));
+        if config.server.memory_shrink_percent.is_some() && memory_limit.is_none() {
+            warn!("server.memory_shrink_percent is set, but neither server.memory_limit_bytes nor a cgroup memory limit is; \
+                   the cache is never shrunk");
+        }
+        let memory_watchdog = Arc::new(MemoryWatchdog::new(&config.server, memory_limit, cache.clone(), metrics.clone()));
+        if memory_watchdog.is_enabled() && tokio::runtime::Handle::try_current().is_ok() {
+            memory::spawn_monitor(
+                memory_watchdog.clone(),
+                ProcessMemoryReader,
+                Duration::from_secs(config.server.memory_sample_interval),
+                background.clone(),
+            );
+        }
+        let slow_requests = Arc::new(SlowRequestLog::new(&config.server));
+        if slow_requests.is_enabled() && tokio::runtime::Handle::try_current().is_ok() {
+            slow::spawn_pruner(slow_requests.clone(), SLOW_REQUEST_PRUNE_INTERVAL, background.clone());
+        }
+        let error_pages = ErrorPages::load(config.server.error_template_path.as_deref()).unwrap_or_else(|e| {
+            error!("Failed to read the error page template, using the built-in one: {}", e);
+            ErrorPages::default()
+        });
+        let runtime_states = Arc::new(RuntimeStates::default());
+        if tokio::runtime::Handle::try_current().is_ok() {
+            runtime_state::spawn_expiry(runtime_states.clone(), audit.clone(), Duration::from_secs(1), background.clone());
+        }
+        // An unreachable collector is retried by the exporter without holding up startup
+        let otlp = config.telemetry.otlp_endpoint.as_ref().map(|endpoint| {
+            Arc::new(OtlpExporter::new(
+                endpoint,
+                Duration::from_secs(config.telemetry.otlp_interval),
+                Duration::from_secs(config.telemetry.otlp_timeout),
+                metrics.clone(),
+            ))
+        });
+        if let Some(otlp) = otlp.clone().filter(|_| tokio::runtime::Handle::try_current().is_ok()) {
+            otlp.spawn(background.clone());
+        }
         
         Self {
             config: Arc::new(config),
             cache,
-            client,
+            admission,
+            fetcher,
             image_converter,
+            route_images,
+            encoders,
+            metrics,
+            audit,
+            via_header,
+            refreshes,
+            conversion_permits,
+            conversion_threads,
+            prewarm_slots,
+            trusted_proxies,
+            url_template,
+            upstream_auth,
+            accept_formats: AcceptCache::new(128),
+            bandwidth,
+            host_limiter,
+            error_pages,
+            memos: MemoStore::default(),
+            cache_pressure,
+            forwarded_headers: Arc::new(forwarded_headers),
+            public_stats: Arc::new(PublicStats::default()),
+            conversion_shed,
+            memory_watchdog,
+            slow_requests,
+            invalidator,
+            runtime_states,
+            prefetcher,
+            otlp,
+            _background: Arc::new(background.drop_guard()),
         }
     }
 }
 
-/// Main proxy handler
-pub async fn proxy_handler(
-    State(state): State<AppState>,
-    uri: Uri,
-    headers: HeaderMap,
-    _request: Request,
-) -> Result<Response, ProxyError> {
-    let path = uri.path();
-    let query = uri.query().unwrap_or("");
-    
-    debug!("Proxying request: {} {}", path, query);
-    
-    // Handle root path with redirect
-    if path == "/" {
-        return Ok(Response::builder()
-            .status(StatusCode::MOVED_PERMANENTLY)
-            .header(header::LOCATION, "https://github.com/BlockG-ws/akkoproxy")
-            .body(Body::empty())
-            .expect("Failed to build root redirect response"));
+/// Converter applying `image`
+pub(crate) fn build_converter(image: &ImageConfig) -> ImageConverter {
+    ImageConverter::new(image.quality, image.max_dimension, image.enable_avif, image.enable_webp)
+        .with_max_pixels(image.max_pixels)
+        .with_fast_resize_threshold(image.fast_resize_threshold_pixels)
+        .with_tiers(image.tiers.clone())
+        .with_avif_threads(Some(image.avif_threads).filter(|threads| *threads > 0))
+        .with_deterministic_encoding(image.deterministic_encoding)
+}
+
+impl AppState {
+    /// Image settings and converter for requests to `path`, by upstream route
+    fn image_settings(&self, path: &str) -> (&ImageConfig, &Arc<ImageConverter>) {
+        let route = self.config.upstream.route_for(path).and_then(|(index, _)| self.route_images[index].as_ref());
+        match route {
+            Some(route) => (&route.image, &route.converter),
+            None => (&self.config.image, &self.image_converter),
+        }
     }
     
-    // Only handle /media and /proxy paths
-    if !path.starts_with("/media") && !path.starts_with("/proxy") {
-        warn!("Path not allowed: {}", path);
-        return Err(ProxyError::PathNotAllowed);
+    /// Path a request for `path` is fetched and cached under
+    ///
+    /// With `upstream.normalize_slashes` repeated slashes are collapsed and a
+    /// trailing slash is dropped, so every spelling of a path shares one entry.
+    fn request_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if self.config.upstream.normalize_slashes {
+            normalize_slashes(path)
+        } else {
+            Cow::Borrowed(path)
+        }
     }
     
-    // Parse query parameters if behind_cloudflare_free is enabled
-    let (format_from_query, upstream_query) = if state.config.server.behind_cloudflare_free && !query.is_empty() {
-        parse_query_for_format(query)
-    } else {
-        (None, query.to_string())
-    };
+    /// Upstream URL `path` and `query` are fetched from
+    ///
+    /// A route with its own URL takes precedence over `url_template` and `url`.
+    fn upstream_url(&self, path: &str, query: &str) -> String {
+        let route_url = self.config.upstream.route_for(path).and_then(|(_, route)| route.base_url());
+        match (route_url, self.url_template.as_deref()) {
+            (Some(url), _) if query.is_empty() => format!("{}{}", url, path),
+            (Some(url), _) => format!("{}{}?{}", url, path, query),
+            (None, Some(template)) => template.render(path, query),
+            (None, None) if query.is_empty() => format!("{}{}", self.config.upstream.base_url(), path),
+            (None, None) => format!("{}{}?{}", self.config.upstream.base_url(), path, query),
+        }
+    }
     
-    // Build upstream URL (without format query if it was present)
-    let upstream_url = if upstream_query.is_empty() {
-        format!("{}{}", state.config.upstream.url, path)
-    } else {
-        format!("{}{}?{}", state.config.upstream.url, path, upstream_query)
-    };
+    /// Credentials for the upstream request of `path`
+    ///
+    /// Routes with a `url` of their own lead to other servers, which never get them.
+    fn upstream_auth(&self, path: &str) -> Option<&header::HeaderValue> {
+        let route_url = self.config.upstream.route_for(path).and_then(|(_, route)| route.base_url());
+        self.upstream_auth.as_ref().filter(|_| route_url.is_none())
+    }
     
-    // Determine desired format
-    let desired_format = if let Some(fmt) = format_from_query {
-        // Use format from query parameter if available
-        fmt
-    } else {
-        // Get Accept header to determine desired format
-        let accept = headers
-            .get(header::ACCEPT)
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("*/*");
-        
-        parse_accept_header(
-            accept,
-            state.config.image.enable_avif,
-            state.config.image.enable_webp,
+    /// Externally visible base URL for building absolute URLs
+    ///
+    /// `peer` is the address of the directly connected client, which decides
+    /// whether its X-Forwarded-* headers are trusted.
+    pub fn external_base_url(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> String {
+        forwarded::external_base_url(
+            self.config.server.external_base_url.as_deref(),
+            &self.trusted_proxies,
+            self.config.server.bind.primary(),
+            headers,
+            peer,
         )
-    };
+    }
     
-    // Generate cache key
-    let cache_key = CacheKey::new(
-        format!("{}{}", path, if query.is_empty() { String::new() } else { format!("?{}", query) }),
-        format!("{:?}", desired_format),
-    );
+    /// Address of the client a request came from
+    ///
+    /// Behind Cloudflare this is taken from CF-Connecting-IP, otherwise it is the peer.
+    pub fn client_ip(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        match self.config.server.cdn_mode() {
+            CdnMode::Cloudflare => forwarded::cloudflare_client_ip(&self.trusted_proxies, headers, peer),
+            _ => peer,
+        }
+    }
     
-    // Check cache first
-    if let Some(cached) = state.cache.get(&cache_key).await {
-        debug!("Cache hit for {}", path);
-        return Ok(build_response(
-            cached.data.clone(), 
-            &cached.content_type, 
-            &state.config.server.via_header, 
-            cached.upstream_headers.as_ref(),
-            true, // is_cache_hit
-        ));
+    /// Whether conversions are shed, by CPU usage or by an operator
+    pub fn conversions_shed(&self) -> bool {
+        self.conversion_shed.is_shedding() || self.runtime_states.is_active(RuntimeState::ShedConversions)
     }
     
-    debug!("Cache miss for {}, fetching from upstream: {}", path, upstream_url);
+    /// Telemetry sinks with their state, and whether any of them is degraded
+    pub fn telemetry(&self) -> (Vec<(&'static str, SinkState)>, bool) {
+        let sinks = telemetry::sinks(self.otlp.as_deref());
+        let degraded = sinks.iter().any(|(_, state)| matches!(state, SinkState::Degraded(_)));
+        (sinks, degraded)
+    }
     
-    // Fetch from upstream
-    let response = state.client
-        .get(&upstream_url)
-        .send()
-        .await
-        .map_err(|e| {
-            error!("Failed to fetch from upstream: {}", e);
-            ProxyError::UpstreamError(e)
-        })?;
+    /// Push the last metrics to the telemetry sinks that are up, before shutting down
+    pub async fn flush_telemetry(&self) {
+        if let Some(otlp) = &self.otlp {
+            otlp.flush().await;
+        }
+    }
     
-    let status = response.status();
+    /// The same state with conversions running on the prefetch threads
+    fn for_prefetch(&self) -> Self {
+        Self {
+            conversion_permits: self.prefetcher.conversion_permits.clone(),
+            conversion_threads: self.prefetcher.conversion_threads,
+            ..self.clone()
+        }
+    }
     
-    // Handle non-success responses (redirects, errors, etc.)
-    // For non-2xx responses, preserve and forward the response with its status code
-    if !status.is_success() {
-        debug!("Upstream returned non-success status: {}", status);
-        
-        // Preserve upstream headers
-        let upstream_headers = if state.config.server.preserve_upstream_headers {
-            Some(response.headers().clone())
-        } else {
-            None
-        };
-        
-        let body_bytes = response.bytes().await.map_err(|e| {
-            error!("Failed to read response body: {}", e);
-            ProxyError::UpstreamError(e)
-        })?;
+    /// Re-read the configuration file and apply the settings that can change at runtime
+    ///
+    /// Only the debug log sample rate is applied, and memos of decisions that depend on
+    /// settings changed in the file are forgotten. The attempt is recorded in the audit log.
+    pub fn reload_config(&self, path: &Path) -> anyhow::Result<Config> {
+        let result = Config::from_file(path);
+        self.audit.record(AuditEvent::new("reload", serde_json::json!({
+            "path": path.display().to_string(),
+            "outcome": if result.is_ok() { "applied" } else { "failed" },
+        })));
         
-        // Build response with the actual status code from upstream
-        return Ok(build_response_with_status(
-            body_bytes,
-            status,
-            &state.config.server.via_header,
-            upstream_headers.as_ref(),
-        ));
+        let (config, notes) = result?;
+        for note in &notes {
+            warn!("Configuration: {}", note);
+        }
+        logging::set_debug_sample_rate(config.server.debug_log_sample_rate);
+        if config.image.conversion_time_budget_ms != self.config.image.conversion_time_budget_ms {
+            self.memos.forget_where(|memo| matches!(memo, DecisionMemo::OverBudget(_)));
+        }
+        if config.max_convert_input_size() != self.config.max_convert_input_size() {
+            self.memos.forget_where(|memo| memo == DecisionMemo::TooLargeLogged);
+        }
+        if config.cache.max_item_size != self.config.cache.max_item_size {
+            self.memos.forget_where(|memo| matches!(memo, DecisionMemo::Uncacheable(_)));
+        }
+        Ok(config)
+    }
+}
+
+/// Build the application router
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(root_handler))
+        .route("/health", get(health_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/stats", get(public_stats_handler))
+        .route("/admin/stats/formats", get(format_stats_handler).post(reset_format_stats_handler))
+        .route("/admin/stats/inflight", get(inflight_stats_handler).post(clear_inflight_handler))
+        .route("/admin/stats/hosts", get(host_stats_handler))
+        .route("/admin/slow", get(slow_requests_handler))
+        .route("/admin/explain", get(explain_handler))
+        .route("/admin/state", get(runtime_state_handler).post(set_runtime_state_handler))
+        .route("/admin/cache/entry", get(cache_entry_handler))
+        .route("/admin/cache/purge", post(purge_handler))
+        .route("/admin/cache/purge_tag", post(purge_tag_handler))
+        .route(INVALIDATION_ENDPOINT, post(invalidation_handler))
+        .route("/admin/cache/warm", post(warm_handler))
+        .route("/prefetch", post(prefetch_handler))
+        .fallback(proxy_handler)
+        .layer(middleware::from_fn_with_state(state.clone(), limit_request_headers))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Reject requests whose headers exceed the configured count or total size with 431
+async fn limit_request_headers(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let (count, size) = (headers.len(), headers_size(headers));
+    if count > state.config.server.max_request_headers || size > state.config.server.max_request_header_bytes {
+        debug!("Rejecting request with {} headers totalling {} bytes", count, size);
+        return (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "Request header fields too large").into_response();
     }
     
-    // Preserve upstream headers if configured (for success responses)
-    let upstream_headers = if state.config.server.preserve_upstream_headers {
-        Some(response.headers().clone())
-    } else {
-        None
-    };
-    
-    let content_type = response
-        .headers()
-        .get(header::CONTENT_TYPE)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("application/octet-stream")
-        .to_string();
-    
-    let body_bytes = response.bytes().await.map_err(|e| {
-        error!("Failed to read response body: {}", e);
-        ProxyError::UpstreamError(e)
-    })?;
+    next.run(request).await
+}
+
+/// Cancels a request's token if it is dropped before being disarmed
+///
+/// axum drops the handler future when the client disconnects, which drops any
+/// in-flight upstream fetch with it; the token carries that signal on to
+/// conversions running on the blocking pool.
+struct CancelOnDrop {
+    token: CancellationToken,
+    metrics: Arc<Metrics>,
+    armed: bool,
+}
+
+impl CancelOnDrop {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            metrics,
+            armed: true,
+        }
+    }
     
-    // Check if this is an image and conversion is requested
-    // Skip conversion if upstream format already satisfies the desired format
-    let upstream_format = format_from_content_type(&content_type);
-    let needs_conversion = should_convert_image(
-        &content_type,
-        upstream_format,
-        desired_format,
-        body_bytes.len(),
-        state.config.cache.max_item_size as usize,
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.armed {
+            debug!("Client disconnected, cancelling request");
+            self.token.cancel();
+            Metrics::incr(&self.metrics.cancelled_requests);
+        }
+    }
+}
+
+/// Main proxy handler
+pub async fn proxy_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    uri: Uri,
+    headers: HeaderMap,
+    _request: Request,
+) -> Response {
+    let started = Instant::now();
+    let path = state.request_path(uri.path());
+    let deadline = Duration::from_secs(state.config.server.request_deadline);
+    let span = tracing::info_span!(
+        "request",
+        path = %path,
+        upstream_timeout_secs = state.config.upstream.timeout_for(&path),
     );
+    let guard = CancelOnDrop::new(state.metrics.clone());
+    let timings = RequestTimings::default();
+    let handled = handle_proxy_request(&state, &uri, &headers, &guard.token, &timings).instrument(span);
+    let result = match tokio::time::timeout(deadline, handled).await {
+        Ok(result) => result,
+        Err(_) => {
+            // Conversions on blocking threads watch the token, dropping the future does not stop them
+            guard.token.cancel();
+            Err(ProxyError::DeadlineExceeded(deadline))
+        }
+    };
+    guard.disarm();
     
-    let (final_data, final_content_type) = if needs_conversion {
-        debug!("Converting image to {:?}", desired_format);
-        
-        match state.image_converter.convert(&body_bytes, desired_format) {
-            Ok((converted, mime_type)) => {
-                info!("Successfully converted image: {} bytes -> {} bytes", body_bytes.len(), converted.len());
-                (converted, mime_type.to_string())
-            }
-            Err(e) => {
-                warn!("Failed to convert image: {}, returning original", e);
-                (body_bytes, content_type)
-            }
+    // Paths mark their responses with the flow they took, plain misses go unmarked
+    let flow = match &result {
+        Ok(response) if !response.status().is_server_error() => {
+            response.extensions().get::<RequestFlow>().copied().unwrap_or(RequestFlow::Miss)
         }
-    } else {
-        if is_image_content_type(&content_type) && upstream_format.is_some() {
-            debug!("Skipping conversion: upstream format {:?} already satisfies desired format {:?}", 
-                   upstream_format, desired_format);
-        } else {
-            debug!("Not converting: is_image={}, format={:?}, size={}", 
-                   is_image_content_type(&content_type), desired_format, body_bytes.len());
+        _ => RequestFlow::Error,
+    };
+    state.metrics.request_duration.observe(flow, started.elapsed());
+    let mut response = match result {
+        Ok(response) => {
+            let response = add_cdn_headers(&state, &path, add_content_disposition(&state, &path, response));
+            // A 304 has no body to cut, so ranges apply only to responses sent in full
+            let response = serve_not_modified(&state, &headers, response);
+            serve_range(&state, &headers, response).await
+        }
+        Err(e) => {
+            let request_id = error_page::request_id(&headers);
+            let (status, code, _) = e.describe();
+            info!("Request {} for {} failed with {} ({})", request_id, path, status.as_u16(), code);
+            let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+            e.into_negotiated_response(&state.error_pages, accept, Some(&request_id))
         }
-        (body_bytes, content_type)
     };
+    // Ordered last, once every path has added the headers it sets
+    canonical_order(response.headers_mut());
     
-    // Cache the response
-    if final_data.len() <= state.config.cache.max_item_size as usize {
-        let cached_response = CachedResponse {
-            data: final_data.clone(),
-            content_type: final_content_type.clone(),
-            upstream_headers: upstream_headers.clone(),
-        };
-        state.cache.put(cache_key, cached_response).await;
-        debug!("Cached response for {}", path);
-    } else {
-        debug!("Response too large to cache: {} bytes", final_data.len());
+    let total = started.elapsed();
+    if state.slow_requests.is_slow(total) {
+        let mut slow = SlowRequest::new(path.into_owned(), response.status().as_u16(), total, timings.phases());
+        slow.cache_status = response
+            .headers()
+            .get(X_CACHE_STATUS)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        slow.response_bytes = axum::body::HttpBody::size_hint(response.body()).exact();
+        if state.slow_requests.includes_client_ips() {
+            slow.client_ip = state.client_ip(&headers, connect_info.map(|ConnectInfo(addr)| addr.ip()));
+        }
+        debug!("Keeping slow request for {} ({:?})", slow.path, total);
+        state.slow_requests.record(slow);
     }
-    
-    Ok(build_response(
-        final_data, 
-        &final_content_type, 
-        &state.config.server.via_header, 
-        upstream_headers.as_ref(),
-        false, // is_cache_hit
-    ))
+    response
 }
 
-/// Parse query string to extract format parameter and return modified query
-/// Returns (format_option, remaining_query_string)
-/// 
-/// This parser is intentionally simple and only handles basic ASCII format values
-/// ("avif", "webp") with case-insensitive matching. It handles '+' as space
-/// (common in query strings) but does not perform full URL decoding.
-/// 
-/// Cloudflare Transform Rules generate clean query parameters like "format=avif"
-/// so complex URL decoding is not necessary for this use case.
-fn parse_query_for_format(query: &str) -> (Option<OutputFormat>, String) {
-    let mut format_value = None;
-    let mut remaining_params = Vec::new();
+/// Answer a conditional request with an empty 304 when the client's copy is current
+///
+/// Only 200 responses are checked, and a 304 keeps every header but those
+/// describing the body.
+fn serve_not_modified(state: &AppState, request_headers: &HeaderMap, response: Response) -> Response {
+    if response.status() != StatusCode::OK || !conditional::is_not_modified(request_headers, response.headers()) {
+        return response;
+    }
+    Metrics::incr(&state.metrics.not_modified_responses);
+    let (mut parts, _) = response.into_parts();
+    parts.status = StatusCode::NOT_MODIFIED;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Cut a complete response down to the byte range the request asked for
+///
+/// Only bodies held in memory, cache hits and buffered misses, are cut; streamed
+/// bodies are served as upstream answered. Players learn that they can seek from
+/// the `Accept-Ranges` upstream sent along with unconverted media.
+async fn serve_range(state: &AppState, request_headers: &HeaderMap, response: Response) -> Response {
+    let len = match axum::body::HttpBody::size_hint(response.body()).exact() {
+        Some(len) if response.status() == StatusCode::OK => len,
+        _ => return response,
+    };
+    let Some(requested) = request_headers.get(header::RANGE).and_then(|v| v.to_str().ok()) else {
+        return response;
+    };
+    if !range::if_range_matches(request_headers, response.headers()) {
+        return response;
+    }
     
-    for param in query.split('&') {
-        if let Some((key, value)) = param.split_once('=') {
-            if key == "format" {
-                // Parse the format value directly (case-insensitive, trimmed)
-                // We expect simple ASCII values like "avif" or "webp"
-                // Strip common whitespace encodings like +
-                let normalized = value.replace('+', " ");
-                format_value = match normalized.trim().to_lowercase().as_str() {
-                    "avif" => Some(OutputFormat::Avif),
-                    "webp" => Some(OutputFormat::WebP),
-                    _ => None, // Invalid or unsupported format values are ignored
-                };
-            } else {
-                remaining_params.push(param);
-            }
-        } else {
-            // Keep parameters without values (e.g., "debug" in "?debug&other=value")
-            remaining_params.push(param);
+    let satisfiable = match range::resolve(requested, len) {
+        Ranged::Full => return response,
+        Ranged::Partial(range) => Some(range),
+        Ranged::Unsatisfiable => None,
+    };
+    Metrics::incr(&state.metrics.range_responses);
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = match satisfiable {
+        Some(range) => {
+            let content_range = range::content_range(&range, len);
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            parts.headers.insert(
+                header::CONTENT_RANGE,
+                header::HeaderValue::from_str(&content_range).expect("Content-Range is a valid header value"),
+            );
+            // The body is already in memory, collecting it only unwraps it
+            let body = axum::body::to_bytes(body, usize::MAX).await.unwrap_or_default();
+            body.slice(range.start as usize..range.end as usize)
+        }
+        None => {
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.insert(
+                header::CONTENT_RANGE,
+                header::HeaderValue::from_str(&format!("bytes */{}", len)).expect("Content-Range is a valid header value"),
+            );
+            parts.headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+            Bytes::new()
         }
+    };
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Name the served format's extension in Content-Disposition when the path's extension names another
+///
+/// Only with `extension_consistency = "rewrite_disposition"`, so saved files open with the right decoder.
+fn add_content_disposition(state: &AppState, path: &str, mut response: Response) -> Response {
+    if state.config.server.extension_consistency != ExtensionConsistency::RewriteDisposition
+        || !response.status().is_success()
+    {
+        return response;
     }
-    
-    (format_value, remaining_params.join("&"))
+    let Some(extension) = upstream::path_extension(path) else {
+        return response;
+    };
+    let served = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(format_from_content_type);
+    let served_extension = match (format_from_extension(extension), served) {
+        (Some(named), Some(served)) if named != served => format_extension(served),
+        _ => None,
+    };
+    let Some(served_extension) = served_extension else {
+        return response;
+    };
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let stem = &name[..name.len() - extension.len() - 1];
+    if stem.contains(['"', '\\']) {
+        return response;
+    }
+    if let Ok(value) = header::HeaderValue::from_str(&format!("inline; filename=\"{}.{}\"", stem, served_extension)) {
+        response.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+    }
+    response
 }
 
-/// Determine if image conversion is needed
-fn should_convert_image(
-    content_type: &str,
-    upstream_format: Option<OutputFormat>,
-    desired_format: OutputFormat,
-    content_size: usize,
-    max_size: usize,
-) -> bool {
-    // Must be an image
-    if !is_image_content_type(content_type) {
-        return false;
+/// Add the headers read by the configured CDN to a proxied response
+///
+/// The Cache-Control chosen by the proxy moves to CDN-Cache-Control, browsers get
+/// at most `cdn.browser_ttl`. Behind Cloudflare the response is also tagged with
+/// its path hash and upstream domain, so it can be purged at the CDN too.
+fn add_cdn_headers(state: &AppState, path: &str, mut response: Response) -> Response {
+    let mode = state.config.server.cdn_mode();
+    if !mode.emits_cdn_cache_control() {
+        return response;
     }
     
-    // Must not be requesting original format
-    if desired_format == OutputFormat::Original {
-        return false;
+    let headers = response.headers_mut();
+    if let Some(cache_control) = headers.get(header::CACHE_CONTROL).cloned() {
+        if let Some(browser) = browser_cache_control(&cache_control, state.config.server.cdn.browser_ttl) {
+            headers.insert(header::CACHE_CONTROL, browser);
+        }
+        headers.insert(CDN_CACHE_CONTROL, cache_control);
     }
     
-    // Must be within size limits
-    if content_size > max_size {
-        return false;
+    if mode == CdnMode::Cloudflare {
+        let mut tags = vec![format!("path:{}", path_hash(path))];
+        tags.extend(upstream_host(state, path).map(|host| format!("domain:{}", host)));
+        add_cache_tags(&mut response, &tags);
+    }
+    response
+}
+
+/// Add tags to the response's Cache-Tag header, keeping those already listed
+fn add_cache_tags(response: &mut Response, tags: &[String]) {
+    let headers = response.headers_mut();
+    let mut merged: Vec<String> = headers
+        .get(CACHE_TAG)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect())
+        .unwrap_or_default();
+    for tag in tags {
+        if !merged.contains(tag) {
+            merged.push(tag.clone());
+        }
+    }
+    if let Ok(value) = header::HeaderValue::from_str(&merged.join(",")) {
+        headers.insert(CACHE_TAG, value);
+    }
+}
+
+/// Tags a cache entry is stored with, so it can be purged along with others like it
+///
+/// Every entry is tagged with the upstream domain; successful responses also with
+/// the class of their content type and whether they were converted.
+fn entry_tags(state: &AppState, path: &str, content_type: Option<&str>, converted: bool) -> Vec<String> {
+    let mut tags: Vec<String> = upstream_host(state, path)
+        .map(|host| format!("domain:{}", host))
+        .into_iter()
+        .collect();
+    if let Some(content_type) = content_type {
+        let class = match content_type.split('/').next().unwrap_or_default() {
+            class @ ("image" | "video" | "audio" | "text") => class,
+            _ => "other",
+        };
+        tags.push(format!("type:{}", class));
+        tags.push(if converted { "converted" } else { "original" }.to_string());
+    }
+    tags
+}
+
+/// Upstream response headers as replayed to clients
+///
+/// Authorization is dropped here as well as when responses are built, so an
+/// upstream echoing our credentials back never gets them into a cache entry.
+fn preserved_headers(state: &AppState, upstream_headers: &HeaderMap) -> HeaderMap {
+    let mut headers = upstream_headers.clone();
+    headers.remove(header::AUTHORIZATION);
+    if state.config.server.ignore_request_cookies {
+        strip_vary_cookie(&mut headers);
+    }
+    headers
+}
+
+/// Normalized Content-Type upstream declared, `None` if missing, empty or invalid
+///
+/// A broken upstream may send several; the first one wins and the conflict is logged.
+/// The copy of upstream headers never includes any of them.
+fn upstream_content_type(path: &str, headers: &HeaderMap) -> Option<String> {
+    let mut values = headers.get_all(header::CONTENT_TYPE).iter();
+    let first = values.next()?;
+    let ignored = values.count();
+    if ignored > 0 {
+        warn!("Upstream sent {} Content-Type headers for {}, using the first: {:?}", ignored + 1, path, first);
+    }
+    first.to_str().ok().map(normalize_content_type).filter(|content_type| !content_type.is_empty())
+}
+
+/// Content type of the first `server.content_type_overrides` rule matching the
+/// response whose target the body's signature agrees with, or the upstream one
+fn override_content_type(state: &AppState, path: &str, content_type: String, body: &[u8]) -> String {
+    let rules = &state.config.server.content_type_overrides;
+    if rules.is_empty() {
+        return content_type;
+    }
+    
+    let essence = |content_type: &str| content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let upstream_type = essence(&content_type);
+    let extension = upstream::path_extension(path);
+    let mut refused = false;
+    for rule in rules {
+        let type_matches = rule.match_content_type.as_deref().is_none_or(|t| essence(t) == upstream_type);
+        let extension_matches = rule.match_extension.as_deref().is_none_or(|wanted| {
+            extension.is_some_and(|extension| extension.eq_ignore_ascii_case(wanted.trim_start_matches('.')))
+        });
+        if !(type_matches && extension_matches) {
+            continue;
+        }
+        
+        if body_is_image_type(&rule.override_to, body) {
+            debug!("Overriding content type {} of {} with {}", content_type, path, rule.override_to);
+            Metrics::incr(&state.metrics.content_type_overrides);
+            return normalize_content_type(&rule.override_to);
+        }
+        debug!("Not overriding content type {} of {} with {}, the body disagrees", content_type, path, rule.override_to);
+        refused = true;
+    }
+    if refused {
+        Metrics::incr(&state.metrics.content_type_overrides_refused);
+    }
+    content_type
+}
+
+/// Browser Cache-Control capped at `browser_ttl`, if it differs from the CDN's
+fn browser_cache_control(cache_control: &header::HeaderValue, browser_ttl: Option<u64>) -> Option<header::HeaderValue> {
+    let browser_ttl = browser_ttl?;
+    let max_age = cache_control
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|directive| directive.trim().strip_prefix("max-age="))
+        .and_then(|max_age| max_age.parse::<u64>().ok())?;
+    let value = format!("public, max-age={}", max_age.min(browser_ttl));
+    header::HeaderValue::from_str(&value).ok()
+}
+
+/// Whether the upstream body differs from the one an entry was made from
+///
+/// Entries stored before their source digest was recorded fall back to comparing ETags.
+fn source_changed(meta: &CachedMeta, etag: Option<&str>, digest: &BodyDigest) -> bool {
+    match (&meta.source_digest, &meta.etag, etag) {
+        (Some(source), _, _) => *source != digest.to_hex(),
+        (None, Some(old), Some(new)) => old != new,
+        _ => false,
+    }
+}
+
+/// Whether `Cache-Control` forbids changing the representation
+fn has_no_transform(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+/// Append `detail` to the X-Cache-Status of a response, as in `MISS; no-transform`
+fn add_cache_status_detail(response: &mut Response, detail: &str) {
+    let Some(status) = response.headers().get(X_CACHE_STATUS).and_then(|v| v.to_str().ok()) else {
+        return;
+    };
+    if let Ok(value) = header::HeaderValue::from_str(&format!("{}; {}", status, detail)) {
+        response.headers_mut().insert(X_CACHE_STATUS, value);
+    }
+}
+
+/// Mark a response to a request with `Cache-Control: no-transform`
+fn note_no_transform(mut response: Response, plan: &RequestPlan) -> Response {
+    if plan.no_transform {
+        add_cache_status_detail(&mut response, NO_TRANSFORM_DETAIL);
+    }
+    response
+}
+
+/// Host a path is fetched from
+fn upstream_host(state: &AppState, path: &str) -> Option<String> {
+    url::Url::parse(&state.upstream_url(path, "")).ok()?.host_str().map(str::to_string)
+}
+
+async fn handle_proxy_request(
+    state: &AppState,
+    uri: &Uri,
+    headers: &HeaderMap,
+    cancel: &CancellationToken,
+    timings: &RequestTimings,
+) -> Result<Response, ProxyError> {
+    let path = uri.path();
+    sampled_debug!("Proxying request: {} {}", path, uri.query().unwrap_or(""));
+    
+    let plan = plan_request(state, uri, headers).inspect_err(|e| {
+        if matches!(e, ProxyError::PathNotAllowed) {
+            warn!("Path not allowed: {}", path);
+        }
+    })?;
+    let bypass_cache = plan.bypass_cache;
+    let path = plan.path.as_str();
+    
+    // Check cache first, negative entries apply to every format of an upstream URL
+    let lookup = if bypass_cache {
+        debug!("Bypassing cache for {}", path);
+        None
+    } else {
+        match state.cache.lookup(&plan.negative_key).await {
+            Some(lookup) => Some((&plan.negative_key, lookup)),
+            None => state.cache.lookup(&plan.cache_key).await.map(|lookup| (&plan.cache_key, lookup)),
+        }
+    };
+    
+    // Only one request refreshes an expired entry or fetches a missing one, the others follow it
+    let mut stale = None;
+    let refresh_guard = match lookup {
+        Some((_, lookup)) if lookup.fresh => {
+            sampled_debug!("Cache hit for {}", path);
+            return Ok(note_no_transform(cached_response(state, &lookup.response, CacheStatus::Hit), &plan));
+        }
+        Some((key, lookup)) => match state.refreshes.join(key) {
+            RefreshRole::Leader(guard) => {
+                debug!("Refreshing expired cache entry for {}", path);
+                Metrics::incr(&state.metrics.refresh_leaders);
+                if serves_stale_while_revalidating(state, &lookup) {
+                    let response = note_no_transform(cached_response(state, &lookup.response, CacheStatus::Stale), &plan);
+                    refresh_in_background(state, plan, lookup.response, guard);
+                    return Ok(response);
+                }
+                stale = Some(lookup.response);
+                Some(guard)
+            }
+            RefreshRole::Follower(mut done) => {
+                Metrics::incr(&state.metrics.refresh_followers);
+                if state.config.cache.serve_stale_during_refresh || serves_stale_while_revalidating(state, &lookup) {
+                    sampled_debug!("Serving stale entry for {} during refresh", path);
+                    return Ok(note_no_transform(cached_response(state, &lookup.response, CacheStatus::Stale), &plan));
+                }
+                
+                // Fails once the leader is done, however it ended
+                let _ = done.changed().await;
+                let outcome = done.borrow().clone();
+                if let Some(Ok(shared)) = outcome {
+                    return Ok(note_no_transform(cached_response(state, &shared.entry, shared.status), &plan));
+                }
+                if let Some(cached) = state.cache.get(key).await {
+                    return Ok(note_no_transform(cached_response(state, &cached, CacheStatus::Hit), &plan));
+                }
+                None
+            }
+        },
+        None if bypass_cache => None,
+        None => match state.refreshes.join(&plan.cache_key) {
+            RefreshRole::Leader(guard) => Some(guard),
+            RefreshRole::Follower(mut done) => {
+                sampled_debug!("Waiting for the fetch of {} in flight", path);
+                Metrics::incr(&state.metrics.coalesced_misses);
+                let _ = done.changed().await;
+                let outcome = done.borrow().clone();
+                match outcome {
+                    Some(Ok(shared)) => {
+                        return Ok(note_no_transform(cached_response(state, &shared.entry, shared.status), &plan));
+                    }
+                    Some(Err(failure)) => return Err(ProxyError::Coalesced(failure)),
+                    // Streamed and partial responses can't be shared, so this one is fetched again
+                    None => None,
+                }
+            }
+        },
+    };
+    
+    // During maintenance upstream is left alone, expired entries are still better than nothing
+    if let Some(remaining) = state.runtime_states.remaining(RuntimeState::Maintenance) {
+        if let Some(stale) = &stale {
+            return Ok(note_no_transform(cached_response(state, stale, CacheStatus::Stale), &plan));
+        }
+        return Err(ProxyError::Maintenance { retry_after: (remaining != Duration::MAX).then(|| remaining.as_secs() + 1) });
+    }
+    
+    let result = fetch_from_upstream(state, plan, headers, stale, cancel, timings).await;
+    share_outcome(refresh_guard.as_ref(), result)
+}
+
+/// What a leader hands the requests that waited for it: the entry it served, or its failure
+type FetchOutcome = Result<SharedEntry, Arc<ProxyError>>;
+
+/// Entry a fetch was served from, whether or not it was stored, for the requests that waited on it
+///
+/// Attached to the response by [`fetch_from_upstream`] when its body was buffered, so
+/// entries left out of the cache by admission, size or shedding are shared as well.
+#[derive(Clone)]
+struct SharedEntry {
+    entry: Arc<CachedResponse>,
+    status: CacheStatus,
+}
+
+/// Hand the result of a fetch to the requests following `guard`
+fn share_outcome(
+    guard: Option<&RefreshGuard<FetchOutcome>>,
+    result: Result<Response, ProxyError>,
+) -> Result<Response, ProxyError> {
+    match (result, guard) {
+        (Ok(mut response), guard) => {
+            let shared = response.extensions_mut().remove::<SharedEntry>();
+            if let (Some(guard), Some(shared)) = (guard, shared) {
+                guard.finish(Ok(shared));
+            }
+            Ok(response)
+        }
+        // Followers fail the same way instead of fetching again, failures are never cached
+        (Err(e), Some(guard)) => {
+            let e = Arc::new(e);
+            guard.finish(Err(e.clone()));
+            Err(ProxyError::Coalesced(e))
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Whether an expired entry is still within `cache.stale_while_revalidate` of its expiry
+fn serves_stale_while_revalidating(state: &AppState, lookup: &CacheLookup) -> bool {
+    lookup.expired_for < Duration::from_secs(state.config.cache.stale_while_revalidate)
+}
+
+/// Refetch an entry that was just served stale, holding its refresh until done
+///
+/// The client's headers are left out, so a `Range` it sent never narrows the refetch.
+/// Requests arriving meanwhile are served the stale entry as well.
+fn refresh_in_background(
+    state: &AppState,
+    plan: RequestPlan,
+    stale: Arc<CachedResponse>,
+    guard: RefreshGuard<FetchOutcome>,
+) {
+    Metrics::incr(&state.metrics.background_refreshes);
+    let state = state.clone();
+    tokio::spawn(async move {
+        // Upstream is left alone during maintenance, the stale entry is served until it ends
+        if state.runtime_states.remaining(RuntimeState::Maintenance).is_some() {
+            return;
+        }
+        let path = plan.path.clone();
+        let result = fetch_from_upstream(
+            &state,
+            plan,
+            &HeaderMap::new(),
+            Some(stale),
+            &CancellationToken::new(),
+            &RequestTimings::default(),
+        )
+        .await;
+        if let Err(e) = share_outcome(Some(&guard), result) {
+            debug!("Background refresh of {} failed: {}", path, e.describe().2);
+        }
+    });
+}
+
+/// Fetch a missing or expired entry from upstream, convert it and store it
+///
+/// An expired entry is passed as `stale`, to be revalidated with its ETag.
+async fn fetch_from_upstream(
+    state: &AppState,
+    plan: RequestPlan,
+    headers: &HeaderMap,
+    stale: Option<Arc<CachedResponse>>,
+    cancel: &CancellationToken,
+    timings: &RequestTimings,
+) -> Result<Response, ProxyError> {
+    let RequestPlan { bypass_cache, desired_format, .. } = plan;
+    let path = plan.path.as_str();
+    let upstream_url = &plan.upstream_url;
+    sampled_debug!("Cache miss for {}, fetching from upstream: {}", path, upstream_url);
+    
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(header::ACCEPT_ENCODING, upstream::accept_encoding(path));
+    
+    // Revalidate an expired entry instead of downloading it again when upstream gave an ETag
+    if let Some(etag) = stale.as_ref().and_then(|stale| stale.meta.etag.as_deref()) {
+        if let Ok(etag) = header::HeaderValue::from_str(etag) {
+            request_headers.insert(header::IF_NONE_MATCH, etag);
+        }
+    }
+    
+    // Upstream can answer ranges of a body served as it is, a conversion needs all of it
+    if desired_format == OutputFormat::Original {
+        let names = HeaderNameSet::from_iter([header::RANGE, header::IF_RANGE]);
+        request_headers.extend(forward_request_headers(headers, &names));
+    }
+    
+    // Client cookies are never forwarded, whatever headers are added above
+    if state.config.server.ignore_request_cookies {
+        request_headers.remove(header::COOKIE);
+    }
+    strip_hop_by_hop(&mut request_headers);
+    if let Some(auth) = state.upstream_auth(path) {
+        request_headers.insert(header::AUTHORIZATION, auth.clone());
+    }
+    
+    // Fetch from upstream, headers and body within the timeout of the path
+    let timeout = Duration::from_secs(state.config.upstream.timeout_for(path));
+    let fetch_started = Instant::now();
+    let sent = send_upstream(
+        state.fetcher.as_ref(),
+        upstream_url,
+        request_headers.clone(),
+        state.config.upstream.header_timeout.map(Duration::from_secs),
+        timeout,
+    )
+    .await;
+    timings.add_upstream(fetch_started.elapsed());
+    let mut response = sent?;
+    timings.set_upstream_status(response.status.as_u16());
+    let body_idle_timeout = state.config.upstream.body_idle_timeout.map(Duration::from_secs);
+    
+    // Only an answer covering the whole body is cached and converted, slices are passed on
+    if response.status == StatusCode::PARTIAL_CONTENT && range::is_whole_body(&response.headers) {
+        response.status = StatusCode::OK;
+        response.headers.remove(header::CONTENT_RANGE);
+    }
+    if matches!(response.status, StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE)
+        && request_headers.contains_key(header::RANGE)
+    {
+        return Ok(upstream_range_response(state, path, response));
+    }
+    
+    let status = response.status;
+    
+    if let (StatusCode::NOT_MODIFIED, Some(stale)) = (status, &stale) {
+        debug!("Upstream confirmed cached entry for {} is unchanged", path);
+        let mut refreshed = CachedResponse::new(stale.data.clone(), stale.meta.refreshed());
+        // The renewed entry keeps the hits of the one it replaces, this one included
+        refreshed.access = stale.access.clone();
+        let response = cached_response(state, &refreshed, CacheStatus::Hit);
+        let key = if stale.meta.status.is_success() { &plan.cache_key } else { &plan.negative_key };
+        state.cache.put(key.clone(), refreshed).await;
+        return Ok(note_no_transform(response, &plan));
+    }
+    
+    // Handle non-success responses (redirects, errors, etc.)
+    // For non-2xx responses, preserve and forward the response with its status code
+    if !status.is_success() {
+        debug!("Upstream returned non-success status: {}", status);
+        
+        // Preserve upstream headers, within the size cap
+        let mut truncated = Vec::new();
+        let upstream_headers = if state.config.server.preserve_upstream_headers {
+            let max_header_size = state.config.upstream.max_error_header_size as usize;
+            if headers_size(&response.headers) > max_header_size {
+                truncated.push("headers");
+                let mut kept = HeaderMap::new();
+                if let Some(location) = response.headers.get(header::LOCATION) {
+                    kept.insert(header::LOCATION, location.clone());
+                }
+                Some(kept)
+            } else {
+                Some(preserved_headers(state, &response.headers))
+            }
+        } else {
+            None
+        };
+        
+        // Error bodies are easy to provoke, so never buffer more than the cap
+        let max_body_size = state.config.upstream.max_error_body_size as usize;
+        let read_started = Instant::now();
+        let read = read_body_limited(response, body_idle_timeout, timeout, max_body_size, None).await;
+        timings.add_upstream(read_started.elapsed());
+        let (body_bytes, digest) = match read? {
+            BodyRead::Complete(body, digest) => (body, digest),
+            BodyRead::OverLimit(..) => {
+                truncated.push("body");
+                let body_bytes = Bytes::from_static(ERROR_BODY_TOO_LARGE.as_bytes());
+                let digest = BodyDigest::of(&body_bytes);
+                (body_bytes, digest)
+            }
+        };
+        
+        if !truncated.is_empty() {
+            warn!("Upstream {} response for {} exceeded size caps: {}", status, path, truncated.join(", "));
+            Metrics::incr(&state.metrics.truncated_error_responses);
+            let mut response = build_response_with_status(
+                body_bytes,
+                status,
+                &state.config.server.via_header,
+                upstream_headers.as_ref(),
+                "no-store",
+            );
+            response.headers_mut().insert(
+                X_AKKOPROXY_TRUNCATED,
+                header::HeaderValue::from_str(&truncated.join(", ")).expect("Truncation note is a valid header value"),
+            );
+            return Ok(response);
+        }
+        
+        // Cache the response only if the status policy allows it
+        let ttl = status_ttl(status, &state.config.cache.status_policy);
+        let tags = entry_tags(state, path, None, false);
+        let entry = CachedResponse::new(
+            body_bytes.clone(),
+            CachedMeta::new(String::new(), status)
+                .with_ttl(ttl)
+                .with_headers(upstream_headers.clone())
+                .with_digest(digest)
+                .with_tags(tags.clone()),
+        );
+        if ttl.is_some()
+            && !bypass_cache
+            && body_bytes.len() <= state.config.cache.max_item_size as usize
+            && admit(state, &plan.negative_key, stale.is_some()).await
+        {
+            state.cache.put(plan.negative_key, entry.clone()).await;
+            debug!("Cached {} response for {} for {:?}", status, path, ttl);
+        }
+        
+        // Build response with the actual status code from upstream
+        let mut response = build_response_with_status(
+            body_bytes,
+            status,
+            &state.config.server.via_header,
+            upstream_headers.as_ref(),
+            &status_cache_control(ttl),
+        );
+        if state.config.cache.emit_cache_tags && ttl.is_some() && !bypass_cache {
+            add_cache_tags(&mut response, &tags);
+        }
+        response.extensions_mut().insert(SharedEntry { entry: Arc::new(entry), status: CacheStatus::Hit });
+        return Ok(response);
+    }
+    
+    // The expired failure this replaces would otherwise be found before the new entry
+    if stale.as_ref().is_some_and(|stale| !stale.meta.status.is_success()) {
+        state.cache.invalidate(&plan.negative_key).await;
+    }
+    
+    // Preserve upstream headers if configured (for success responses)
+    let upstream_headers = if state.config.server.preserve_upstream_headers {
+        Some(preserved_headers(state, &response.headers))
+    } else {
+        None
+    };
+    // Otherwise players still need a few of them to seek in passed-through media
+    let media_headers = match upstream_headers {
+        Some(_) => None,
+        None => Some(select_headers(&response.headers, &state.forwarded_headers)),
+    };
+    let upstream_etag = response
+        .headers
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let upstream_no_transform = !state.config.server.ignore_no_transform && has_no_transform(&response.headers);
+    
+    let declared_type = upstream_content_type(path, &response.headers);
+    
+    // Bodies too large to cache or convert are passed on as they arrive instead of buffered
+    let buffer_limit = body_buffer_limit(state, declared_type.as_deref());
+    let content_length = response
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > buffer_limit as u64) {
+        let content_type = declared_type.unwrap_or_else(|| "application/octet-stream".to_string());
+        let served_headers = upstream_headers.or(media_headers);
+        return Ok(stream_response(state, path, &content_type, Bytes::new(), response.body, served_headers.as_ref(), content_length));
+    }
+    
+    let mut retry = BodyRetry::new(
+        state.fetcher.clone(),
+        upstream_url,
+        request_headers,
+        &response,
+        state.config.upstream.body_retries_for(path),
+        state.metrics.clone(),
+    );
+    let read_started = Instant::now();
+    let read = read_body_limited(response, body_idle_timeout, timeout, buffer_limit, Some(&mut retry)).await;
+    timings.add_upstream(read_started.elapsed());
+    let (body_bytes, upstream_digest) = match read? {
+        BodyRead::Complete(body, digest) => (body, digest),
+        BodyRead::OverLimit(read, rest) => {
+            let content_type = declared_or_sniffed_type(path, declared_type, &read);
+            let content_type = override_content_type(state, path, content_type, &read);
+            let served_headers = upstream_headers.or(media_headers);
+            return Ok(stream_response(state, path, &content_type, read, rest, served_headers.as_ref(), content_length));
+        }
+    };
+    let content_type = declared_or_sniffed_type(path, declared_type, &body_bytes);
+    let original_size = body_bytes.len();
+    timings.set_upstream_bytes(original_size as u64);
+    state.metrics.formats.record_source(&content_type, original_size);
+    let content_type = override_content_type(state, path, content_type, &body_bytes);
+    
+    // Never cache empty bodies or error pages served under an image content type
+    if body_bytes.is_empty()
+        || (is_image_content_type(&content_type) && !body_matches_image_type(&content_type, &body_bytes))
+    {
+        warn!("Upstream returned an empty or mislabeled {} body for {}, not caching", content_type, path);
+        Metrics::incr(&state.metrics.mislabeled_upstream);
+        return Ok(build_response(
+            body_bytes,
+            &content_type,
+            &state.config.server.via_header,
+            upstream_headers.as_ref(),
+            CacheStatus::Bypass,
+            Some((&upstream_digest, SystemTime::now())),
+        ));
+    }
+    
+    // A replaced source leaves the other formats converted from the old one behind
+    if let Some(stale) = stale.as_ref().filter(|stale| stale.meta.status.is_success()) {
+        if source_changed(&stale.meta, upstream_etag.as_deref(), &upstream_digest) {
+            let removed = state
+                .cache
+                .invalidate_other_variants(&plan.cache_key, &upstream_digest.to_hex())
+                .await;
+            state.memos.forget_path(path);
+            if removed > 0 {
+                info!("Upstream source of {} changed, removed {} stale variants", path, removed);
+                state.metrics.variant_invalidations.fetch_add(removed as u64, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+    }
+    
+    let animated = is_image_content_type(&content_type) && is_animated(&body_bytes);
+    // JPEG has no alpha channel, so a transparent source demanded as JPEG is served as PNG
+    let keeps_alpha = desired_format == OutputFormat::Jpeg
+        && is_image_content_type(&content_type)
+        && has_alpha(&body_bytes);
+    let desired_format = if keeps_alpha { OutputFormat::Png } else { desired_format };
+    let decision = match decide_conversion(state, &plan, desired_format, &content_type, body_bytes.len(), animated) {
+        // Upstream forbids changing the image, so a cached copy never becomes a conversion
+        ConversionDecision::Convert | ConversionDecision::Skip(SkipReason::Shed) if upstream_no_transform => {
+            ConversionDecision::Skip(SkipReason::NoTransform)
+        }
+        decision => decision,
+    };
+    let no_transform = decision == ConversionDecision::Skip(SkipReason::NoTransform);
+    if let ConversionDecision::Skip(reason) = decision {
+        state.metrics.record_conversion_skipped(reason);
+        if reason == SkipReason::TooLarge {
+            log_too_large(state, path, body_bytes.len(), state.config.max_convert_input_size() as usize).await;
+        }
+    }
+    let needs_conversion = decision == ConversionDecision::Convert;
+    // Shed responses are not cached, so the variant is converted once the load has passed
+    let shed = decision == ConversionDecision::Skip(SkipReason::Shed);
+    
+    // A first frame requested without a negotiated format is served as PNG
+    let target_format = if animated && desired_format == OutputFormat::Original {
+        OutputFormat::Png
+    } else {
+        desired_format
+    };
+    
+    let siblings = if needs_conversion {
+        sibling_formats(state, &plan, &content_type, body_bytes.len(), target_format)
+    } else {
+        Vec::new()
+    };
+    let source = body_bytes.clone();
+    
+    // A source that failed to decode may have been damaged in transit, so it is fetched again next time
+    let mut corrupt = false;
+    // Siblings are converted from the decoded source instead of decoding it again
+    let mut decoded = None;
+    let (final_data, final_content_type, digest, converted) = if needs_conversion {
+        sampled_debug!("Converting image to {:?}", target_format);
+        
+        let convert_started = Instant::now();
+        let result = convert_within_budget(state, path, body_bytes.clone(), target_format, !siblings.is_empty(), cancel).await;
+        timings.add_convert(convert_started.elapsed());
+        match result {
+            Ok((Converted { data, mime_type, digest: Some(digest), .. }, kept)) => {
+                info!("Successfully converted image: {} bytes -> {} bytes", body_bytes.len(), data.len());
+                audit_quality(state, path, body_bytes.clone(), data.clone(), mime_type);
+                decoded = kept;
+                (data, mime_type.to_string(), digest, true)
+            }
+            // The converter handed the source back untouched
+            Ok((Converted { digest: None, .. }, _)) => (body_bytes, content_type, upstream_digest, false),
+            Err(ConvertError::Image(ImageError::DecodeLimitsExceeded(variant_error)))
+                if state.config.server.strict_variant_errors =>
+            {
+                warn!("Rejecting variant of {}: {}", path, variant_error);
+                return Err(ProxyError::VariantUnavailable(variant_error));
+            }
+            Err(e) => {
+                match &e {
+                    ConvertError::Image(ImageError::DecodeUnsupported(_)) => {
+                        state.memos.remember(path, DecisionMemo::Undecodable);
+                    }
+                    ConvertError::Image(ImageError::DecodeCorrupt(_)) => corrupt = true,
+                    _ => {}
+                }
+                warn!("Failed to convert image: {}, returning original", e);
+                (body_bytes, content_type, upstream_digest, false)
+            }
+        }
+    } else {
+        sampled_debug!("Not converting {} ({}, {} bytes): {:?}", path, content_type, body_bytes.len(), decision);
+        (body_bytes, content_type, upstream_digest, false)
+    };
+    let tags = entry_tags(state, path, Some(&final_content_type), converted);
+    // Converting invalidates the media headers, so only passed-through bodies get them
+    let mut served_headers = if converted { upstream_headers.clone() } else { upstream_headers.clone().or(media_headers) };
+    if converted {
+        replace_etag(served_headers.as_mut(), &digest.to_hex());
+    }
+    if keeps_alpha && final_content_type == "image/png" {
+        served_headers.get_or_insert_with(HeaderMap::new).insert(
+            header::WARNING,
+            header::HeaderValue::from_static(ALPHA_KEPT_WARNING),
+        );
+    }
+    
+    let entry = CachedResponse::new(
+        final_data.clone(),
+        CachedMeta::new(final_content_type.clone(), StatusCode::OK)
+            .with_headers(served_headers.clone())
+            .with_original_size(original_size)
+            .with_etag(upstream_etag.clone())
+            .with_digest(digest)
+            .with_source_digest(upstream_digest)
+            .with_tags(tags.clone()),
+    );
+    // Requests waiting for this one get the entry even if it is not cached
+    let shared_status = if shed { CacheStatus::Shed } else { CacheStatus::Hit };
+    
+    // Cache the response, or adopt the entry a concurrent miss stored first
+    let mut adopted = None;
+    if bypass_cache {
+        debug!("Not caching response for {}: cache bypassed", path);
+    } else if shed {
+        sampled_debug!("Not caching response for {}: conversions shed", path);
+    } else if corrupt {
+        debug!("Not caching response for {}: source failed to decode", path);
+    } else if final_data.len() > state.config.cache.max_item_size as usize {
+        debug!("Response too large to cache: {} bytes", final_data.len());
+        if converted {
+            // Serving the original next time beats converting again without caching the result
+            state.memos.remember(path, DecisionMemo::Uncacheable(target_format));
+        }
+    } else if !admit(state, &plan.cache_key, stale.is_some()).await {
+        sampled_debug!("Not caching response for {}: first miss under the admission policy", path);
+    } else {
+        match state.cache.put_if_absent(plan.cache_key.clone(), entry.clone()).await {
+            PutOutcome::Inserted => sampled_debug!("Cached response for {}", path),
+            PutOutcome::Existing(entry) => {
+                debug!("Serving the entry for {} a concurrent request stored first", path);
+                adopted = Some(entry);
+            }
+        }
+        
+        if converted && adopted.is_none() {
+            for format in siblings {
+                let meta = CachedMeta::new(String::new(), StatusCode::OK)
+                    .with_headers(upstream_headers.clone())
+                    .with_original_size(original_size)
+                    .with_etag(upstream_etag.clone())
+                    .with_source_digest(upstream_digest);
+                let key = plan.cache_key.with_format(format!("{:?}", format));
+                prewarm(state, key, source.clone(), decoded.clone(), format, meta);
+            }
+        }
+    }
+    
+    if let Some(entry) = adopted {
+        record_served(state, &entry.meta.content_type, entry.data.len(), false, entry.meta.original_size);
+        let mut response = assemble_response(
+            entry.data.clone(),
+            StatusCode::OK,
+            entry.headers.clone(),
+            state.via_header.clone(),
+            CacheStatus::Miss.cache_control(),
+            Some(CacheStatus::Miss),
+        );
+        if state.config.cache.emit_cache_tags {
+            add_cache_tags(&mut response, &entry.meta.tags);
+        }
+        if converted {
+            response.extensions_mut().insert(RequestFlow::MissConvert);
+        }
+        if no_transform {
+            add_cache_status_detail(&mut response, NO_TRANSFORM_DETAIL);
+        }
+        response.extensions_mut().insert(SharedEntry { entry, status: shared_status });
+        return Ok(response);
+    }
+    
+    record_served(state, &final_content_type, final_data.len(), false, Some(original_size as u64));
+    let mut response = build_response(
+        final_data, 
+        &final_content_type, 
+        &state.config.server.via_header, 
+        served_headers.as_ref(),
+        if bypass_cache {
+            CacheStatus::Bypass
+        } else if shed {
+            CacheStatus::Shed
+        } else {
+            CacheStatus::Miss
+        },
+        // The same validators the stored entry is served with
+        Some((&digest, entry.meta.last_modified.unwrap_or(entry.meta.inserted_at))),
+    );
+    if state.config.cache.emit_cache_tags && !bypass_cache && !shed {
+        add_cache_tags(&mut response, &tags);
+    }
+    if converted {
+        response.extensions_mut().insert(RequestFlow::MissConvert);
+    }
+    if no_transform {
+        add_cache_status_detail(&mut response, NO_TRANSFORM_DETAIL);
+    }
+    response.extensions_mut().insert(SharedEntry { entry: Arc::new(entry), status: shared_status });
+    Ok(response)
+}
+
+/// Enabled conversion formats other than `target` that an image would also be converted to
+///
+/// Empty unless sibling prewarming is on and `target` is itself one of them.
+fn sibling_formats(
+    state: &AppState,
+    plan: &RequestPlan,
+    content_type: &str,
+    size: usize,
+    target: OutputFormat,
+) -> Vec<OutputFormat> {
+    let (image, _) = state.image_settings(plan.cache_key.base_path());
+    let convertible = [(OutputFormat::Avif, image.enable_avif), (OutputFormat::WebP, image.enable_webp)];
+    if !image.prewarm_sibling_formats
+        || plan.static_frame
+        || !convertible.iter().any(|(format, enabled)| *enabled && *format == target)
+    {
+        return Vec::new();
+    }
+    
+    convertible
+        .into_iter()
+        .filter(|(format, enabled)| *enabled && *format != target)
+        .map(|(format, _)| format)
+        .filter(|format| {
+            should_convert_image(
+                content_type,
+                format_from_content_type(content_type),
+                *format,
+                size,
+                image.min_convert_size as usize,
+                state.config.max_convert_input_size() as usize,
+            )
+            .is_ok()
+                && output_may_be_cacheable(state, plan.cache_key.base_path(), *format, size)
+        })
+        .collect()
+}
+
+/// Convert an already fetched original to `format` in the background and cache it under `key`
+///
+/// Runs on the same conversion thread budget as requests, encoding `decoded`, the
+/// source decoded by the request's conversion, when given. Dropped when the
+/// prewarm queue is full or memory is short, so background work never piles up under load.
+fn prewarm(
+    state: &AppState,
+    key: CacheKey,
+    source: Bytes,
+    decoded: Option<Arc<DecodedImage>>,
+    format: OutputFormat,
+    meta: CachedMeta,
+) {
+    if state.memory_watchdog.is_under_pressure() {
+        debug!("Memory short, not converting {} to {:?}", key.base_path(), format);
+        Metrics::incr(&state.metrics.prewarm_dropped);
+        return;
+    }
+    let Ok(slot) = state.prewarm_slots.clone().try_acquire_owned() else {
+        debug!("Prewarm queue full, not converting {} to {:?}", key.base_path(), format);
+        Metrics::incr(&state.metrics.prewarm_dropped);
+        return;
+    };
+    
+    let state = state.clone();
+    tokio::spawn(async move {
+        let _slot = slot;
+        if state.cache.get(&key).await.is_some() {
+            return;
+        }
+        let decode = decoded.map_or(SourceDecode::Once, SourceDecode::Reuse);
+        match convert_image(&state, key.base_path(), source, format, decode, &CancellationToken::new(), None).await {
+            Ok((Converted { data, digest: Some(_), .. }, _)) if data.len() > state.config.cache.max_item_size as usize => {
+                debug!("Not prewarming {:?} variant of {}: {} bytes is too large to cache", format, key.base_path(), data.len());
+                state.memos.remember(key.base_path(), DecisionMemo::Uncacheable(format));
+            }
+            Ok((Converted { data, mime_type, digest: Some(digest), .. }, _)) => {
+                let tags = entry_tags(&state, key.base_path(), Some(mime_type), true);
+                let mut meta = CachedMeta {
+                    content_type: mime_type.to_string(),
+                    ..meta
+                };
+                replace_etag(meta.headers.as_mut(), &digest.to_hex());
+                let meta = meta.with_digest(digest).with_tags(tags);
+                sampled_debug!("Prewarmed {:?} variant of {}", format, key.base_path());
+                // A request may have converted the variant meanwhile, which is kept
+                if let PutOutcome::Inserted = state.cache.put_if_absent(key, CachedResponse::new(data, meta)).await {
+                    Metrics::incr(&state.metrics.prewarmed_variants);
+                }
+            }
+            Ok((Converted { digest: None, .. }, _)) => {
+                debug!("Not prewarming {:?} variant of {}: source left unconverted", format, key.base_path());
+            }
+            Err(e) => debug!("Failed to prewarm {:?} variant of {}: {}", format, key.base_path(), e),
+        }
+    });
+}
+
+/// Score a sample of conversions against their source in the background
+///
+/// Only formats the image crate can decode are audited. Scores go to the quality
+/// histogram; those below `image.quality_audit_threshold` are also logged as warnings.
+/// Nothing is audited while memory is short.
+fn audit_quality(state: &AppState, path: &str, source: Bytes, converted: Bytes, mime_type: &'static str) {
+    static SAMPLED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    
+    if state.memory_watchdog.is_under_pressure() {
+        return;
+    }
+    if !AUDITED_FORMATS.iter().any(|(mime, _)| *mime == mime_type) || !logging::sample_at(state.config.image.quality_audit, &SAMPLED) {
+        return;
+    }
+    
+    let metrics = state.metrics.clone();
+    let threshold = state.config.image.quality_audit_threshold;
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || match quality_score(&source, &converted) {
+        Ok(score) => {
+            metrics.conversion_quality.observe(mime_type, score);
+            if score < threshold {
+                Metrics::incr(&metrics.quality_audit_below_threshold);
+                warn!("Conversion of {} to {} scored {:.1} dB, below the {:.1} dB threshold", path, mime_type, score, threshold);
+            } else {
+                debug!("Conversion of {} to {} scored {:.1} dB", path, mime_type, score);
+            }
+        }
+        Err(e) => debug!("Failed to audit conversion of {} to {}: {:#}", path, mime_type, e),
+    });
+}
+
+/// Check whether a missed response for `key` may be stored, counting the decision
+///
+/// Responses refreshing an expired entry were admitted the first time round.
+async fn admit(state: &AppState, key: &CacheKey, refreshing: bool) -> bool {
+    if !refreshing && !state.admission.admit(key).await {
+        Metrics::incr(&state.metrics.cache_admission_rejected);
+        return false;
+    }
+    Metrics::incr(&state.metrics.cache_admitted);
+    true
+}
+
+/// Count a successful response in the format and public statistics
+fn record_served(state: &AppState, content_type: &str, size: usize, hit: bool, original_size: Option<u64>) {
+    state.metrics.formats.record_served(content_type, size);
+    state.public_stats.record(hit, size, original_size);
+}
+
+/// Response replaying a cache entry from its prebuilt headers, counted as a hit on the entry
+fn cached_response(state: &AppState, cached: &CachedResponse, cache_status: CacheStatus) -> Response {
+    cached.access.record_hit();
+    let mut response = if cached.meta.status.is_success() {
+        record_served(state, &cached.meta.content_type, cached.data.len(), true, cached.meta.original_size);
+        assemble_response(
+            cached.data.clone(),
+            StatusCode::OK,
+            cached.headers.clone(),
+            state.via_header.clone(),
+            cache_status.cache_control(),
+            Some(cache_status),
+        )
+    } else {
+        assemble_response(
+            cached.data.clone(),
+            cached.meta.status,
+            cached.headers.clone(),
+            state.via_header.clone(),
+            header::HeaderValue::from_str(&status_cache_control(cached.meta.ttl()))
+                .expect("Cache-Control directives are valid header values"),
+            None,
+        )
+    };
+    if state.config.cache.emit_cache_tags {
+        add_cache_tags(&mut response, &cached.meta.tags);
+    }
+    response.extensions_mut().insert(RequestFlow::Hit);
+    response
+}
+
+/// Decisions about a proxied request that follow from the request and configuration alone
+#[derive(Debug)]
+struct RequestPlan {
+    /// Request path as fetched and cached, see [`AppState::request_path`]
+    path: String,
+    /// Format forced by the query string, if any
+    format_from_query: Option<OutputFormat>,
+    /// An admin asked for the unconverted original
+    no_convert: bool,
+    bypass_cache: bool,
+    force_original: bool,
+    /// The request forbade changing the representation with `Cache-Control: no-transform`
+    no_transform: bool,
+    /// A static first frame of an animation was requested
+    static_frame: bool,
+    upstream_url: String,
+    desired_format: OutputFormat,
+    /// Name of the User-Agent override that changed the negotiated format
+    ua_override: Option<String>,
+    /// Legacy format the client accepts as much as a demanded JPEG or PNG
+    legacy_alternative: Option<OutputFormat>,
+    /// Format named by the path's extension and kept under `extension_consistency = "no_convert"`
+    extension_format: Option<OutputFormat>,
+    cache_key: CacheKey,
+    negative_key: CacheKey,
+}
+
+/// Work out how a request will be served before anything is fetched
+///
+/// Shared by the proxy handler and the explain endpoint, so it must stay free of side
+/// effects beyond memoizing format negotiation.
+fn plan_request(state: &AppState, uri: &Uri, headers: &HeaderMap) -> Result<RequestPlan, ProxyError> {
+    let config = &state.config;
+    let path = state.request_path(uri.path());
+    let path = path.as_ref();
+    let query = uri.query().unwrap_or("");
+    
+    // Only handle /media and /proxy paths
+    if !path.starts_with("/media") && !path.starts_with("/proxy") {
+        return Err(ProxyError::PathNotAllowed);
+    }
+    
+    // Parse query parameters behind the Cloudflare Free plan
+    // ?format=original is honored in every mode so the unconverted image can be inspected
+    let (format_from_query, upstream_query) = if query.is_empty() {
+        (None, String::new())
+    } else {
+        match parse_query_for_format(query) {
+            (Some(OutputFormat::Original), remaining) => (Some(OutputFormat::Original), remaining),
+            parsed if config.server.cdn_mode() == CdnMode::CloudflareFree => parsed,
+            _ => (None, query.to_string()),
+        }
+    };
+    
+    // Admins may force the unconverted original with a request header
+    let no_convert = headers
+        .get(X_AKKOPROXY_NO_CONVERT)
+        .is_some_and(|v| v.as_bytes() == b"1")
+        && is_admin_request(headers, config.server.admin_token.as_deref());
+    let bypass_cache = no_convert && config.server.no_convert_bypass_cache;
+    let force_original = no_convert || format_from_query == Some(OutputFormat::Original);
+    let no_transform = !config.server.ignore_no_transform && has_no_transform(headers);
+    let (image, _) = state.image_settings(path);
+    
+    // The format parameter is only read behind Cloudflare Free, which keys its cache on it, so
+    // any other format served here would be stored under it for as long as the CDN keeps it
+    let disabled_format = match format_from_query {
+        Some(OutputFormat::Avif) if !image.enable_avif => Some("avif"),
+        Some(OutputFormat::WebP) if !image.enable_webp => Some("webp"),
+        _ => None,
+    };
+    if let Some(format) = disabled_format.filter(|_| !no_convert && !no_transform) {
+        warn!(
+            "Request for {} asks for format={}, which is disabled; fix the Cloudflare Transform Rule adding it",
+            path, format,
+        );
+        let redirect = match config.server.cloudflare_format_mismatch {
+            FormatMismatch::Reject => None,
+            FormatMismatch::Redirect if upstream_query.is_empty() => Some(path.to_string()),
+            FormatMismatch::Redirect => Some(format!("{}?{}", path, upstream_query)),
+        };
+        return Err(ProxyError::FormatDisabled { format, redirect });
+    }
+    
+    // Strip the first-frame marker (?frame=first or ?static=1) from the upstream query
+    let (static_frame, upstream_query) = if upstream_query.is_empty() {
+        (false, upstream_query)
+    } else {
+        parse_query_for_static(&upstream_query)
+    };
+    let static_frame = static_frame && !no_transform;
+    
+    // Build upstream URL (without format query if it was present)
+    let upstream_path = if upstream_query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, upstream_query)
+    };
+    let upstream_url = state.upstream_url(path, &upstream_query);
+    
+    // Determine desired format
+    let mut ua_override = None;
+    let mut legacy_alternative = None;
+    let desired_format = if no_convert || no_transform {
+        OutputFormat::Original
+    } else if let Some(fmt) = format_from_query {
+        // Use format from query parameter if available
+        fmt
+    } else {
+        // Get Accept header to determine desired format; a missing one is read as the
+        // policy says, and the entry is keyed by the format that comes out of it
+        let accept = match (headers.get(header::ACCEPT), image.no_accept_behavior) {
+            (Some(accept), _) => accept.to_str().unwrap_or("*/*"),
+            (None, NoAcceptBehavior::TreatAsAvifCapable) => AVIF_CAPABLE_ACCEPT,
+            (None, _) => "*/*",
+        };
+        
+        let negotiated = match (headers.get(header::ACCEPT), image.no_accept_behavior) {
+            (None, NoAcceptBehavior::DefaultFormat) => {
+                forced_output_format(image, image.no_accept_format).unwrap_or(OutputFormat::Original)
+            }
+            _ => state.accept_formats.negotiate(
+                accept,
+                image.enable_avif,
+                image.enable_webp,
+                image.treat_image_wildcard_as_auto,
+            ),
+        };
+        
+        // Clients known to misstate what they render are overridden by User-Agent. Responses
+        // deliberately carry no Vary: User-Agent, which would split shared caches per client
+        // version; the override list is expected to change rarely.
+        match user_agent_override(image, headers) {
+            Some((rule, format)) if format != negotiated => {
+                ua_override = Some(rule.name.clone());
+                format
+            }
+            _ => {
+                legacy_alternative = legacy_alternative_format(accept, negotiated);
+                negotiated
+            }
+        }
+    };
+    
+    // Clients picking a decoder by extension get the format it names, unless they refuse it
+    let extension_format = match config.server.extension_consistency {
+        ExtensionConsistency::NoConvert => upstream::path_extension(path)
+            .and_then(format_from_extension)
+            .filter(|format| {
+                let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+                !format_media_type(*format).is_some_and(|media_type| accept_refuses(accept, media_type))
+            }),
+        _ => None,
+    };
+    
+    // Generate cache keys, negative entries apply to every format of an upstream URL
+    let mut format_key = format!("{:?}", desired_format);
+    if static_frame {
+        format_key.push_str(":static");
+    }
+    if let Some(rule) = &ua_override {
+        format_key.push_str(":ua=");
+        format_key.push_str(rule);
+    }
+    if let Some(alternative) = legacy_alternative {
+        format_key.push_str(&format!(":or={:?}", alternative));
+    }
+    if config.upstream.route_for(path).is_some_and(|(_, route)| route.image.is_some()) {
+        format_key.push_str(&format!(":q={},max={}", image.quality, image.max_dimension));
+    }
+    let cache_key = CacheKey::new(
+        format!("{}{}", path, if query.is_empty() { String::new() } else { format!("?{}", query) }),
+        format_key,
+    );
+    let negative_key = CacheKey::negative(upstream_path);
+    
+    Ok(RequestPlan {
+        path: path.to_string(),
+        format_from_query,
+        no_convert,
+        bypass_cache,
+        force_original,
+        no_transform,
+        static_frame,
+        upstream_url,
+        desired_format,
+        ua_override,
+        legacy_alternative,
+        extension_format,
+        cache_key,
+        negative_key,
+    })
+}
+
+/// First User-Agent override matching the request and the format it forces
+///
+/// A matching rule forcing a disabled format stops the search and forces nothing.
+fn user_agent_override<'a>(image: &'a ImageConfig, headers: &HeaderMap) -> Option<(&'a UaOverride, OutputFormat)> {
+    if image.ua_overrides.is_empty() {
+        return None;
+    }
+    let user_agent = headers.get(header::USER_AGENT)?.as_bytes();
+    let rule = image
+        .ua_overrides
+        .iter()
+        .find(|rule| contains_ignore_ascii_case(user_agent, rule.pattern.as_bytes()))?;
+    Some((rule, forced_output_format(image, rule.format)?))
+}
+
+/// Output format a configured format stands for, `None` if it is disabled
+fn forced_output_format(image: &ImageConfig, format: ForcedFormat) -> Option<OutputFormat> {
+    match format {
+        ForcedFormat::Avif => image.enable_avif.then_some(OutputFormat::Avif),
+        ForcedFormat::Webp => image.enable_webp.then_some(OutputFormat::WebP),
+        ForcedFormat::Jpeg => Some(OutputFormat::Jpeg),
+        ForcedFormat::Png => Some(OutputFormat::Png),
+        ForcedFormat::Original => Some(OutputFormat::Original),
+    }
+}
+
+fn contains_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window.eq_ignore_ascii_case(needle))
+}
+
+/// Whether an upstream body is converted, and why not otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConversionDecision {
+    Convert,
+    /// The unconverted original was explicitly requested
+    OriginalRequested,
+    /// Animations are only converted when a static first frame was requested,
+    /// since the encoders would otherwise silently drop every other frame
+    Animated,
+    Skip(SkipReason),
+}
+
+impl ConversionDecision {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConversionDecision::Convert => "convert",
+            ConversionDecision::OriginalRequested => "original_requested",
+            ConversionDecision::Animated => "animated",
+            ConversionDecision::Skip(reason) => reason.as_str(),
+        }
+    }
+}
+
+/// Decide whether to convert an upstream body of `size` bytes
+fn decide_conversion(
+    state: &AppState,
+    plan: &RequestPlan,
+    desired_format: OutputFormat,
+    content_type: &str,
+    size: usize,
+    animated: bool,
+) -> ConversionDecision {
+    let max_convert_input_size = state.config.max_convert_input_size() as usize;
+    
+    if plan.force_original {
+        return ConversionDecision::OriginalRequested;
+    }
+    if plan.no_transform && is_image_content_type(content_type) {
+        return ConversionDecision::Skip(SkipReason::NoTransform);
+    }
+    let extension_mismatch = plan
+        .extension_format
+        .is_some_and(|format| desired_format != format && desired_format != OutputFormat::Original);
+    if extension_mismatch && is_image_content_type(content_type) {
+        return ConversionDecision::Skip(SkipReason::ExtensionMismatch);
+    }
+    if animated {
+        return match plan.static_frame {
+            false => ConversionDecision::Animated,
+            true if size > max_convert_input_size => ConversionDecision::Skip(SkipReason::TooLarge),
+            true => convert_unless_shed(state),
+        };
+    }
+    
+    // A source in the other legacy format the client accepts as much needs no lossy re-encode
+    let upstream_format = format_from_content_type(content_type);
+    let desired_format = match upstream_format {
+        Some(format) if plan.legacy_alternative == Some(format) => format,
+        _ => desired_format,
+    };
+    
+    // Skip conversion if upstream format already satisfies the desired format
+    let result = should_convert_image(
+        content_type,
+        upstream_format,
+        desired_format,
+        size,
+        state.config.image.min_convert_size as usize,
+        max_convert_input_size,
+    );
+    match result {
+        Ok(()) if state.image_settings(plan.cache_key.base_path()).1.encode_settings(size).is_none() => {
+            ConversionDecision::Skip(SkipReason::TierDisabled)
+        }
+        Ok(()) if state.memos.contains(plan.cache_key.base_path(), DecisionMemo::Undecodable) => {
+            ConversionDecision::Skip(SkipReason::Undecodable)
+        }
+        Ok(()) if !output_may_be_cacheable(state, plan.cache_key.base_path(), desired_format, size) => {
+            ConversionDecision::Skip(SkipReason::Uncacheable)
+        }
+        Ok(()) => convert_unless_shed(state),
+        Err(reason) => ConversionDecision::Skip(reason),
+    }
+}
+
+/// Whether converting a source of `size` bytes to `format` could give a body `cache.max_item_size` admits
+///
+/// A lossless encode of a source over the limit is assumed to stay over it, and a
+/// conversion that already came out too large is not repeated for an hour. The
+/// original is served instead, which is cached when it fits.
+fn output_may_be_cacheable(state: &AppState, path: &str, format: OutputFormat, size: usize) -> bool {
+    if state.memos.contains(path, DecisionMemo::Uncacheable(format)) {
+        return false;
+    }
+    !format.is_lossless() || size as u64 <= state.config.cache.max_item_size
+}
+
+/// Convert, unless conversions are shed while the host is overloaded or by an operator
+fn convert_unless_shed(state: &AppState) -> ConversionDecision {
+    if state.conversions_shed() {
+        ConversionDecision::Skip(SkipReason::Shed)
+    } else {
+        ConversionDecision::Convert
+    }
+}
+
+/// What a conversion does with the decoded form of its source
+enum SourceDecode {
+    /// Decode the source and drop the decoded image afterwards
+    Once,
+    /// Decode the source and hand the decoded image back for converting it to further formats
+    Keep,
+    /// Convert this already decoded source instead of decoding it again
+    Reuse(Arc<DecodedImage>),
+}
+
+/// Convert an image on the blocking thread pool
+///
+/// Once `cancel` fires the conversion stops at its next stage boundary and is
+/// counted as cancelled; nobody is left waiting for its result at that point.
+/// With a `time_budget` the conversion is abandoned the same way once it has run
+/// that long after getting its threads, failing with [`ConversionOverBudget`].
+/// Failures other than cancellations are counted by kind.
+async fn convert_image(
+    state: &AppState,
+    path: &str,
+    data: Bytes,
+    target_format: OutputFormat,
+    decode: SourceDecode,
+    cancel: &CancellationToken,
+    time_budget: Option<Duration>,
+) -> Result<(Converted, Option<Arc<DecodedImage>>), ConvertError> {
+    let converter = state.image_settings(path).1.clone();
+    let metrics = state.metrics.clone();
+    let cancel = cancel.clone();
+    let attempt = cancel.child_token();
+    
+    // AVIF encodes run on several threads, so they weigh accordingly against the budget
+    let budget = state.conversion_threads;
+    let weight = match target_format {
+        OutputFormat::Avif => state.config.effective_avif_threads().min(budget),
+        _ => 1,
+    };
+    let reused = matches!(decode, SourceDecode::Reuse(_));
+    let permit = state
+        .conversion_permits
+        .clone()
+        .acquire_many_owned(weight as u32)
+        .await
+        .expect("Conversion semaphore is never closed");
+    
+    let task = tokio::task::spawn_blocking({
+        let attempt = attempt.clone();
+        move || {
+            let _permit = permit;
+            let result = match decode {
+                SourceDecode::Once => converter.convert_digested(&data, target_format, &attempt).map(|converted| (converted, None)),
+                SourceDecode::Keep => converter.convert_keeping_decoded(&data, target_format, &attempt),
+                SourceDecode::Reuse(decoded) => {
+                    converter.convert_decoded(&data, decoded, target_format, &attempt).map(|converted| (converted, None))
+                }
+            };
+            match &result {
+                // A reused decode was resized, and counted, by the conversion it came from
+                Ok((Converted { resize: Some((kind, duration)), .. }, _)) if !reused => {
+                    metrics.record_resize(*kind, *duration)
+                }
+                Ok(_) => {}
+                // Attempts abandoned over the time budget are counted by the caller
+                Err(ImageError::Cancelled(_)) if cancel.is_cancelled() => {
+                    debug!("Image conversion to {:?} cancelled", target_format);
+                    Metrics::incr(&metrics.cancelled_conversions);
+                }
+                Err(e) => metrics.record_conversion_error(e),
+            }
+            result
+        }
+    });
+    
+    let Some(time_budget) = time_budget else {
+        return Ok(task.await??);
+    };
+    match tokio::time::timeout(time_budget, task).await {
+        Ok(result) => Ok(result??),
+        Err(_) => {
+            attempt.cancel();
+            Err(ConversionOverBudget(time_budget).into())
+        }
+    }
+}
+
+/// Convert an image, stepping down the fallback ladder while attempts fail to encode or run over the time budget
+///
+/// An abandoned encode cannot be interrupted and keeps its threads until it
+/// finishes in the background, so formats found too slow for a path are
+/// remembered and skipped for that path for a while. A failed encode says
+/// nothing about the source, so the next format is tried straight away. Fails
+/// with the error of the last rung once every rung has failed; any other
+/// failure lies with the source and ends the attempt.
+async fn convert_within_budget(
+    state: &AppState,
+    path: &str,
+    data: Bytes,
+    target_format: OutputFormat,
+    keep_decoded: bool,
+    cancel: &CancellationToken,
+) -> Result<(Converted, Option<Arc<DecodedImage>>), ConvertError> {
+    let time_budget = state.config.image.conversion_time_budget_ms.map(Duration::from_millis);
+    
+    let mut last_error = None;
+    for format in fallback_rungs(state, path, target_format) {
+        let memo = DecisionMemo::OverBudget(format);
+        if time_budget.is_some() && state.memos.contains(path, memo) {
+            sampled_debug!("Skipping {:?} conversion of {}: recently over budget", format, path);
+            continue;
+        }
+        
+        let decode = if keep_decoded { SourceDecode::Keep } else { SourceDecode::Once };
+        match convert_image(state, path, data.clone(), format, decode, cancel, time_budget).await {
+            Err(ConvertError::OverBudget(e)) => {
+                debug!("Converting {} to {:?} took longer than {:?}", path, format, e.0);
+                Metrics::incr(&state.metrics.conversions_over_budget);
+                state.memos.remember(path, memo);
+                last_error = Some(ConvertError::OverBudget(e));
+            }
+            Err(e @ ConvertError::Image(ImageError::EncodeFailed { .. })) => {
+                warn!("Converting {} to {:?} failed: {}", path, format, e);
+                last_error = Some(e);
+            }
+            result => {
+                if result.is_ok() && format != target_format {
+                    info!("Converted {} to {:?} instead of {:?} after the preferred formats failed", path, format, target_format);
+                    Metrics::incr(&state.metrics.conversion_fallbacks);
+                }
+                return result;
+            }
+        }
+    }
+    // Every rung was skipped as recently over budget
+    Err(last_error.unwrap_or_else(|| ConversionOverBudget(time_budget.unwrap_or_default()).into()))
+}
+
+/// Why a conversion produced no image
+#[derive(Debug, thiserror::Error)]
+enum ConvertError {
+    #[error(transparent)]
+    Image(#[from] ImageError),
+    #[error(transparent)]
+    OverBudget(#[from] ConversionOverBudget),
+    #[error("Image conversion task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// `target` followed by the enabled formats after it on the fallback ladder
+fn fallback_rungs(state: &AppState, path: &str, target: OutputFormat) -> Vec<OutputFormat> {
+    let (image, _) = state.image_settings(path);
+    let ladder: Vec<OutputFormat> = image
+        .fallback_ladder
+        .iter()
+        .filter_map(|format| match format {
+            FallbackFormat::Avif => image.enable_avif.then_some(OutputFormat::Avif),
+            FallbackFormat::Webp => image.enable_webp.then_some(OutputFormat::WebP),
+            FallbackFormat::Jpeg => Some(OutputFormat::Jpeg),
+            FallbackFormat::Png => Some(OutputFormat::Png),
+        })
+        .collect();
+    
+    let mut rungs = vec![target];
+    if let Some(position) = ladder.iter().position(|format| *format == target) {
+        rungs.extend(ladder[position + 1..].iter().filter(|format| **format != target));
+    }
+    rungs
+}
+
+/// Check whether the request carries the configured admin token as a bearer token
+fn is_admin_request(headers: &HeaderMap, admin_token: Option<&str>) -> bool {
+    let Some(admin_token) = admin_token.filter(|t| !t.is_empty()) else {
+        return false;
+    };
+    
+    bearer_token(headers).is_some_and(|token| tokens_match(token, admin_token))
+}
+
+/// Compare tokens in constant time
+///
+/// Both sides are hashed first so neither the contents nor the length of the
+/// configured token can be learned from how long a rejection takes.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    given.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Token sent as `Authorization: Bearer <token>`, if any
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Check a request to an admin endpoint for the admin token and record it in the audit log
+fn authorize_admin(
+    state: &AppState,
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    action: &'static str,
+    params: serde_json::Value,
+) -> bool {
+    let authorized = is_admin_request(headers, state.config.server.admin_token.as_deref());
+    
+    let mut event = AuditEvent::new(action, params);
+    event.client_ip = state.client_ip(headers, connect_info.map(|ConnectInfo(addr)| addr.ip()));
+    event.token_fingerprint = bearer_token(headers).map(token_fingerprint);
+    event.authorized = authorized;
+    state.audit.record(event);
+    
+    authorized
+}
+
+/// Send an upstream request, failing if response headers take longer than `header_timeout`
+///
+/// The whole exchange must finish within `timeout`: the headers are waited for
+/// no longer, and the returned body fails once the rest of it has passed.
+async fn send_upstream(
+    fetcher: &dyn UpstreamFetcher,
+    url: &str,
+    headers: HeaderMap,
+    header_timeout: Option<Duration>,
+    timeout: Duration,
+) -> Result<UpstreamResponse, ProxyError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let header_wait = header_timeout.map_or(timeout, |header_timeout| header_timeout.min(timeout));
+    let result = tokio::time::timeout(header_wait, fetcher.fetch(url, headers)).await.map_err(|_| {
+        if header_wait < timeout {
+            error!("Upstream did not send response headers within {:?}", header_wait);
+            ProxyError::UpstreamHeaderTimeout
+        } else {
+            error!("Upstream did not respond within {:?}", timeout);
+            ProxyError::UpstreamTimeout(timeout)
+        }
+    })?;
+    
+    let response = result.map_err(|e| {
+        error!("Failed to fetch from upstream: {}", e);
+        match e {
+            FetchError::Http(e) if e.is_timeout() => ProxyError::UpstreamTimeout(timeout),
+            e => ProxyError::UpstreamError(e),
+        }
+    })?;
+    Ok(UpstreamResponse {
+        body: upstream::with_deadline(response.body, deadline, timeout),
+        ..response
+    })
+}
+
+/// Upstream body as far as [`read_body_limited`] read it
+enum BodyRead {
+    /// The whole body and its digest
+    Complete(Bytes, BodyDigest),
+    /// The body grew past the limit: the bytes read so far, and the unread rest
+    OverLimit(Bytes, BoxStream<'static, Result<Bytes, FetchError>>),
+}
+
+/// Read the upstream body, stopping as soon as it exceeds `limit` bytes
+///
+/// Fails if no chunk arrives within `idle_timeout`. Chunks are hashed as they
+/// arrive, so the digest needs no second pass over the body. A transfer failing
+/// partway is fetched again through `retry` while it has attempts left; a resumed
+/// transfer appends to the chunks received so far, a restarted one replaces them.
+/// Each transfer has `timeout` to finish, one running out of it is not retried.
+async fn read_body_limited(
+    response: UpstreamResponse,
+    idle_timeout: Option<Duration>,
+    timeout: Duration,
+    limit: usize,
+    mut retry: Option<&mut BodyRetry>,
+) -> Result<BodyRead, ProxyError> {
+    let mut body = response.body;
+    let mut buffer = Vec::new();
+    let mut hasher = BodyHasher::default();
+    
+    loop {
+        let chunk = match idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, body.next()).await.map_err(|_| {
+                error!("Upstream body stalled for more than {:?}", timeout);
+                ProxyError::UpstreamBodyTimeout
+            })?,
+            None => body.next().await,
+        };
+        
+        match chunk.transpose() {
+            Ok(Some(chunk)) if buffer.len() + chunk.len() > limit => {
+                buffer.extend_from_slice(&chunk);
+                return Ok(BodyRead::OverLimit(Bytes::from(buffer), body));
+            }
+            Ok(Some(chunk)) => {
+                hasher.update(&chunk);
+                buffer.extend_from_slice(&chunk);
+            }
+            Ok(None) => return Ok(BodyRead::Complete(Bytes::from(buffer), hasher.finish())),
+            Err(FetchError::TimedOut(timeout)) => {
+                error!("Upstream body did not finish within {:?}", timeout);
+                return Err(ProxyError::UpstreamTimeout(timeout));
+            }
+            Err(e) => {
+                let refetched = match retry.as_deref_mut() {
+                    Some(retry) => retry.refetch(buffer.len() as u64).await,
+                    None => None,
+                };
+                let deadline = tokio::time::Instant::now() + timeout;
+                match refetched {
+                    Some(Ok(Refetched::Resumed(rest))) => body = upstream::with_deadline(rest, deadline, timeout),
+                    Some(Ok(Refetched::Restarted(whole))) => {
+                        buffer.clear();
+                        hasher = BodyHasher::default();
+                        body = upstream::with_deadline(whole, deadline, timeout);
+                    }
+                    Some(Err(retry_error)) => {
+                        error!("Failed to read response body: {}, then to fetch it again: {}", e, retry_error);
+                        return Err(ProxyError::UpstreamError(e));
+                    }
+                    None => {
+                        error!("Failed to read response body: {}", e);
+                        return Err(ProxyError::UpstreamError(e));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Most bytes of an upstream body buffered before it is streamed instead
+///
+/// Anything that may be an image can be converted, so its limit is the larger of
+/// the conversion and cache limits; other bodies are only ever cached.
+fn body_buffer_limit(state: &AppState, declared_type: Option<&str>) -> usize {
+    let max_item_size = state.config.cache.max_item_size;
+    let limit = match declared_type {
+        Some(content_type) if !is_image_content_type(content_type) => max_item_size,
+        _ => max_item_size.max(state.config.max_convert_input_size()),
+    };
+    usize::try_from(limit).unwrap_or(usize::MAX)
+}
+
+/// Content type upstream declared, or else the one the body starts like
+fn declared_or_sniffed_type(path: &str, declared_type: Option<String>, body: &[u8]) -> String {
+    declared_type.unwrap_or_else(|| match sniff_content_type(body) {
+        Some(sniffed) => {
+            debug!("Upstream sent no Content-Type for {}, the body is {}", path, sniffed);
+            sniffed.to_string()
+        }
+        None => "application/octet-stream".to_string(),
+    })
+}
+
+/// Response passing an upstream body on as it arrives, neither cached nor converted
+///
+/// `read` holds what was already read before the body turned out too large to
+/// buffer. A stall longer than `upstream.body_idle_timeout` or a failing transfer
+/// aborts the response; the body is not fetched again, as part of it has been sent.
+fn stream_response(
+    state: &AppState,
+    path: &str,
+    content_type: &str,
+    read: Bytes,
+    rest: BoxStream<'static, Result<Bytes, FetchError>>,
+    served_headers: Option<&HeaderMap>,
+    content_length: Option<u64>,
+) -> Response {
+    debug!("Streaming {} ({}) without caching it", path, content_type);
+    Metrics::incr(&state.metrics.streamed_responses);
+    if is_image_content_type(content_type) {
+        state.metrics.record_conversion_skipped(SkipReason::TooLarge);
+    }
+    // The body is not known yet, so it gets no validators
+    let mut response = build_response(Bytes::new(), content_type, &state.config.server.via_header, served_headers, CacheStatus::Miss, None);
+    *response.body_mut() = relay_body(state, path, read, rest);
+    if let Some(length) = content_length {
+        response.headers_mut().insert(header::CONTENT_LENGTH, header::HeaderValue::from(length));
+    }
+    add_cache_status_detail(&mut response, STREAMED_DETAIL);
+    response
+}
+
+/// Upstream's 206 or 416 answer to a forwarded `Range` request, passed on as it arrives
+///
+/// Slices are neither cached nor converted. `Content-Range` and `Accept-Ranges`
+/// are kept whether or not upstream headers are preserved.
+fn upstream_range_response(state: &AppState, path: &str, response: UpstreamResponse) -> Response {
+    debug!("Passing on upstream's {} answer to a range request for {}", response.status, path);
+    let served_headers = if state.config.server.preserve_upstream_headers {
+        preserved_headers(state, &response.headers)
+    } else {
+        select_headers(&response.headers, &state.forwarded_headers)
+    };
+    let content_type = upstream_content_type(path, &response.headers);
+    let content_length = response.headers.get(header::CONTENT_LENGTH).cloned();
+    let mut headers = entry_headers(content_type.as_deref().map(content_type_value), Some(&served_headers));
+    if let Some(length) = content_length {
+        headers.insert(header::CONTENT_LENGTH, length);
+    }
+    let mut passed_on = assemble_response(
+        Bytes::new(),
+        response.status,
+        headers,
+        state.via_header.clone(),
+        CacheStatus::Bypass.cache_control(),
+        Some(CacheStatus::Bypass),
+    );
+    *passed_on.body_mut() = relay_body(state, path, Bytes::new(), response.body);
+    passed_on
+}
+
+/// Body passing `read` and then the unread `rest` of an upstream body on as it arrives
+///
+/// Upstream is read ahead of the client into a spool of `server.stream_spool_size`
+/// bytes. A stall longer than `upstream.body_idle_timeout` or a failing transfer aborts it.
+fn relay_body(state: &AppState, path: &str, read: Bytes, rest: BoxStream<'static, Result<Bytes, FetchError>>) -> Body {
+    let idle_timeout = state.config.upstream.body_idle_timeout.map(Duration::from_secs);
+    let path = path.to_string();
+    let rest = futures::stream::unfold(Some(rest), move |rest| {
+        let path = path.clone();
+        async move {
+            let mut rest = rest?;
+            let chunk = match idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, rest.next()).await.unwrap_or_else(|_| {
+                    Some(Err(FetchError::Stalled(timeout)))
+                }),
+                None => rest.next().await,
+            };
+            match chunk? {
+                Ok(chunk) => Some((Ok(chunk), Some(rest))),
+                Err(e) => {
+                    warn!("Streaming {} from upstream failed: {}", path, e);
+                    Some((Err(e), None))
+                }
+            }
+        }
+    });
+    let rest = match state.config.server.stream_spool_size as usize {
+        0 => rest.boxed(),
+        size => spool(rest.boxed(), size),
+    };
+    let read = (!read.is_empty()).then_some(Ok(read));
+    Body::from_stream(futures::stream::iter(read).chain(rest))
+}
+
+/// Read `upstream` on its own task, up to `size` bytes ahead of whoever reads the returned stream
+///
+/// A slow client then paces the upstream transfer only once the spool is full. The
+/// task stops when the returned stream is dropped, so a client that goes away ends
+/// the transfer as well.
+fn spool(
+    mut upstream: BoxStream<'static, Result<Bytes, FetchError>>,
+    size: usize,
+) -> BoxStream<'static, Result<Bytes, FetchError>> {
+    // Room is taken a chunk at a time, as many permits as the chunk has bytes
+    let size = size.min(u32::MAX as usize);
+    let room = Arc::new(Semaphore::new(size));
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = upstream.next().await {
+            // A chunk larger than the whole spool waits for it to drain completely
+            let permits = chunk.as_ref().map_or(0, |chunk| chunk.len().min(size)) as u32;
+            let Ok(permit) = room.clone().acquire_many_owned(permits).await else {
+                break;
+            };
+            if sender.send((chunk, permit)).is_err() {
+                break;
+            }
+        }
+    });
+    // The chunk's room in the spool is given back once it is handed on
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        let (chunk, _permit) = receiver.recv().await?;
+        Some((chunk, receiver))
+    })
+    .boxed()
+}
+
+/// Total size of a header map as sent on the wire, ignoring separators
+fn headers_size(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+/// Parse query string to extract format parameter and return modified query
+/// Returns (format_option, remaining_query_string)
+/// 
+/// Values are matched case-insensitively after percent-decoding, with '+' read as
+/// a space. Whitespace, a byte-order mark, quotes and a `data:` or `image/` prefix
+/// are stripped, since some rule engines and reverse proxies add them around the
+/// value they insert.
+/// 
+/// When the parameter occurs more than once the first occurrence wins; every
+/// occurrence is removed from the query. The remaining parameters are passed on
+/// exactly as received, encoding included.
+fn parse_query_for_format(query: &str) -> (Option<OutputFormat>, String) {
+    let mut format_value = None;
+    let mut seen = false;
+    let mut remaining_params = Vec::new();
+    
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some((key, value)) if decode_query_component(key) == "format" => {
+                if seen {
+                    debug!("Ignoring repeated format parameter {:?}, the first one wins", value);
+                    continue;
+                }
+                seen = true;
+                // Invalid or unsupported format values are ignored
+                format_value = match normalize_format_value(&decode_query_component(value)).as_str() {
+                    "avif" => Some(OutputFormat::Avif),
+                    "webp" => Some(OutputFormat::WebP),
+                    "original" => Some(OutputFormat::Original),
+                    _ => None,
+                };
+            }
+            // Parameters without values (e.g., "debug" in "?debug&other=value") are kept too
+            _ => remaining_params.push(param),
+        }
+    }
+    
+    (format_value, remaining_params.join("&"))
+}
+
+/// `path` with runs of slashes collapsed into one and without a trailing slash
+fn normalize_slashes(path: &str) -> Cow<'_, str> {
+    let trailing = path.len() > 1 && path.ends_with('/');
+    if !trailing && !path.contains("//") {
+        return Cow::Borrowed(path);
+    }
+    
+    let mut normalized = String::with_capacity(path.len());
+    for (i, segment) in path.split('/').enumerate() {
+        if i == 0 || !segment.is_empty() {
+            if i > 0 {
+                normalized.push('/');
+            }
+            normalized.push_str(segment);
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Cow::Owned(normalized)
+}
+
+/// Percent-decode a query string key or value, reading '+' as a space
+fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    percent_encoding::percent_decode_str(&component).decode_utf8_lossy().into_owned()
+}
+
+/// Lowercase a decoded format value without the decoration rule engines add around it
+fn normalize_format_value(value: &str) -> String {
+    let value = value
+        .trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}' || c == '"' || c == '\'')
+        .to_ascii_lowercase();
+    let value = value.strip_prefix("data:").unwrap_or(&value);
+    value.strip_prefix("image/").unwrap_or(value).to_string()
+}
+
+/// Parse query string to extract the first-frame marker and return modified query
+/// Returns (static_frame_requested, remaining_query_string)
+///
+/// Both `frame=first` and `static=1` request a static poster of an animated image.
+/// The marker is always consumed so it never reaches upstream, even when its value
+/// is not recognized.
+fn parse_query_for_static(query: &str) -> (bool, String) {
+    let mut static_frame = false;
+    let mut remaining_params = Vec::new();
+    
+    for param in query.split('&') {
+        match param.split_once('=') {
+            Some(("frame", value)) => {
+                static_frame |= value.eq_ignore_ascii_case("first");
+            }
+            Some(("static", value)) => {
+                static_frame |= value == "1" || value.eq_ignore_ascii_case("true");
+            }
+            _ => remaining_params.push(param),
+        }
+    }
+    
+    (static_frame, remaining_params.join("&"))
+}
+
+/// Determine if image conversion is needed, returning why not otherwise
+fn should_convert_image(
+    content_type: &str,
+    upstream_format: Option<OutputFormat>,
+    desired_format: OutputFormat,
+    content_size: usize,
+    min_size: usize,
+    max_size: usize,
+) -> Result<(), SkipReason> {
+    // Must be an image
+    if !is_image_content_type(content_type) {
+        return Err(SkipReason::NotImage);
+    }
+    
+    // Must not be requesting original format
+    // Skip conversion if upstream format already satisfies desired format
+    if desired_format == OutputFormat::Original
+        || matches!(upstream_format, Some(fmt) if format_satisfies(fmt, desired_format))
+    {
+        return Err(SkipReason::FormatSatisfied);
+    }
+    
+    // Must be within size limits
+    if content_size > max_size {
+        return Err(SkipReason::TooLarge);
+    }
+    if content_size < min_size {
+        return Err(SkipReason::BelowMinSize);
+    }
+    
+    Ok(())
+}
+
+/// Log an image skipped for exceeding the conversion size cap, at most once per path per hour
+async fn log_too_large(state: &AppState, path: &str, size: usize, max_size: usize) {
+    if !state.memos.remember_once(path, DecisionMemo::TooLargeLogged) {
+        return;
+    }
+    info!(
+        "Not converting {}: {} bytes exceeds image.max_convert_input_size of {} bytes",
+        path, size, max_size
+    );
+}
+
+/// Build HTTP response with appropriate headers
+///
+/// Bodies are given the validators their cache entries are served with; empty
+/// ones, which streamed responses fill in later, get none.
+fn build_response(
+    data: Bytes, 
+    content_type: &str, 
+    via_header: &str,
+    upstream_headers: Option<&HeaderMap>,
+    cache_status: CacheStatus,
+    validators: Option<(&BodyDigest, SystemTime)>,
+) -> Response {
+    let mut headers = entry_headers(Some(content_type_value(content_type)), upstream_headers);
+    if let Some((digest, last_modified)) = validators {
+        add_validators(&mut headers, &digest.to_hex(), last_modified);
+    }
+    assemble_response(
+        data,
+        StatusCode::OK,
+        headers,
+        header::HeaderValue::from_str(via_header).expect("Invalid Via header"),
+        cache_status.cache_control(),
+        Some(cache_status),
+    )
+}
+
+/// Build HTTP response with custom status code and headers
+fn build_response_with_status(
+    data: Bytes,
+    status: StatusCode,
+    via_header: &str,
+    upstream_headers: Option<&HeaderMap>,
+    cache_control: &str,
+) -> Response {
+    assemble_response(
+        data,
+        status,
+        entry_headers(None, upstream_headers),
+        header::HeaderValue::from_str(via_header).expect("Invalid Via header"),
+        header::HeaderValue::from_str(cache_control).expect("Invalid Cache-Control header"),
+        None,
+    )
+}
+
+/// Response from headers fixed for its body plus the ones set per request
+fn assemble_response(
+    data: Bytes,
+    status: StatusCode,
+    mut headers: HeaderMap,
+    via_header: header::HeaderValue,
+    cache_control: header::HeaderValue,
+    cache_status: Option<CacheStatus>,
+) -> Response {
+    headers.insert(header::VIA, via_header);
+    headers.insert(header::CACHE_CONTROL, cache_control);
+    if let Some(cache_status) = cache_status {
+        headers.insert(X_CACHE_STATUS, cache_status.header_value());
+    }
+    
+    let mut response = Response::new(Body::from(data));
+    *response.status_mut() = status;
+    *response.headers_mut() = headers;
+    response
+}
+
+/// Root handler, redirecting to the configured landing page
+pub async fn root_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or("");
+    if state.config.server.root_json && prefers_json(accept) {
+        return root_status(&state);
+    }
+    
+    let redirect = &state.config.server.root_redirect;
+    let location = if redirect.starts_with('/') {
+        let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+        format!("{}{}", state.external_base_url(&headers, peer), redirect)
+    } else {
+        redirect.clone()
+    };
+    
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, location)
+        .header(header::VIA, &state.config.server.via_header)
+        .header(header::CACHE_CONTROL, "public, max-age=86400")
+        .header(header::VARY, "Accept")
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::X_FRAME_OPTIONS, "DENY")
+        .header(header::REFERRER_POLICY, "no-referrer")
+        .body(Body::empty())
+        .expect("Failed to build root redirect response")
+}
+
+/// Whether an Accept header explicitly ranks JSON above HTML
+///
+/// Browsers list `text/html` first and API clients ask for JSON by name, so
+/// wildcards alone never select the status document.
+fn prefers_json(accept: &str) -> bool {
+    let json = explicit_quality(accept, "application/json");
+    json > 0.0 && json > explicit_quality(accept, "text/html")
+}
+
+/// Status document served at `/` to clients asking for JSON
+#[derive(Debug, Serialize)]
+struct RootStatus {
+    name: &'static str,
+    version: &'static str,
+    /// Rounded down to whole minutes, like `/stats`
+    uptime_seconds: u64,
+    cache_entries: u64,
+    upstream_host: Option<String>,
+    formats: RootFormats,
+    /// `active`, or `shed` while conversions are paused under load
+    conversions: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct RootFormats {
+    avif: bool,
+    webp: bool,
+}
+
+fn root_status(state: &AppState) -> Response {
+    let status = RootStatus {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: state.public_stats.snapshot().uptime_seconds,
+        cache_entries: state.cache.stats().entry_count,
+        upstream_host: upstream_host(state, "/"),
+        formats: RootFormats {
+            avif: state.config.image.enable_avif,
+            webp: state.config.image.enable_webp,
+        },
+        conversions: if state.conversions_shed() { "shed" } else { "active" },
+    };
+    
+    let mut response = axum::Json(status).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::VIA, state.via_header.clone());
+    headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+    headers.insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, header::HeaderValue::from_static("nosniff"));
+    response
+}
+
+/// Health check handler
+///
+/// The first line is always `OK`; the encoder self-test results follow, so a
+/// build that lost a format stays healthy but shows why it no longer converts.
+/// States switched on through `/admin/state` are listed as 0 or 1, followed by
+/// whether telemetry is degraded and which of its sinks are up.
+pub async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = format!(
+        "OK\nencoder_avif: {}\nencoder_webp: {}\nconversions: {}\n",
+        state.encoders.avif,
+        state.encoders.webp,
+        if state.conversions_shed() { "shed" } else { "active" },
+    );
+    for runtime_state in RuntimeState::ALL {
+        body.push_str(&format!(
+            "runtime_state_active{{state=\"{}\"}}: {}\n",
+            runtime_state.as_str(),
+            u8::from(state.runtime_states.is_active(runtime_state)),
+        ));
+    }
+    let (sinks, degraded) = state.telemetry();
+    body.push_str(&format!("telemetry_degraded: {}\n", u8::from(degraded)));
+    for (sink, sink_state) in sinks {
+        body.push_str(&format!("telemetry_sink_up{{sink=\"{}\"}}: {}\n", sink, u8::from(sink_state == SinkState::Active)));
+    }
+    (StatusCode::OK, body)
+}
+
+/// Metrics handler
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let stats = state.cache.stats();
+    let mut body = format!(
+        "# Cache Statistics\ncache_entries {}\ncache_size_bytes {}\ncache_inserts_total {}\ncache_evictions_total {}\n\
+         cache_utilization_percent {:.1}\ncache_pressure_ratio {:.3}\n\
+         conversion_shedding {}\nprocess_cpu_percent {:.1}\ndecision_memo_entries {}\n{}",
+        stats.entry_count,
+        stats.weighted_size,
+        stats.inserts,
+        stats.evictions,
+        stats.utilization_percent(),
+        state.cache_pressure.ratio(),
+        u8::from(state.conversions_shed()),
+        state.conversion_shed.cpu_percent(),
+        state.memos.len(),
+        state.metrics.render(),
+    );
+    if let Some(bandwidth) = &state.bandwidth {
+        body.push_str(&format!("upstream_throttle_utilization {:.3}\n", bandwidth.utilization()));
+    }
+    if let Some(host_limiter) = &state.host_limiter {
+        for usage in host_limiter.usage().into_iter().take(TOP_HOSTS) {
+            body.push_str(&format!(
+                "upstream_host_in_flight{{host=\"{}\"}} {}\nupstream_host_queued{{host=\"{}\"}} {}\n",
+                usage.host, usage.in_flight, usage.host, usage.queued,
+            ));
+        }
+    }
+    if let Some(limit) = state.memory_watchdog.limit() {
+        body.push_str(&format!(
+            "memory_pressure {}\nprocess_resident_memory_bytes {}\nmemory_limit_bytes {}\n",
+            u8::from(state.memory_watchdog.is_under_pressure()),
+            state.memory_watchdog.rss(),
+            limit,
+        ));
+    }
+    body.push_str(&format!(
+        "refreshes_in_flight {}\nrefreshes_swept_total {}\n",
+        state.refreshes.len(),
+        state.refreshes.swept(),
+    ));
+    for runtime_state in RuntimeState::ALL {
+        body.push_str(&format!(
+            "runtime_state_active{{state=\"{}\"}} {}\n",
+            runtime_state.as_str(),
+            u8::from(state.runtime_states.is_active(runtime_state)),
+        ));
+    }
+    let (sinks, degraded) = state.telemetry();
+    body.push_str(&format!("telemetry_degraded {}\n", u8::from(degraded)));
+    for (sink, sink_state) in sinks {
+        body.push_str(&format!("telemetry_sink_up{{sink=\"{}\"}} {}\n", sink, u8::from(sink_state == SinkState::Active)));
+    }
+    for (bucket, entries) in HIT_BUCKETS.iter().zip(state.cache.hit_distribution()) {
+        body.push_str(&format!("cache_entries_by_hits{{hits=\"{}\"}} {}\n", bucket, entries));
+    }
+    
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        body,
+    )
+}
+
+/// Coarse statistics for public status pages, without authentication
+///
+/// Answers 404 when `server.public_stats` is off.
+pub async fn public_stats_handler(State(state): State<AppState>) -> Response {
+    if !state.config.server.public_stats {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    
+    axum::Json(state.public_stats.snapshot()).into_response()
+}
+
+/// Admin endpoint reporting body size distributions by content type
+pub async fn format_stats_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "format_stats", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    axum::Json(state.metrics.formats.snapshot()).into_response()
+}
+
+/// Admin endpoint zeroing the format statistics
+pub async fn reset_format_stats_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "format_stats_reset", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    state.metrics.formats.reset();
+    info!("Format statistics reset");
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Admin endpoint listing the cache refreshes in flight, for finding stuck keys
+pub async fn inflight_stats_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "inflight_stats", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let mut entries = state.refreshes.entries();
+    entries.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+    let keys: Vec<_> = entries
+        .into_iter()
+        .map(|(key, age)| serde_json::json!({ "cache_key": format!("{:?}", key), "age_ms": age.as_millis() as u64 }))
+        .collect();
+    axum::Json(serde_json::json!({
+        "count": keys.len(),
+        "swept": state.refreshes.swept(),
+        "keys": keys,
+    }))
+    .into_response()
+}
+
+/// Admin endpoint listing the upstream hosts with fetches in flight or queued, busiest first
+pub async fn host_stats_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "host_stats", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let hosts = state.host_limiter.as_ref().map(|limiter| limiter.usage()).unwrap_or_default();
+    axum::Json(serde_json::json!({
+        "max_concurrent_per_host": state.config.upstream.max_concurrent_per_host,
+        "hosts": hosts,
+    }))
+    .into_response()
+}
+
+/// Admin endpoint listing the slowest requests of the last hour, slowest first
+pub async fn slow_requests_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "slow_requests", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let log = &state.slow_requests;
+    axum::Json(serde_json::json!({
+        "threshold_ms": log.threshold().map(|threshold| threshold.as_millis() as u64),
+        "window_secs": slow::SLOW_REQUEST_WINDOW.as_secs(),
+        "capacity": log.capacity(),
+        "requests": log.snapshot(),
+    }))
+    .into_response()
+}
+
+/// Admin endpoint dropping the refreshes in flight for a path, releasing the requests waiting on them
+pub async fn clear_inflight_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<PurgeParams>,
+) -> Response {
+    let audit_params = serde_json::to_value(&params).unwrap_or_default();
+    if !authorize_admin(&state, &headers, connect_info, "inflight_clear", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let cleared = state.refreshes.clear_path(&state.request_path(&params.path));
+    info!("Cleared {} in-flight refreshes of {}", cleared, params.path);
+    axum::Json(serde_json::json!({ "path": params.path, "cleared": cleared })).into_response()
+}
+
+/// Body of `POST /admin/state`
+///
+/// States are switched on for `ttl_secs` or with `permanent`, one of which is
+/// required; states given as `false` are switched off.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateChange {
+    maintenance: Option<bool>,
+    shed_conversions: Option<bool>,
+    ttl_secs: Option<u64>,
+    #[serde(default)]
+    permanent: bool,
+    /// Shown with the state in `GET /admin/state`, e.g. why it was set
+    note: Option<String>,
+}
+
+/// Admin endpoint listing the states switched on, with who set them and the time they have left
+pub async fn runtime_state_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    if !authorize_admin(&state, &headers, connect_info, "state_list", serde_json::json!({})) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    axum::Json(serde_json::json!({ "states": state.runtime_states.list() })).into_response()
+}
+
+/// Admin endpoint switching maintenance and conversion shedding on or off
+pub async fn set_runtime_state_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Request,
+) -> Response {
+    let change = match axum::Json::<StateChange>::from_request(request, &()).await {
+        Ok(axum::Json(change)) => change,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let audit_params = serde_json::to_value(&change).unwrap_or_default();
+    if !authorize_admin(&state, &headers, connect_info, "state_set", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let changes: Vec<_> = [
+        (RuntimeState::Maintenance, change.maintenance),
+        (RuntimeState::ShedConversions, change.shed_conversions),
+    ]
+    .into_iter()
+    .filter_map(|(runtime_state, on)| Some((runtime_state, on?)))
+    .collect();
+    if changes.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No state given").into_response();
+    }
+    let ttl = match (change.ttl_secs, change.permanent) {
+        (Some(_), true) => {
+            return (StatusCode::BAD_REQUEST, "ttl_secs and permanent cannot be combined").into_response();
+        }
+        (Some(0), false) => return (StatusCode::BAD_REQUEST, "ttl_secs must be greater than 0").into_response(),
+        (Some(secs), false) => Some(Duration::from_secs(secs)),
+        (None, false) if changes.iter().any(|(_, on)| *on) => {
+            return (StatusCode::BAD_REQUEST, "States are set with ttl_secs or \"permanent\": true").into_response();
+        }
+        (None, _) => None,
+    };
+    
+    let actor = Actor {
+        client_ip: state.client_ip(&headers, connect_info.map(|ConnectInfo(addr)| addr.ip())),
+        token_fingerprint: bearer_token(&headers).map(token_fingerprint),
+    };
+    for (runtime_state, on) in changes {
+        if on {
+            state.runtime_states.set(runtime_state, ttl, change.note.clone(), actor.clone());
+        } else {
+            state.runtime_states.clear(runtime_state);
+        }
+    }
+    axum::Json(serde_json::json!({ "states": state.runtime_states.list() })).into_response()
+}
+
+/// Query parameters of the explain endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExplainParams {
+    /// Request path and query to explain, e.g. `/media/foo.jpg?format=webp`
+    path: String,
+    /// Accept header of the simulated request
+    accept: Option<String>,
+    /// User-Agent header of the simulated request
+    user_agent: Option<String>,
+    /// Upstream content type and body size to evaluate the conversion decision with
+    content_type: Option<String>,
+    size: Option<usize>,
+    #[serde(default)]
+    animated: bool,
+}
+
+/// Admin endpoint tracing how a request would be served
+///
+/// Runs the same decision functions as the proxy handler as a dry run: upstream
+/// is never contacted and no cache entry is inserted or removed.
+pub async fn explain_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<ExplainParams>,
+) -> Response {
+    let audit_params = serde_json::to_value(&params).unwrap_or_default();
+    if !authorize_admin(&state, &headers, connect_info, "explain", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let Ok(uri) = params.path.parse::<Uri>() else {
+        return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
+    };
+    
+    let mut request_headers = HeaderMap::new();
+    if let Some(accept) = params.accept.as_deref().and_then(|a| header::HeaderValue::from_str(a).ok()) {
+        request_headers.insert(header::ACCEPT, accept);
+    }
+    if let Some(user_agent) = params.user_agent.as_deref().and_then(|a| header::HeaderValue::from_str(a).ok()) {
+        request_headers.insert(header::USER_AGENT, user_agent);
+    }
+    
+    let mut steps = Vec::new();
+    let plan = match plan_request(&state, &uri, &request_headers) {
+        Ok(plan) => plan,
+        Err(ProxyError::FormatDisabled { format, redirect }) => {
+            steps.push(serde_json::json!({ "step": "path_check", "path": uri.path(), "outcome": "allowed" }));
+            steps.push(serde_json::json!({
+                "step": "query",
+                "format": format,
+                "outcome": "format_disabled",
+                "redirect": redirect,
+            }));
+            let response = if redirect.is_some() { 302 } else { 406 };
+            return axum::Json(serde_json::json!({ "steps": steps, "response": response })).into_response();
+        }
+        Err(_) => {
+            steps.push(serde_json::json!({ "step": "path_check", "path": uri.path(), "outcome": "rejected" }));
+            return axum::Json(serde_json::json!({ "steps": steps, "response": 403 })).into_response();
+        }
+    };
+    steps.push(serde_json::json!({ "step": "path_check", "path": uri.path(), "outcome": "allowed" }));
+    steps.push(serde_json::json!({
+        "step": "query",
+        "format": plan.format_from_query.map(|f| format!("{:?}", f)),
+        "static_frame": plan.static_frame,
+        "no_convert": plan.no_convert,
+        "force_original": plan.force_original,
+        "no_transform": plan.no_transform,
+        "upstream_url": plan.upstream_url,
+    }));
+    steps.push(serde_json::json!({
+        "step": "negotiation",
+        "accept": params.accept.as_deref().unwrap_or("*/*"),
+        "desired_format": format!("{:?}", plan.desired_format),
+        "ua_override": plan.ua_override,
+    }));
+    
+    let negative = state.cache.get(&plan.negative_key).await;
+    let variant = state.cache.get(&plan.cache_key).await;
+    let outcome = if plan.bypass_cache {
+        "bypass"
+    } else if negative.is_some() {
+        "negative_hit"
+    } else if variant.is_some() {
+        "hit"
+    } else {
+        "miss"
+    };
+    steps.push(serde_json::json!({
+        "step": "cache_lookup",
+        "cache_key": format!("{:?}", plan.cache_key),
+        "negative_key": format!("{:?}", plan.negative_key),
+        "negative_entry": negative.map(|cached| cached.meta.clone()),
+        "variant_entry": variant.map(|cached| cached.meta.clone()),
+        "outcome": outcome,
+    }));
+    
+    let conversion = match (&params.content_type, params.size) {
+        (Some(content_type), Some(size)) => {
+            let decision = decide_conversion(&state, &plan, plan.desired_format, content_type, size, params.animated);
+            serde_json::json!({
+                "step": "conversion",
+                "content_type": content_type,
+                "size": size,
+                "animated": params.animated,
+                "min_convert_size": state.config.image.min_convert_size,
+                "max_convert_input_size": state.config.max_convert_input_size(),
+                "max_item_size": state.config.cache.max_item_size,
+                "outcome": decision.as_str(),
+            })
+        }
+        _ => serde_json::json!({
+            "step": "conversion",
+            "outcome": "unknown",
+            "note": "pass content_type and size to evaluate the conversion decision",
+        }),
+    };
+    steps.push(conversion);
+    
+    axum::Json(serde_json::json!({ "steps": steps })).into_response()
+}
+
+/// Query parameters of the cache entry endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheEntryParams {
+    /// Request path whose cache entries are listed, e.g. `/media/foo.jpg`
+    path: String,
+}
+
+/// Admin endpoint listing the cache entries of a path with their metadata and hits
+///
+/// Looking entries up here does not count as a hit.
+pub async fn cache_entry_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Query(params): Query<CacheEntryParams>,
+) -> Response {
+    let audit_params = serde_json::to_value(&params).unwrap_or_default();
+    if !authorize_admin(&state, &headers, connect_info, "cache_entry", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let mut entries = state.cache.entries_for_path(&state.request_path(&params.path));
+    entries.sort_by_cached_key(|(key, _)| format!("{:?}", key));
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|(key, cached)| {
+            serde_json::json!({
+                "cache_key": format!("{:?}", key),
+                "size": cached.data.len(),
+                "hits": cached.access.hits(),
+                "last_access": cached.access.last_access(),
+                "meta": cached.meta,
+            })
+        })
+        .collect();
+    axum::Json(serde_json::json!({ "path": params.path, "entries": entries })).into_response()
+}
+
+/// Query parameters of the purge endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PurgeParams {
+    /// Request path whose cache entries are removed, e.g. `/media/foo.jpg`
+    path: String,
+}
+
+/// Admin endpoint removing every cached format and query variant of a path
+///
+/// Takes the path as a query parameter, or many paths as an NDJSON body of
+/// `{"path": ...}` lines, answered with one result line each.
+pub async fn purge_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Request,
+) -> Response {
+    if ndjson::is_ndjson(&headers) {
+        if !authorize_admin(&state, &headers, connect_info, "cache_purge", serde_json::json!({ "bulk": true })) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        return bulk_response(state, request.into_body(), "purge", purge_path);
+    }
+    
+    let params = match Query::<PurgeParams>::try_from_uri(request.uri()) {
+        Ok(Query(params)) => params,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let audit_params = serde_json::json!({ "path": params.path });
+    if !authorize_admin(&state, &headers, connect_info, "cache_purge", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let path = state.request_path(&params.path);
+    let purged = state.invalidator.purge(&state.cache, InvalidationTarget::Path(path.to_string())).await;
+    state.memos.forget_path(&path);
+    info!("Purged {} cache entries for {}", purged, params.path);
+    axum::Json(serde_json::json!({ "path": params.path, "purged": purged })).into_response()
+}
+
+async fn purge_path(state: AppState, params: PurgeParams, _cancel: CancellationToken) -> serde_json::Value {
+    let path = state.request_path(&params.path);
+    let purged = state.invalidator.purge(&state.cache, InvalidationTarget::Path(path.to_string())).await;
+    state.memos.forget_path(&path);
+    debug!("Purged {} cache entries for {}", purged, params.path);
+    serde_json::json!({ "path": params.path, "outcome": "purged", "purged": purged })
+}
+
+/// Body of the warmup endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WarmParams {
+    /// Request path to fetch into the cache, e.g. `/media/foo.jpg`
+    path: String,
+    /// Accept header to request it with, selecting the format that is cached
+    #[serde(default)]
+    accept: Option<String>,
+}
+
+/// Admin endpoint fetching a path into the cache as a client request would
+///
+/// Takes a JSON body like `{"path": "/media/foo.jpg", "accept": "image/avif"}`, or
+/// many of them as NDJSON lines, answered with one result line each.
+pub async fn warm_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Request,
+) -> Response {
+    if ndjson::is_ndjson(&headers) {
+        if !authorize_admin(&state, &headers, connect_info, "cache_warm", serde_json::json!({ "bulk": true })) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        return bulk_response(state, request.into_body(), "warm", warm_path);
+    }
+    
+    let params = match axum::Json::<WarmParams>::from_request(request, &()).await {
+        Ok(axum::Json(params)) => params,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let audit_params = serde_json::json!({ "path": params.path, "accept": params.accept });
+    if !authorize_admin(&state, &headers, connect_info, "cache_warm", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let guard = CancelOnDrop::new(state.metrics.clone());
+    let result = warm_path(state.clone(), params, guard.token.clone()).await;
+    guard.disarm();
+    axum::Json(result).into_response()
+}
+
+/// Fetch a path through the proxy and report whether it ended up cached
+async fn warm_path(state: AppState, params: WarmParams, cancel: CancellationToken) -> serde_json::Value {
+    let accept = params.accept.as_deref().unwrap_or("*/*");
+    let (Some(uri), Ok(accept)) = (
+        params.path.parse::<Uri>().ok().filter(|_| params.path.starts_with('/')),
+        header::HeaderValue::from_str(accept),
+    ) else {
+        return serde_json::json!({
+            "path": params.path,
+            "outcome": "invalid",
+            "error": "expected a request path like /media/foo.jpg and a valid Accept header",
+        });
+    };
+    let headers = HeaderMap::from_iter([(header::ACCEPT, accept)]);
+    
+    match handle_proxy_request(&state, &uri, &headers, &cancel, &RequestTimings::default()).await {
+        Ok(response) => {
+            let outcome = match response.headers().get(X_CACHE_STATUS).and_then(|v| v.to_str().ok()) {
+                Some("HIT") | Some("STALE") => "cached",
+                Some("MISS") => "warmed",
+                _ => "not_cached",
+            };
+            serde_json::json!({ "path": params.path, "outcome": outcome, "status": response.status().as_u16() })
+        }
+        Err(e) => {
+            let status = e.into_response().status();
+            serde_json::json!({ "path": params.path, "outcome": "failed", "status": status.as_u16() })
+        }
+    }
+}
+
+/// Public endpoint queueing paths a client is about to show for fetching into the cache
+///
+/// Takes a JSON array of request paths, fetched in the background with the
+/// request's Accept header, and answers 202 with the status of each: `cached`,
+/// `queued`, or `rejected` with a reason. Clients are limited to
+/// `server.prefetch_rate_limit` requests per minute.
+pub async fn prefetch_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    request: Request,
+) -> Response {
+    let max_batch = state.config.server.prefetch_max_batch;
+    if max_batch == 0 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    
+    let client = state.client_ip(&headers, connect_info.map(|ConnectInfo(addr)| addr.ip()));
+    if let Err(retry_after) = state.prefetcher.limiter.acquire(client) {
+        Metrics::incr(&state.metrics.prefetch_rate_limited);
+        let retry_after = retry_after.as_secs_f64().ceil() as u64;
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            "Too many prefetch requests",
+        )
+            .into_response();
+    }
+    
+    let paths = match axum::Json::<Vec<String>>::from_request(request, &()).await {
+        Ok(axum::Json(paths)) => paths,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if paths.len() > max_batch {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} paths may be prefetched at once", max_batch),
+        )
+            .into_response();
+    }
+    
+    let accept = headers.get(header::ACCEPT).cloned().unwrap_or(header::HeaderValue::from_static("*/*"));
+    let mut results = Vec::with_capacity(paths.len());
+    for path in paths {
+        let outcome = match queue_prefetch(&state, &path, &accept).await {
+            Ok(status) => serde_json::json!({ "path": path, "status": status }),
+            Err(reason) => serde_json::json!({ "path": path, "status": "rejected", "reason": reason }),
+        };
+        results.push(outcome);
+    }
+    (StatusCode::ACCEPTED, axum::Json(serde_json::json!({ "paths": results }))).into_response()
+}
+
+/// Queue a path for fetching unless it is cached already, returning its status or why it was rejected
+async fn queue_prefetch(state: &AppState, path: &str, accept: &header::HeaderValue) -> Result<&'static str, &'static str> {
+    let uri = path.parse::<Uri>().ok().filter(|_| path.starts_with('/')).ok_or("invalid")?;
+    let headers = HeaderMap::from_iter([(header::ACCEPT, accept.clone())]);
+    let plan = plan_request(state, &uri, &headers).map_err(|e| match e {
+        ProxyError::FormatDisabled { .. } => "format_disabled",
+        _ => "path_not_allowed",
+    })?;
+    if state.cache.get(&plan.negative_key).await.is_some() || state.cache.get(&plan.cache_key).await.is_some() {
+        return Ok("cached");
+    }
+    
+    let Some(slot) = state.prefetcher.enqueue() else {
+        Metrics::incr(&state.metrics.prefetch_dropped);
+        return Err("queue_full");
+    };
+    let state = state.for_prefetch();
+    let params = WarmParams {
+        path: path.to_string(),
+        accept: accept.to_str().ok().map(str::to_string),
+    };
+    tokio::spawn(async move {
+        let _slot = slot;
+        let _worker = state.prefetcher.worker().await;
+        let result = warm_path(state.clone(), params, CancellationToken::new()).await;
+        if result["outcome"] == "warmed" {
+            Metrics::incr(&state.metrics.prefetched_paths);
+        }
+    });
+    Ok("queued")
+}
+
+/// Run `action` for every line of an NDJSON request body, streaming back one result line per input line
+///
+/// At most [`BULK_CONCURRENCY`] lines are in flight, and the body is only read as
+/// fast as they complete, so neither side is ever buffered whole. Results keep the
+/// input order. A client going away drops the response stream, which stops reading
+/// the body, drops the operations in flight and cancels their conversions.
+fn bulk_response<P, F, Fut>(state: AppState, body: Body, action: &'static str, run: F) -> Response
+where
+    P: serde::de::DeserializeOwned,
+    F: Fn(AppState, P, CancellationToken) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = serde_json::Value> + Send + 'static,
+{
+    let cancel = CancellationToken::new();
+    let guard = cancel.clone().drop_guard();
+    let results = ndjson::lines(body.into_data_stream(), MAX_BULK_LINE_BYTES)
+        .map(move |(line, content)| {
+            let pending = content
+                .map_err(|e| e.to_string())
+                .and_then(|content| serde_json::from_str::<P>(&content).map_err(|e| e.to_string()))
+                .map(|params| run(state.clone(), params, cancel.clone()));
+            async move {
+                let mut result = match pending {
+                    Ok(pending) => pending.await,
+                    Err(error) => serde_json::json!({ "outcome": "invalid", "error": error }),
+                };
+                result["line"] = line.into();
+                result["action"] = action.into();
+                result
+            }
+        })
+        .buffered(BULK_CONCURRENCY)
+        .map(move |result| {
+            let _guard = &guard;
+            result
+        });
+    ndjson::response(results)
+}
+
+/// Body of the tag purge endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PurgeTagParams {
+    /// Tag whose cache entries are removed, e.g. `domain:media.example.social`
+    tag: String,
+}
+
+/// Admin endpoint removing every cache entry carrying a tag
+pub async fn purge_tag_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    axum::Json(params): axum::Json<PurgeTagParams>,
+) -> Response {
+    let audit_params = serde_json::json!({ "tag": params.tag });
+    if !authorize_admin(&state, &headers, connect_info, "cache_purge_tag", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let purged = state.invalidator.purge(&state.cache, InvalidationTarget::Tag(params.tag.clone())).await;
+    info!("Purged {} cache entries tagged {}", purged, params.tag);
+    axum::Json(serde_json::json!({ "tag": params.tag, "purged": purged })).into_response()
+}
+
+/// Admin endpoint applying a purge published by another replica
+///
+/// Takes an [`Invalidation`] as JSON. Purges of this replica itself and
+/// replayed ones are acknowledged without being applied.
+pub async fn invalidation_handler(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    axum::Json(message): axum::Json<Invalidation>,
+) -> Response {
+    let audit_params = serde_json::json!({ "node": message.node, "seq": message.seq, "target": message.target });
+    if !authorize_admin(&state, &headers, connect_info, "cache_invalidation", audit_params) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    
+    let Some(purged) = state.invalidator.receive(&state.cache, &message).await else {
+        return axum::Json(serde_json::json!({ "applied": false, "purged": 0 })).into_response();
+    };
+    if let InvalidationTarget::Path(path) = &message.target {
+        state.memos.forget_path(path);
+    }
+    info!("Purged {} cache entries for {:?} published by {}", purged, message.target, message.node);
+    axum::Json(serde_json::json!({ "applied": true, "purged": purged })).into_response()
+}
+
+/// Proxy error types
+#[derive(Debug)]
+pub enum ProxyError {
+    PathNotAllowed,
+    UpstreamError(FetchError),
+    UpstreamHeaderTimeout,
+    UpstreamBodyTimeout,
+    /// Upstream did not finish within the timeout of the path
+    UpstreamTimeout(Duration),
+    /// The response did not start within `server.request_deadline`
+    DeadlineExceeded(Duration),
+    VariantUnavailable(VariantError),
+    /// The `format` query parameter names a disabled format behind Cloudflare Free
+    FormatDisabled { format: &'static str, redirect: Option<String> },
+    /// Upstream is not contacted during maintenance, `retry_after` is in seconds
+    Maintenance { retry_after: Option<u64> },
+    /// The request fetching the same key failed, shared with those that waited for it
+    Coalesced(Arc<ProxyError>),
+}
+
+impl ProxyError {
+    /// Status, stable code and message reported for the error
+    fn describe(&self) -> (StatusCode, &'static str, String) {
+        match self {
+            ProxyError::PathNotAllowed => (StatusCode::FORBIDDEN, "path_not_allowed", "Path not allowed".to_string()),
+            ProxyError::UpstreamError(e @ FetchError::HostBusy(_)) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream_busy", format!("Upstream error: {}", e))
+            }
+            ProxyError::UpstreamError(e) => (StatusCode::BAD_GATEWAY, "upstream_error", format!("Upstream error: {}", e)),
+            ProxyError::UpstreamHeaderTimeout => {
+                (StatusCode::GATEWAY_TIMEOUT, "upstream_header_timeout", "Upstream response headers timed out".to_string())
+            }
+            ProxyError::UpstreamBodyTimeout => {
+                (StatusCode::GATEWAY_TIMEOUT, "upstream_body_timeout", "Upstream response body timed out".to_string())
+            }
+            ProxyError::UpstreamTimeout(timeout) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "upstream_timeout",
+                format!("Upstream did not respond within {}s", timeout.as_secs()),
+            ),
+            ProxyError::DeadlineExceeded(deadline) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "request_deadline",
+                format!("Request did not complete within {}s", deadline.as_secs()),
+            ),
+            ProxyError::Maintenance { .. } => {
+                (StatusCode::SERVICE_UNAVAILABLE, "maintenance", "Down for maintenance".to_string())
+            }
+            ProxyError::FormatDisabled { redirect: Some(_), .. } => (StatusCode::FOUND, "format_disabled", String::new()),
+            ProxyError::FormatDisabled { format, redirect: None } => {
+                (StatusCode::NOT_ACCEPTABLE, "format_disabled", format!("Format {} is not enabled", format))
+            }
+            ProxyError::VariantUnavailable(e) => (StatusCode::PAYLOAD_TOO_LARGE, "variant_unavailable", e.to_string()),
+            ProxyError::Coalesced(e) => e.describe(),
+        }
+    }
+    
+    /// Response in the format `accept` asks for, see [`ErrorFormat::negotiate`]
+    ///
+    /// Unavailable variants keep their JSON body for clients not asking for HTML,
+    /// as its limits are meant to be read by programs.
+    pub fn into_negotiated_response(self, pages: &ErrorPages, accept: &str, request_id: Option<&str>) -> Response {
+        self.negotiated_response(pages, accept, request_id)
+    }
+    
+    fn negotiated_response(&self, pages: &ErrorPages, accept: &str, request_id: Option<&str>) -> Response {
+        if let ProxyError::Coalesced(e) = self {
+            return e.negotiated_response(pages, accept, request_id);
+        }
+        let (status, code, message) = self.describe();
+        let (format, details) = match self {
+            ProxyError::FormatDisabled { redirect: Some(location), .. } => {
+                return (
+                    StatusCode::FOUND,
+                    [(header::LOCATION, location.clone()), (header::CACHE_CONTROL, "no-store".to_string())],
+                )
+                    .into_response();
+            }
+            ProxyError::VariantUnavailable(e) => {
+                let details = match e {
+                    VariantError::TooManyPixels { actual, limit } => serde_json::json!({
+                        "limit": "image.max_pixels",
+                        "max": limit,
+                        "actual": actual,
+                    }),
+                    VariantError::DecoderLimits(_) => serde_json::json!({ "limit": "decoder" }),
+                };
+                let format = match ErrorFormat::negotiate(accept) {
+                    ErrorFormat::Html => ErrorFormat::Html,
+                    _ => ErrorFormat::Json,
+                };
+                (format, Some(details))
+            }
+            _ => (ErrorFormat::negotiate(accept), None),
+        };
+        
+        let body = ErrorBody { status, code, message: &message, request_id };
+        let mut response = pages.render(format, &body, details);
+        match self {
+            ProxyError::Maintenance { retry_after } => {
+                response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+                if let Some(retry_after) = *retry_after {
+                    response.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+                }
+            }
+            ProxyError::FormatDisabled { .. } => {
+                response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("no-store"));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        self.into_negotiated_response(&ErrorPages::default(), "", None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    #[cfg(feature = "avif")]
+    use crate::image::encode_gif;
+    use crate::image::ResizeKind;
+    use crate::upstream::mock::{MockFetcher, MockResponse};
+    use tower::ServiceExt;
+
+    /// Serve a fixed body on every path and return the upstream base URL
+    async fn spawn_upstream(body: Vec<u8>, content_type: &'static str) -> String {
+        let app = axum::Router::new().fallback(move || {
+            let body = body.clone();
+            async move { ([(header::CONTENT_TYPE, content_type)], body) }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}", addr)
+    }
+
+    fn mock_state(config: Config, fetcher: MockFetcher) -> (AppState, Arc<MockFetcher>) {
+        let fetcher = Arc::new(fetcher);
+        (AppState::with_fetcher(config, fetcher.clone()), fetcher)
+    }
+
+    fn mock_config() -> Config {
+        Config::with_upstream("http://upstream.test".to_string())
+    }
+
+    async fn get(state: &AppState, uri: &str, accept: &str) -> Response {
+        let request = Request::builder()
+            .uri(uri)
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap();
+        send(state, request).await
+    }
+
+    async fn send(state: &AppState, request: Request) -> Response {
+        router(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap()
+    }
+
+    async fn body_bytes(response: Response) -> Bytes {
+        axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()
+    }
+
+    /// `image` encoded as `format`
+    fn encode(image: impl Into<image::DynamicImage>, format: image::ImageFormat) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        image.into().write_to(&mut std::io::Cursor::new(&mut buffer), format).unwrap();
+        buffer
+    }
+
+    fn encode_jpeg() -> Vec<u8> {
+        encode(image::RgbImage::from_pixel(8, 8, image::Rgb([200, 10, 10])), image::ImageFormat::Jpeg)
+    }
+
+    /// JPEG of a `size` pixels square pattern that compresses poorly
+    fn encode_noise_jpeg(size: u32) -> Vec<u8> {
+        let noise = image::RgbImage::from_fn(size, size, |x, y| image::Rgb([((x * 7) ^ (y * 13)) as u8, (x * y) as u8, (x + y * 3) as u8]));
+        encode(noise, image::ImageFormat::Jpeg)
+    }
+
+    #[test]
+    fn test_build_response_no_duplicate_headers() {
+        // Create upstream headers that include content-type and via
+        let mut upstream_headers = HeaderMap::new();
+        upstream_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
+        upstream_headers.insert(header::VIA, HeaderValue::from_static("upstream-proxy"));
+        upstream_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        upstream_headers.insert(HeaderName::from_static("x-cache-status"), HeaderValue::from_static("upstream-hit"));
+        upstream_headers.insert(HeaderName::from_static("x-custom-header"), HeaderValue::from_static("custom-value"));
+        
+        // Build response with different content-type
+        let response = build_response(
+            Bytes::from("test data"),
+            "image/avif",
+            "akkoproxy/1.0",
+            Some(&upstream_headers),
+            CacheStatus::Hit,
+            None,
+        );
+        
+        let headers = response.headers();
+        
+        // Content-Type should only have the proxy's value (image/avif), not upstream's (image/jpeg)
+        let content_types: Vec<_> = headers.get_all(header::CONTENT_TYPE).iter().collect();
+        assert_eq!(content_types.len(), 1, "Content-Type should not be duplicated");
+        assert_eq!(content_types[0], "image/avif");
+        
+        // Via should only have the proxy's value
+        let via_values: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(via_values.len(), 1, "Via should not be duplicated");
+        assert_eq!(via_values[0], "akkoproxy/1.0");
+        
+        // Cache-Control should only have the proxy's value
+        let cache_control_values: Vec<_> = headers.get_all(header::CACHE_CONTROL).iter().collect();
+        assert_eq!(cache_control_values.len(), 1, "Cache-Control should not be duplicated");
+        assert_eq!(cache_control_values[0], "public, max-age=31536000, immutable");
+        
+        // X-Cache-Status should only have the proxy's value
+        let x_cache_status_values: Vec<_> = headers.get_all("x-cache-status").iter().collect();
+        assert_eq!(x_cache_status_values.len(), 1, "X-Cache-Status should not be duplicated");
+        assert_eq!(x_cache_status_values[0], "HIT");
+        
+        // Custom header should be preserved
+        assert_eq!(headers.get("x-custom-header").unwrap(), "custom-value");
+    }
+    
+    #[test]
+    fn test_build_response_with_status_no_duplicate_headers() {
+        // Create upstream headers
+        let mut upstream_headers = HeaderMap::new();
+        upstream_headers.insert(header::VIA, HeaderValue::from_static("upstream-proxy"));
+        upstream_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("https://example.com"));
+        upstream_headers.insert(HeaderName::from_static("x-custom-header"), HeaderValue::from_static("custom-value"));
+        
+        // Build response
+        let response = build_response_with_status(
+            Bytes::from("redirect"),
+            StatusCode::MOVED_PERMANENTLY,
+            "akkoproxy/1.0",
+            Some(&upstream_headers),
+            "no-store",
+        );
+        
+        let headers = response.headers();
+        
+        // Via should only have the proxy's value
+        let via_values: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(via_values.len(), 1, "Via should not be duplicated");
+        assert_eq!(via_values[0], "akkoproxy/1.0");
+        
+        // Access-Control-Allow-Origin should have upstream's value (not replaced)
+        let acao_values: Vec<_> = headers.get_all(header::ACCESS_CONTROL_ALLOW_ORIGIN).iter().collect();
+        assert_eq!(acao_values.len(), 1, "Access-Control-Allow-Origin should not be duplicated");
+        assert_eq!(acao_values[0], "https://example.com");
+        
+        // Custom header should be preserved
+        assert_eq!(headers.get("x-custom-header").unwrap(), "custom-value");
+    }
+    
+    #[test]
+    fn test_normalize_slashes() {
+        for path in ["/media/foo.png", "/media//foo.png", "/media///foo.png", "/media/foo.png/", "//media//foo.png//"] {
+            assert_eq!(normalize_slashes(path), "/media/foo.png", "{}", path);
+        }
+        assert!(matches!(normalize_slashes("/media/a/b.png"), Cow::Borrowed(_)));
+        assert_eq!(normalize_slashes("/"), "/");
+        assert_eq!(normalize_slashes("//"), "/");
+    }
+    
+    #[tokio::test]
+    async fn test_slash_variants_share_cache_key() {
+        let (state, fetcher) = mock_state(mock_config(), MockFetcher::always(MockResponse::ok("text/plain", "x")));
+        let variants = ["/media/foo.txt", "/media//foo.txt", "/media///foo.txt", "/media/foo.txt/", "/media//foo.txt/?v=1"];
+        for uri in variants {
+            let plan = plan_request(&state, &uri.parse().unwrap(), &HeaderMap::new()).unwrap();
+            assert_eq!(plan.cache_key.base_path(), "/media/foo.txt", "{}", uri);
+            assert!(plan.upstream_url.starts_with("http://upstream.test/media/foo.txt"), "{}", uri);
+        }
+        
+        // One upstream request serves every spelling
+        for (i, uri) in variants[..4].iter().enumerate() {
+            let response = get(&state, uri, "*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), if i == 0 { "MISS" } else { "HIT" });
+        }
+        assert_eq!(fetcher.requests(), vec!["http://upstream.test/media/foo.txt"]);
+        
+        // Upstreams telling them apart get the paths as requested
+        let mut config = mock_config();
+        config.upstream.normalize_slashes = false;
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "x")));
+        get(&state, "/media//foo.txt", "*/*").await;
+        get(&state, "/media/foo.txt/", "*/*").await;
+        assert_eq!(fetcher.requests(), vec!["http://upstream.test/media//foo.txt", "http://upstream.test/media/foo.txt/"]);
+    }
+    
+    #[test]
+    fn test_parse_query_for_format() {
+        // Test format=avif
+        let (format, remaining) = parse_query_for_format("format=avif&other=value");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "other=value");
+        
+        // Test format=webp
+        let (format, remaining) = parse_query_for_format("format=webp");
+        assert_eq!(format, Some(OutputFormat::WebP));
+        assert_eq!(remaining, "");
+        
+        // Test no format parameter
+        let (format, remaining) = parse_query_for_format("other=value&another=test");
+        assert_eq!(format, None);
+        assert_eq!(remaining, "other=value&another=test");
+        
+        // Test format with unknown value
+        let (format, remaining) = parse_query_for_format("format=jpeg&other=value");
+        assert_eq!(format, None);
+        assert_eq!(remaining, "other=value");
+        
+        // Test format in middle
+        let (format, remaining) = parse_query_for_format("a=1&format=avif&b=2");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "a=1&b=2");
+        
+        // Test format with URL encoding (spaces as +)
+        let (format, remaining) = parse_query_for_format("format=webp+test&other=value");
+        assert_eq!(format, None); // Should not match due to extra text
+        assert_eq!(remaining, "other=value");
+        
+        // Test format=original
+        let (format, remaining) = parse_query_for_format("format=original&other=value");
+        assert_eq!(format, Some(OutputFormat::Original));
+        assert_eq!(remaining, "other=value");
+        
+        // Test format with case insensitivity
+        let (format, remaining) = parse_query_for_format("format=AVIF");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "");
+        
+        let (format, remaining) = parse_query_for_format("format=WebP");
+        assert_eq!(format, Some(OutputFormat::WebP));
+        assert_eq!(remaining, "");
+        
+        // Test format with whitespace (+ is space in query strings)
+        let (format, remaining) = parse_query_for_format("format=+avif+&other=value");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "other=value");
+        
+        // Test percent-encoded values and keys
+        let (format, remaining) = parse_query_for_format("format=%61vif&other=a%20b");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "other=a%20b");
+        
+        let (format, remaining) = parse_query_for_format("%66ormat=WEB%50&x=%2F");
+        assert_eq!(format, Some(OutputFormat::WebP));
+        assert_eq!(remaining, "x=%2F");
+        
+        // Test duplicates: the first occurrence wins and all are removed
+        let (format, remaining) = parse_query_for_format("format=avif&a=1&format=webp");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "a=1");
+        
+        let (format, remaining) = parse_query_for_format("format=gif&format=webp");
+        assert_eq!(format, None);
+        assert_eq!(remaining, "");
+        
+        // Test quoted values, byte-order marks and media type prefixes
+        let (format, remaining) = parse_query_for_format("format=%22avif%22&other=%22kept%22");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        assert_eq!(remaining, "other=%22kept%22");
+        
+        let (format, _) = parse_query_for_format("format='webp'");
+        assert_eq!(format, Some(OutputFormat::WebP));
+        
+        let (format, _) = parse_query_for_format("format=%EF%BB%BFavif");
+        assert_eq!(format, Some(OutputFormat::Avif));
+        
+        let (format, _) = parse_query_for_format("format=image%2Fwebp");
+        assert_eq!(format, Some(OutputFormat::WebP));
+        
+        let (format, _) = parse_query_for_format("format=data:image/avif");
+        assert_eq!(format, Some(OutputFormat::Avif));
+    }
+    
+    #[test]
+    fn test_parse_query_for_static() {
+        let (static_frame, remaining) = parse_query_for_static("frame=first&other=value");
+        assert!(static_frame);
+        assert_eq!(remaining, "other=value");
+        
+        let (static_frame, remaining) = parse_query_for_static("a=1&static=1");
+        assert!(static_frame);
+        assert_eq!(remaining, "a=1");
+        
+        // Unrecognized values are stripped but do not request a static frame
+        let (static_frame, remaining) = parse_query_for_static("frame=last&static=0");
+        assert!(!static_frame);
+        assert_eq!(remaining, "");
+        
+        let (static_frame, remaining) = parse_query_for_static("other=value&debug");
+        assert!(!static_frame);
+        assert_eq!(remaining, "other=value&debug");
+    }
+    
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_static_frame_of_animated_gif() {
+        let gif = encode_gif(3);
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("image/gif", gif.clone())),
+        );
+        
+        // Plain URL keeps the animation untouched
+        let response = get(&state, "/media/anim.gif", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/gif");
+        assert_eq!(body_bytes(response).await.as_ref(), gif.as_slice());
+        
+        // First frame is converted to the negotiated format
+        let response = get(&state, "/media/anim.gif?frame=first", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(!is_animated(&body_bytes(response).await));
+        
+        let response = get(&state, "/media/anim.gif?static=1", "image/avif,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        
+        // The marker never reaches upstream
+        assert!(fetcher.requests().iter().all(|url| url == "http://upstream.test/media/anim.gif"));
+        
+        // Static inputs behave as if the parameter were absent
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("image/gif", encode_gif(1))),
+        );
+        let response = get(&state, "/media/still.gif?frame=first", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+    }
+    
+    #[tokio::test]
+    async fn test_format_original_query_skips_conversion() {
+        let jpeg = encode_jpeg();
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("image/jpeg", jpeg.clone())),
+        );
+        
+        let response = get(&state, "/media/a.jpg?format=original", "image/avif,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(body_bytes(response).await.as_ref(), jpeg.as_slice());
+        
+        let response = get(&state, "/media/a.jpg?format=original", "image/avif,*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(body_bytes(response).await.as_ref(), jpeg.as_slice());
+        assert_eq!(fetcher.requests(), vec!["http://upstream.test/media/a.jpg"]);
+    }
+    
+    #[tokio::test]
+    async fn test_no_convert_header_requires_admin_token() {
+        let jpeg = encode_jpeg();
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.server.no_convert_bypass_cache = true;
+        let (state, _) = mock_state(
+            config,
+            MockFetcher::always(MockResponse::ok("image/jpeg", jpeg.clone())),
+        );
+        
+        let request = |token: &str| {
+            Request::builder()
+                .uri("/media/a.jpg")
+                .header(header::ACCEPT, "image/webp,*/*")
+                .header(X_AKKOPROXY_NO_CONVERT, "1")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        
+        // Without a valid token the header is ignored
+        let response = send(&state, request("wrong")).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        
+        // With the token the original is served and the cache is bypassed
+        for _ in 0..2 {
+            let response = send(&state, request("secret")).await;
+            assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "BYPASS");
+            assert_eq!(body_bytes(response).await.as_ref(), jpeg.as_slice());
+        }
+    }
+    
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secre", "secret"));
+        assert!(!tokens_match("secret2", "secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+    
+    /// Accept a single raw TCP connection, write `head` after `delay`, then stall
+    async fn spawn_stalling_upstream(delay: Duration, head: &'static str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            tokio::time::sleep(delay).await;
+            socket.write_all(head.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+        format!("http://{}", addr)
+    }
+    
+    #[tokio::test]
+    async fn test_upstream_header_timeout() {
+        let upstream = spawn_stalling_upstream(Duration::from_secs(60), "").await;
+        let mut config = Config::with_upstream(upstream);
+        config.upstream.header_timeout = Some(1);
+        let state = AppState::new(config);
+        
+        let response = get(&state, "/media/slow.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+    
+    #[tokio::test]
+    async fn test_upstream_body_idle_timeout() {
+        let upstream = spawn_stalling_upstream(
+            Duration::ZERO,
+            "HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 100\r\n\r\npartial",
+        )
+        .await;
+        let mut config = Config::with_upstream(upstream);
+        config.upstream.header_timeout = Some(1);
+        config.upstream.body_idle_timeout = Some(1);
+        let state = AppState::new(config);
+        
+        let response = get(&state, "/media/stalled.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+    
+    #[tokio::test]
+    async fn test_client_disconnect_stops_upstream_transfer() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        
+        // Upstream trickles a large body and records how much it managed to send
+        let sent = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = format!("http://{}", listener.local_addr().unwrap());
+        let sent_by_upstream = sent.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            // Small enough to be buffered rather than streamed
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: 5000000\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            let chunk = [0u8; 1024];
+            while socket.write_all(&chunk).await.is_ok() {
+                sent_by_upstream.fetch_add(chunk.len(), Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        
+        let state = AppState::new(Config::with_upstream(upstream));
+        let client_state = state.clone();
+        let client = tokio::spawn(async move { get(&client_state, "/media/big.jpg", "*/*").await });
+        
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.abort();
+        let _ = client.await;
+        assert_eq!(Metrics::get(&state.metrics.cancelled_requests), 1);
+        
+        // Once the connection is torn down the upstream can no longer send
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let after_abort = sent.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(after_abort > 0);
+        assert_eq!(sent.load(Ordering::SeqCst), after_abort);
+    }
+    
+    #[tokio::test]
+    async fn test_non_success_status_forwarded() {
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with(
+                    "/media/moved.jpg",
+                    MockResponse::ok("text/html", "moved")
+                        .status(StatusCode::FOUND)
+                        .header(header::LOCATION, "https://elsewhere.test/a.jpg"),
+                )
+                .with(
+                    "/media/missing.jpg",
+                    MockResponse::ok("text/plain", "not found").status(StatusCode::NOT_FOUND),
+                ),
+        );
+        
+        let response = get(&state, "/media/moved.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "https://elsewhere.test/a.jpg");
+        
+        let response = get(&state, "/media/missing.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_bytes(response).await.as_ref(), b"not found");
+        
+        // Fetch failures surface as 502
+        let response = get(&state, "/media/unknown.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+    
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_conversion_follows_accept_header() {
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        
+        let response = get(&state, "/media/a.jpg", "image/avif,image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        
+        let response = get(&state, "/media/a.jpg", "*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        
+        // Non-media paths never reach upstream
+        let response = get(&state, "/api/v1/accounts", "*/*").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    
+    #[tokio::test]
+    async fn test_user_agent_overrides() {
+        use crate::config::{ForcedFormat, UaOverride};
+        
+        let mut config = mock_config();
+        config.image.ua_overrides = vec![
+            UaOverride { name: "apps".to_string(), pattern: "Tusky".to_string(), format: ForcedFormat::Webp },
+            UaOverride { name: "frames".to_string(), pattern: "OldFrame".to_string(), format: ForcedFormat::Original },
+        ];
+        config.validate().unwrap();
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let request = |user_agent: &str, accept: &str| {
+            Request::builder()
+                .uri("/media/a.jpg")
+                .header(header::USER_AGENT, user_agent)
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let cached = |format: &str| CacheKey::new("/media/a.jpg".to_string(), format.to_string());
+        
+        // A matching app gets WebP for */*, cached under the rule's name
+        let response = send(&state, request("Tusky/24.1 Android", "*/*")).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(!response.headers().get(header::VARY).unwrap().to_str().unwrap().contains("User-Agent"));
+        assert!(state.cache.get(&cached("WebP:ua=apps")).await.is_some());
+        
+        // Other clients are negotiated by Accept as usual
+        let response = send(&state, request("Mozilla/5.0", "*/*")).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        
+        // Matching is case-insensitive, and a rule agreeing with Accept leaves the key alone
+        let response = send(&state, request("tusky/25", "image/webp,*/*")).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(state.cache.get(&cached("WebP")).await.is_some());
+        
+        // Overrides win over an explicit Accept preference
+        let response = send(&state, request("OldFrame/1.0", "image/webp,*/*")).await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert!(state.cache.get(&cached("Original:ua=frames")).await.is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_image_wildcard_negotiation() {
+        let mut config = mock_config();
+        config.image.enable_avif = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let cached = |format: &str| CacheKey::new("/media/a.jpg".to_string(), format.to_string());
+        
+        // Any image will do, so the best enabled format is converted to and cached under its name
+        let response = get(&state, "/media/a.jpg", "image/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(state.cache.get(&cached("WebP")).await.is_some());
+        let response = get(&state, "/media/a.jpg", "image/*,*/*;q=0.8").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        
+        // No preference keeps the original
+        let response = get(&state, "/media/a.jpg", "*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert!(state.cache.get(&cached("Original")).await.is_some());
+        
+        let mut config = mock_config();
+        config.image.treat_image_wildcard_as_auto = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = get(&state, "/media/a.jpg", "image/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert!(state.cache.get(&cached("Original")).await.is_some());
+    }
+    
+    #[tokio::test]
+    async fn test_no_accept_behavior() {
+        let best = if crate::image::AVIF_COMPILED { OutputFormat::Avif } else { OutputFormat::WebP };
+        let cases = [
+            (NoAcceptBehavior::Original, OutputFormat::Original),
+            (NoAcceptBehavior::DefaultFormat, OutputFormat::WebP),
+            (NoAcceptBehavior::TreatAsAvifCapable, best),
+        ];
+        for (behavior, absent) in cases {
+            let mut config = mock_config();
+            config.image.no_accept_behavior = behavior;
+            let (state, _) = mock_state(config, MockFetcher::default());
+            let plan = |accept: Option<&str>| {
+                let mut headers = HeaderMap::new();
+                if let Some(accept) = accept {
+                    headers.insert(header::ACCEPT, HeaderValue::from_str(accept).unwrap());
+                }
+                plan_request(&state, &"/media/a.jpg".parse().unwrap(), &headers).unwrap()
+            };
+            
+            // Only a missing header follows the policy, and the key names the outcome
+            let missing = plan(None);
+            assert_eq!(missing.desired_format, absent, "{:?}", behavior);
+            assert_eq!(missing.cache_key, CacheKey::new("/media/a.jpg".to_string(), format!("{:?}", absent)));
+            assert_eq!(plan(Some("*/*")).desired_format, OutputFormat::Original, "{:?}", behavior);
+            assert_eq!(plan(Some("image/webp,*/*")).desired_format, OutputFormat::WebP, "{:?}", behavior);
+        }
+        
+        // A disabled default format serves the original
+        let mut config = mock_config();
+        config.image.no_accept_behavior = NoAcceptBehavior::DefaultFormat;
+        config.image.no_accept_format = ForcedFormat::Webp;
+        config.image.enable_webp = false;
+        let (state, _) = mock_state(config, MockFetcher::default());
+        let plan = plan_request(&state, &"/media/a.jpg".parse().unwrap(), &HeaderMap::new()).unwrap();
+        assert_eq!(plan.desired_format, OutputFormat::Original);
+        
+        // Requests without the header share the entry of browsers getting the same format
+        let mut config = mock_config();
+        config.image.no_accept_behavior = NoAcceptBehavior::TreatAsAvifCapable;
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = get(&state, "/media/a.jpg", "image/avif,image/webp,*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        let response = send(&state, Request::builder().uri("/media/a.jpg").body(Body::empty()).unwrap()).await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(fetcher.requests().len(), 1);
+    }
+    
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_extension_consistency() {
+        let png = encode(image::RgbImage::from_pixel(8, 8, image::Rgb([1, 2, 3])), image::ImageFormat::Png);
+        let fetcher = || MockFetcher::always(MockResponse::ok("image/png", png.clone()));
+        
+        // By default the path's extension plays no part
+        let (state, _) = mock_state(mock_config(), fetcher());
+        let response = get(&state, "/media/a.png", "image/avif").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        assert!(response.headers().get(header::CONTENT_DISPOSITION).is_none());
+        
+        // no_convert keeps the PNG the extension promises
+        let mut config = mock_config();
+        config.server.extension_consistency = ExtensionConsistency::NoConvert;
+        let (state, _) = mock_state(config, fetcher());
+        let response = get(&state, "/media/a.png", "image/avif").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        assert_eq!(body_bytes(response).await.as_ref(), png.as_slice());
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::ExtensionMismatch), 1);
+        
+        // Unless the client refuses that format, or the extension names none
+        let response = get(&state, "/media/b.png", "image/avif,image/png;q=0").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        let response = get(&state, "/media/c", "image/avif").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        
+        // rewrite_disposition converts and names the served format for downloads
+        let mut config = mock_config();
+        config.server.extension_consistency = ExtensionConsistency::RewriteDisposition;
+        let (state, _) = mock_state(config, fetcher());
+        let response = get(&state, "/media/dir/a.png", "image/avif").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        assert_eq!(response.headers().get(header::CONTENT_DISPOSITION).unwrap(), "inline; filename=\"a.avif\"");
+        let response = get(&state, "/media/dir/a.png", "image/avif").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(response.headers().get(header::CONTENT_DISPOSITION).unwrap(), "inline; filename=\"a.avif\"");
+        let response = get(&state, "/media/dir/a.png", "*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        assert!(response.headers().get(header::CONTENT_DISPOSITION).is_none());
+    }
+    
+    #[cfg(feature = "avif")]
+    #[tokio::test]
+    async fn test_health_responsive_during_conversions() {
+        let (state, _) = mock_state(mock_config(), MockFetcher::always(MockResponse::ok("image/jpeg", encode_noise_jpeg(160))));
+        
+        // Encodes run on the blocking pool, so even this single-threaded runtime keeps answering
+        let conversions: Vec<_> = (0..2)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move { get(&state, &format!("/media/{}.jpg", i), "image/avif").await })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let started = Instant::now();
+        assert_eq!(get(&state, "/health", "*/*").await.status(), StatusCode::OK);
+        assert!(started.elapsed() < Duration::from_millis(500), "{:?}", started.elapsed());
+        assert!(conversions.iter().any(|conversion| !conversion.is_finished()));
+        
+        for conversion in conversions {
+            assert_eq!(conversion.await.unwrap().headers()[header::CONTENT_TYPE], "image/avif");
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_health_reports_encoder_self_test() {
+        let mut config = mock_config();
+        config.image.enable_webp = false;
+        let (state, _) = mock_state(config, MockFetcher::default());
+        
+        let response = get(&state, "/health", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(body_bytes(response).await.to_vec()).unwrap();
+        let mut lines = body.lines();
+        assert_eq!(lines.next(), Some("OK"));
+        let avif = if crate::image::AVIF_COMPILED { "ok" } else { "failed (AVIF support was not compiled in)" };
+        assert_eq!(lines.next(), Some(format!("encoder_avif: {}", avif).as_str()));
+        assert_eq!(lines.next(), Some("encoder_webp: disabled"));
+        assert_eq!(state.config.image.enable_avif, crate::image::AVIF_COMPILED);
+    }
+    
+    #[tokio::test]
+    async fn test_root_redirect_headers() {
+        let upstream = spawn_upstream(encode_jpeg(), "image/jpeg").await;
+        let state = AppState::new(Config::with_upstream(upstream));
+        
+        let response = get(&state, "/", "*/*").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        let headers = response.headers();
+        assert_eq!(headers.get(header::LOCATION).unwrap(), "https://github.com/BlockG-ws/akkoproxy");
+        assert_eq!(headers.get(header::VIA).unwrap(), state.config.server.via_header.as_str());
+        assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "public, max-age=86400");
+        assert_eq!(headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        
+        // Proxied paths are unaffected
+        let response = get(&state, "/media/a.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        
+        let response = get(&state, "/other", "*/*").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    
+    #[tokio::test]
+    async fn test_root_status_json_for_json_clients() {
+        let mut config = mock_config();
+        config.image.enable_avif = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        get(&state, "/media/a.jpg", "*/*").await;
+        state.cache.run_pending_tasks().await;
+        
+        let response = get(&state, "/", "application/json").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
+        let status: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(status["cache_entries"], 1);
+        assert_eq!(status["upstream_host"], "upstream.test");
+        assert_eq!(status["formats"], serde_json::json!({ "avif": false, "webp": true }));
+        assert_eq!(status["conversions"], "active");
+        assert!(status["uptime_seconds"].is_u64());
+        
+        // Browsers, wildcards and clients ranking HTML first are still redirected
+        for accept in ["text/html,application/xhtml+xml,*/*;q=0.8", "*/*", "text/html, application/json;q=0.9", ""] {
+            let response = get(&state, "/", accept).await;
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY, "{}", accept);
+            assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
+        }
+        
+        let mut config = mock_config();
+        config.server.root_json = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = get(&state, "/", "application/json").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+    }
+    
+    #[test]
+    fn test_should_convert_image() {
+        let convert = |content_type, upstream, desired, size| {
+            should_convert_image(content_type, upstream, desired, size, 100, 1000)
+        };
+        
+        assert_eq!(convert("image/jpeg", Some(OutputFormat::Jpeg), OutputFormat::Avif, 500), Ok(()));
+        assert_eq!(convert("image/gif", None, OutputFormat::WebP, 500), Ok(()));
+        assert_eq!(
+            convert("text/html", None, OutputFormat::Avif, 500),
+            Err(SkipReason::NotImage)
+        );
+        assert_eq!(
+            convert("image/avif", Some(OutputFormat::Avif), OutputFormat::Avif, 500),
+            Err(SkipReason::FormatSatisfied)
+        );
+        assert_eq!(
+            convert("image/jpeg", Some(OutputFormat::Jpeg), OutputFormat::Original, 500),
+            Err(SkipReason::FormatSatisfied)
+        );
+        assert_eq!(
+            convert("image/jpeg", Some(OutputFormat::Jpeg), OutputFormat::Avif, 1001),
+            Err(SkipReason::TooLarge)
+        );
+        assert_eq!(
+            convert("image/jpeg", Some(OutputFormat::Jpeg), OutputFormat::Avif, 99),
+            Err(SkipReason::BelowMinSize)
+        );
+    }
+    
+    #[tokio::test]
+    async fn test_large_bodies_streamed() {
+        let video = vec![7u8; 5000];
+        let mut config = mock_config();
+        config.cache.max_item_size = 1000;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default()
+                .with("/media/clip.mp4", MockResponse::ok("video/mp4", video.clone()))
+                .with("/media/sized.mp4", MockResponse::ok("video/mp4", video.clone()).header(header::CONTENT_LENGTH, "5000"))
+                .with("/media/small.mp4", MockResponse::ok("video/mp4", vec![7u8; 1000])),
+        );
+        
+        // Found too large while reading, or announced so by Content-Length
+        for path in ["/media/clip.mp4", "/media/sized.mp4"] {
+            for _ in 0..2 {
+                let response = get(&state, path, "*/*").await;
+                assert_eq!(response.status(), StatusCode::OK);
+                assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "video/mp4");
+                assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS; streamed");
+                assert_eq!(body_bytes(response).await, video);
+            }
+        }
+        assert_eq!(fetcher.requests().len(), 4);
+        assert_eq!(Metrics::get(&state.metrics.streamed_responses), 4);
+        
+        // Bodies within the cache limit are still buffered and cached
+        assert_eq!(get(&state, "/media/small.mp4", "*/*").await.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(get(&state, "/media/small.mp4", "*/*").await.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+    }
+    
+    #[tokio::test]
+    async fn test_streamed_body_spooled_ahead_of_stalled_client() {
+        let video = vec![7u8; 50_000];
+        let setup = |stream_spool_size: u64| {
+            let mut config = mock_config();
+            config.cache.max_item_size = 1000;
+            config.server.stream_spool_size = stream_spool_size;
+            mock_state(config, MockFetcher::always(MockResponse::ok("video/mp4", video.clone()).chunked(1000)))
+        };
+        
+        // The client reads nothing, upstream is still read to its end
+        let (state, fetcher) = setup(1024 * 1024);
+        let response = get(&state, "/media/clip.mp4", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS; streamed");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(fetcher.completed_bodies(), 1);
+        assert_eq!(body_bytes(response).await, video);
+        
+        // A full spool holds upstream back until the client catches up
+        let (state, fetcher) = setup(10_000);
+        let response = get(&state, "/media/clip.mp4", "*/*").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(fetcher.completed_bodies(), 0);
+        assert_eq!(body_bytes(response).await, video);
+        assert_eq!(fetcher.completed_bodies(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_size_limits_split_between_conversion_and_cache() {
+        // Lossless WebP of noise comes out larger than its JPEG source
+        let jpeg = encode_noise_jpeg(48);
+        let size = jpeg.len() as u64;
+        let setup = |max_convert_input_size: u64, max_item_size: u64| {
+            let mut config = mock_config();
+            config.image.max_convert_input_size = Some(max_convert_input_size);
+            config.cache.max_item_size = max_item_size;
+            mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg.clone())))
+        };
+        let fetch = |state: AppState| async move {
+            let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+            let content_type = response.headers().get(header::CONTENT_TYPE).unwrap().clone();
+            let cache_status = response.headers().get(X_CACHE_STATUS).unwrap().clone();
+            let body = body_bytes(response).await;
+            (content_type, cache_status, body.len() as u64)
+        };
+        
+        // Source convertible, output cacheable: converted once and served from the cache
+        let (state, fetcher) = setup(size, 10 * 1024 * 1024);
+        let (content_type, _, webp_size) = fetch(state.clone()).await;
+        assert_eq!(content_type, "image/webp");
+        assert!(webp_size > size, "{} <= {}", webp_size, size);
+        assert_eq!(fetch(state.clone()).await.1, "HIT");
+        assert_eq!(fetcher.requests().len(), 1);
+        
+        // Source convertible, output too large to cache: converted once, then the original is served and cached
+        let (state, fetcher) = setup(size, size);
+        let (content_type, cache_status, _) = fetch(state.clone()).await;
+        assert_eq!((content_type.to_str().unwrap(), cache_status.to_str().unwrap()), ("image/webp", "MISS"));
+        assert!(state.memos.contains("/media/a.jpg", DecisionMemo::Uncacheable(OutputFormat::WebP)));
+        let (content_type, cache_status, _) = fetch(state.clone()).await;
+        assert_eq!((content_type.to_str().unwrap(), cache_status.to_str().unwrap()), ("image/jpeg", "MISS"));
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::Uncacheable), 1);
+        let (content_type, cache_status, _) = fetch(state.clone()).await;
+        assert_eq!((content_type.to_str().unwrap(), cache_status.to_str().unwrap()), ("image/jpeg", "HIT"));
+        assert_eq!(fetcher.requests().len(), 2);
+        
+        // Source too large to convert but cacheable: served and cached as is
+        let (state, _) = setup(size - 1, size);
+        let (content_type, _, _) = fetch(state.clone()).await;
+        assert_eq!(content_type, "image/jpeg");
+        assert_eq!(fetch(state.clone()).await.1, "HIT");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::TooLarge), 1);
+        assert!(state.metrics.render().contains("conversion_skipped_total{reason=\"too_large\"} 1"));
+        
+        // Source too large to convert and to cache: streamed through every time
+        let (state, fetcher) = setup(size - 1, size - 1);
+        assert_eq!(fetch(state.clone()).await.1, "MISS; streamed");
+        assert_eq!(fetch(state.clone()).await.1, "MISS; streamed");
+        assert_eq!(fetcher.requests().len(), 2);
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::TooLarge), 2);
+        
+        // A lossless output of a source over the cache limit is not even attempted
+        let (state, _) = setup(10 * size, size - 1);
+        assert_eq!(fetch(state.clone()).await.0, "image/jpeg");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::Uncacheable), 1);
+        assert!(!state.memos.contains("/media/a.jpg", DecisionMemo::Uncacheable(OutputFormat::WebP)));
+    }
+    
+    #[tokio::test]
+    async fn test_mislabeled_upstream_body_not_cached() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with("/media/oops.jpg", MockResponse::ok("image/jpeg", "<html>oops</html>"))
+                .with("/media/empty.png", MockResponse::ok("image/png", "")),
+        );
+        
+        for _ in 0..2 {
+            let response = get(&state, "/media/oops.jpg", "image/avif,*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "BYPASS");
+            assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+            assert_eq!(body_bytes(response).await.as_ref(), b"<html>oops</html>");
+            
+            let response = get(&state, "/media/empty.png", "image/avif,*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "BYPASS");
+        }
+        
+        assert_eq!(state.cache.stats().entry_count, 0);
+        assert_eq!(fetcher.requests().len(), 4);
+        assert_eq!(Metrics::get(&state.metrics.mislabeled_upstream), 4);
+    }
+    
+    #[tokio::test]
+    async fn test_variant_limits_strict_and_lax() {
+        let jpeg = encode_jpeg();
+        
+        // Lax mode serves the original when the source exceeds the pixel limit
+        let mut config = mock_config();
+        config.image.max_pixels = Some(63);
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg.clone())));
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(body_bytes(response).await.as_ref(), jpeg.as_slice());
+        
+        // Strict mode explains the limit instead
+        let mut config = mock_config();
+        config.image.max_pixels = Some(63);
+        config.server.strict_variant_errors = true;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg.clone())));
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["limit"], "image.max_pixels");
+        assert_eq!(body["max"], 63);
+        assert_eq!(body["actual"], 64);
+        
+        // Sources within the limit are unaffected in strict mode, and so are
+        // requests that need no conversion
+        let response = get(&state, "/media/a.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut config = mock_config();
+        config.image.max_pixels = Some(64);
+        config.server.strict_variant_errors = true;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg)));
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+    }
+    
+    #[tokio::test]
+    async fn test_status_policy_caching() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with(
+                    "/media/temp.jpg",
+                    MockResponse::ok("text/html", "found")
+                        .status(StatusCode::FOUND)
+                        .header(header::LOCATION, "https://elsewhere.test/a.jpg"),
+                )
+                .with(
+                    "/media/gone.jpg",
+                    MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND),
+                ),
+        );
+        
+        // 302 is never cached
+        for _ in 0..2 {
+            let response = get(&state, "/media/temp.jpg", "*/*").await;
+            assert_eq!(response.status(), StatusCode::FOUND);
+            assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+        }
+        assert_eq!(fetcher.requests().len(), 2);
+        
+        // 404 is negatively cached
+        for _ in 0..2 {
+            let response = get(&state, "/media/gone.jpg", "*/*").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "public, max-age=60");
+            assert_eq!(body_bytes(response).await.as_ref(), b"gone");
+        }
+        assert_eq!(fetcher.requests().len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_format_stats_endpoint() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, _) = mock_state(
+            config,
+            MockFetcher::default()
+                .with("/media/small.txt", MockResponse::ok("text/plain; charset=utf-8", vec![b'a'; 100]))
+                .with("/media/large.txt", MockResponse::ok("text/plain", vec![b'a'; 10_000])),
+        );
+        
+        for uri in ["/media/small.txt", "/media/small.txt", "/media/large.txt"] {
+            get(&state, uri, "*/*").await;
+        }
+        
+        let stats_request = |method: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/admin/stats/formats")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = send(&state, stats_request("GET", "wrong")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        
+        let response = send(&state, stats_request("GET", "secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        // The repeated request is a cache hit, counted as served but not fetched
+        let source = &stats["source"]["text/plain"];
+        assert_eq!(source["count"], 2);
+        assert_eq!(source["total_bytes"], 10_100);
+        assert_eq!(source["buckets"][0]["count"], 1);
+        assert_eq!(source["buckets"][2]["count"], 1);
+        let served = &stats["served"]["text/plain"];
+        assert_eq!(served["count"], 3);
+        assert_eq!(served["buckets"][0]["count"], 2);
+        assert_eq!(served["p50"], 1024);
+        
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains("served_body_size_bytes_bucket{content_type=\"text/plain\",le=\"1024\"} 2\n"));
+        
+        let response = send(&state, stats_request("POST", "secret")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(&state, stats_request("GET", "secret")).await;
+        let stats: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(stats["source"], serde_json::json!({}));
+    }
+    
+    #[tokio::test]
+    async fn test_content_type_overrides() {
+        use crate::config::ContentTypeOverride;
+        
+        let pixels = image::RgbImage::from_pixel(4, 4, image::Rgb([1, 2, 3]));
+        let webp = encode(pixels.clone(), image::ImageFormat::WebP);
+        let png = encode(pixels, image::ImageFormat::Png);
+        
+        let mut config = mock_config();
+        config.server.content_type_overrides = vec![
+            ContentTypeOverride {
+                match_content_type: Some("image/PNG".to_string()),
+                match_extension: None,
+                override_to: "image/webp".to_string(),
+            },
+            ContentTypeOverride {
+                match_content_type: None,
+                match_extension: Some(".webp".to_string()),
+                override_to: "image/webp".to_string(),
+            },
+        ];
+        config.validate().unwrap();
+        let (state, _) = mock_state(
+            config,
+            MockFetcher::default()
+                .with("/media/mislabeled.png", MockResponse::ok("image/png", webp.clone()))
+                .with("/media/real.png", MockResponse::ok("image/png", png.clone()))
+                .with("/media/unlabeled.WEBP", MockResponse::ok("application/octet-stream", webp.clone())),
+        );
+        
+        // The body agrees with the rule, the correction is served and cached
+        for expected in ["MISS", "HIT"] {
+            let response = get(&state, "/media/mislabeled.png", "*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), expected);
+            assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        }
+        
+        // A real PNG matches the same rule but is left alone
+        let response = get(&state, "/media/real.png", "*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        assert_eq!(body_bytes(response).await.as_ref(), png.as_slice());
+        
+        // Rules may match on the path extension alone
+        let response = get(&state, "/media/unlabeled.WEBP", "*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        
+        assert_eq!(Metrics::get(&state.metrics.content_type_overrides), 2);
+        assert_eq!(Metrics::get(&state.metrics.content_type_overrides_refused), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_cache_pressure_gauges() {
+        let mut config = mock_config();
+        config.cache.max_capacity = 2;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "churn")));
+        
+        for i in 0..6 {
+            get(&state, &format!("/media/{}.txt", i), "*/*").await;
+        }
+        state.cache.run_pending_tasks().await;
+        
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains("cache_inserts_total 6\n"), "{}", metrics);
+        assert!(metrics.contains("cache_evictions_total 4\n"), "{}", metrics);
+        assert!(metrics.contains("cache_utilization_percent 100.0\n"), "{}", metrics);
+        assert!(metrics.contains("cache_pressure_ratio 0.000\n"), "{}", metrics);
+    }
+    
+    #[tokio::test]
+    async fn test_media_headers_forwarded_without_preserving_upstream_headers() {
+        let with_media_headers = |response: MockResponse| {
+            response
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+                .header(header::CONTENT_DISPOSITION, "inline; filename=\"clip.mp4\"")
+                .header(HeaderName::from_static("x-content-duration"), "12.5")
+                .header(HeaderName::from_static("x-custom-header"), "custom-value")
+        };
+        let mut config = mock_config();
+        config.server.preserve_upstream_headers = false;
+        config.server.always_forward_headers = vec!["X-Content-Duration".to_string()];
+        config.validate().unwrap();
+        let fetcher = MockFetcher::default()
+            .with("/media/clip.mp4", with_media_headers(MockResponse::ok("video/mp4", "not really a video")))
+            .with("/media/photo.jpg", with_media_headers(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let (state, _) = mock_state(config, fetcher);
+        
+        // Passed-through bodies keep the media headers, on the miss and on later hits
+        for cache_status in ["MISS", "HIT"] {
+            let response = get(&state, "/media/clip.mp4", "*/*").await;
+            let headers = response.headers();
+            assert_eq!(headers[X_CACHE_STATUS], cache_status);
+            assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+            assert_eq!(headers[header::LAST_MODIFIED], "Wed, 21 Oct 2015 07:28:00 GMT");
+            assert_eq!(headers[header::CONTENT_DISPOSITION], "inline; filename=\"clip.mp4\"");
+            assert_eq!(headers["x-content-duration"], "12.5");
+            assert!(!headers.contains_key("x-custom-header"));
+        }
+        
+        // Converted bodies are a different file, so none of them apply
+        let response = get(&state, "/media/photo.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        for name in ["accept-ranges", "content-disposition", "x-content-duration", "x-custom-header"] {
+            assert!(!response.headers().contains_key(name), "{}", name);
+        }
+        // The proxy dates the converted file itself
+        assert_ne!(response.headers()[header::LAST_MODIFIED], "Wed, 21 Oct 2015 07:28:00 GMT");
+        
+        let mut config = mock_config();
+        config.server.always_forward_headers = vec!["not a header".to_string()];
+        assert!(config.validate().is_err());
+    }
+    
+    #[tokio::test]
+    async fn test_demanded_legacy_formats() {
+        let photo = encode_jpeg();
+        let logo = encode(image::RgbaImage::from_pixel(8, 8, image::Rgba([10, 200, 10, 0])), image::ImageFormat::Png);
+        let logo_webp = encode(image::RgbaImage::from_pixel(8, 8, image::Rgba([10, 200, 10, 0])), image::ImageFormat::WebP);
+        let fetcher = MockFetcher::default()
+            .with("/media/photo.jpg", MockResponse::ok("image/jpeg", photo.clone()))
+            .with("/media/logo.png", MockResponse::ok("image/png", logo.clone()))
+            .with("/media/logo.webp", MockResponse::ok("image/webp", logo_webp));
+        let (state, _) = mock_state(mock_config(), fetcher);
+
+        // PNG demanded from a JPEG source is decoded once and encoded losslessly
+        let response = get(&state, "/media/photo.jpg", "image/png").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let direct = encode(image::load_from_memory(&photo).unwrap(), image::ImageFormat::Png);
+        assert_eq!(body_bytes(response).await.len(), direct.len());
+
+        // JPEG demanded from a transparent PNG keeps the source rather than flattening it
+        for cache_status in ["MISS", "HIT"] {
+            let response = get(&state, "/media/logo.png", "image/jpeg").await;
+            assert_eq!(response.headers()[X_CACHE_STATUS], cache_status);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+            assert_eq!(response.headers()[header::WARNING], ALPHA_KEPT_WARNING);
+            assert_eq!(body_bytes(response).await, logo);
+        }
+
+        // Other transparent sources are converted to PNG instead of JPEG
+        let response = get(&state, "/media/logo.webp", "image/jpeg").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(response.headers()[header::WARNING], ALPHA_KEPT_WARNING);
+        let decoded = image::load_from_memory(&body_bytes(response).await).unwrap();
+        assert_eq!(decoded.to_rgba8().get_pixel(0, 0)[3], 0);
+
+        // The demanded format, or one accepted as much, is passed through untouched
+        for accept in ["image/jpeg", "image/png, image/jpeg"] {
+            let response = get(&state, "/media/photo.jpg", accept).await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg", "{}", accept);
+            assert!(!response.headers().contains_key(header::WARNING));
+            assert_eq!(body_bytes(response).await, photo, "{}", accept);
+        }
+
+        // Clients demanding only PNG never get the passed-through JPEG from the cache
+        let response = get(&state, "/media/photo.jpg", "image/png").await;
+        assert_eq!(response.headers()[X_CACHE_STATUS], "HIT");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn test_resizes_counted_by_outcome() {
+        let square = |side: u32| encode(image::RgbImage::from_pixel(side, side, image::Rgb([200, 10, 10])), image::ImageFormat::Png);
+        let mut config = mock_config();
+        config.image.max_dimension = 16;
+        config.image.fast_resize_threshold_pixels = 600;
+        let fetcher = MockFetcher::default()
+            .with("/media/small.png", MockResponse::ok("image/png", square(8)))
+            .with("/media/medium.png", MockResponse::ok("image/png", square(20)))
+            .with("/media/large.png", MockResponse::ok("image/png", square(40)));
+        let (state, _) = mock_state(config, fetcher);
+
+        for path in ["/media/small.png", "/media/medium.png", "/media/large.png"] {
+            let response = get(&state, path, "image/jpeg").await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+            let side = if path == "/media/small.png" { 8 } else { 16 };
+            let decoded = image::load_from_memory(&body_bytes(response).await).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (side, side), "{}", path);
+        }
+
+        // Only the source over the threshold took the two-step path
+        let metrics = &state.metrics;
+        assert_eq!(metrics.resizes(ResizeKind::Skipped), 1);
+        assert_eq!(metrics.resizes(ResizeKind::SingleStep), 1);
+        assert_eq!(metrics.resizes(ResizeKind::TwoStep), 1);
+        assert_eq!(metrics.resize_duration.count(), 2);
+        let rendered = metrics.render();
+        assert!(rendered.contains("image_resizes_total{outcome=\"two_step\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("image_resize_duration_seconds_count 2\n"), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_serve_the_first_stored_body() {
+        let fetcher = MockFetcher::always(MockResponse::ok("text/plain", "first fetch"));
+        fetcher.set_delay(Duration::from_millis(200));
+        let (state, fetcher) = mock_state(mock_config(), fetcher);
+        
+        // The slow miss finishes after a later one has stored a different body; released
+        // as a stuck fetch is, the later one fetches instead of waiting for it
+        let slow = tokio::spawn({
+            let state = state.clone();
+            async move { body_bytes(get(&state, "/media/a.txt", "*/*").await).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.refreshes.clear_path("/media/a.txt"), 1);
+        fetcher.set_delay(Duration::ZERO);
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "second fetch"));
+        let fast = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(fast.headers()[X_CACHE_STATUS], "MISS");
+        assert_eq!(body_bytes(fast).await, "second fetch");
+        
+        // The slow request adopts the stored entry instead of replacing it
+        assert_eq!(slow.await.unwrap(), "second fetch");
+        assert_eq!(fetcher.requests().len(), 2);
+        let hit = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(hit.headers()[X_CACHE_STATUS], "HIT");
+        assert_eq!(body_bytes(hit).await, "second fetch");
+        assert_eq!(state.cache.stats().inserts, 1);
+    }
+    
+    #[tokio::test]
+    async fn test_conversions_shed_under_cpu_pressure() {
+        let mut config = mock_config();
+        config.server.conversion_shed_cpu_percent = Some(90.0);
+        config.server.conversion_resume_cpu_percent = Some(50.0);
+        config.server.conversion_shed_samples = 2;
+        config.validate().unwrap();
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let health = || async { String::from_utf8(body_bytes(get(&state, "/health", "*/*").await).await.to_vec()).unwrap() };
+        
+        // Samples stand in for the background sampler
+        state.conversion_shed.observe(95.0);
+        assert_eq!(get(&state, "/media/a.jpg", "image/webp").await.headers()[header::CONTENT_TYPE], "image/webp");
+        state.conversion_shed.observe(97.0);
+        assert!(health().await.contains("conversions: shed\n"));
+        
+        // Originals are served without being cached, by the proxy or anyone else
+        let response = get(&state, "/media/b.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "SHED");
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(state.cache.get(&CacheKey::new("/media/b.jpg".to_string(), "WebP".to_string())).await.is_none());
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::Shed), 1);
+        // Variants converted before are still served from the cache
+        assert_eq!(get(&state, "/media/a.jpg", "image/webp").await.headers()[header::CONTENT_TYPE], "image/webp");
+        
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains("conversion_shedding 1\nprocess_cpu_percent 97.0\n"), "{}", metrics);
+        
+        // Conversions resume only after enough samples below the lower threshold
+        state.conversion_shed.observe(70.0);
+        state.conversion_shed.observe(40.0);
+        assert_eq!(get(&state, "/media/b.jpg", "image/webp").await.headers()[header::CONTENT_TYPE], "image/jpeg");
+        state.conversion_shed.observe(40.0);
+        let response = get(&state, "/media/b.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "MISS");
+        assert!(health().await.contains("conversions: active\n"));
+    }
+    
+    #[tokio::test]
+    async fn test_error_pages_negotiated() {
+        let template = std::env::temp_dir().join(format!("akkoproxy-error-template-{}.html", std::process::id()));
+        std::fs::write(&template, "<h1>Fehler {{status}}</h1><p>{{code}} {{request_id}}</p>").unwrap();
+        let mut config = mock_config();
+        config.server.error_template_path = Some(template.clone());
+        config.validate().unwrap();
+        // Nothing is programmed, so upstream fails every request
+        let (state, _) = mock_state(config, MockFetcher::default());
+        std::fs::remove_file(&template).unwrap();
+        let failing = |accept: &'static str, path: &'static str| {
+            let state = state.clone();
+            async move {
+                let request = Request::builder()
+                    .uri(path)
+                    .header(header::ACCEPT, accept)
+                    .header(error_page::X_REQUEST_ID, "edge-1")
+                    .body(Body::empty())
+                    .unwrap();
+                send(&state, request).await
+            }
+        };
+        
+        let html = failing("text/html,application/xhtml+xml,*/*;q=0.8", "/media/a.jpg").await;
+        assert_eq!(html.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(html.headers()[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert_eq!(html.headers()[error_page::X_REQUEST_ID], "edge-1");
+        assert_eq!(body_bytes(html).await, "<h1>Fehler 502</h1><p>upstream_error edge-1</p>");
+        
+        let json = failing("application/json", "/media/a.jpg").await;
+        assert_eq!(json.status(), StatusCode::BAD_GATEWAY);
+        let json: serde_json::Value = serde_json::from_slice(&body_bytes(json).await).unwrap();
+        assert_eq!(json["status"], 502);
+        assert_eq!(json["code"], "upstream_error");
+        assert_eq!(json["request_id"], "edge-1");
+        assert!(json["error"].as_str().unwrap().starts_with("Upstream error: "));
+        
+        let text = failing("image/avif,image/webp,*/*", "/media/a.jpg").await;
+        assert_eq!(text.status(), StatusCode::BAD_GATEWAY);
+        assert!(text.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+        assert!(String::from_utf8(body_bytes(text).await.to_vec()).unwrap().starts_with("Upstream error: "));
+        
+        // Markup in the path, or an unusable request id, never reaches the page
+        let request = Request::builder()
+            .uri("/media/%3Cscript%3Ealert(1)%3C/script%3E'&onerror=alert(1).jpg")
+            .header(header::ACCEPT, "text/html")
+            .header(error_page::X_REQUEST_ID, "\"><img src=x>")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&state, request).await;
+        let request_id = response.headers()[error_page::X_REQUEST_ID].to_str().unwrap().to_string();
+        assert_eq!(request_id.len(), 16);
+        let page = String::from_utf8(body_bytes(response).await.to_vec()).unwrap();
+        assert_eq!(page, format!("<h1>Fehler 502</h1><p>upstream_error {}</p>", request_id));
+        
+        // Built in, the page is a complete document
+        let (state, _) = mock_state(mock_config(), MockFetcher::default());
+        let page = body_bytes(get(&state, "/media/a.jpg", "text/html").await).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains("<strong>502 Bad Gateway</strong>"), "{}", page);
+    }
+    
+    #[tokio::test]
+    async fn test_header_order_is_canonical() {
+        // Content-Length is added by axum after the handler, at the end of every response
+        fn names(response: &Response) -> Vec<String> {
+            response
+                .headers()
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .filter(|name| name != "content-length")
+                .collect()
+        }
+        
+        let scrambled = |response: MockResponse| {
+            response
+                .header(header::HeaderName::from_static("x-upstream-z"), "1")
+                .header(header::ETAG, "\"abc\"")
+                .header(header::VARY, "Origin")
+                .header(header::HeaderName::from_static("x-upstream-a"), "2")
+                .header(header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")
+        };
+        let mut config = mock_config();
+        config.server.cdn.mode = CdnMode::Cloudflare;
+        let fetcher = MockFetcher::always(scrambled(MockResponse::ok("image/jpeg", encode_jpeg())))
+            .with("/media/a.txt", scrambled(MockResponse::ok("text/plain", "hello")))
+            .with("/media/gone.txt", scrambled(MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND)));
+        let (state, _) = mock_state(config, fetcher);
+        
+        for (path, accept) in [("/media/a.jpg", "image/webp"), ("/media/a.txt", "*/*"), ("/media/gone.txt", "*/*")] {
+            let miss = get(&state, path, accept).await;
+            let hit = get(&state, path, accept).await;
+            assert_eq!(names(&miss), names(&hit), "{}", path);
+            assert_eq!(miss.headers().get(X_CACHE_STATUS).is_some(), miss.status().is_success());
+            
+            // Proxy headers lead, upstream ones follow by name
+            let names = names(&hit);
+            let upstream = &names[names.iter().position(|name| name == "cache-tag").unwrap() + 1..];
+            assert_eq!(upstream, ["etag", "last-modified", "x-upstream-a", "x-upstream-z"], "{}", path);
+            assert_eq!(names[0], if hit.status().is_success() { "content-type" } else { "cache-control" });
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_runtime_states_expire() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let set = |body: &'static str| {
+            let state = state.clone();
+            async move {
+                let request = Request::builder()
+                    .method("POST")
+                    .uri("/admin/state")
+                    .header(header::AUTHORIZATION, "Bearer secret")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap();
+                send(&state, request).await
+            }
+        };
+        let metrics = || async {
+            let response = send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await;
+            String::from_utf8(body_bytes(response).await.to_vec()).unwrap()
+        };
+        assert_eq!(get(&state, "/media/cached.jpg", "*/*").await.status(), StatusCode::OK);
+        
+        // States without a TTL must be marked permanent
+        assert_eq!(set(r#"{"maintenance": true}"#).await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(set(r#"{"maintenance": true, "ttl_secs": 5, "permanent": true}"#).await.status(), StatusCode::BAD_REQUEST);
+        assert!(!state.runtime_states.is_active(RuntimeState::Maintenance));
+        
+        let response = set(r#"{"maintenance": true, "shed_conversions": true, "ttl_secs": 1, "note": "migrating bucket"}"#).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(listed["states"][0]["state"], "maintenance");
+        assert_eq!(listed["states"][0]["note"], "migrating bucket");
+        assert_eq!(listed["states"][0]["remaining_secs"], 1);
+        assert_eq!(listed["states"][1]["state"], "shed_conversions");
+        assert!(listed["states"][0]["set_by"]["token_fingerprint"].is_string());
+        
+        // Cached entries are served, everything else waits for the maintenance to end
+        let fetched = fetcher.requests().len();
+        assert_eq!(get(&state, "/media/cached.jpg", "*/*").await.status(), StatusCode::OK);
+        let response = get(&state, "/media/new.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(fetcher.requests().len(), fetched);
+        assert!(metrics().await.contains("runtime_state_active{state=\"maintenance\"} 1\n"));
+        
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = get(&state, "/media/new.jpg", "image/webp").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(metrics().await.contains("runtime_state_active{state=\"maintenance\"} 0\n"));
+        
+        // A permanent state lasts until it is switched off
+        assert_eq!(set(r#"{"shed_conversions": true, "permanent": true}"#).await.status(), StatusCode::OK);
+        assert_eq!(get(&state, "/media/other.jpg", "image/webp").await.headers()[X_CACHE_STATUS], "SHED");
+        let health = String::from_utf8(body_bytes(get(&state, "/health", "*/*").await).await.to_vec()).unwrap();
+        assert!(health.contains("conversions: shed\n"), "{}", health);
+        assert!(health.contains("runtime_state_active{state=\"shed_conversions\"}: 1\n"), "{}", health);
+        assert_eq!(set(r#"{"shed_conversions": false}"#).await.status(), StatusCode::OK);
+        assert_eq!(get(&state, "/media/other.jpg", "image/webp").await.headers()[header::CONTENT_TYPE], "image/webp");
+    }
+    
+    #[tokio::test]
+    async fn test_route_image_settings() {
+        use crate::config::{RouteImageConfig, UpstreamRoute};
+        
+        let source = encode(image::RgbImage::from_fn(128, 128, |x, y| image::Rgb([x as u8 * 2, y as u8 * 2, 90])), image::ImageFormat::Png);
+        
+        let mut config = mock_config();
+        config.upstream.routes = vec![
+            UpstreamRoute { prefix: "/media/".to_string(), url: None, image: None },
+            UpstreamRoute {
+                prefix: "/proxy/".to_string(),
+                url: Some("http://remote.test".to_string()),
+                image: Some(RouteImageConfig {
+                    quality: Some(30),
+                    max_dimension: Some(32),
+                    enable_avif: Some(false),
+                    ..Default::default()
+                }),
+            },
+        ];
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/png", source)));
+        
+        // Identical source bytes, converted with each route's settings
+        let first_party = body_bytes(get(&state, "/media/a.png", "image/webp").await).await;
+        let remote = body_bytes(get(&state, "/proxy/a.png", "image/webp").await).await;
+        let first_party = image::load_from_memory(&first_party).unwrap();
+        let remote_image = image::load_from_memory(&remote).unwrap();
+        assert_eq!((first_party.width(), first_party.height()), (128, 128));
+        assert_eq!((remote_image.width(), remote_image.height()), (32, 32));
+        assert_eq!(fetcher.requests(), vec!["http://upstream.test/media/a.png", "http://remote.test/proxy/a.png"]);
+        
+        // The route's settings are part of its cache key, and AVIF is off for it
+        let key = CacheKey::new("/proxy/a.png".to_string(), "WebP:q=30,max=32".to_string());
+        assert!(state.cache.get(&key).await.is_some());
+        let response = get(&state, "/proxy/a.png", "image/avif,image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "HIT");
+        assert_eq!(body_bytes(response).await, remote);
+    }
+    
+    #[tokio::test]
+    async fn test_missing_and_duplicate_upstream_content_types() {
+        let png = encode(image::RgbImage::from_pixel(8, 8, image::Rgb([10, 200, 10])), image::ImageFormat::Png);
+        let response = |body: Vec<u8>, content_types: &[&'static str]| {
+            let mut response = MockResponse::ok("text/plain", body).header(header::ETAG, "\"v1\"");
+            response.headers.remove(header::CONTENT_TYPE);
+            for content_type in content_types {
+                response.headers.append(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            response
+        };
+        let fetcher = MockFetcher::default()
+            .with("/media/none.png", response(png.clone(), &[]))
+            .with("/media/none.bin", response(b"opaque".to_vec(), &[]))
+            .with("/media/two.png", response(png.clone(), &["image/png", "text/html"]))
+            .with("/media/two.txt", response(b"hello".to_vec(), &["text/plain; charset=utf-8", "image/png"]));
+        let (state, _) = mock_state(mock_config(), fetcher);
+        
+        let cases = [
+            ("/media/none.png", "image/png"),
+            ("/media/none.bin", "application/octet-stream"),
+            ("/media/two.png", "image/png"),
+            ("/media/two.txt", "text/plain; charset=utf-8"),
+        ];
+        // The miss and the hit replaying the cached headers both carry exactly one
+        for _ in 0..2 {
+            for (path, expected) in cases {
+                let response = get(&state, path, "*/*").await;
+                assert_eq!(response.status(), StatusCode::OK, "{}", path);
+                let content_types: Vec<_> = response.headers().get_all(header::CONTENT_TYPE).iter().collect();
+                assert_eq!(content_types, vec![expected], "{}", path);
+                assert_eq!(response.headers()[header::ETAG], "\"v1\"");
+            }
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_request_duration_by_flow() {
+        let mut config = mock_config();
+        config.telemetry.latency_buckets = vec![0.25, 60.0];
+        let fetcher = MockFetcher::default()
+            .with("/media/a.txt", MockResponse::ok("text/plain", "plain"))
+            .with("/media/b.jpg", MockResponse::ok("image/jpeg", encode_jpeg()));
+        let (state, _) = mock_state(config, fetcher);
+        
+        get(&state, "/media/a.txt", "*/*").await;
+        get(&state, "/media/a.txt", "*/*").await;
+        get(&state, "/media/a.txt", "*/*").await;
+        let response = get(&state, "/media/b.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(get(&state, "/media/missing.png", "*/*").await.status().is_server_error());
+        
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        for (flow, count) in [("hit", 2), ("miss", 1), ("miss_convert", 1), ("error", 1)] {
+            assert!(metrics.contains(&format!("request_duration_seconds_count{{flow=\"{}\"}} {}\n", flow, count)), "{}", metrics);
+            assert!(metrics.contains(&format!("request_duration_seconds_bucket{{flow=\"{}\",le=\"60\"}} {}\n", flow, count)), "{}", metrics);
+            assert!(metrics.contains(&format!("request_duration_seconds_bucket{{flow=\"{}\",le=\"+Inf\"}} {}\n", flow, count)), "{}", metrics);
+        }
+        assert!(metrics.contains("request_duration_seconds_bucket{flow=\"hit\",le=\"0.25\"} 2\n"), "{}", metrics);
+    }
+    
+    #[tokio::test]
+    async fn test_conversion_quality_audit() {
+        use crate::config::{ForcedFormat, UaOverride};
+        
+        let pattern = image::RgbImage::from_fn(96, 96, |x, y| image::Rgb([(x * 2) as u8, (y * 2) as u8, if (x / 3 + y / 3) % 2 == 0 { 40 } else { 200 }]));
+        let png = encode(pattern, image::ImageFormat::Png);
+        let audited = |quality: u8, fraction: f64| {
+            let mut config = mock_config();
+            config.image.quality = quality;
+            config.image.quality_audit = fraction;
+            config.image.quality_audit_threshold = 30.0;
+            config.image.ua_overrides = vec![
+                UaOverride { name: "jpeg".to_string(), pattern: "JpegOnly".to_string(), format: ForcedFormat::Jpeg },
+            ];
+            config.validate().unwrap();
+            mock_state(config, MockFetcher::always(MockResponse::ok("image/png", png.clone()))).0
+        };
+        async fn convert(state: &AppState) {
+            let request = Request::builder()
+                .uri("/media/a.png")
+                .header(header::USER_AGENT, "JpegOnly/1.0")
+                .body(Body::empty())
+                .unwrap();
+            let response = send(state, request).await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+            // The audit runs after the response, so wait for its score
+            for _ in 0..500 {
+                if state.metrics.conversion_quality.count("image/jpeg") > 0 {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("Conversion was not audited");
+        }
+        
+        // A low quality scores below the threshold, which is counted alongside the warning
+        let low = audited(10, 1.0);
+        convert(&low).await;
+        assert_eq!(Metrics::get(&low.metrics.quality_audit_below_threshold), 1);
+        let high = audited(90, 1.0);
+        convert(&high).await;
+        assert_eq!(Metrics::get(&high.metrics.quality_audit_below_threshold), 0);
+        
+        let metrics = body_bytes(send(&low, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains("conversion_quality_psnr_db_count{format=\"jpeg\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("conversion_quality_psnr_db_bucket{format=\"jpeg\",le=\"30\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("conversion_quality_psnr_db_count{format=\"webp\"} 0\n"), "{}", metrics);
+        assert!(metrics.contains("quality_audit_below_threshold_total 1\n"), "{}", metrics);
+        
+        // Nothing is audited by default
+        let unaudited = audited(10, 0.0);
+        let request = Request::builder().uri("/media/a.png").header(header::USER_AGENT, "JpegOnly/1.0").body(Body::empty()).unwrap();
+        assert_eq!(send(&unaudited, request).await.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(unaudited.metrics.conversion_quality.count("image/jpeg"), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_public_stats() {
+        async fn stats(state: &AppState) -> Response {
+            send(state, Request::builder().uri("/stats").body(Body::empty()).unwrap()).await
+        }
+        
+        let (state, _) = mock_state(mock_config(), MockFetcher::always(MockResponse::ok("text/plain", "x".repeat(1234))));
+        let before: serde_json::Value = serde_json::from_slice(&body_bytes(stats(&state).await).await).unwrap();
+        assert_eq!(before["requests_served"], 0);
+        assert_eq!(before["hit_rate_last_hour"], serde_json::Value::Null);
+        
+        // One miss and three hits, with sizes rounded to two significant digits
+        for _ in 0..4 {
+            assert_eq!(get(&state, "/media/a.txt", "*/*").await.status(), StatusCode::OK);
+        }
+        let after: serde_json::Value = serde_json::from_slice(&body_bytes(stats(&state).await).await).unwrap();
+        assert_eq!(after["requests_served"], 4);
+        assert_eq!(after["hit_rate_last_hour"], 0.75);
+        assert_eq!(after["bytes_served"], 4900);
+        assert_eq!(after["bytes_saved"], 0);
+        assert!(after["uptime_seconds"].is_u64());
+        assert_eq!(after.as_object().unwrap().len(), 5);
+        
+        let mut config = mock_config();
+        config.server.public_stats = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "x")));
+        get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(stats(&state).await.status(), StatusCode::NOT_FOUND);
+    }
+    
+    #[tokio::test]
+    async fn test_cache_entry_hits() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "x")));
+        
+        // a: one miss and three hits, b: one miss and one hit, c: only the miss
+        for (path, requests) in [("/media/a.txt", 4), ("/media/b.txt", 2), ("/media/c.txt", 1)] {
+            for _ in 0..requests {
+                assert_eq!(get(&state, path, "*/*").await.status(), StatusCode::OK);
+            }
+        }
+        
+        let request = Request::builder()
+            .uri("/admin/cache/entry?path=/media/a.txt")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let entry: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        let entries = entry["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["hits"], 3);
+        assert!(entries[0]["last_access"].is_object());
+        assert_eq!(entries[0]["meta"]["content_type"], "text/plain");
+        
+        // Looking at the entry is not a hit
+        let cached = state.cache.get(&CacheKey::new("/media/a.txt".to_string(), "Original".to_string())).await.unwrap();
+        assert_eq!(cached.access.hits(), 3);
+        
+        let metrics = send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await;
+        let metrics = String::from_utf8(body_bytes(metrics).await.to_vec()).unwrap();
+        assert!(metrics.contains("cache_entries_by_hits{hits=\"0\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("cache_entries_by_hits{hits=\"1\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("cache_entries_by_hits{hits=\"2-5\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("cache_entries_by_hits{hits=\"6+\"} 0\n"), "{}", metrics);
+        
+        let unauthorized = Request::builder().uri("/admin/cache/entry?path=/media/a.txt").body(Body::empty()).unwrap();
+        assert_eq!(send(&state, unauthorized).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    
+    async fn explain(state: &AppState, query: &str) -> serde_json::Value {
+        let request = Request::builder()
+            .uri(format!("/admin/explain?{}", query))
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&body_bytes(response).await).unwrap()
+    }
+    
+    fn step<'a>(explanation: &'a serde_json::Value, name: &str) -> &'a serde_json::Value {
+        explanation["steps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|step| step["step"] == name)
+            .unwrap()
+    }
+    
+    #[tokio::test]
+    async fn test_explain_matches_handler() {
+        let jpeg = encode_jpeg();
+        let size = jpeg.len();
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg)));
+        
+        // Exempt path
+        let explanation = explain(&state, "path=/other.jpg").await;
+        assert_eq!(step(&explanation, "path_check")["outcome"], "rejected");
+        assert_eq!(get(&state, "/other.jpg", "*/*").await.status(), StatusCode::FORBIDDEN);
+        
+        // Format already satisfied
+        let explanation = explain(
+            &state,
+            &format!("path=/media/a.jpg&accept=image/jpeg&content_type=image/jpeg&size={}", size),
+        ).await;
+        assert_eq!(step(&explanation, "negotiation")["desired_format"], "Jpeg");
+        assert_eq!(step(&explanation, "cache_lookup")["outcome"], "miss");
+        assert_eq!(step(&explanation, "conversion")["outcome"], "format_satisfied");
+        assert!(fetcher.requests().is_empty());
+        
+        let response = get(&state, "/media/a.jpg", "image/jpeg").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::FormatSatisfied), 1);
+        
+        let explanation = explain(&state, "path=/media/a.jpg&accept=image/jpeg").await;
+        let lookup = step(&explanation, "cache_lookup");
+        assert_eq!(lookup["outcome"], "hit");
+        assert_eq!(lookup["variant_entry"]["content_type"], "image/jpeg");
+        assert_eq!(step(&explanation, "conversion")["outcome"], "unknown");
+        
+        // Oversized
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.image.max_convert_input_size = Some(size as u64 - 1);
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let explanation = explain(
+            &state,
+            &format!("path=/media/a.jpg&accept=image/webp&content_type=image/jpeg&size={}", size),
+        ).await;
+        assert_eq!(step(&explanation, "conversion")["outcome"], "too_large");
+        
+        let response = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::TooLarge), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_oversized_request_headers_rejected() {
+        let mut config = mock_config();
+        config.server.max_request_header_bytes = 2048;
+        config.server.max_request_headers = 20;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(state)).await.unwrap() });
+        
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/media/a.txt", addr);
+        let response = client.get(&url).header(header::COOKIE, "a".repeat(4096)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        
+        let mut request = client.get(&url);
+        for i in 0..30 {
+            request = request.header(format!("x-extra-{}", i), "1");
+        }
+        assert_eq!(request.send().await.unwrap().status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        
+        let response = client.get(&url).header(header::COOKIE, "a".repeat(512)).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    
+    #[tokio::test]
+    async fn test_second_hit_admission_policy() {
+        let mut config = mock_config();
+        config.cache.admission_policy = AdmissionPolicy::SecondHit;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default()
+                .with("/media/once.txt", MockResponse::ok("text/plain", "once"))
+                .with("/media/twice.txt", MockResponse::ok("text/plain", "twice"))
+                .with("/media/gone.txt", MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND)),
+        );
+        let cached = |uri: &str| CacheKey::new(uri.to_string(), "Original".to_string());
+        
+        let response = get(&state, "/media/once.txt", "*/*").await;
+        assert_eq!(body_bytes(response).await.as_ref(), b"once");
+        assert!(state.cache.get(&cached("/media/once.txt")).await.is_none());
+        
+        for expected in ["MISS", "MISS", "HIT"] {
+            let response = get(&state, "/media/twice.txt", "*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), expected);
+        }
+        assert!(state.cache.get(&cached("/media/twice.txt")).await.is_some());
+        
+        // Negative entries are admitted the same way
+        for _ in 0..3 {
+            get(&state, "/media/gone.txt", "*/*").await;
+        }
+        
+        let requests = fetcher.requests();
+        assert_eq!(requests.iter().filter(|url| url.ends_with("/media/twice.txt")).count(), 2);
+        assert_eq!(requests.iter().filter(|url| url.ends_with("/media/gone.txt")).count(), 2);
+        assert_eq!(Metrics::get(&state.metrics.cache_admitted), 2);
+        assert_eq!(Metrics::get(&state.metrics.cache_admission_rejected), 3);
+    }
+    
+    #[tokio::test]
+    async fn test_cdn_mode_headers() {
+        let immutable = "public, max-age=31536000, immutable";
+        for mode in [CdnMode::None, CdnMode::CloudflareFree, CdnMode::Cloudflare, CdnMode::Generic] {
+            let mut config = mock_config();
+            config.server.cdn.mode = mode;
+            let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+            
+            let response = get(&state, "/media/a.txt?format=webp", "*/*").await;
+            let headers = response.headers();
+            assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), immutable, "{:?}", mode);
+            assert_eq!(
+                headers.get(CDN_CACHE_CONTROL).is_some(),
+                matches!(mode, CdnMode::Cloudflare | CdnMode::Generic),
+                "{:?}", mode,
+            );
+            let expected_tags = format!("path:{},domain:upstream.test", path_hash("/media/a.txt"));
+            assert_eq!(
+                headers.get(CACHE_TAG).map(|v| v.to_str().unwrap()),
+                (mode == CdnMode::Cloudflare).then_some(expected_tags.as_str()),
+                "{:?}", mode,
+            );
+            
+            // Only the free plan mode reads the format from the query string
+            let expected_url = if mode == CdnMode::CloudflareFree {
+                "http://upstream.test/media/a.txt"
+            } else {
+                "http://upstream.test/media/a.txt?format=webp"
+            };
+            assert_eq!(fetcher.requests(), vec![expected_url]);
+        }
+        
+        // Browsers get a shorter lifetime than the CDN
+        let mut config = mock_config();
+        config.server.cdn.mode = CdnMode::Cloudflare;
+        config.server.cdn.browser_ttl = Some(600);
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "public, max-age=600");
+        assert_eq!(response.headers().get(CDN_CACHE_CONTROL).unwrap(), immutable);
+    }
+    
+    #[tokio::test]
+    async fn test_invalidation_from_peer() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        get(&state, "/media/a.txt", "*/*").await;
+        
+        let invalidate = |token: &str, seq: u64| {
+            let message = Invalidation {
+                node: "peer".to_string(),
+                seq,
+                target: InvalidationTarget::Path("/media/a.txt".to_string()),
+            };
+            Request::builder()
+                .method("POST")
+                .uri(INVALIDATION_ENDPOINT)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&message).unwrap()))
+                .unwrap()
+        };
+        assert_eq!(send(&state, invalidate("wrong", 1)).await.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&state, invalidate("secret", 1)).await;
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body, serde_json::json!({ "applied": true, "purged": 1 }));
+        
+        // A replay leaves the entry stored since alone
+        get(&state, "/media/a.txt", "*/*").await;
+        let response = send(&state, invalidate("secret", 1)).await;
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body, serde_json::json!({ "applied": false, "purged": 0 }));
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(fetcher.requests().len(), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_purge_by_cache_tag() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.upstream.url_template = Some("http://{shard:1}.upstream.test{path}".to_string());
+        config.cache.emit_cache_tags = true;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        
+        let response = get(&state, "/media/a1.txt", "*/*").await;
+        assert_eq!(response.headers().get(CACHE_TAG).unwrap(), "domain:a.upstream.test,type:text,original");
+        get(&state, "/media/a2.txt", "*/*").await;
+        get(&state, "/media/b1.txt", "*/*").await;
+        
+        let purge = |token: &str| {
+            Request::builder()
+                .method("POST")
+                .uri("/admin/cache/purge_tag")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"tag":"domain:a.upstream.test"}"#))
+                .unwrap()
+        };
+        assert_eq!(send(&state, purge("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+        let response = send(&state, purge("secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["purged"], 2);
+        
+        for (uri, expected) in [("/media/a1.txt", "MISS"), ("/media/a2.txt", "MISS"), ("/media/b1.txt", "HIT")] {
+            let response = get(&state, uri, "*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), expected, "{}", uri);
+        }
+        let response = get(&state, "/media/b1.txt", "*/*").await;
+        assert_eq!(response.headers().get(CACHE_TAG).unwrap(), "domain:b.upstream.test,type:text,original");
+    }
+    
+    #[tokio::test]
+    async fn test_bulk_warm_and_purge_stream_ndjson() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        get(&state, "/media/0.txt", "*/*").await;
+        
+        let bulk = |uri: &str, body: Body| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header(header::AUTHORIZATION, "Bearer secret")
+                .header(header::CONTENT_TYPE, ndjson::NDJSON)
+                .body(body)
+                .unwrap()
+        };
+        
+        // Each result arrives before the next line is sent, so nothing is buffered whole
+        let (lines, body) = futures::channel::mpsc::unbounded::<Result<Bytes, std::io::Error>>();
+        let response = send(&state, bulk("/admin/cache/warm", Body::from_stream(body))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), ndjson::NDJSON);
+        let mut results = Box::pin(ndjson::lines(response.into_body().into_data_stream(), usize::MAX));
+        for i in 0..1000 {
+            let line = format!("{{\"path\": \"/media/{}.txt\"}}\n", i);
+            lines.unbounded_send(Ok(Bytes::from(line))).unwrap();
+            let (_, result) = results.next().await.unwrap();
+            let result: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+            assert_eq!(result["path"], format!("/media/{}.txt", i));
+            assert_eq!(result["action"], "warm");
+            assert_eq!(result["outcome"], if i == 0 { "cached" } else { "warmed" });
+        }
+        lines.unbounded_send(Ok(Bytes::from("not json\n"))).unwrap();
+        drop(lines);
+        let (_, result) = results.next().await.unwrap();
+        let result: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+        assert_eq!((result["line"].as_u64(), result["outcome"].as_str()), (Some(1001), Some("invalid")));
+        assert!(results.next().await.is_none());
+        assert_eq!(fetcher.requests().len(), 1000);
+        
+        let paths: String = (0..1000).map(|i| format!("{{\"path\": \"/media/{}.txt\"}}\n", i)).collect();
+        let response = send(&state, bulk("/admin/cache/purge", Body::from(paths.clone()))).await;
+        let body = body_bytes(response).await;
+        let results: Vec<serde_json::Value> = body
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(results.len(), 1000);
+        assert!(results.iter().all(|result| result["outcome"] == "purged" && result["purged"] == 1));
+        assert_eq!(get(&state, "/media/999.txt", "*/*").await.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        
+        let mut unauthorized = bulk("/admin/cache/purge", Body::from(paths));
+        unauthorized.headers_mut().remove(header::AUTHORIZATION);
+        assert_eq!(send(&state, unauthorized).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_request_cookies_do_not_affect_caching() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg()).header(header::VARY, "Origin, Cookie")),
+        );
+        let request = |cookie: Option<&str>| {
+            let mut request = Request::builder().uri("/media/a.jpg").header(header::ACCEPT, "image/webp");
+            if let Some(cookie) = cookie {
+                request = request.header(header::COOKIE, cookie);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        
+        let with_cookie = send(&state, request(Some("session=secret"))).await;
+        assert_eq!(with_cookie.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(with_cookie.headers().get(header::VARY).unwrap(), "Accept, Origin");
+        let with_cookie_body = body_bytes(with_cookie).await;
+        
+        let without_cookie = send(&state, request(None)).await;
+        assert_eq!(without_cookie.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(without_cookie.headers().get(header::VARY).unwrap(), "Accept, Origin");
+        assert_eq!(body_bytes(without_cookie).await, with_cookie_body);
+        
+        assert_eq!(fetcher.requests().len(), 1);
+        assert!(!fetcher.request_headers()[0].contains_key(header::COOKIE));
+        
+        // Upstream's Vary is kept as is when cookies are not ignored
+        let mut config = mock_config();
+        config.server.ignore_request_cookies = false;
+        let (state, _) = mock_state(
+            config,
+            MockFetcher::always(MockResponse::ok("text/plain", "hello").header(header::VARY, "Cookie")),
+        );
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept, Cookie");
+    }
+    
+    #[tokio::test]
+    async fn test_compressed_upstream_text_is_decoded() {
+        let text = "{\"note\": \"compress me\"} ".repeat(200);
+        let encodings = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = encodings.clone();
+        let body = text.clone();
+        let app = axum::Router::new()
+            .fallback(move |headers: HeaderMap| {
+                let body = body.clone();
+                seen.lock().unwrap().push(headers.get(header::ACCEPT_ENCODING).cloned());
+                async move { ([(header::CONTENT_TYPE, "application/json")], body) }
+            })
+            .layer(tower_http::compression::CompressionLayer::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        
+        let state = AppState::new(Config::with_upstream(format!("http://{}", addr)));
+        for expected in ["MISS", "HIT"] {
+            let response = get(&state, "/media/status.json", "*/*").await;
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), expected);
+            assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+            assert!(response.headers().get(header::CONTENT_LENGTH).is_none_or(|len| len == text.len().to_string().as_str()));
+            assert_eq!(body_bytes(response).await, text.as_bytes());
+        }
+        
+        // Media is fetched without compression
+        get(&state, "/media/photo.jpg", "*/*").await;
+        let encodings = encodings.lock().unwrap();
+        assert_eq!(encodings[0].as_ref().unwrap(), "gzip, br");
+        assert_eq!(encodings[1].as_ref().unwrap(), "identity");
+    }
+    
+    #[tokio::test]
+    async fn test_upstream_auth_is_sent_but_never_returned() {
+        use crate::config::{Secret, UpstreamAuth};
+        
+        // Upstream refuses requests without the expected credentials and echoes them back otherwise
+        async fn spawn_authenticated_upstream(expected: &'static str) -> String {
+            let app = axum::Router::new().fallback(move |headers: HeaderMap| async move {
+                match headers.get(header::AUTHORIZATION) {
+                    Some(value) if value == expected => {
+                        (StatusCode::OK, [(header::AUTHORIZATION, value.clone())], "secret media").into_response()
+                    }
+                    _ => (StatusCode::UNAUTHORIZED, [(header::AUTHORIZATION, "denied")], "no").into_response(),
+                }
+            });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+            format!("http://{}", addr)
+        }
+        
+        std::env::set_var("AKKOPROXY_TEST_UPSTREAM_TOKEN", "t0ken");
+        let cases = [
+            (
+                "Basic YWxpY2U6aHVudGVyMg==",
+                UpstreamAuth::Basic {
+                    username: "alice".to_string(),
+                    password: Secret::Value("hunter2".to_string()),
+                },
+            ),
+            (
+                "Bearer t0ken",
+                UpstreamAuth::Bearer {
+                    token: Secret::Env { env: "AKKOPROXY_TEST_UPSTREAM_TOKEN".to_string() },
+                },
+            ),
+        ];
+        for (expected, auth) in cases {
+            let upstream = spawn_authenticated_upstream(expected).await;
+            
+            // Without credentials upstream refuses, and its echoed header is still dropped
+            let state = AppState::new(Config::with_upstream(upstream.clone()));
+            let response = get(&state, "/media/private.txt", "*/*").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(response.headers().get(header::AUTHORIZATION).is_none());
+            
+            let mut config = Config::with_upstream(upstream);
+            config.server.preserve_upstream_headers = true;
+            config.upstream.auth = Some(auth);
+            config.validate().unwrap();
+            let state = AppState::new(config);
+            for status in ["MISS", "HIT"] {
+                let response = get(&state, "/media/private.txt", "*/*").await;
+                assert_eq!(response.status(), StatusCode::OK, "{}", expected);
+                assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), status);
+                assert!(response.headers().get(header::AUTHORIZATION).is_none());
+                assert_eq!(body_bytes(response).await.as_ref(), b"secret media");
+            }
+            
+            let cached = state
+                .cache
+                .get(&CacheKey::new("/media/private.txt".to_string(), "Original".to_string()))
+                .await
+                .unwrap();
+            assert!(cached.headers.get(header::AUTHORIZATION).is_none());
+            assert!(cached.meta.headers.as_ref().unwrap().get(header::AUTHORIZATION).is_none());
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_upstream_auth_not_sent_to_route_hosts() {
+        use crate::config::{Secret, UpstreamAuth, UpstreamRoute};
+        
+        let mut config = mock_config();
+        config.upstream.auth = Some(UpstreamAuth::Bearer { token: Secret::Value("t0ken".to_string()) });
+        config.upstream.routes.push(UpstreamRoute {
+            prefix: "/proxy/".to_string(),
+            url: Some("http://remote-media.test".to_string()),
+            image: None,
+        });
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "media")));
+        
+        get(&state, "/media/a.txt", "*/*").await;
+        get(&state, "/proxy/a.txt", "*/*").await;
+        let requests = fetcher.requests();
+        assert!(requests[1].starts_with("http://remote-media.test/"), "{}", requests[1]);
+        let request_headers = fetcher.request_headers();
+        assert_eq!(request_headers[0][header::AUTHORIZATION], "Bearer t0ken");
+        assert!(request_headers[1].get(header::AUTHORIZATION).is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_disabled_query_format_behind_cloudflare_free() {
+        let mut config = mock_config();
+        config.server.cdn.mode = CdnMode::CloudflareFree;
+        config.image.enable_webp = false;
+        let (state, fetcher) = mock_state(config.clone(), MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        
+        // Rejected before upstream is contacted, and kept out of every cache
+        let response = get(&state, "/media/a.jpg?format=webp&v=1", "image/webp").await;
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+        assert!(fetcher.requests().is_empty());
+        
+        config.server.cloudflare_format_mismatch = FormatMismatch::Redirect;
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = get(&state, "/media/a.jpg?format=webp&v=1", "image/webp").await;
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/media/a.jpg?v=1");
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+        let response = get(&state, "/media/a.jpg?format=webp", "image/webp").await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/media/a.jpg");
+        assert!(fetcher.requests().is_empty());
+        
+        // Outside that mode the parameter is not read and the enabled formats are negotiated
+        let mut config = mock_config();
+        config.image.enable_webp = false;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = get(&state, "/media/a.jpg?format=webp", "image/webp").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+    }
+    
+    #[tokio::test]
+    async fn test_upstream_url_template() {
+        let mut config = mock_config();
+        config.upstream.url_template = Some("http://{shard:1}.upstream.test{path}".to_string());
+        config.server.cdn.mode = CdnMode::CloudflareFree;
+        config.validate().unwrap();
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+
+        let response = get(&state, "/media/abc.txt?format=webp&v=1", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        let response = get(&state, "/media/abc.txt?format=webp&v=1", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        get(&state, "/media/b/xyz.txt", "*/*").await;
+
+        assert_eq!(fetcher.requests(), vec![
+            "http://a.upstream.test/media/abc.txt?v=1",
+            "http://x.upstream.test/media/b/xyz.txt",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_body_transfers_are_resumed_or_restarted() {
+        let video: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let fetcher = MockFetcher::default()
+            .with("/media/resumable.mp4", MockResponse::ok("video/mp4", video.clone())
+                .header(header::ACCEPT_RANGES, "bytes")
+                .header(header::ETAG, "\"v1\"")
+                .drop_after(900, 1))
+            .with("/media/plain.mp4", MockResponse::ok("video/mp4", video.clone()).drop_after(500, 1))
+            .with("/media/flaky.mp4", MockResponse::ok("video/mp4", video.clone()).drop_after(100, 5));
+        let (state, fetcher) = mock_state(mock_config(), fetcher);
+
+        // The first transfer drops at 900 bytes and only the rest is requested again
+        let response = get(&state, "/media/resumable.mp4", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, video);
+        let retried = &fetcher.request_headers()[1];
+        assert_eq!(retried[header::RANGE], "bytes=900-");
+        assert_eq!(retried[header::IF_RANGE], "\"v1\"");
+        assert_eq!(Metrics::get(&state.metrics.resumed_transfers), 1);
+        assert_eq!(Metrics::get(&state.metrics.resume_bytes_saved), 900);
+
+        // Without validators the whole body is fetched again, never spliced
+        let response = get(&state, "/media/plain.mp4", "*/*").await;
+        assert_eq!(body_bytes(response).await, video);
+        assert!(!fetcher.request_headers()[3].contains_key(header::RANGE));
+        assert_eq!(Metrics::get(&state.metrics.resumed_transfers), 1);
+
+        // Once the retries run out the request fails
+        let response = get(&state, "/media/flaky.mp4", "*/*").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(fetcher.requests().len(), 4 + 3);
+
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8_lossy(&metrics);
+        assert!(metrics.contains("resumed_transfers_total 1\n"), "{}", metrics);
+        assert!(metrics.contains("resume_bytes_saved_total 900\n"), "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let video: Bytes = (0..1000u32).map(|i| i as u8).collect::<Vec<u8>>().into();
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with("/media/video.mp4", MockResponse::ok("video/mp4", video.clone()).header(header::ACCEPT_RANGES, "bytes"))
+                .with("/media/other.mp4", MockResponse::ok("video/mp4", video.clone()).header(header::ACCEPT_RANGES, "bytes"))
+                .with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        let ranged = |uri: &str, range: &'static str, accept: &'static str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT, accept)
+                .header(header::RANGE, range)
+                .body(Body::empty())
+                .unwrap()
+        };
+        
+        // Players open media from its start, which upstream answers whole, so it is cached
+        let response = send(&state, ranged("/media/video.mp4", "bytes=0-", "*/*")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-999/1000");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "MISS");
+        assert_eq!(fetcher.request_headers()[0][header::RANGE], "bytes=0-");
+        assert_eq!(body_bytes(response).await, video);
+        
+        // Seeking within the cached body slices it
+        let response = send(&state, ranged("/media/video.mp4", "bytes=100-199", "*/*")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 100-199/1000");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "HIT");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "100");
+        assert_eq!(body_bytes(response).await, video.slice(100..200));
+        let response = send(&state, ranged("/media/video.mp4", "bytes=-10", "*/*")).await;
+        assert_eq!(body_bytes(response).await, video.slice(990..));
+        
+        for unsatisfiable in ["bytes=1000-", "bytes=5-2", "bytes=abc"] {
+            let response = send(&state, ranged("/media/video.mp4", unsatisfiable, "*/*")).await;
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE, "{}", unsatisfiable);
+            assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */1000");
+            assert!(body_bytes(response).await.is_empty());
+        }
+        // Several ranges and a changed representation get the whole body
+        let response = send(&state, ranged("/media/video.mp4", "bytes=0-1,5-6", "*/*")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut request = ranged("/media/video.mp4", "bytes=0-1", "*/*");
+        request.headers_mut().insert(header::IF_RANGE, HeaderValue::from_static("\"old\""));
+        assert_eq!(send(&state, request).await.status(), StatusCode::OK);
+        assert_eq!(fetcher.requests().len(), 1);
+        
+        // Slices from upstream are passed on without caching them
+        let response = send(&state, ranged("/media/other.mp4", "bytes=900-", "*/*")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 900-999/1000");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "BYPASS");
+        assert_eq!(body_bytes(response).await, video.slice(900..));
+        assert_eq!(get(&state, "/media/other.mp4", "*/*").await.headers()[X_CACHE_STATUS], "MISS");
+        assert_eq!(fetcher.requests().len(), 3);
+        
+        // Converted images are fetched whole, the range applies to the converted body
+        let response = send(&state, ranged("/media/a.jpg", "bytes=0-9", "image/webp")).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(!fetcher.request_headers()[3].contains_key(header::RANGE));
+        assert_eq!(&body_bytes(response).await[..4], b"RIFF");
+        assert_eq!(Metrics::get(&state.metrics.range_responses), 7);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_coalesced() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::default().with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        fetcher.set_delay(Duration::from_millis(200));
+        
+        // One request fetches and converts, the others wait for its result
+        let requests: Vec<_> = (0..10)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { get(&state, "/media/a.jpg", "image/webp").await })
+            })
+            .collect();
+        let mut bodies = Vec::new();
+        for request in requests {
+            let response = request.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+            bodies.push(body_bytes(response).await);
+        }
+        assert!(bodies.windows(2).all(|pair| pair[0] == pair[1]));
+        assert_eq!(fetcher.requests().len(), 1);
+        assert_eq!(Metrics::get(&state.metrics.coalesced_misses), 9);
+        assert!(state.refreshes.is_empty());
+        
+        // A failure reaches every waiter and is not cached
+        let requests: Vec<_> = (0..5)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { get(&state, "/media/missing.jpg", "image/webp").await })
+            })
+            .collect();
+        for request in requests {
+            assert_eq!(request.await.unwrap().status(), StatusCode::BAD_GATEWAY);
+        }
+        assert_eq!(fetcher.requests().len(), 2);
+        fetcher.set_delay(Duration::ZERO);
+        fetcher.respond("/media/missing.jpg", MockResponse::ok("image/jpeg", encode_jpeg()));
+        assert_eq!(get(&state, "/media/missing.jpg", "image/webp").await.status(), StatusCode::OK);
+        assert_eq!(fetcher.requests().len(), 3);
+        
+        // An entry left out of the cache is still shared with the waiting requests
+        let mut config = mock_config();
+        config.cache.admission_policy = AdmissionPolicy::SecondHit;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        fetcher.set_delay(Duration::from_millis(200));
+        let requests: Vec<_> = (0..10)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move { get(&state, "/media/a.jpg", "image/webp").await })
+            })
+            .collect();
+        let mut bodies = Vec::new();
+        for request in requests {
+            let response = request.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+            bodies.push(body_bytes(response).await);
+        }
+        assert!(bodies.windows(2).all(|pair| pair[0] == pair[1]));
+        assert_eq!(fetcher.requests().len(), 1);
+        assert_eq!(Metrics::get(&state.metrics.coalesced_misses), 9);
+        assert!(state.cache.get(&CacheKey::new("/media/a.jpg".to_string(), "WebP".to_string())).await.is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_slow_requests_listed() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.server.slow_request_threshold_ms = Some(150);
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default()
+                .with("/media/a.txt", MockResponse::ok("text/plain", "fast"))
+                .with("/media/b.txt", MockResponse::ok("text/plain", "fast"))
+                .with("/media/slow.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        let slow_requests = |token: &str| {
+            Request::builder()
+                .uri("/admin/slow")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        
+        get(&state, "/media/a.txt", "*/*").await;
+        fetcher.set_delay(Duration::from_millis(200));
+        let response = get(&state, "/media/slow.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        fetcher.set_delay(Duration::ZERO);
+        get(&state, "/media/b.txt", "*/*").await;
+        // Hits of the slow path are fast again
+        get(&state, "/media/slow.jpg", "image/webp").await;
+        
+        let response = send(&state, slow_requests("secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let listed: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(listed["threshold_ms"], 150);
+        assert_eq!(listed["window_secs"], 3600);
+        let requests = listed["requests"].as_array().unwrap();
+        assert_eq!(requests.len(), 1, "{:?}", requests);
+        let slow = &requests[0];
+        assert_eq!(slow["path"], "/media/slow.jpg");
+        assert_eq!(slow["status"], 200);
+        assert_eq!(slow["upstream_status"], 200);
+        assert_eq!(slow["cache_status"], "MISS");
+        let total = slow["total_ms"].as_f64().unwrap();
+        let upstream = slow["upstream_ms"].as_f64().unwrap();
+        let convert = slow["convert_ms"].as_f64().unwrap();
+        assert!(upstream >= 200.0 && convert > 0.0 && upstream + convert <= total, "{}", slow);
+        assert!(total < 10_000.0);
+        assert_eq!(slow["upstream_bytes"], encode_jpeg().len());
+        assert!(slow["response_bytes"].as_u64().unwrap() > 0);
+        assert!(slow.get("client_ip").is_none());
+        
+        assert_eq!(send(&state, slow_requests("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    
+    #[tokio::test]
+    async fn test_miss_and_hit_share_validators() {
+        let (state, _) = mock_state(mock_config(), MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let miss = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(miss.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        let hit = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(hit.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        for name in [header::ETAG, header::LAST_MODIFIED] {
+            assert_eq!(miss.headers().get(&name).unwrap(), hit.headers().get(&name).unwrap(), "{}", name);
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg()))
+                .with("/media/b.jpg", MockResponse::ok("image/jpeg", encode_jpeg()).header(header::ETAG, "\"upstream\""))
+                .with("/media/gone.jpg", MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND)),
+        );
+        let conditional = |uri: &str, name: header::HeaderName, value: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT, "image/webp")
+                .header(name, value)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The miss and later hits carry the same validators, derived from the converted body
+        let response = get(&state, "/media/a.jpg", "image/webp").await;
+        let etag = response.headers()[header::ETAG].to_str().unwrap().to_string();
+        let last_modified = response.headers()[header::LAST_MODIFIED].to_str().unwrap().to_string();
+        assert_eq!(etag.len(), 34);
+        assert!(etag.starts_with('"') && !etag.starts_with("W/"));
+        let response = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(response.headers()[header::LAST_MODIFIED], last_modified.as_str());
+
+        for (name, value) in [
+            (header::IF_NONE_MATCH, etag.clone()),
+            (header::IF_NONE_MATCH, format!("W/{}", etag)),
+            (header::IF_NONE_MATCH, format!("\"other\", {}", etag)),
+            (header::IF_MODIFIED_SINCE, last_modified.clone()),
+        ] {
+            let response = send(&state, conditional("/media/a.jpg", name.clone(), &value)).await;
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{}: {}", name, value);
+            let headers = response.headers();
+            assert_eq!(headers[header::ETAG], etag.as_str());
+            assert_eq!(headers[X_CACHE_STATUS], "HIT");
+            assert!(headers.contains_key(header::CACHE_CONTROL));
+            assert!(headers.contains_key(header::VIA));
+            assert!(!headers.contains_key(header::CONTENT_TYPE));
+            assert!(body_bytes(response).await.is_empty());
+        }
+
+        // Changed validators get the whole body, as does a stale tag overruling a matching date
+        let response = send(&state, conditional("/media/a.jpg", header::IF_NONE_MATCH, "\"other\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(&state, conditional("/media/a.jpg", header::IF_MODIFIED_SINCE, "Wed, 21 Oct 2015 07:28:00 GMT")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut request = conditional("/media/a.jpg", header::IF_NONE_MATCH, "\"other\"");
+        request.headers_mut().insert(header::IF_MODIFIED_SINCE, HeaderValue::from_str(&last_modified).unwrap());
+        assert_eq!(send(&state, request).await.status(), StatusCode::OK);
+
+        // Upstream's tag is kept on the unconverted source, and a miss can be answered with 304 too
+        let mut request = conditional("/media/b.jpg", header::IF_NONE_MATCH, "\"upstream\"");
+        request.headers_mut().insert(header::ACCEPT, HeaderValue::from_static("image/jpeg"));
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[X_CACHE_STATUS], "MISS");
+        assert_eq!(response.headers()[header::ETAG], "\"upstream\"");
+        
+        // It identifies the source, so converted bodies get their own, even with upstream headers preserved
+        let response = send(&state, conditional("/media/b.jpg", header::IF_NONE_MATCH, "\"upstream\"")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let converted_etag = response.headers()[header::ETAG].clone();
+        assert_ne!(converted_etag, "\"upstream\"");
+        assert_eq!(converted_etag, etag.as_str());
+        let response = get(&state, "/media/b.jpg", "image/webp").await;
+        assert_eq!(response.headers()[X_CACHE_STATUS], "HIT");
+        assert_eq!(response.headers()[header::ETAG], converted_etag);
+
+        // Error responses carry no validators and are never answered with 304
+        let response = send(&state, conditional("/media/gone.jpg", header::IF_NONE_MATCH, "*")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(!response.headers().contains_key(header::ETAG));
+
+        assert_eq!(fetcher.requests().len(), 4);
+        assert_eq!(Metrics::get(&state.metrics.not_modified_responses), 5);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slashes_on_upstream_urls() {
+        use crate::config::UpstreamRoute;
+        
+        let mut config = Config::with_upstream("http://upstream.test/".to_string());
+        config.upstream.routes = vec![UpstreamRoute {
+            prefix: "/proxy/".to_string(),
+            url: Some("http://remote.test/base/".to_string()),
+            image: None,
+        }];
+        config.validate().unwrap();
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        
+        get(&state, "/media/a.txt", "*/*").await;
+        get(&state, "/media/b.txt?v=1", "*/*").await;
+        get(&state, "/proxy/c.txt", "*/*").await;
+        assert_eq!(fetcher.requests(), vec![
+            "http://upstream.test/media/a.txt",
+            "http://upstream.test/media/b.txt?v=1",
+            "http://remote.test/base/proxy/c.txt",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_decision_memos_forgotten_on_purge_and_reload() {
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.image.max_convert_input_size = Some(16);
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        
+        // Skipping a large image is logged once and remembered
+        get(&state, "/media/a.jpg", "image/webp").await;
+        assert!(state.memos.contains("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        state.memos.remember("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::Avif));
+        state.memos.remember("/media/b.jpg", DecisionMemo::OverBudget(OutputFormat::Avif));
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        assert!(String::from_utf8(metrics.to_vec()).unwrap().contains("decision_memo_entries 3\n"));
+        
+        let purge = Request::builder()
+            .method("POST")
+            .uri("/admin/cache/purge?path=/media/a.jpg")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&state, purge).await.status(), StatusCode::OK);
+        assert!(!state.memos.contains("/media/a.jpg", DecisionMemo::TooLargeLogged));
+        assert!(!state.memos.contains("/media/a.jpg", DecisionMemo::OverBudget(OutputFormat::Avif)));
+        assert!(state.memos.contains("/media/b.jpg", DecisionMemo::OverBudget(OutputFormat::Avif)));
+        
+        // Reloading keeps memos whose settings are unchanged, and forgets the others
+        let config_path = std::env::temp_dir().join(format!("akkoproxy-memos-{}.toml", std::process::id()));
+        let reload = |extra: &str| {
+            std::fs::write(
+                &config_path,
+                format!("[upstream]\nurl = \"http://upstream.test\"\n[server]\ndebug_log_sample_rate = 0.1\n[image]\nmax_convert_input_size = 16\n{}", extra),
+            )
+            .unwrap();
+            state.reload_config(&config_path).unwrap();
+        };
+        reload("");
+        assert!(state.memos.contains("/media/b.jpg", DecisionMemo::OverBudget(OutputFormat::Avif)));
+        reload("conversion_time_budget_ms = 500\n");
+        let _ = std::fs::remove_file(&config_path);
+        assert!(state.memos.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn test_admin_actions_audited() {
+        let temp_dir = std::env::temp_dir();
+        let audit_path = temp_dir.join(format!("akkoproxy-audit-{}.log", std::process::id()));
+        let config_path = temp_dir.join(format!("akkoproxy-audit-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&audit_path);
+
+        let mut config = mock_config();
+        config.server.admin_token = Some("secret".to_string());
+        config.server.audit_log_path = Some(audit_path.clone());
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("text/plain", "hello")));
+        get(&state, "/media/a.txt", "*/*").await;
+
+        let purge = |token: &str| {
+            let mut request = Request::builder()
+                .method("POST")
+                .uri("/admin/cache/purge?path=/media/a.txt")
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(SocketAddr::from(([192, 0, 2, 7], 4000))));
+            request
+        };
+        let response = send(&state, purge("secret")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["purged"], 1);
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(send(&state, purge("wrong")).await.status(), StatusCode::UNAUTHORIZED);
+
+        // The sample rate is process-wide, so reload with the one the logging tests use
+        std::fs::write(&config_path, "[upstream]\nurl = \"http://upstream.test\"\n[server]\ndebug_log_sample_rate = 0.1\n").unwrap();
+        state.reload_config(&config_path).unwrap();
+        state.audit.flush().await;
+
+        let contents = std::fs::read_to_string(&audit_path).unwrap();
+        let _ = std::fs::remove_file(&audit_path);
+        let _ = std::fs::remove_file(&config_path);
+        assert!(!contents.contains("secret"));
+        let events: Vec<serde_json::Value> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(events.len(), 3);
+
+        assert_eq!(events[0]["action"], "cache_purge");
+        assert_eq!(events[0]["params"]["path"], "/media/a.txt");
+        assert_eq!(events[0]["client_ip"], "192.0.2.7");
+        assert_eq!(events[0]["token_fingerprint"], token_fingerprint("secret"));
+        assert_eq!(events[0]["authorized"], true);
+        assert!(events[0]["timestamp"].as_f64().unwrap() > 0.0);
+        assert_eq!(events[1]["token_fingerprint"], token_fingerprint("wrong"));
+        assert_eq!(events[1]["authorized"], false);
+
+        assert_eq!(events[2]["action"], "reload");
+        assert_eq!(events[2]["params"]["outcome"], "applied");
+        assert_eq!(events[2]["client_ip"], serde_json::Value::Null);
     }
-    
-    // Skip conversion if upstream format already satisfies desired format
-    !matches!(upstream_format, Some(fmt) if format_satisfies(fmt, desired_format))
-}
 
-/// Build HTTP response with appropriate headers
-fn build_response(
-    data: Bytes, 
-    content_type: &str, 
-    via_header: &str,
-    upstream_headers: Option<&HeaderMap>,
-    is_cache_hit: bool,
-) -> Response {
-    let mut builder = Response::builder()
-        .status(StatusCode::OK);
-    
-    // Check if upstream has CORS header
-    let upstream_has_cors = upstream_headers
-        .map(|h| h.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN))
-        .unwrap_or(false);
-    
-    // Check if upstream has Vary header
-    let upstream_vary = upstream_headers
-        .and_then(|h| h.get(header::VARY))
-        .and_then(|v| v.to_str().ok());
-    
-    // Add upstream headers if configured
-    if let Some(headers) = upstream_headers {
-        for (key, value) in headers.iter() {
-            // Skip headers that shouldn't be copied (those set by the proxy)
-            // Also skip Vary header as we'll handle it specially
-            if !should_exclude_header(key) && key != header::VARY {
-                builder = builder.header(key, value);
+    #[tokio::test]
+    async fn test_prefetch_batch() {
+        let mut config = mock_config();
+        config.server.prefetch_rate_limit = 1;
+        config.server.prefetch_max_batch = 3;
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let prefetch = |paths: serde_json::Value| {
+            Request::builder()
+                .method("POST")
+                .uri("/prefetch")
+                .header(header::ACCEPT, "image/webp,*/*")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(paths.to_string()))
+                .unwrap()
+        };
+        get(&state, "/media/cached.jpg", "image/webp,*/*").await;
+        
+        let response = send(&state, prefetch(serde_json::json!(["/media/cached.jpg", "/media/new.jpg", "/other/x.jpg"]))).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let result: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(
+            result["paths"],
+            serde_json::json!([
+                { "path": "/media/cached.jpg", "status": "cached" },
+                { "path": "/media/new.jpg", "status": "queued" },
+                { "path": "/other/x.jpg", "status": "rejected", "reason": "path_not_allowed" },
+            ])
+        );
+        
+        for _ in 0..100 {
+            if Metrics::get(&state.metrics.prefetched_paths) > 0 {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(50)).await;
         }
+        for path in ["/media/cached.jpg", "/media/new.jpg"] {
+            let response = get(&state, path, "image/webp,*/*").await;
+            assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        }
+        assert_eq!(fetcher.requests().len(), 2);
+        assert_eq!(Metrics::get(&state.metrics.prefetched_paths), 1);
+        
+        // One request per minute is allowed here
+        let response = send(&state, prefetch(serde_json::json!(["/media/other.jpg"]))).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "60");
+        assert_eq!(Metrics::get(&state.metrics.prefetch_rate_limited), 1);
+        
+        let mut config = mock_config();
+        config.server.prefetch_max_batch = 1;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = send(&state, prefetch(serde_json::json!(["/media/a.jpg", "/media/b.jpg"]))).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        
+        let mut config = mock_config();
+        config.server.prefetch_max_batch = 0;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        let response = send(&state, prefetch(serde_json::json!(["/media/a.jpg"]))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
     
-    // Always set/override these headers
-    builder = builder
-        .header(header::CONTENT_TYPE, content_type)
-        .header(header::VIA, via_header)
-        .header(header::CACHE_CONTROL, "public, max-age=31536000, immutable")
-        .header("X-Cache-Status", if is_cache_hit { "HIT" } else { "MISS" });
-    
-    // Always add Vary header with Accept
-    // If upstream has Vary header, prepend "Accept" to it
-    builder = builder.header(header::VARY, build_vary_header(upstream_vary));
-    
-    // Only set CORS header if upstream didn't provide one
-    if !upstream_has_cors {
-        builder = builder.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_sibling_format_prewarmed_on_miss() {
+        let mut config = mock_config();
+        config.image.prewarm_sibling_formats = true;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg()).header(header::ETAG, "\"upstream\"")),
+        );
+        
+        let response = get(&state, "/media/a.jpg", "image/avif,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        let avif_etag = response.headers()[header::ETAG].clone();
+        assert_ne!(avif_etag, "\"upstream\"");
+        
+        for _ in 0..100 {
+            if Metrics::get(&state.metrics.prewarmed_variants) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        // Each variant is tagged by its own bytes, not by the source's tag
+        assert_ne!(response.headers()[header::ETAG], "\"upstream\"");
+        assert_ne!(response.headers()[header::ETAG], avif_etag);
+        assert_eq!(fetcher.requests().len(), 1);
+        assert_eq!(Metrics::get(&state.metrics.prewarmed_variants), 1);
+        // The sibling was encoded from the source the request decoded
+        assert_eq!(state.image_converter.decode_count(), 1);
     }
-    
-    builder
-        .body(Body::from(data))
-        .expect("Failed to build response")
-}
 
-/// Build HTTP response with custom status code and headers
-fn build_response_with_status(
-    data: Bytes,
-    status: StatusCode,
-    via_header: &str,
-    upstream_headers: Option<&HeaderMap>,
-) -> Response {
-    let mut builder = Response::builder()
-        .status(status);
-    
-    // Check if upstream has CORS header
-    let upstream_has_cors = upstream_headers
-        .map(|h| h.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN))
-        .unwrap_or(false);
-    
-    // Check if upstream has Vary header
-    let upstream_vary = upstream_headers
-        .and_then(|h| h.get(header::VARY))
-        .and_then(|v| v.to_str().ok());
-    
-    // Add upstream headers if configured
-    if let Some(headers) = upstream_headers {
-        for (key, value) in headers.iter() {
-            // Skip headers that shouldn't be copied (those set by the proxy)
-            // Also skip Vary header as we'll handle it specially
-            if !should_exclude_header(key) && key != header::VARY {
-                builder = builder.header(key, value);
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_memory_pressure_shrinks_cache_and_pauses_prewarming() {
+        const LIMIT: u64 = 1 << 40;
+        let mut config = mock_config();
+        config.image.prewarm_sibling_formats = true;
+        config.cache.max_capacity = 100;
+        config.server.memory_limit_bytes = Some(LIMIT);
+        config.server.memory_shrink_percent = Some(90.0);
+        config.server.memory_shrunk_capacity_percent = 10.0;
+        config.server.memory_sample_interval = 3600;
+        config.validate().unwrap();
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
+        // The background sampler takes its first sample right away and the next in an hour
+        for _ in 0..100 {
+            if state.memory_watchdog.rss() > 0 {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-    }
-    
-    // Always add Via header
-    builder = builder.header(header::VIA, via_header);
-    
-    // Always add Vary header with Accept
-    // If upstream has Vary header, prepend "Accept" to it
-    builder = builder.header(header::VARY, build_vary_header(upstream_vary));
-    
-    // Only set CORS header if upstream didn't provide one
-    if !upstream_has_cors {
-        builder = builder.header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
-    }
-    
-    builder
-        .body(Body::from(data))
-        .expect("Failed to build response with status")
-}
+        assert!(state.memory_watchdog.rss() > 0);
 
-/// Health check handler
-pub async fn health_handler() -> impl IntoResponse {
-    (StatusCode::OK, "OK")
-}
+        // Samples stand in for the background sampler
+        assert!(state.memory_watchdog.observe(LIMIT).await);
+        assert_eq!(state.cache.capacity(), 10);
+        let response = get(&state, "/media/a.jpg", "image/avif,*/*").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/avif");
+        assert_eq!(Metrics::get(&state.metrics.prewarm_dropped), 1);
+        assert_eq!(Metrics::get(&state.metrics.prewarmed_variants), 0);
 
-/// Metrics handler
-pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
-    let stats = state.cache.stats();
-    let body = format!(
-        "# Cache Statistics\ncache_entries {}\ncache_size_bytes {}\n",
-        stats.entry_count,
-        stats.weighted_size
-    );
-    
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
-        body,
-    )
-}
+        let metrics = body_bytes(send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await).await;
+        let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+        assert!(metrics.contains(&format!("memory_pressure 1\nprocess_resident_memory_bytes {}\nmemory_limit_bytes {}\n", LIMIT, LIMIT)), "{}", metrics);
+        assert!(metrics.contains("memory_pressure_shrinks_total 1\n"), "{}", metrics);
 
-/// Proxy error types
-#[derive(Debug)]
-pub enum ProxyError {
-    PathNotAllowed,
-    UpstreamError(reqwest::Error),
-}
+        assert!(!state.memory_watchdog.observe(LIMIT / 2).await);
+        assert_eq!(state.cache.capacity(), 100);
+        assert!(get(&state, "/media/b.jpg", "image/avif,*/*").await.status().is_success());
+        assert_eq!(Metrics::get(&state.metrics.prewarm_dropped), 1);
+    }
 
-impl IntoResponse for ProxyError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            ProxyError::PathNotAllowed => {
-                (StatusCode::FORBIDDEN, "Path not allowed".to_string())
-            }
-            ProxyError::UpstreamError(e) => {
-                (StatusCode::BAD_GATEWAY, format!("Upstream error: {}", e))
-            }
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_conversion_time_budget_descends_fallback_ladder() {
+        let mut config = mock_config();
+        config.image.conversion_time_budget_ms = Some(1);
+        let jpeg = encode(image::RgbImage::from_fn(128, 128, |x, y| image::Rgb([x as u8, y as u8, (x ^ y) as u8])), image::ImageFormat::Jpeg);
+        let (state, fetcher) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", jpeg)));
+        assert_eq!(fallback_rungs(&state, "/media/a.jpg", OutputFormat::Avif), vec![OutputFormat::Avif, OutputFormat::WebP]);
+        assert_eq!(fallback_rungs(&state, "/media/a.jpg", OutputFormat::WebP), vec![OutputFormat::WebP]);
+        
+        // Neither rung fits in a millisecond, so the original is served
+        let response = get(&state, "/media/a.jpg", "image/avif,image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(Metrics::get(&state.metrics.conversions_over_budget), 2);
+        assert_eq!(Metrics::get(&state.metrics.cancelled_conversions), 0);
+        
+        // Once the entry is gone the slow formats are not tried again
+        state.cache.purge_path("/media/a.jpg").await;
+        let response = get(&state, "/media/a.jpg", "image/avif,image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(fetcher.requests().len(), 2);
+        assert_eq!(Metrics::get(&state.metrics.conversions_over_budget), 2);
+    }
+
+    #[tokio::test]
+    async fn test_conversion_errors_handled_by_kind() {
+        let mut truncated = encode_jpeg();
+        truncated.truncate(truncated.len() / 2);
+        let mut avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf".to_vec();
+        avif.resize(64, 0);
+        let wide = encode(image::DynamicImage::new_luma8(70_000, 1), image::ImageFormat::Png);
+        let mut config = mock_config();
+        config.image.max_dimension = 100_000;
+        config.image.fallback_ladder = vec![FallbackFormat::Jpeg, FallbackFormat::Png];
+        let fetcher = MockFetcher::default()
+            .with("/media/broken.jpg", MockResponse::ok("image/jpeg", truncated))
+            .with("/media/photo.avif", MockResponse::ok("image/avif", avif.clone()))
+            .with("/media/wide.png", MockResponse::ok("image/png", wide));
+        let (state, fetcher) = mock_state(config, fetcher);
+        
+        // A corrupt source is served as received and fetched again next time
+        for _ in 0..2 {
+            let response = get(&state, "/media/broken.jpg", "image/webp").await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+            assert_eq!(response.headers()[X_CACHE_STATUS], "MISS");
+        }
+        assert_eq!(fetcher.requests().len(), 2);
+        assert_eq!(state.metrics.conversion_errors("decode_corrupt"), 2);
+        
+        // An undecodable source is not tried again for other formats
+        let response = get(&state, "/media/photo.avif", "image/webp").await;
+        assert_eq!(body_bytes(response).await, avif);
+        assert!(state.memos.contains("/media/photo.avif", DecisionMemo::Undecodable));
+        let response = get(&state, "/media/photo.avif", "image/png").await;
+        assert_eq!(body_bytes(response).await, avif);
+        assert_eq!(state.metrics.conversion_errors("decode_unsupported"), 1);
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::Undecodable), 1);
+        
+        // JPEG cannot be that wide, so the next format of the ladder is served
+        let response = get(&state, "/media/wide.png", "image/jpeg").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(state.metrics.conversion_errors("encode_failed"), 1);
+        assert_eq!(Metrics::get(&state.metrics.conversion_fallbacks), 1);
+        assert!(state.metrics.render().contains("conversion_errors_total{kind=\"encode_failed\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_no_transform_serves_originals() {
+        let jpeg = encode_jpeg();
+        let fetcher = MockFetcher::default()
+            .with("/media/a.jpg", MockResponse::ok("image/jpeg", jpeg.clone()))
+            .with(
+                "/media/b.jpg",
+                MockResponse::ok("image/jpeg", jpeg.clone()).header(header::CACHE_CONTROL, "public, No-Transform"),
+            );
+        let (state, fetcher) = mock_state(mock_config(), fetcher);
+        let no_transform = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(header::ACCEPT, "image/webp")
+                .header(header::CACHE_CONTROL, "no-cache, no-transform")
+                .body(Body::empty())
+                .unwrap()
         };
         
-        (status, message).into_response()
+        // Requested by the client: the original is cached under the Original variant
+        for cache_status in ["MISS; no-transform", "HIT; no-transform"] {
+            let response = send(&state, no_transform("/media/a.jpg")).await;
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+            assert_eq!(response.headers()[X_CACHE_STATUS], cache_status);
+            assert_eq!(body_bytes(response).await, jpeg);
+        }
+        assert!(state.cache.get(&CacheKey::new("/media/a.jpg".to_string(), "Original".to_string())).await.is_some());
+        let response = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        
+        // Required by upstream: no client gets a conversion
+        let response = get(&state, "/media/b.jpg", "image/webp").await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "MISS; no-transform");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::NoTransform), 2);
+        assert!(state.metrics.render().contains("conversion_skipped_total{reason=\"no_transform\"} 2\n"));
+        assert_eq!(fetcher.requests().len(), 3);
+        
+        // Operators may choose to convert anyway
+        let mut config = mock_config();
+        config.server.ignore_no_transform = true;
+        let fetcher = MockFetcher::default().with(
+            "/media/b.jpg",
+            MockResponse::ok("image/jpeg", jpeg).header(header::CACHE_CONTROL, "no-transform"),
+        );
+        let (state, _) = mock_state(config, fetcher);
+        let response = send(&state, no_transform("/media/b.jpg")).await;
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(response.headers()[X_CACHE_STATUS], "MISS");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::NoTransform), 0);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::http::{HeaderMap, HeaderName, HeaderValue};
+    #[tokio::test]
+    async fn test_cached_digest_matches_served_body() {
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with("/media/a.txt", MockResponse::ok("text/plain", "passthrough body"))
+                .with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        
+        for (uri, accept, format) in [("/media/a.txt", "*/*", "Original"), ("/media/a.jpg", "image/webp", "WebP")] {
+            let response = get(&state, uri, accept).await;
+            let served = body_bytes(response).await;
+            let cached = state.cache.get(&CacheKey::new(uri.to_string(), format.to_string())).await.unwrap();
+            assert_eq!(cached.data, served);
+            assert_eq!(cached.meta.digest, Some(BodyDigest::of(&served).to_hex()), "{}", uri);
+        }
+    }
 
-    #[test]
-    fn test_build_response_no_duplicate_headers() {
-        // Create upstream headers that include content-type and via
-        let mut upstream_headers = HeaderMap::new();
-        upstream_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/jpeg"));
-        upstream_headers.insert(header::VIA, HeaderValue::from_static("upstream-proxy"));
-        upstream_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
-        upstream_headers.insert(HeaderName::from_static("x-cache-status"), HeaderValue::from_static("upstream-hit"));
-        upstream_headers.insert(HeaderName::from_static("x-custom-header"), HeaderValue::from_static("custom-value"));
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_avif_conversion_within_small_thread_budget() {
+        let mut config = mock_config();
+        config.image.avif_threads = 4;
+        config.image.max_conversion_threads = 1;
+        let (state, _) = mock_state(config, MockFetcher::always(MockResponse::ok("image/jpeg", encode_jpeg())));
         
-        // Build response with different content-type
-        let response = build_response(
-            Bytes::from("test data"),
-            "image/avif",
-            "akkoproxy/1.0",
-            Some(&upstream_headers),
-            true,
+        // The AVIF weight is capped at the budget instead of waiting forever
+        let response = get(&state, "/media/a.jpg", "image/avif").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        assert_eq!(state.conversion_permits.available_permits(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_parameterized_upstream_content_type() {
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("IMAGE/JPEG; charset=utf-8", encode_jpeg())),
         );
         
-        let headers = response.headers();
+        let response = get(&state, "/media/a.jpg", "image/jpeg").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/jpeg");
+        assert_eq!(state.metrics.conversion_skipped(SkipReason::FormatSatisfied), 1);
         
-        // Content-Type should only have the proxy's value (image/avif), not upstream's (image/jpeg)
-        let content_types: Vec<_> = headers.get_all(header::CONTENT_TYPE).iter().collect();
-        assert_eq!(content_types.len(), 1, "Content-Type should not be duplicated");
-        assert_eq!(content_types[0], "image/avif");
+        let response = get(&state, "/media/a.jpg", "image/webp").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+    }
+    
+    #[tokio::test]
+    async fn test_expired_entry_refreshed_once_under_stampede() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_ttl = 60;
+        config.cache.serve_stale_during_refresh = true;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with(
+                "/media/a.txt",
+                MockResponse::ok("text/plain", "original").header(header::ETAG, "\"v1\""),
+            ),
+        );
         
-        // Via should only have the proxy's value
-        let via_values: Vec<_> = headers.get_all(header::VIA).iter().collect();
-        assert_eq!(via_values.len(), 1, "Via should not be duplicated");
-        assert_eq!(via_values[0], "akkoproxy/1.0");
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         
-        // Cache-Control should only have the proxy's value
-        let cache_control_values: Vec<_> = headers.get_all(header::CACHE_CONTROL).iter().collect();
-        assert_eq!(cache_control_values.len(), 1, "Cache-Control should not be duplicated");
-        assert_eq!(cache_control_values[0], "public, max-age=31536000, immutable");
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "").status(StatusCode::NOT_MODIFIED));
+        fetcher.set_delay(Duration::from_millis(300));
+        let responses = futures::future::join_all((0..20).map(|_| get(&state, "/media/a.txt", "*/*"))).await;
         
-        // X-Cache-Status should only have the proxy's value
-        let x_cache_status_values: Vec<_> = headers.get_all("x-cache-status").iter().collect();
-        assert_eq!(x_cache_status_values.len(), 1, "X-Cache-Status should not be duplicated");
-        assert_eq!(x_cache_status_values[0], "HIT");
+        let mut statuses = Vec::new();
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::OK);
+            statuses.push(response.headers().get(X_CACHE_STATUS).unwrap().to_str().unwrap().to_string());
+            assert_eq!(body_bytes(response).await.as_ref(), b"original");
+        }
+        assert_eq!(statuses.iter().filter(|s| *s == "STALE").count(), 19);
+        assert_eq!(statuses.iter().filter(|s| *s == "HIT").count(), 1);
         
-        // Custom header should be preserved
-        assert_eq!(headers.get("x-custom-header").unwrap(), "custom-value");
+        // Exactly one conditional request went upstream
+        let request_headers = fetcher.request_headers();
+        assert_eq!(request_headers.len(), 2);
+        assert_eq!(request_headers[1].get(header::IF_NONE_MATCH).unwrap(), "\"v1\"");
+        assert_eq!(Metrics::get(&state.metrics.refresh_leaders), 1);
+        assert_eq!(Metrics::get(&state.metrics.refresh_followers), 19);
+        
+        // The revalidated entry is fresh again
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(fetcher.requests().len(), 2);
     }
     
-    #[test]
-    fn test_build_response_with_status_no_duplicate_headers() {
-        // Create upstream headers
-        let mut upstream_headers = HeaderMap::new();
-        upstream_headers.insert(header::VIA, HeaderValue::from_static("upstream-proxy"));
-        upstream_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("https://example.com"));
-        upstream_headers.insert(HeaderName::from_static("x-custom-header"), HeaderValue::from_static("custom-value"));
+    #[tokio::test]
+    async fn test_slow_prefix_fetch_not_swept_as_stuck() {
+        use crate::config::TimeoutOverride;
         
-        // Build response
-        let response = build_response_with_status(
-            Bytes::from("redirect"),
-            StatusCode::MOVED_PERMANENTLY,
-            "akkoproxy/1.0",
-            Some(&upstream_headers),
+        let mut config = mock_config();
+        config.upstream.timeout = 1;
+        config.upstream.timeout_overrides = vec![TimeoutOverride {
+            prefix: "/media/archive/".to_string(),
+            timeout: 5,
+            body_retries: None,
+        }];
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/archive/a.txt", MockResponse::ok("text/plain", "archived")),
         );
+        fetcher.set_delay(Duration::from_millis(2500));
         
-        let headers = response.headers();
+        // The second request arrives after twice the global timeout, with the first still fetching
+        let late = async {
+            tokio::time::sleep(Duration::from_millis(2200)).await;
+            get(&state, "/media/archive/a.txt", "*/*").await
+        };
+        let (first, second) = tokio::join!(get(&state, "/media/archive/a.txt", "*/*"), late);
+        for response in [first, second] {
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(body_bytes(response).await.as_ref(), b"archived");
+        }
+        assert_eq!(fetcher.requests().len(), 1);
+        assert_eq!(Metrics::get(&state.metrics.coalesced_misses), 1);
+        assert_eq!(state.refreshes.swept(), 0);
+    }
+    
+    #[tokio::test]
+    async fn test_stale_while_revalidate_refreshes_in_background() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_while_revalidate = 60;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/a.txt", MockResponse::ok("text/plain", "original")),
+        );
         
-        // Via should only have the proxy's value
-        let via_values: Vec<_> = headers.get_all(header::VIA).iter().collect();
-        assert_eq!(via_values.len(), 1, "Via should not be duplicated");
-        assert_eq!(via_values[0], "akkoproxy/1.0");
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         
-        // Access-Control-Allow-Origin should have upstream's value (not replaced)
-        let acao_values: Vec<_> = headers.get_all(header::ACCESS_CONTROL_ALLOW_ORIGIN).iter().collect();
-        assert_eq!(acao_values.len(), 1, "Access-Control-Allow-Origin should not be duplicated");
-        assert_eq!(acao_values[0], "https://example.com");
+        // Every request is answered from the expired entry without waiting for upstream
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "updated"));
+        fetcher.set_delay(Duration::from_millis(300));
+        let responses = futures::future::join_all((0..5).map(|_| get(&state, "/media/a.txt", "*/*"))).await;
+        for response in responses {
+            assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "STALE");
+            assert_eq!(body_bytes(response).await.as_ref(), b"original");
+        }
+        assert_eq!(Metrics::get(&state.metrics.background_refreshes), 1);
+        assert_eq!(Metrics::get(&state.metrics.refresh_followers), 4);
         
-        // Custom header should be preserved
-        assert_eq!(headers.get("x-custom-header").unwrap(), "custom-value");
+        // Once the one background refresh is done the new body is served fresh
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(body_bytes(response).await.as_ref(), b"updated");
+        assert_eq!(fetcher.requests().len(), 2);
     }
     
-    #[test]
-    fn test_parse_query_for_format() {
-        // Test format=avif
-        let (format, remaining) = parse_query_for_format("format=avif&other=value");
-        assert_eq!(format, Some(OutputFormat::Avif));
-        assert_eq!(remaining, "other=value");
+    #[tokio::test]
+    async fn test_recovered_path_replaces_expired_negative_entry() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_ttl = 60;
+        config.cache.serve_stale_during_refresh = true;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with(
+                "/media/a.txt",
+                MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND),
+            ),
+        );
         
-        // Test format=webp
-        let (format, remaining) = parse_query_for_format("format=webp");
-        assert_eq!(format, Some(OutputFormat::WebP));
-        assert_eq!(remaining, "");
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         
-        // Test no format parameter
-        let (format, remaining) = parse_query_for_format("other=value&another=test");
-        assert_eq!(format, None);
-        assert_eq!(remaining, "other=value&another=test");
+        // Upstream has the file now, the request refreshing the expired 404 gets it
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "back"));
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
         
-        // Test format with unknown value
-        let (format, remaining) = parse_query_for_format("format=jpeg&other=value");
-        assert_eq!(format, None);
-        assert_eq!(remaining, "other=value");
+        // The expired 404 no longer shadows the stored body
+        let response = get(&state, "/media/a.txt", "*/*").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "HIT");
+        assert_eq!(body_bytes(response).await.as_ref(), b"back");
+        assert_eq!(fetcher.requests().len(), 2);
+        assert_eq!(Metrics::get(&state.metrics.refresh_leaders), 1);
+    }
+    
+    #[tokio::test]
+    #[cfg(feature = "avif")]
+    async fn test_changed_source_invalidates_other_variants() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_ttl = 60;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/a.jpg", MockResponse::ok("image/jpeg", encode_jpeg())),
+        );
+        let cached = |format: &str| CacheKey::new("/media/a.jpg".to_string(), format.to_string());
         
-        // Test format in middle
-        let (format, remaining) = parse_query_for_format("a=1&format=avif&b=2");
-        assert_eq!(format, Some(OutputFormat::Avif));
-        assert_eq!(remaining, "a=1&b=2");
+        get(&state, "/media/a.jpg", "image/avif,*/*").await;
+        get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         
-        // Test format with URL encoding (spaces as +)
-        let (format, remaining) = parse_query_for_format("format=webp+test&other=value");
-        assert_eq!(format, None); // Should not match due to extra text
-        assert_eq!(remaining, "other=value");
+        // Refreshing WebP with the same source leaves AVIF alone
+        get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert!(state.cache.lookup(&cached("Avif")).await.is_some());
+        assert_eq!(Metrics::get(&state.metrics.variant_invalidations), 0);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
         
-        // Test format with case insensitivity
-        let (format, remaining) = parse_query_for_format("format=AVIF");
-        assert_eq!(format, Some(OutputFormat::Avif));
-        assert_eq!(remaining, "");
+        // A replaced source takes the AVIF made from the old one with it
+        let replaced = encode(image::RgbImage::from_pixel(8, 8, image::Rgb([10, 10, 200])), image::ImageFormat::Jpeg);
+        fetcher.respond("/media/a.jpg", MockResponse::ok("image/jpeg", replaced));
+        let response = get(&state, "/media/a.jpg", "image/webp,*/*").await;
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(state.cache.lookup(&cached("Avif")).await.is_none());
+        assert!(state.cache.get(&cached("WebP")).await.is_some());
+        assert_eq!(Metrics::get(&state.metrics.variant_invalidations), 1);
         
-        let (format, remaining) = parse_query_for_format("format=WebP");
-        assert_eq!(format, Some(OutputFormat::WebP));
-        assert_eq!(remaining, "");
+        // The next AVIF request converts the new source
+        let response = get(&state, "/media/a.jpg", "image/avif,*/*").await;
+        assert_eq!(response.headers().get(X_CACHE_STATUS).unwrap(), "MISS");
+        assert_eq!(fetcher.requests().len(), 5);
+    }
+    
+    #[tokio::test]
+    async fn test_refresh_followers_wait_without_stale_serving() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_ttl = 60;
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/a.txt", MockResponse::ok("text/plain", "v1")),
+        );
         
-        // Test format with whitespace (+ is space in query strings)
-        let (format, remaining) = parse_query_for_format("format=+avif+&other=value");
-        assert_eq!(format, Some(OutputFormat::Avif));
-        assert_eq!(remaining, "other=value");
+        get(&state, "/media/a.txt", "*/*").await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        
+        // Without an ETag the leader downloads the body again and followers get its result
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "v2"));
+        fetcher.set_delay(Duration::from_millis(200));
+        let responses = futures::future::join_all((0..5).map(|_| get(&state, "/media/a.txt", "*/*"))).await;
+        for response in responses {
+            assert_eq!(body_bytes(response).await.as_ref(), b"v2");
+        }
+        assert_eq!(fetcher.requests().len(), 2);
+        assert!(fetcher.request_headers()[1].get(header::IF_NONE_MATCH).is_none());
+    }
+    
+    #[tokio::test]
+    async fn test_cancelled_refresh_leader_releases_followers() {
+        let mut config = mock_config();
+        config.cache.ttl = 1;
+        config.cache.stale_ttl = 60;
+        config.server.admin_token = Some("secret".to_string());
+        let (state, fetcher) = mock_state(
+            config,
+            MockFetcher::default().with("/media/a.txt", MockResponse::ok("text/plain", "v1")),
+        );
+        
+        get(&state, "/media/a.txt", "*/*").await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        
+        // The leader hangs upstream and a follower waits for it
+        fetcher.respond("/media/a.txt", MockResponse::ok("text/plain", "v2"));
+        fetcher.set_delay(Duration::from_secs(60));
+        let leader = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "/media/a.txt", "*/*").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        fetcher.set_delay(Duration::ZERO);
+        let follower = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "/media/a.txt", "*/*").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(state.refreshes.len(), 1);
+        assert!(!follower.is_finished());
+        
+        let request = Request::builder()
+            .uri("/admin/stats/inflight")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let inflight: serde_json::Value = serde_json::from_slice(&body_bytes(send(&state, request).await).await).unwrap();
+        assert_eq!(inflight["count"], 1);
+        assert!(inflight["keys"][0]["cache_key"].as_str().unwrap().contains("/media/a.txt"));
+        
+        // Cancelling the leader lets the follower fetch on its own
+        leader.abort();
+        let response = tokio::time::timeout(Duration::from_secs(5), follower).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await.as_ref(), b"v2");
+        assert!(state.refreshes.is_empty());
+        
+        // An admin can release the followers of a stuck key by hand
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        fetcher.set_delay(Duration::from_secs(60));
+        let stuck = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "/media/a.txt", "*/*").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        fetcher.set_delay(Duration::ZERO);
+        let follower = tokio::spawn({
+            let state = state.clone();
+            async move { get(&state, "/media/a.txt", "*/*").await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let request = Request::builder()
+            .method("POST")
+            .uri("/admin/stats/inflight?path=/media/a.txt")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let cleared: serde_json::Value = serde_json::from_slice(&body_bytes(send(&state, request).await).await).unwrap();
+        assert_eq!(cleared["cleared"], 1);
+        let response = tokio::time::timeout(Duration::from_secs(5), follower).await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.refreshes.is_empty());
+        stuck.abort();
+        
+        let metrics = send(&state, Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await;
+        let metrics = String::from_utf8(body_bytes(metrics).await.to_vec()).unwrap();
+        assert!(metrics.contains("refreshes_in_flight 0\n"), "{}", metrics);
+        assert!(metrics.contains("refreshes_swept_total 1\n"), "{}", metrics);
+    }
+    
+    #[tokio::test]
+    async fn test_stuck_refresh_swept_in_background() {
+        let mut config = mock_config();
+        config.upstream.timeout = 1;
+        let (state, _) = mock_state(config, MockFetcher::default());
+        
+        // A leader that never finishes, as if its task had been leaked
+        let key = CacheKey::new("/media/a.txt".to_string(), "Original".to_string());
+        std::mem::forget(state.refreshes.join(&key));
+        assert_eq!(state.refreshes.len(), 1);
+        
+        // Dropped past the deadline of two seconds without any request touching it
+        tokio::time::sleep(Duration::from_millis(3500)).await;
+        assert!(state.refreshes.is_empty());
+        assert_eq!(state.refreshes.swept(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_negative_cache_entry_shared_across_formats() {
+        let (state, fetcher) = mock_state(
+            mock_config(),
+            MockFetcher::always(MockResponse::ok("text/plain", "gone").status(StatusCode::NOT_FOUND)),
+        );
+        
+        let response = get(&state, "/media/foo.jpg", "image/avif,*/*").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        
+        for accept in ["image/webp,*/*", "*/*"] {
+            let response = get(&state, "/media/foo.jpg", accept).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(body_bytes(response).await.as_ref(), b"gone");
+        }
+        let response = get(&state, "/media/foo.jpg?format=original", "*/*").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(fetcher.requests().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn test_oversized_error_response_truncated() {
+        let huge_body = vec![b'x'; 5 * 1024 * 1024];
+        let huge_header = "y".repeat(32 * 1024);
+        let mut huge_headers = MockResponse::ok("text/html", huge_body.clone())
+            .status(StatusCode::NOT_FOUND);
+        huge_headers.headers.insert(
+            HeaderName::from_static("x-huge"),
+            HeaderValue::from_str(&huge_header).unwrap(),
+        );
+        let (state, _) = mock_state(
+            mock_config(),
+            MockFetcher::default()
+                .with("/media/huge.jpg", MockResponse::ok("text/html", huge_body).status(StatusCode::NOT_FOUND))
+                .with("/media/headers.jpg", huge_headers)
+                .with("/media/small.jpg", MockResponse::ok("text/html", "small").status(StatusCode::NOT_FOUND)),
+        );
+        
+        let response = get(&state, "/media/huge.jpg", "*/*").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(X_AKKOPROXY_TRUNCATED).unwrap(), "body");
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-store");
+        assert_eq!(body_bytes(response).await.as_ref(), ERROR_BODY_TOO_LARGE.as_bytes());
+        
+        let response = get(&state, "/media/headers.jpg", "*/*").await;
+        assert_eq!(response.headers().get(X_AKKOPROXY_TRUNCATED).unwrap(), "headers, body");
+        assert!(response.headers().get("x-huge").is_none());
+        
+        let response = get(&state, "/media/small.jpg", "*/*").await;
+        assert!(response.headers().get(X_AKKOPROXY_TRUNCATED).is_none());
+        assert_eq!(body_bytes(response).await.as_ref(), b"small");
+        
+        assert_eq!(Metrics::get(&state.metrics.truncated_error_responses), 2);
+    }
+    
+    #[tokio::test]
+    async fn test_relative_root_redirect_uses_external_base_url() {
+        let mut config = mock_config();
+        config.server.root_redirect = "/about".to_string();
+        config.server.external_base_url = Some("https://media.example.com".to_string());
+        let (state, _) = mock_state(config, MockFetcher::default());
+        
+        let response = get(&state, "/", "*/*").await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "https://media.example.com/about");
+        
+        // Without connection info the forwarded headers of the client are ignored
+        let mut config = mock_config();
+        config.server.root_redirect = "/about".to_string();
+        config.server.trusted_proxies = vec!["0.0.0.0/0".to_string()];
+        let (state, _) = mock_state(config, MockFetcher::default());
+        let request = Request::builder()
+            .uri("/")
+            .header("x-forwarded-proto", "https")
+            .header("x-forwarded-host", "evil.example")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "http://0.0.0.0:3000/about");
     }
     
     #[test]
@@ -647,7 +7206,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             Some(&upstream_headers),
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         // Should use upstream CORS value
@@ -659,7 +7219,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             None,
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         // Should use default "*"
@@ -674,7 +7235,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             None,
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
@@ -686,7 +7248,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             Some(&upstream_headers),
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
@@ -703,7 +7266,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             Some(&upstream_headers),
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept, Origin, User-Agent");
@@ -717,7 +7281,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             Some(&upstream_headers),
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         // Should not duplicate Accept
@@ -732,7 +7297,8 @@ mod tests {
             "text/plain",
             "akkoproxy/1.0",
             Some(&upstream_headers),
-            false,
+            CacheStatus::Miss,
+            None,
         );
         
         // Should recognize case-insensitive match and not duplicate
@@ -747,6 +7313,7 @@ mod tests {
             StatusCode::NOT_FOUND,
             "akkoproxy/1.0",
             None,
+            "no-store",
         );
         
         assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept");
@@ -760,6 +7327,7 @@ mod tests {
             StatusCode::MOVED_PERMANENTLY,
             "akkoproxy/1.0",
             Some(&upstream_headers),
+            "no-store",
         );
         
         assert_eq!(response.headers().get(header::VARY).unwrap(), "Accept, Origin");
@@ -0,0 +1,131 @@
+//! Byte ranges of `Range` requests, as media players send them to seek
+//!
+//! Only single ranges are served; a request for several ranges gets the whole
+//! body, which HTTP allows and players do not ask for anyway.
+
+use axum::http::{header, HeaderMap};
+use std::ops::Range;
+
+/// What a `Range` header asks of a body of known length
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranged {
+    /// The whole body, as no single byte range was asked for
+    Full,
+    /// The bytes in this range
+    Partial(Range<u64>),
+    /// A malformed range or one starting past the end, answered with 416
+    Unsatisfiable,
+}
+
+/// Resolve the `Range` header value `range` against a body of `len` bytes
+///
+/// Units other than `bytes` are ignored, as HTTP requires.
+pub fn resolve(range: &str, len: u64) -> Ranged {
+    let Some((unit, specs)) = range.trim().split_once('=') else {
+        return Ranged::Unsatisfiable;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranged::Full;
+    }
+    if specs.contains(',') {
+        return Ranged::Full;
+    }
+    let Some((first, last)) = specs.trim().split_once('-') else {
+        return Ranged::Unsatisfiable;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last `last` bytes
+        _ if first.is_empty() => match last.parse::<u64>() {
+            Ok(suffix) if suffix > 0 => len.saturating_sub(suffix)..len,
+            _ => return Ranged::Unsatisfiable,
+        },
+        (Ok(first), _) if last.is_empty() => first..len,
+        (Ok(first), Ok(last)) if first <= last => first..last.saturating_add(1).min(len),
+        _ => return Ranged::Unsatisfiable,
+    };
+    if range.start >= len {
+        return Ranged::Unsatisfiable;
+    }
+    Ranged::Partial(range)
+}
+
+/// `Content-Range` value of `range` out of a body of `len` bytes
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// Whether a 206 response with these headers carries its whole body after all
+///
+/// Players open media with `Range: bytes=0-`, which upstream answers with the
+/// complete file; such answers can be cached and converted like a 200.
+pub fn is_whole_body(headers: &HeaderMap) -> bool {
+    let parsed = headers
+        .get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|range| {
+            let (span, len) = range.strip_prefix("bytes ")?.split_once('/')?;
+            let (first, last) = span.split_once('-')?;
+            Some((first.trim().parse::<u64>().ok()?, last.trim().parse::<u64>().ok()?, len.trim().parse::<u64>().ok()?))
+        });
+    matches!(parsed, Some((0, last, len)) if last + 1 == len)
+}
+
+/// Whether the `If-Range` of a request, if any, matches a response with `response_headers`
+///
+/// A changed representation is sent whole rather than sliced. Only strong
+/// ETags and exactly equal `Last-Modified` dates count as matching.
+pub fn if_range_matches(request_headers: &HeaderMap, response_headers: &HeaderMap) -> bool {
+    let Some(if_range) = request_headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let matches = |name| response_headers.get(name).is_some_and(|value| value == if_range);
+    if if_range.as_bytes().starts_with(b"W/") {
+        return false;
+    }
+    matches(header::ETAG) || matches(header::LAST_MODIFIED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve("bytes=0-", 1000), Ranged::Partial(0..1000));
+        assert_eq!(resolve("bytes=100-199", 1000), Ranged::Partial(100..200));
+        assert_eq!(resolve("bytes=900-5000", 1000), Ranged::Partial(900..1000));
+        assert_eq!(resolve("bytes=-100", 1000), Ranged::Partial(900..1000));
+        assert_eq!(resolve("bytes=-5000", 1000), Ranged::Partial(0..1000));
+        assert_eq!(resolve("Bytes = 5-5", 1000), Ranged::Partial(5..6));
+
+        // Several ranges and other units are served whole
+        assert_eq!(resolve("bytes=0-1,5-9", 1000), Ranged::Full);
+        assert_eq!(resolve("items=0-1", 1000), Ranged::Full);
+
+        for unsatisfiable in ["bytes=1000-", "bytes=5-2", "bytes=-0", "bytes=x-", "bytes=", "bytes 0-1", "bytes=-"] {
+            assert_eq!(resolve(unsatisfiable, 1000), Ranged::Unsatisfiable, "{}", unsatisfiable);
+        }
+        assert_eq!(resolve("bytes=0-", 0), Ranged::Unsatisfiable);
+        assert_eq!(content_range(&(100..200), 1000), "bytes 100-199/1000");
+    }
+
+    #[test]
+    fn test_whole_body_and_if_range() {
+        let headers = |pairs: &[(header::HeaderName, &'static str)]| -> HeaderMap {
+            pairs.iter().map(|(name, value)| (name.clone(), HeaderValue::from_static(value))).collect()
+        };
+        assert!(is_whole_body(&headers(&[(header::CONTENT_RANGE, "bytes 0-999/1000")])));
+        assert!(!is_whole_body(&headers(&[(header::CONTENT_RANGE, "bytes 0-499/1000")])));
+        assert!(!is_whole_body(&headers(&[(header::CONTENT_RANGE, "bytes 0-999/*")])));
+        assert!(!is_whole_body(&headers(&[(header::CONTENT_RANGE, "bytes */1000")])));
+
+        let response = headers(&[(header::ETAG, "\"v1\""), (header::LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT")]);
+        assert!(if_range_matches(&HeaderMap::new(), &response));
+        assert!(if_range_matches(&headers(&[(header::IF_RANGE, "\"v1\"")]), &response));
+        assert!(if_range_matches(&headers(&[(header::IF_RANGE, "Wed, 21 Oct 2015 07:28:00 GMT")]), &response));
+        assert!(!if_range_matches(&headers(&[(header::IF_RANGE, "\"v2\"")]), &response));
+        assert!(!if_range_matches(&headers(&[(header::IF_RANGE, "W/\"v1\"")]), &response));
+    }
+}
@@ -0,0 +1,150 @@
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use std::sync::Arc;
+use tracing::info;
+
+use crate::metrics::Metrics;
+use crate::upstream::{FetchError, UpstreamFetcher, UpstreamResponse};
+
+/// Body stream of an upstream response
+pub type BodyStream = BoxStream<'static, Result<Bytes, FetchError>>;
+
+/// How a failed body transfer continues
+pub enum Refetched {
+    /// The rest of the body, starting at the offset already received
+    Resumed(BodyStream),
+    /// The whole body again; whatever was received must be discarded
+    Restarted(BodyStream),
+}
+
+/// Everything needed to fetch an upstream body again after its transfer failed partway
+///
+/// With a strong ETag and `Accept-Ranges: bytes` on the first response, the
+/// transfer is resumed with a `Range` request guarded by `If-Range`, so upstream
+/// answers with the whole new body instead of a slice of it if the file changed
+/// in between. Without those validators the body is fetched again in full.
+pub struct BodyRetry {
+    fetcher: Arc<dyn UpstreamFetcher>,
+    url: String,
+    headers: HeaderMap,
+    /// ETag to resume against, `None` when the response cannot be resumed
+    validator: Option<HeaderValue>,
+    attempts_left: u32,
+    metrics: Arc<Metrics>,
+}
+
+impl BodyRetry {
+    /// Retry for the body of `response`, fetched from `url` with `headers`
+    pub fn new(
+        fetcher: Arc<dyn UpstreamFetcher>,
+        url: &str,
+        mut headers: HeaderMap,
+        response: &UpstreamResponse,
+        attempts: u32,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        // A retry must get a body, not a confirmation that a stale entry is current
+        headers.remove(header::IF_NONE_MATCH);
+        Self {
+            fetcher,
+            url: url.to_string(),
+            validator: resume_validator(&headers, &response.headers),
+            headers,
+            attempts_left: attempts,
+            metrics,
+        }
+    }
+
+    /// Fetch the body again after `received` bytes of it arrived, `None` once out of attempts
+    pub async fn refetch(&mut self, received: u64) -> Option<Result<Refetched, FetchError>> {
+        self.attempts_left = self.attempts_left.checked_sub(1)?;
+        Some(self.send(received).await)
+    }
+
+    async fn send(&self, received: u64) -> Result<Refetched, FetchError> {
+        let mut headers = self.headers.clone();
+        let validator = self.validator.as_ref().filter(|_| received > 0);
+        if let Some(etag) = validator {
+            headers.insert(
+                header::RANGE,
+                HeaderValue::from_str(&format!("bytes={}-", received)).expect("Byte range is a valid header value"),
+            );
+            headers.insert(header::IF_RANGE, etag.clone());
+        }
+
+        let response = self.fetcher.fetch(&self.url, headers).await?;
+        match response.status {
+            StatusCode::PARTIAL_CONTENT if validator.is_some() && range_start(&response.headers) == Some(received) => {
+                info!("Resumed upstream transfer of {} at byte {}", self.url, received);
+                Metrics::incr(&self.metrics.resumed_transfers);
+                self.metrics.resume_bytes_saved.fetch_add(received, std::sync::atomic::Ordering::Relaxed);
+                Ok(Refetched::Resumed(response.body))
+            }
+            StatusCode::OK => {
+                info!("Fetching {} again from the start after {} bytes", self.url, received);
+                Ok(Refetched::Restarted(response.body))
+            }
+            status => Err(FetchError::RetryStatus(status)),
+        }
+    }
+}
+
+/// ETag a transfer can be resumed against
+///
+/// Ranges of a body decompressed on the fly do not line up with what upstream
+/// sends, so only bodies requested without content coding qualify. Weak ETags
+/// cannot guard byte ranges.
+fn resume_validator(request_headers: &HeaderMap, response_headers: &HeaderMap) -> Option<HeaderValue> {
+    let identity = request_headers
+        .get(header::ACCEPT_ENCODING)
+        .is_some_and(|encoding| encoding == "identity");
+    let ranges = response_headers
+        .get(header::ACCEPT_RANGES)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|units| units.split(',').any(|unit| unit.trim().eq_ignore_ascii_case("bytes")));
+    let etag = response_headers
+        .get(header::ETAG)
+        .filter(|etag| etag.as_bytes().starts_with(b"\""))?;
+    (identity && ranges).then(|| etag.clone())
+}
+
+/// First byte of a `Content-Range: bytes <start>-<end>/<size>` response
+fn range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (name.clone(), HeaderValue::from_static(value))).collect()
+    }
+
+    #[test]
+    fn test_resume_validator() {
+        let identity = headers(&[(header::ACCEPT_ENCODING, "identity")]);
+        let resumable = headers(&[(header::ACCEPT_RANGES, "bytes"), (header::ETAG, "\"v1\"")]);
+        assert_eq!(resume_validator(&identity, &resumable).unwrap(), "\"v1\"");
+
+        // Decompressed bodies, weak ETags and missing range support all rule it out
+        let compressed = headers(&[(header::ACCEPT_ENCODING, "gzip, br")]);
+        assert_eq!(resume_validator(&compressed, &resumable), None);
+        let weak = headers(&[(header::ACCEPT_RANGES, "bytes"), (header::ETAG, "W/\"v1\"")]);
+        assert_eq!(resume_validator(&identity, &weak), None);
+        let no_ranges = headers(&[(header::ACCEPT_RANGES, "none"), (header::ETAG, "\"v1\"")]);
+        assert_eq!(resume_validator(&identity, &no_ranges), None);
+        let no_etag = headers(&[(header::ACCEPT_RANGES, "bytes")]);
+        assert_eq!(resume_validator(&identity, &no_etag), None);
+    }
+
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start(&headers(&[(header::CONTENT_RANGE, "bytes 900-999/1000")])), Some(900));
+        assert_eq!(range_start(&headers(&[(header::CONTENT_RANGE, "bytes */1000")])), None);
+        assert_eq!(range_start(&HeaderMap::new()), None);
+    }
+}
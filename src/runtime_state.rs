@@ -0,0 +1,225 @@
+//! Operational states switched on at runtime through `POST /admin/state`
+//!
+//! Every state is set either for a limited time or explicitly as permanent, so
+//! a forgotten maintenance window ends by itself. Expired states stop applying
+//! at once; a background task removes them and records their expiry.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::audit::{AuditEvent, AuditLog};
+
+/// A state operators can switch on and off while the proxy runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuntimeState {
+    /// Serve from the cache only, answering 503 where upstream would be asked
+    Maintenance,
+    /// Skip conversions as if the host were overloaded
+    ShedConversions,
+}
+
+impl RuntimeState {
+    pub const ALL: [RuntimeState; 2] = [RuntimeState::Maintenance, RuntimeState::ShedConversions];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuntimeState::Maintenance => "maintenance",
+            RuntimeState::ShedConversions => "shed_conversions",
+        }
+    }
+}
+
+/// Who switched a state on, as recorded in the audit log
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Actor {
+    pub client_ip: Option<IpAddr>,
+    pub token_fingerprint: Option<String>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    note: Option<String>,
+    actor: Actor,
+    /// Seconds since the Unix epoch
+    set_at: f64,
+    /// `None` for permanent states
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// An active state as listed by `GET /admin/state`
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveState {
+    pub state: RuntimeState,
+    pub note: Option<String>,
+    pub set_by: Actor,
+    pub set_at: f64,
+    /// Seconds until the state expires, `None` for permanent states
+    pub remaining_secs: Option<u64>,
+}
+
+/// The states currently switched on
+#[derive(Debug, Default)]
+pub struct RuntimeStates {
+    active: Mutex<HashMap<RuntimeState, Entry>>,
+}
+
+impl RuntimeStates {
+    /// Switch `state` on for `ttl`, or until switched off when `None`, replacing an earlier setting
+    pub fn set(&self, state: RuntimeState, ttl: Option<Duration>, note: Option<String>, actor: Actor) {
+        let entry = Entry {
+            note,
+            actor,
+            set_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        match ttl {
+            Some(ttl) => info!("Runtime state {} set for {}s", state.as_str(), ttl.as_secs()),
+            None => info!("Runtime state {} set permanently", state.as_str()),
+        }
+        self.active.lock().unwrap().insert(state, entry);
+    }
+
+    /// Switch `state` off, returning whether it was on
+    pub fn clear(&self, state: RuntimeState) -> bool {
+        let now = Instant::now();
+        let removed = self.active.lock().unwrap().remove(&state);
+        let was_active = removed.is_some_and(|entry| !entry.is_expired(now));
+        if was_active {
+            info!("Runtime state {} cleared", state.as_str());
+        }
+        was_active
+    }
+
+    /// Whether `state` is on and has not expired yet
+    pub fn is_active(&self, state: RuntimeState) -> bool {
+        self.remaining(state).is_some()
+    }
+
+    /// Time left until `state` expires: `None` when it is off, `Duration::MAX` when permanent
+    pub fn remaining(&self, state: RuntimeState) -> Option<Duration> {
+        let now = Instant::now();
+        let active = self.active.lock().unwrap();
+        let entry = active.get(&state).filter(|entry| !entry.is_expired(now))?;
+        Some(entry.expires_at.map_or(Duration::MAX, |expires_at| expires_at - now))
+    }
+
+    /// The active states, in the order of [`RuntimeState::ALL`]
+    pub fn list(&self) -> Vec<ActiveState> {
+        let now = Instant::now();
+        let active = self.active.lock().unwrap();
+        RuntimeState::ALL
+            .into_iter()
+            .filter_map(|state| {
+                let entry = active.get(&state).filter(|entry| !entry.is_expired(now))?;
+                Some(ActiveState {
+                    state,
+                    note: entry.note.clone(),
+                    set_by: entry.actor.clone(),
+                    set_at: entry.set_at,
+                    remaining_secs: entry
+                        .expires_at
+                        .map(|expires_at| (expires_at - now).as_secs_f64().ceil() as u64),
+                })
+            })
+            .collect()
+    }
+
+    /// Remove the states that have expired, returning them
+    pub fn sweep(&self) -> Vec<RuntimeState> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.active.lock().unwrap().retain(|state, entry| {
+            let keep = !entry.is_expired(now);
+            if !keep {
+                expired.push(*state);
+            }
+            keep
+        });
+        expired
+    }
+}
+
+/// Sweep expired states out of `states` every `interval` until `cancel` fires
+///
+/// Each expiry is logged and recorded in `audit` as a `state_expired` action.
+pub fn spawn_expiry(states: Arc<RuntimeStates>, audit: AuditLog, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    for state in states.sweep() {
+                        info!("Runtime state {} expired", state.as_str());
+                        audit.record(AuditEvent::new("state_expired", serde_json::json!({ "state": state })));
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_states_expire() {
+        let states = RuntimeStates::default();
+        assert!(!states.is_active(RuntimeState::Maintenance));
+
+        states.set(RuntimeState::Maintenance, Some(Duration::from_millis(30)), Some("migrating".to_string()), Actor::default());
+        states.set(RuntimeState::ShedConversions, None, None, Actor::default());
+        assert!(states.is_active(RuntimeState::Maintenance));
+        assert_eq!(states.remaining(RuntimeState::ShedConversions), Some(Duration::MAX));
+        let listed = states.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].note.as_deref(), Some("migrating"));
+        assert_eq!(listed[0].remaining_secs, Some(1));
+        assert_eq!(listed[1].remaining_secs, None);
+
+        // Expired states stop applying before they are swept
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!states.is_active(RuntimeState::Maintenance));
+        assert_eq!(states.list().len(), 1);
+        assert_eq!(states.sweep(), vec![RuntimeState::Maintenance]);
+        assert!(states.sweep().is_empty());
+
+        assert!(states.clear(RuntimeState::ShedConversions));
+        assert!(!states.clear(RuntimeState::ShedConversions));
+        assert!(states.list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_task_sweeps() {
+        let states = Arc::new(RuntimeStates::default());
+        let cancel = CancellationToken::new();
+        spawn_expiry(states.clone(), AuditLog::disabled(), Duration::from_millis(5), cancel.clone());
+        states.set(RuntimeState::Maintenance, Some(Duration::from_millis(20)), None, Actor::default());
+
+        for _ in 0..200 {
+            if states.active.lock().unwrap().is_empty() {
+                cancel.cancel();
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        panic!("Expired state was not swept");
+    }
+}
@@ -0,0 +1,236 @@
+use axum::extract::ConnectInfo;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::time::Sleep;
+use tokio_util::sync::CancellationToken;
+use tower::Service;
+use tracing::{debug, warn};
+
+use crate::metrics::Metrics;
+
+/// Serve `app` on `listener` until `shutdown` resolves, then drain the open connections
+///
+/// Works like `axum::serve` with `ConnectInfo<SocketAddr>`, except that a connection
+/// whose client reads nothing of a response for `write_timeout` is closed. Most responses
+/// are only written once their body is complete and cached, so aborting a slow client
+/// never affects the upstream transfer or the cache entry. Streamed bodies, too large
+/// to buffer, are read from upstream into a spool ahead of the client instead, and
+/// closing the connection aborts the upstream transfer along with it.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    write_timeout: Option<Duration>,
+    metrics: Arc<Metrics>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    let builder = auto::Builder::new(TokioExecutor::new());
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors, which frees up as connections close
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let io = TokioIo::new(WriteTimeout::new(stream, write_timeout, metrics.clone()));
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+            request.extensions_mut().insert(ConnectInfo(remote));
+            app.clone().call(request)
+        });
+        let connection = graceful.watch(builder.serve_connection_with_upgrades(io, service).into_owned());
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Connection from {} ended with an error: {}", remote, e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+    Ok(())
+}
+
+/// Serve `app` on every listener until `shutdown` resolves, then drain them all
+///
+/// The listeners share one router, and with it one cache. They stop accepting
+/// together, and this returns once the connections of every one have drained.
+pub async fn serve_all(
+    listeners: Vec<TcpListener>,
+    app: Router,
+    write_timeout: Option<Duration>,
+    metrics: Arc<Metrics>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let stop = CancellationToken::new();
+    let servers = futures::future::try_join_all(listeners.into_iter().map(|listener| {
+        serve(listener, app.clone(), write_timeout, metrics.clone(), stop.clone().cancelled_owned())
+    }));
+    tokio::pin!(servers);
+
+    tokio::select! {
+        served = &mut servers => return served.map(drop),
+        _ = shutdown => stop.cancel(),
+    }
+    servers.await.map(drop)
+}
+
+/// Connection failing its writes once the client has accepted no data for a while
+///
+/// The timer only runs while a write is pending, so idle keep-alive connections
+/// are unaffected, and it restarts whenever the client takes any data.
+pub struct WriteTimeout<IO> {
+    io: IO,
+    timeout: Option<Duration>,
+    /// Fires when the pending write has made no progress for `timeout`
+    stalled: Option<Pin<Box<Sleep>>>,
+    metrics: Arc<Metrics>,
+}
+
+impl<IO> WriteTimeout<IO> {
+    pub fn new(io: IO, timeout: Option<Duration>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            io,
+            timeout,
+            stalled: None,
+            metrics,
+        }
+    }
+
+    /// Pass on the outcome of a write, failing it if it has been pending too long
+    fn guard<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        let Some(timeout) = self.timeout.filter(|_| poll.is_pending()) else {
+            self.stalled = None;
+            return poll;
+        };
+        let stalled = self.stalled.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match stalled.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.stalled = None;
+                Metrics::incr(&self.metrics.client_write_timeouts);
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("client accepted no data for {:?}", timeout),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for WriteTimeout<IO> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<IO> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.io).poll_write(cx, buf);
+        self.guard(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.io).poll_write_vectored(cx, bufs);
+        self.guard(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.io).poll_flush(cx);
+        self.guard(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::CacheKey;
+    use crate::config::Config;
+    use crate::proxy::{router, AppState};
+    use crate::upstream::mock::{MockFetcher, MockResponse};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_stalled_client_is_cut_off_after_the_entry_is_cached() {
+        const BODY_SIZE: usize = 16 * 1024 * 1024;
+        let mut config = Config::with_upstream("http://upstream.test".to_string());
+        config.cache.max_item_size = 2 * BODY_SIZE as u64;
+        let fetcher = Arc::new(MockFetcher::always(MockResponse::ok("application/octet-stream", vec![7u8; BODY_SIZE])));
+        let state = AppState::with_fetcher(config, fetcher.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = serve(
+            listener,
+            router(state.clone()),
+            Some(Duration::from_millis(500)),
+            state.metrics.clone(),
+            std::future::pending(),
+        );
+        tokio::spawn(server);
+
+        // A client with a tiny receive window that stops reading after the first bytes
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let mut client = socket.connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /media/big.bin HTTP/1.1\r\nHost: proxy.test\r\nAccept: */*\r\n\r\n")
+            .await
+            .unwrap();
+        let mut head = [0u8; 1024];
+        client.read_exact(&mut head).await.unwrap();
+        assert!(head.starts_with(b"HTTP/1.1 200 OK"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // The server gave up on the client, which never gets the whole body
+        let mut received = head.len();
+        let mut buffer = vec![0u8; 64 * 1024];
+        while let Ok(read @ 1..) = client.read(&mut buffer).await {
+            received += read;
+        }
+        assert!(received < BODY_SIZE, "{}", received);
+        assert_eq!(Metrics::get(&state.metrics.client_write_timeouts), 1);
+
+        // Upstream was read in full once and the entry is intact
+        assert_eq!(fetcher.requests().len(), 1);
+        let cached = state
+            .cache
+            .get(&CacheKey::new("/media/big.bin".to_string(), "Original".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(cached.data.len(), BODY_SIZE);
+        assert!(cached.data.iter().all(|byte| *byte == 7));
+    }
+}
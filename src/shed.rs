@@ -0,0 +1,256 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+
+/// Source of CPU usage samples, in percent of all cores
+pub trait CpuSampler: Send + 'static {
+    /// Usage since the previous call, `None` while unknown
+    fn sample(&mut self) -> Option<f64>;
+}
+
+/// CPU time of this process, read from `/proc/self/stat`
+///
+/// Reports nothing on systems without procfs, so conversions are never shed there.
+pub struct ProcessCpuSampler {
+    last: Option<(Duration, Instant)>,
+    cores: f64,
+}
+
+impl Default for ProcessCpuSampler {
+    fn default() -> Self {
+        Self {
+            last: None,
+            cores: std::thread::available_parallelism().map_or(1, |cores| cores.get()) as f64,
+        }
+    }
+}
+
+impl CpuSampler for ProcessCpuSampler {
+    fn sample(&mut self) -> Option<f64> {
+        let now = Instant::now();
+        let cpu = process_cpu_time()?;
+        let (last_cpu, last_at) = self.last.replace((cpu, now))?;
+        let wall = now.duration_since(last_at).as_secs_f64();
+        if wall <= 0.0 {
+            return None;
+        }
+        Some(cpu.saturating_sub(last_cpu).as_secs_f64() / wall / self.cores * 100.0)
+    }
+}
+
+/// User and system time of this process so far
+fn process_cpu_time() -> Option<Duration> {
+    // /proc reports times in USER_HZ, which is 100 on every Linux ABI
+    const TICKS_PER_SECOND: u64 = 100;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so fields are counted from its closing parenthesis
+    let mut fields = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().skip(11);
+    let user: u64 = fields.next()?.parse().ok()?;
+    let system: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_millis((user + system) * 1000 / TICKS_PER_SECOND))
+}
+
+/// Switch that sheds conversions while the host is overloaded
+///
+/// Shedding starts after `samples` consecutive samples above the shed threshold
+/// and stops after as many below the lower resume threshold, so usage hovering
+/// around a single threshold does not flap between the two states.
+#[derive(Debug, Default)]
+pub struct ConversionShed {
+    /// Shed and resume thresholds in percent, `None` never sheds
+    thresholds: Option<(f64, f64)>,
+    samples: u32,
+    shedding: AtomicBool,
+    /// Consecutive samples past the threshold that would change the state
+    streak: AtomicU32,
+    /// Last sampled usage, as `f64` bits
+    cpu_percent: AtomicU64,
+}
+
+impl ConversionShed {
+    pub fn new(server: &ServerConfig) -> Self {
+        Self {
+            thresholds: server
+                .conversion_shed_cpu_percent
+                .zip(server.conversion_resume_cpu_percent()),
+            samples: server.conversion_shed_samples,
+            ..Default::default()
+        }
+    }
+
+    /// Whether usage is sampled at all
+    pub fn is_enabled(&self) -> bool {
+        self.thresholds.is_some()
+    }
+
+    /// Whether conversions are currently shed
+    pub fn is_shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    /// Last sampled CPU usage in percent
+    pub fn cpu_percent(&self) -> f64 {
+        f64::from_bits(self.cpu_percent.load(Ordering::Relaxed))
+    }
+
+    /// Record a usage sample, returning whether conversions are shed afterwards
+    ///
+    /// A warning is logged when shedding starts and a note when it stops.
+    pub fn observe(&self, cpu_percent: f64) -> bool {
+        self.cpu_percent.store(cpu_percent.to_bits(), Ordering::Relaxed);
+        let Some((shed, resume)) = self.thresholds else {
+            return false;
+        };
+
+        let shedding = self.is_shedding();
+        let past_threshold = if shedding { cpu_percent < resume } else { cpu_percent > shed };
+        if !past_threshold {
+            self.streak.store(0, Ordering::Relaxed);
+            return shedding;
+        }
+        if self.streak.fetch_add(1, Ordering::Relaxed) + 1 < self.samples {
+            return shedding;
+        }
+
+        self.streak.store(0, Ordering::Relaxed);
+        self.shedding.store(!shedding, Ordering::Relaxed);
+        if shedding {
+            info!("CPU usage down to {:.1}%, resuming image conversions", cpu_percent);
+        } else {
+            warn!("CPU usage at {:.1}% for {} samples, shedding image conversions until it drops below {:.1}%",
+                  cpu_percent, self.samples, resume);
+        }
+        !shedding
+    }
+}
+
+/// Feed `sampler` into `shed` every `interval` until `cancel` fires
+pub fn spawn_monitor(shed: Arc<ConversionShed>, mut sampler: impl CpuSampler, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    if let Some(cpu_percent) = sampler.sample() {
+                        shed.observe(cpu_percent);
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    fn guard(percent: f64, resume: Option<f64>, samples: u32) -> ConversionShed {
+        let mut server = Config::with_upstream("http://upstream.test".to_string()).server;
+        server.conversion_shed_cpu_percent = Some(percent);
+        server.conversion_resume_cpu_percent = resume;
+        server.conversion_shed_samples = samples;
+        ConversionShed::new(&server)
+    }
+
+    #[test]
+    fn test_shedding_starts_and_stops_with_hysteresis() {
+        let shed = guard(90.0, Some(60.0), 3);
+
+        // A spike shorter than the sample count is ignored
+        assert!(!shed.observe(95.0));
+        assert!(!shed.observe(99.0));
+        assert!(!shed.observe(50.0));
+        assert!(!shed.observe(95.0));
+        assert!(!shed.observe(95.0));
+        assert!(shed.observe(95.0));
+        assert!(shed.is_shedding());
+        assert_eq!(shed.cpu_percent(), 95.0);
+
+        // Between the thresholds shedding continues
+        for _ in 0..5 {
+            assert!(shed.observe(75.0));
+        }
+        assert!(shed.observe(40.0));
+        assert!(shed.observe(40.0));
+        assert!(shed.observe(70.0));
+        assert!(shed.observe(40.0));
+        assert!(shed.observe(40.0));
+        assert!(!shed.observe(40.0));
+        assert!(!shed.is_shedding());
+    }
+
+    #[test]
+    fn test_disabled_shed_never_sheds() {
+        let shed = ConversionShed::new(&Config::with_upstream("http://upstream.test".to_string()).server);
+        assert!(!shed.is_enabled());
+        for _ in 0..10 {
+            assert!(!shed.observe(100.0));
+        }
+        assert_eq!(shed.cpu_percent(), 100.0);
+
+        // The resume threshold defaults to 80% of the shed one
+        let shed = guard(50.0, None, 1);
+        assert!(shed.observe(60.0));
+        assert!(shed.observe(41.0));
+        assert!(!shed.observe(39.0));
+    }
+
+    /// Sampler replaying fixed readings, then reporting nothing
+    struct FakeSampler(Arc<Mutex<VecDeque<f64>>>);
+
+    impl CpuSampler for FakeSampler {
+        fn sample(&mut self) -> Option<f64> {
+            self.0.lock().unwrap().pop_front()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor_feeds_samples() {
+        let shed = Arc::new(guard(80.0, None, 2));
+        let readings = Arc::new(Mutex::new(VecDeque::from([90.0, 95.0])));
+        let cancel = CancellationToken::new();
+        spawn_monitor(shed.clone(), FakeSampler(readings.clone()), Duration::from_millis(5), cancel.clone());
+
+        let wait_for = |shedding: bool| {
+            let shed = shed.clone();
+            async move {
+                for _ in 0..200 {
+                    if shed.is_shedding() == shedding {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                panic!("Shedding did not become {}", shedding);
+            }
+        };
+        wait_for(true).await;
+
+        // Missing readings leave the state alone
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(shed.is_shedding());
+
+        readings.lock().unwrap().extend([10.0, 10.0]);
+        wait_for(false).await;
+        cancel.cancel();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_sampler_reads_procfs() {
+        let mut sampler = ProcessCpuSampler::default();
+        assert_eq!(sampler.sample(), None);
+        std::thread::sleep(Duration::from_millis(20));
+        let percent = sampler.sample().unwrap();
+        assert!((0.0..=100.0 * sampler.cores).contains(&percent), "{}", percent);
+    }
+}
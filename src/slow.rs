@@ -0,0 +1,285 @@
+//! The slowest recent requests, kept for `GET /admin/slow`
+//!
+//! Reports of media that is "slow sometimes" tend to arrive after the logs of
+//! the request are gone. Proxied requests taking longer than
+//! `server.slow_request_threshold_ms` are offered to a log holding the slowest
+//! few of the last hour, with the time spent fetching and converting. Faster
+//! requests only fill in their timings and are never stored. Bodies are never
+//! kept, client addresses only with `server.slow_request_client_ips`.
+
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ServerConfig;
+
+/// How long a slow request is kept
+pub const SLOW_REQUEST_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Time a request spent in each phase and what upstream answered, filled in while it is handled
+#[derive(Debug, Default)]
+pub struct RequestTimings {
+    phases: Mutex<Phases>,
+}
+
+/// Snapshot of [`RequestTimings`], unset for phases the request never reached
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Phases {
+    /// Waiting for upstream's headers and reading its body
+    pub upstream: Option<Duration>,
+    /// Converting, fallback formats included
+    pub convert: Option<Duration>,
+    pub upstream_status: Option<u16>,
+    /// Size of the body read from upstream
+    pub upstream_bytes: Option<u64>,
+}
+
+impl RequestTimings {
+    pub fn add_upstream(&self, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        phases.upstream = Some(phases.upstream.unwrap_or_default() + elapsed);
+    }
+
+    pub fn add_convert(&self, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        phases.convert = Some(phases.convert.unwrap_or_default() + elapsed);
+    }
+
+    pub fn set_upstream_status(&self, status: u16) {
+        self.phases.lock().unwrap().upstream_status = Some(status);
+    }
+
+    pub fn set_upstream_bytes(&self, bytes: u64) {
+        self.phases.lock().unwrap().upstream_bytes = Some(bytes);
+    }
+
+    pub fn phases(&self) -> Phases {
+        *self.phases.lock().unwrap()
+    }
+}
+
+/// A request slower than the threshold, as listed by `/admin/slow`
+#[derive(Debug, Clone, Serialize)]
+pub struct SlowRequest {
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+    pub path: String,
+    pub status: u16,
+    /// `X-Cache-Status` of the response, if it has one
+    pub cache_status: Option<String>,
+    pub upstream_status: Option<u16>,
+    /// Until the response started, streamed bodies are sent afterwards
+    pub total_ms: f64,
+    pub upstream_ms: Option<f64>,
+    pub convert_ms: Option<f64>,
+    pub upstream_bytes: Option<u64>,
+    /// Size of the response body, unset for streamed ones
+    pub response_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    #[serde(skip)]
+    recorded: Option<Instant>,
+    #[serde(skip)]
+    total: Duration,
+}
+
+impl SlowRequest {
+    /// A request to `path` that took `total`, with the `phases` it went through
+    pub fn new(path: String, status: u16, total: Duration, phases: Phases) -> Self {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            path,
+            status,
+            cache_status: None,
+            upstream_status: phases.upstream_status,
+            total_ms: millis(total),
+            upstream_ms: phases.upstream.map(millis),
+            convert_ms: phases.convert.map(millis),
+            upstream_bytes: phases.upstream_bytes,
+            response_bytes: None,
+            client_ip: None,
+            recorded: None,
+            total,
+        }
+    }
+}
+
+/// The slowest requests of the last hour, at most `server.slow_request_log_size` of them
+pub struct SlowRequestLog {
+    threshold: Option<Duration>,
+    capacity: usize,
+    window: Duration,
+    include_client_ips: bool,
+    entries: Mutex<Vec<SlowRequest>>,
+}
+
+impl SlowRequestLog {
+    pub fn new(server: &ServerConfig) -> Self {
+        Self {
+            threshold: server.slow_request_threshold_ms.map(Duration::from_millis),
+            capacity: server.slow_request_log_size,
+            window: SLOW_REQUEST_WINDOW,
+            include_client_ips: server.slow_request_client_ips,
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.threshold.is_some() && self.capacity > 0
+    }
+
+    pub fn threshold(&self) -> Option<Duration> {
+        self.threshold
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn includes_client_ips(&self) -> bool {
+        self.include_client_ips
+    }
+
+    /// Whether a request that took `total` is kept
+    pub fn is_slow(&self, total: Duration) -> bool {
+        self.capacity > 0 && self.threshold.is_some_and(|threshold| total > threshold)
+    }
+
+    /// Keep `request` if the log has room, or in place of the fastest one kept if it is slower
+    pub fn record(&self, mut request: SlowRequest) {
+        let now = Instant::now();
+        request.recorded = Some(now);
+        if !self.include_client_ips {
+            request.client_ip = None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| !self.is_expired(entry, now));
+        if entries.len() < self.capacity {
+            entries.push(request);
+            return;
+        }
+        let fastest = entries.iter_mut().min_by_key(|entry| entry.total);
+        if let Some(fastest) = fastest.filter(|fastest| fastest.total < request.total) {
+            *fastest = request;
+        }
+    }
+
+    /// Drop requests older than the window, returning how many were dropped
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|entry| !self.is_expired(entry, now));
+        before - entries.len()
+    }
+
+    /// Requests kept within the window, slowest first
+    pub fn snapshot(&self) -> Vec<SlowRequest> {
+        let now = Instant::now();
+        let mut entries: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| !self.is_expired(entry, now))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.total));
+        entries
+    }
+
+    fn is_expired(&self, entry: &SlowRequest, now: Instant) -> bool {
+        entry.recorded.is_some_and(|recorded| now.duration_since(recorded) >= self.window)
+    }
+}
+
+/// Drop requests older than the window every `interval` until `cancel` fires
+pub fn spawn_pruner(log: Arc<SlowRequestLog>, interval: Duration, cancel: CancellationToken) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = ticker.tick() => {
+                    log.prune();
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(capacity: usize) -> SlowRequestLog {
+        SlowRequestLog::new(&ServerConfig {
+            slow_request_threshold_ms: Some(100),
+            slow_request_log_size: capacity,
+            ..Default::default()
+        })
+    }
+
+    fn request(path: &str, total_ms: u64) -> SlowRequest {
+        SlowRequest::new(path.to_string(), 200, Duration::from_millis(total_ms), Phases::default())
+    }
+
+    #[test]
+    fn test_keeps_the_slowest_requests() {
+        let log = log(2);
+        assert!(!log.is_slow(Duration::from_millis(100)));
+        assert!(log.is_slow(Duration::from_millis(101)));
+
+        log.record(request("/a", 300));
+        log.record(request("/b", 200));
+        log.record(request("/c", 500));
+        log.record(request("/d", 150));
+        let paths: Vec<_> = log.snapshot().into_iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, ["/c", "/a"]);
+    }
+
+    #[test]
+    fn test_old_requests_are_pruned() {
+        let mut log = log(4);
+        log.window = Duration::from_millis(50);
+        log.record(request("/a", 300));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(log.snapshot().is_empty());
+        log.record(request("/b", 200));
+        assert_eq!(log.prune(), 0);
+        assert_eq!(log.snapshot().len(), 1);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(log.prune(), 1);
+    }
+
+    #[test]
+    fn test_client_ips_only_when_enabled() {
+        let mut slow = request("/a", 300);
+        slow.client_ip = Some("192.0.2.1".parse().unwrap());
+        let log = log(4);
+        log.record(slow.clone());
+        assert_eq!(log.snapshot()[0].client_ip, None);
+
+        let log = SlowRequestLog::new(&ServerConfig {
+            slow_request_threshold_ms: Some(100),
+            slow_request_client_ips: true,
+            ..Default::default()
+        });
+        log.record(slow);
+        assert_eq!(log.snapshot()[0].client_ip, Some("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_disabled_without_threshold() {
+        let log = SlowRequestLog::new(&ServerConfig::default());
+        assert!(!log.is_enabled());
+        assert!(!log.is_slow(Duration::from_secs(3600)));
+    }
+}
@@ -0,0 +1,414 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// Upper bounds in bytes of the size histogram buckets, followed by an implicit +Inf bucket
+pub const SIZE_BUCKETS: [u64; 9] = [
+    1024,
+    4 * 1024,
+    16 * 1024,
+    64 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    4 * 1024 * 1024,
+    16 * 1024 * 1024,
+    64 * 1024 * 1024,
+];
+
+/// Most distinct content types tracked per table, later ones are counted as "other"
+const MAX_CONTENT_TYPES: usize = 64;
+
+/// Size histogram updated with atomic increments only
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    /// Per-bucket (not cumulative) counts, the last entry is the +Inf bucket
+    buckets: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    count: AtomicU64,
+    total_bytes: AtomicU64,
+}
+
+impl SizeHistogram {
+    pub fn record(&self, size: u64) {
+        let index = SIZE_BUCKETS
+            .iter()
+            .position(|bound| size <= *bound)
+            .unwrap_or(SIZE_BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        HistogramSnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            p50: quantile(&buckets, 0.5),
+            p95: quantile(&buckets, 0.95),
+            buckets: SIZE_BUCKETS
+                .iter()
+                .map(|bound| Some(*bound))
+                .chain(std::iter::once(None))
+                .zip(buckets)
+                .map(|(le, count)| BucketSnapshot { le, count })
+                .collect(),
+        }
+    }
+}
+
+/// Upper bound of the bucket containing quantile `q`, `None` if empty or in the +Inf bucket
+fn quantile(buckets: &[u64], q: f64) -> Option<u64> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let rank = (total as f64 * q).ceil().max(1.0) as u64;
+    let mut seen = 0;
+    for (index, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return SIZE_BUCKETS.get(index).copied();
+        }
+    }
+    None
+}
+
+/// Point-in-time copy of a [`SizeHistogram`]
+#[derive(Debug, Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub total_bytes: u64,
+    /// Estimated as the upper bound of the bucket holding the quantile
+    pub p50: Option<u64>,
+    pub p95: Option<u64>,
+    pub buckets: Vec<BucketSnapshot>,
+}
+
+/// Count of one histogram bucket
+#[derive(Debug, Serialize)]
+pub struct BucketSnapshot {
+    /// Upper bound in bytes, `null` for the +Inf bucket
+    pub le: Option<u64>,
+    pub count: u64,
+}
+
+/// Size histograms keyed by content type
+#[derive(Debug, Default)]
+struct HistogramTable {
+    histograms: RwLock<HashMap<String, Arc<SizeHistogram>>>,
+}
+
+impl HistogramTable {
+    fn record(&self, content_type: &str, size: u64) {
+        let key = normalize_content_type(content_type);
+        let existing = self.histograms.read().unwrap().get(&key).cloned();
+        let histogram = match existing {
+            Some(histogram) => histogram,
+            None => {
+                let mut histograms = self.histograms.write().unwrap();
+                let key = if histograms.len() >= MAX_CONTENT_TYPES && !histograms.contains_key(&key) {
+                    "other".to_string()
+                } else {
+                    key
+                };
+                histograms.entry(key).or_default().clone()
+            }
+        };
+        histogram.record(size);
+    }
+
+    fn snapshot(&self) -> BTreeMap<String, HistogramSnapshot> {
+        self.histograms
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(content_type, histogram)| (content_type.clone(), histogram.snapshot()))
+            .collect()
+    }
+
+    fn clear(&self) {
+        self.histograms.write().unwrap().clear();
+    }
+}
+
+/// Content type without parameters, lowercased, so label values stay bounded
+fn normalize_content_type(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    if essence.is_empty() || !essence.bytes().all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\\') {
+        "unknown".to_string()
+    } else {
+        essence
+    }
+}
+
+/// Distribution of body sizes by upstream content type and by served content type
+///
+/// Counters live for the whole process unless explicitly reset.
+#[derive(Debug, Default)]
+pub struct FormatStats {
+    source: HistogramTable,
+    served: HistogramTable,
+}
+
+/// JSON body of the format statistics endpoint
+#[derive(Debug, Serialize)]
+pub struct FormatStatsSnapshot {
+    pub source: BTreeMap<String, HistogramSnapshot>,
+    pub served: BTreeMap<String, HistogramSnapshot>,
+}
+
+impl FormatStats {
+    /// Count an upstream body of `size` bytes
+    pub fn record_source(&self, content_type: &str, size: usize) {
+        self.source.record(content_type, size as u64);
+    }
+
+    /// Count a response body of `size` bytes sent to a client
+    pub fn record_served(&self, content_type: &str, size: usize) {
+        self.served.record(content_type, size as u64);
+    }
+
+    pub fn snapshot(&self) -> FormatStatsSnapshot {
+        FormatStatsSnapshot {
+            source: self.source.snapshot(),
+            served: self.served.snapshot(),
+        }
+    }
+
+    /// Zero every counter
+    pub fn reset(&self) {
+        self.source.clear();
+        self.served.clear();
+    }
+
+    /// Render both tables as Prometheus histograms
+    pub fn render(&self, body: &mut String) {
+        let snapshot = self.snapshot();
+        for (name, table) in [("source_body_size_bytes", &snapshot.source), ("served_body_size_bytes", &snapshot.served)] {
+            let _ = writeln!(body, "# TYPE {} histogram", name);
+            for (content_type, histogram) in table {
+                let mut cumulative = 0;
+                for bucket in &histogram.buckets {
+                    cumulative += bucket.count;
+                    let le = bucket.le.map_or_else(|| "+Inf".to_string(), |le| le.to_string());
+                    let _ = writeln!(
+                        body,
+                        "{}_bucket{{content_type=\"{}\",le=\"{}\"}} {}",
+                        name, content_type, le, cumulative,
+                    );
+                }
+                let _ = writeln!(body, "{}_sum{{content_type=\"{}\"}} {}", name, content_type, histogram.total_bytes);
+                let _ = writeln!(body, "{}_count{{content_type=\"{}\"}} {}", name, content_type, histogram.count);
+            }
+        }
+    }
+}
+
+/// Minutes of history the public hit rate is computed over
+pub const HIT_RATE_MINUTES: usize = 60;
+
+/// Requests and hits counted during one minute of uptime
+#[derive(Debug, Default)]
+struct MinuteSlot {
+    /// Minute of uptime plus one the counts belong to, 0 if never used
+    minute: AtomicU64,
+    requests: AtomicU64,
+    hits: AtomicU64,
+}
+
+/// Totals behind the public `/stats` document
+///
+/// Hits and requests go into a ring of per-minute slots; a slot is reset the
+/// first time it is written in a new minute, so the ring holds the last hour.
+#[derive(Debug)]
+pub struct PublicStats {
+    started: Instant,
+    requests: AtomicU64,
+    bytes_served: AtomicU64,
+    bytes_saved: AtomicU64,
+    minutes: [MinuteSlot; HIT_RATE_MINUTES],
+}
+
+impl Default for PublicStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            bytes_saved: AtomicU64::new(0),
+            minutes: std::array::from_fn(|_| MinuteSlot::default()),
+        }
+    }
+}
+
+impl PublicStats {
+    /// Count a served response of `size` bytes, converted from `original_size` bytes if known
+    pub fn record(&self, hit: bool, size: usize, original_size: Option<u64>) {
+        self.record_at(self.current_minute(), hit, size, original_size);
+    }
+
+    fn record_at(&self, minute: u64, hit: bool, size: usize, original_size: Option<u64>) {
+        let size = size as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(size, Ordering::Relaxed);
+        if let Some(original_size) = original_size {
+            self.bytes_saved.fetch_add(original_size.saturating_sub(size), Ordering::Relaxed);
+        }
+
+        // Counts racing with the reset of a slot may be lost, which coarse numbers tolerate
+        let slot = &self.minutes[minute as usize % HIT_RATE_MINUTES];
+        if slot.minute.swap(minute + 1, Ordering::Relaxed) != minute + 1 {
+            slot.requests.store(0, Ordering::Relaxed);
+            slot.hits.store(0, Ordering::Relaxed);
+        }
+        slot.requests.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            slot.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn current_minute(&self) -> u64 {
+        self.started.elapsed().as_secs() / 60
+    }
+
+    /// Rounded copy of the totals, safe to publish
+    pub fn snapshot(&self) -> PublicStatsSnapshot {
+        self.snapshot_at(self.current_minute(), self.started.elapsed().as_secs())
+    }
+
+    fn snapshot_at(&self, minute: u64, uptime_seconds: u64) -> PublicStatsSnapshot {
+        let oldest = (minute + 1).saturating_sub(HIT_RATE_MINUTES as u64 - 1);
+        let (requests, hits) = self
+            .minutes
+            .iter()
+            .filter(|slot| (oldest..=minute + 1).contains(&slot.minute.load(Ordering::Relaxed)))
+            .fold((0, 0), |(requests, hits), slot| {
+                (requests + slot.requests.load(Ordering::Relaxed), hits + slot.hits.load(Ordering::Relaxed))
+            });
+
+        PublicStatsSnapshot {
+            uptime_seconds: uptime_seconds / 60 * 60,
+            requests_served: round_significant(self.requests.load(Ordering::Relaxed)),
+            hit_rate_last_hour: (requests > 0).then(|| (hits as f64 / requests as f64 * 100.0).round() / 100.0),
+            bytes_served: round_significant(self.bytes_served.load(Ordering::Relaxed)),
+            bytes_saved: round_significant(self.bytes_saved.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Coarse statistics for public status pages
+#[derive(Debug, Serialize)]
+pub struct PublicStatsSnapshot {
+    /// Rounded down to whole minutes
+    pub uptime_seconds: u64,
+    pub requests_served: u64,
+    /// Fraction of the last hour's requests served from cache, to two decimals; `null` without requests
+    pub hit_rate_last_hour: Option<f64>,
+    pub bytes_served: u64,
+    /// Bytes conversion kept off the wire, compared to the upstream originals
+    pub bytes_saved: u64,
+}
+
+/// `value` rounded to two significant digits
+fn round_significant(value: u64) -> u64 {
+    let mut scale = 1;
+    while value / scale >= 100 {
+        scale *= 10;
+    }
+    (value + scale / 2) / scale * scale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_and_quantiles() {
+        let stats = FormatStats::default();
+        for _ in 0..9 {
+            stats.record_source("image/jpeg", 500);
+        }
+        stats.record_source("IMAGE/JPEG; charset=binary", 100 * 1024 * 1024);
+        stats.record_source("image/png", 3000);
+
+        let snapshot = stats.snapshot();
+        let jpeg = &snapshot.source["image/jpeg"];
+        assert_eq!(jpeg.count, 10);
+        assert_eq!(jpeg.total_bytes, 9 * 500 + 100 * 1024 * 1024);
+        assert_eq!(jpeg.buckets[0].count, 9);
+        assert_eq!(jpeg.buckets.last().unwrap().count, 1);
+        assert_eq!(jpeg.p50, Some(1024));
+        assert_eq!(jpeg.p95, None);
+        assert_eq!(snapshot.source["image/png"].p50, Some(4096));
+        assert!(snapshot.served.is_empty());
+
+        let mut body = String::new();
+        stats.render(&mut body);
+        assert!(body.contains("source_body_size_bytes_bucket{content_type=\"image/jpeg\",le=\"1024\"} 9\n"));
+        assert!(body.contains("source_body_size_bytes_bucket{content_type=\"image/jpeg\",le=\"+Inf\"} 10\n"));
+        assert!(body.contains("source_body_size_bytes_count{content_type=\"image/png\"} 1\n"));
+
+        stats.reset();
+        assert!(stats.snapshot().source.is_empty());
+    }
+
+    #[test]
+    fn test_content_type_cardinality_is_bounded() {
+        let stats = FormatStats::default();
+        stats.record_served("bad\"type", 10);
+        for i in 0..MAX_CONTENT_TYPES + 10 {
+            stats.record_served(&format!("image/x-{}", i), 10);
+        }
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.served["unknown"].count, 1);
+        assert_eq!(snapshot.served.len(), MAX_CONTENT_TYPES + 1);
+        assert_eq!(snapshot.served["other"].count, 11);
+    }
+
+    #[test]
+    fn test_public_stats_are_coarse() {
+        assert_eq!(round_significant(0), 0);
+        assert_eq!(round_significant(99), 99);
+        assert_eq!(round_significant(1234), 1200);
+        assert_eq!(round_significant(1250), 1300);
+        assert_eq!(round_significant(987_654_321), 990_000_000);
+
+        let stats = PublicStats::default();
+        assert_eq!(stats.snapshot_at(0, 0).hit_rate_last_hour, None);
+        for i in 0..3 {
+            stats.record_at(0, i > 0, 1000, Some(3000));
+        }
+        stats.record_at(0, false, 1234, None);
+
+        let snapshot = stats.snapshot_at(0, 119);
+        assert_eq!(snapshot.uptime_seconds, 60);
+        assert_eq!(snapshot.requests_served, 4);
+        assert_eq!(snapshot.hit_rate_last_hour, Some(0.5));
+        assert_eq!(snapshot.bytes_served, 4200);
+        assert_eq!(snapshot.bytes_saved, 6000);
+    }
+
+    #[test]
+    fn test_hit_rate_covers_the_last_hour() {
+        let stats = PublicStats::default();
+        stats.record_at(0, false, 10, None);
+        stats.record_at(30, true, 10, None);
+        assert_eq!(stats.snapshot_at(30, 0).hit_rate_last_hour, Some(0.5));
+        assert_eq!(stats.snapshot_at(59, 0).hit_rate_last_hour, Some(0.5));
+
+        // The miss from minute 0 has aged out, and its slot is reused
+        assert_eq!(stats.snapshot_at(60, 0).hit_rate_last_hour, Some(1.0));
+        stats.record_at(60, true, 10, None);
+        stats.record_at(60, true, 10, None);
+        assert_eq!(stats.snapshot_at(60, 0).hit_rate_last_hour, Some(1.0));
+        assert_eq!(stats.minutes[0].requests.load(Ordering::Relaxed), 2);
+
+        assert_eq!(stats.snapshot_at(200, 0).hit_rate_last_hour, None);
+        assert_eq!(stats.snapshot_at(200, 0).requests_served, 4);
+    }
+}
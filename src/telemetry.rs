@@ -0,0 +1,358 @@
+//! Startup of the logging and metrics export sinks
+//!
+//! Each sink is brought up on its own and a failing one is reported instead of
+//! stopping the proxy: logging falls back to its defaults, and an unreachable
+//! OTLP collector is retried in the background while requests are served. The
+//! state of every configured sink is shown on `/health` and `/metrics`.
+
+use serde_json::json;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+use crate::metrics::Metrics;
+
+/// Log filter used unless `RUST_LOG` sets a valid one
+const DEFAULT_FILTER: &str = "akkoproxy=info,tower_http=info";
+
+/// First wait before retrying a failed export, doubled up to the export interval
+const RETRY_MIN: Duration = Duration::from_secs(1);
+
+/// State of one telemetry sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkState {
+    /// Not configured, or not set up by this process
+    Disabled,
+    /// Configured, first attempt still pending
+    Starting,
+    Active,
+    /// Configured but not working, with the reason
+    Degraded(String),
+}
+
+impl SinkState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SinkState::Disabled => "disabled",
+            SinkState::Starting => "starting",
+            SinkState::Active => "active",
+            SinkState::Degraded(_) => "degraded",
+        }
+    }
+}
+
+/// Format of log lines, chosen with the `LOG_FORMAT` environment variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// State of the process-wide log subscriber, set by [`init_logging`]
+static LOGGING: OnceLock<SinkState> = OnceLock::new();
+
+/// Install the log subscriber, falling back to defaults for settings that do not work
+///
+/// An invalid `RUST_LOG` or `LOG_FORMAT` is reported once logging is up and
+/// replaced by the default. A subscriber installed earlier, as by a program
+/// embedding the proxy, is left in place and logging is reported degraded.
+pub fn init_logging() -> SinkState {
+    let mut problems = Vec::new();
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+            problems.push(format!("RUST_LOG {:?} is invalid ({}), using {:?}", directives, e, DEFAULT_FILTER));
+            EnvFilter::new(DEFAULT_FILTER)
+        }),
+        Err(_) => EnvFilter::new(DEFAULT_FILTER),
+    };
+    let format = match std::env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => LogFormat::Text,
+        Ok("json") => LogFormat::Json,
+        Ok(other) => {
+            problems.push(format!("LOG_FORMAT {:?} is neither \"text\" nor \"json\", using text", other));
+            LogFormat::Text
+        }
+    };
+
+    let json = format == LogFormat::Json;
+    let installed = tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| tracing_subscriber::fmt::layer().json()))
+        .with((!json).then(tracing_subscriber::fmt::layer))
+        .try_init();
+    let state = match installed {
+        Ok(()) => {
+            for problem in &problems {
+                warn!("Logging: {}", problem);
+            }
+            SinkState::Active
+        }
+        Err(e) => {
+            // Nothing else may be listening, so this goes to stderr directly
+            eprintln!("Failed to install the log subscriber, keeping the existing one: {}", e);
+            SinkState::Degraded(e.to_string())
+        }
+    };
+    LOGGING.get_or_init(|| state).clone()
+}
+
+/// State of the log subscriber, `Disabled` unless [`init_logging`] ran
+pub fn logging_state() -> SinkState {
+    LOGGING.get().cloned().unwrap_or(SinkState::Disabled)
+}
+
+/// Configured sinks with their state, the log subscriber first
+///
+/// The log subscriber is left out when the process did not set it up, as when
+/// the proxy is embedded into another application.
+pub fn sinks(otlp: Option<&OtlpExporter>) -> Vec<(&'static str, SinkState)> {
+    let mut sinks = Vec::new();
+    match logging_state() {
+        SinkState::Disabled => {}
+        state => sinks.push(("log", state)),
+    }
+    if let Some(otlp) = otlp {
+        sinks.push(("otlp", otlp.state()));
+    }
+    sinks
+}
+
+/// Pushes the counters of [`Metrics`] to an OTLP/HTTP collector as JSON
+///
+/// Counters named `_total` are sent as cumulative sums and everything else as
+/// gauges, labels as attributes.
+pub struct OtlpExporter {
+    url: String,
+    client: reqwest::Client,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    started: SystemTime,
+    state: Mutex<SinkState>,
+}
+
+impl OtlpExporter {
+    /// Exporter posting to `endpoint`'s `/v1/metrics` every `interval`, each push within `timeout`
+    pub fn new(endpoint: &str, interval: Duration, timeout: Duration, metrics: Arc<Metrics>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(format!("akkoproxy/{}", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("Failed to create OTLP client");
+        Self {
+            url: format!("{}/v1/metrics", endpoint.trim_end_matches('/')),
+            client,
+            metrics,
+            interval,
+            started: SystemTime::now(),
+            state: Mutex::new(SinkState::Starting),
+        }
+    }
+
+    pub fn state(&self) -> SinkState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Push the current counters once, recording the outcome as the sink state
+    pub async fn export(&self) -> Result<(), String> {
+        let body = otlp_metrics(&parse_exposition(&self.metrics.render()), self.started, SystemTime::now());
+        let request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        let result = match request.send().await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!("collector answered {}", response.status())),
+            Err(e) => Err(e.to_string()),
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            Ok(()) if *state != SinkState::Active => {
+                info!("Exporting metrics to {}", self.url);
+                *state = SinkState::Active;
+            }
+            Err(e) if !matches!(*state, SinkState::Degraded(_)) => {
+                warn!("Failed to export metrics to {}: {}, retrying in the background", self.url, e);
+                *state = SinkState::Degraded(e.clone());
+            }
+            Err(e) => *state = SinkState::Degraded(e.clone()),
+            Ok(()) => {}
+        }
+        result
+    }
+
+    /// Push once more before shutting down, so the last interval is not lost
+    pub async fn flush(&self) {
+        if self.export().await.is_ok() {
+            info!("Flushed metrics to {}", self.url);
+        }
+    }
+
+    /// Export every interval until `cancel` fires, retrying failures sooner with backoff
+    pub fn spawn(self: Arc<Self>, cancel: CancellationToken) {
+        tokio::spawn(async move {
+            let mut backoff = RETRY_MIN;
+            loop {
+                let wait = match self.export().await {
+                    Ok(()) => {
+                        backoff = RETRY_MIN;
+                        self.interval
+                    }
+                    Err(_) => {
+                        let wait = backoff.min(self.interval);
+                        backoff = (backoff * 2).min(self.interval);
+                        wait
+                    }
+                };
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(wait) => {}
+                }
+            }
+        });
+    }
+}
+
+/// One line of the Prometheus text format
+#[derive(Debug, PartialEq)]
+struct Sample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+/// Samples of a Prometheus text exposition, skipping comments and lines it cannot read
+fn parse_exposition(text: &str) -> Vec<Sample> {
+    text.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let value = value.parse().ok()?;
+            let (name, labels) = match series.split_once('{') {
+                Some((name, labels)) => (name, labels.strip_suffix('}')?),
+                None => (series, ""),
+            };
+            let labels = labels
+                .split("\",")
+                .filter(|label| !label.is_empty())
+                .filter_map(|label| {
+                    let (key, value) = label.split_once("=\"")?;
+                    Some((key.to_string(), value.trim_end_matches('"').to_string()))
+                })
+                .collect();
+            Some(Sample { name: name.to_string(), labels, value })
+        })
+        .collect()
+}
+
+/// OTLP/HTTP JSON request body carrying `samples`, grouped into one metric per name
+fn otlp_metrics(samples: &[Sample], started: SystemTime, now: SystemTime) -> serde_json::Value {
+    let nanos = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+    };
+    let (start, now) = (nanos(started), nanos(now));
+
+    let mut metrics: Vec<(&str, Vec<serde_json::Value>)> = Vec::new();
+    for sample in samples {
+        let point = json!({
+            "attributes": sample
+                .labels
+                .iter()
+                .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+                .collect::<Vec<_>>(),
+            "startTimeUnixNano": start,
+            "timeUnixNano": now,
+            "asDouble": sample.value,
+        });
+        match metrics.iter_mut().find(|(name, _)| *name == sample.name) {
+            Some((_, points)) => points.push(point),
+            None => metrics.push((&sample.name, vec![point])),
+        }
+    }
+    let metrics: Vec<_> = metrics
+        .into_iter()
+        .map(|(name, points)| match name.ends_with("_total") {
+            true => json!({
+                "name": name,
+                "sum": { "dataPoints": points, "aggregationTemporality": 2, "isMonotonic": true },
+            }),
+            false => json!({ "name": name, "gauge": { "dataPoints": points } }),
+        })
+        .collect();
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": "akkoproxy" } }],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": "akkoproxy", "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exposition_to_otlp() {
+        let samples = parse_exposition(
+            "# Request Statistics\n\
+             streamed_responses_total 3\n\
+             conversion_skipped_total{reason=\"too_large\"} 1\n\
+             conversion_skipped_total{reason=\"shed\"} 0\n\
+             request_duration_seconds_bucket{flow=\"hit\",le=\"0.5\"} 7\n\
+             cache_pressure_ratio 0.250\n\
+             broken line\n",
+        );
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples[1].labels, vec![("reason".to_string(), "too_large".to_string())]);
+        assert_eq!(
+            samples[3].labels,
+            vec![("flow".to_string(), "hit".to_string()), ("le".to_string(), "0.5".to_string())]
+        );
+
+        let body = otlp_metrics(&samples, SystemTime::UNIX_EPOCH, SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+        let metrics = &body["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics.as_array().unwrap().len(), 4);
+        assert_eq!(metrics[0]["sum"]["isMonotonic"], true);
+        assert_eq!(metrics[1]["name"], "conversion_skipped_total");
+        assert_eq!(metrics[1]["sum"]["dataPoints"].as_array().unwrap().len(), 2);
+        assert_eq!(metrics[1]["sum"]["dataPoints"][0]["timeUnixNano"], "1000000000");
+        assert_eq!(metrics[3]["gauge"]["dataPoints"][0]["asDouble"], 0.25);
+    }
+
+    #[tokio::test]
+    async fn test_export_state_follows_the_collector() {
+        // Nothing listens on port 1, then a collector comes up
+        let exporter = OtlpExporter::new("http://127.0.0.1:1", Duration::from_secs(60), Duration::from_secs(1), Metrics::new());
+        assert_eq!(exporter.state(), SinkState::Starting);
+        assert!(exporter.export().await.is_err());
+        assert_eq!(exporter.state().as_str(), "degraded");
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = {
+            let received = received.clone();
+            axum::Router::new().route(
+                "/v1/metrics",
+                axum::routing::post(move |body: axum::Json<serde_json::Value>| async move {
+                    received.lock().unwrap().push(body.0);
+                }),
+            )
+        };
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let exporter = OtlpExporter { url: format!("{}v1/metrics", endpoint), ..exporter };
+        assert!(exporter.export().await.is_ok());
+        assert_eq!(exporter.state(), SinkState::Active);
+        assert_eq!(received.lock().unwrap()[0]["resourceMetrics"][0]["resource"]["attributes"][0]["value"]["stringValue"], "akkoproxy");
+    }
+}
@@ -0,0 +1,180 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use futures::stream::StreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+use crate::upstream::{FetchError, UpstreamFetcher, UpstreamResponse};
+
+/// Largest number of bytes granted to one transfer before it queues again
+///
+/// Waiters are served in FIFO order, so capping each grant makes concurrent
+/// transfers take turns instead of one large body holding the link.
+const QUANTUM: usize = 16 * 1024;
+
+/// Token bucket shared by every upstream body read
+pub struct BandwidthLimiter {
+    /// Refill rate in bytes per second
+    rate: f64,
+    /// Bucket capacity in bytes
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.refilled_at = now;
+    }
+}
+
+impl BandwidthLimiter {
+    /// Allow `bytes_per_sec` on average, with bursts of up to one second's worth
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        let burst = rate.max(QUANTUM as f64);
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may be transferred
+    pub async fn acquire(&self, mut bytes: usize) {
+        while bytes > 0 {
+            let grant = bytes.min(QUANTUM);
+            let mut bucket = self.bucket.lock().await;
+            bucket.refill(self.rate, self.burst);
+            let missing = grant as f64 - bucket.tokens;
+            if missing > 0.0 {
+                // Sleeping with the lock held keeps later waiters queued behind this one
+                tokio::time::sleep(Duration::from_secs_f64(missing / self.rate)).await;
+                bucket.refill(self.rate, self.burst);
+            }
+            bucket.tokens -= grant as f64;
+            bytes -= grant;
+        }
+    }
+
+    /// Share of the bucket currently drained, from 0.0 (idle) to 1.0 (saturated)
+    pub fn utilization(&self) -> f64 {
+        match self.bucket.try_lock() {
+            Ok(mut bucket) => {
+                bucket.refill(self.rate, self.burst);
+                (1.0 - bucket.tokens / self.burst).clamp(0.0, 1.0)
+            }
+            // A transfer is waiting for tokens
+            Err(_) => 1.0,
+        }
+    }
+}
+
+/// Fetcher decorator limiting the rate at which upstream bodies are read
+pub struct ThrottledFetcher {
+    inner: Arc<dyn UpstreamFetcher>,
+    limiter: Arc<BandwidthLimiter>,
+}
+
+impl ThrottledFetcher {
+    pub fn new(inner: Arc<dyn UpstreamFetcher>, limiter: Arc<BandwidthLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+#[async_trait]
+impl UpstreamFetcher for ThrottledFetcher {
+    async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+        let response = self.inner.fetch(url, headers).await?;
+        let limiter = self.limiter.clone();
+
+        // Tokens are paid for each chunk before the next one is polled, so a
+        // throttled transfer also stops pulling data off the connection
+        let body = response
+            .body
+            .then(move |chunk| {
+                let limiter = limiter.clone();
+                async move {
+                    if let Ok(chunk) = &chunk {
+                        limiter.acquire(chunk.len()).await;
+                    }
+                    chunk
+                }
+            })
+            .boxed();
+
+        Ok(UpstreamResponse { body, ..response })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upstream::mock::{MockFetcher, MockResponse};
+    use std::time::Instant;
+
+    async fn read_all(fetcher: &ThrottledFetcher, url: &str) -> usize {
+        let mut body = fetcher.fetch(url, HeaderMap::new()).await.unwrap().body;
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            size += chunk.unwrap().len();
+        }
+        size
+    }
+
+    #[tokio::test]
+    async fn test_throttled_body_read_takes_size_over_rate() {
+        // 64 KiB at 32 KiB/s with a full 32 KiB bucket leaves 32 KiB to wait for
+        let limiter = Arc::new(BandwidthLimiter::new(32 * 1024));
+        let fetcher = ThrottledFetcher::new(
+            Arc::new(MockFetcher::always(MockResponse::ok("image/png", vec![0u8; 64 * 1024]))),
+            limiter.clone(),
+        );
+
+        let started = Instant::now();
+        assert_eq!(read_all(&fetcher, "http://upstream.test/a.png").await, 64 * 1024);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(950), "took {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+        assert!(limiter.utilization() > 0.9);
+    }
+
+    #[tokio::test]
+    async fn test_small_transfer_not_starved_by_large_one() {
+        let limiter = Arc::new(BandwidthLimiter::new(64 * 1024));
+        let fetcher = Arc::new(ThrottledFetcher::new(
+            Arc::new(
+                MockFetcher::default()
+                    .with("/large.png", MockResponse::ok("image/png", vec![0u8; 192 * 1024]))
+                    .with("/small.png", MockResponse::ok("image/png", vec![0u8; 16 * 1024])),
+            ),
+            limiter,
+        ));
+
+        let large = tokio::spawn({
+            let fetcher = fetcher.clone();
+            async move {
+                read_all(&fetcher, "http://upstream.test/large.png").await;
+                Instant::now()
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        read_all(&fetcher, "http://upstream.test/small.png").await;
+        let small_done = Instant::now();
+
+        // The small body gets its turn between grants of the large one
+        assert!(small_done < large.await.unwrap());
+    }
+}
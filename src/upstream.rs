@@ -0,0 +1,337 @@
+use async_trait::async_trait;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use std::time::Duration;
+use tracing::debug;
+
+use crate::config::Config;
+
+/// Errors produced while talking to upstream
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error("Upstream answered {0} when the body was requested again")]
+    RetryStatus(StatusCode),
+
+    #[error("No connection to upstream host {0} became free in time")]
+    HostBusy(String),
+
+    #[error("Upstream body stalled for more than {0:?}")]
+    Stalled(Duration),
+
+    #[error("Upstream did not finish within {0:?}")]
+    TimedOut(Duration),
+
+    #[cfg(test)]
+    #[error("{0}")]
+    Mock(String),
+}
+
+/// Extensions of media formats that are already compressed
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "avif", "heic", "heif", "jxl", "bmp", "ico",
+    "mp4", "m4v", "mov", "webm", "mkv", "mp3", "m4a", "aac", "ogg", "oga", "ogv", "opus", "flac", "wav",
+];
+
+/// Accept-Encoding sent upstream for a request path
+///
+/// Media is requested as is, compressing it again would only cost CPU on both
+/// ends. Anything else (JSON, text, SVG) may arrive gzip or brotli encoded and
+/// is decompressed by the client as it is read.
+pub fn accept_encoding(path: &str) -> HeaderValue {
+    match path_extension(path).map(str::to_ascii_lowercase) {
+        Some(extension) if MEDIA_EXTENSIONS.contains(&extension.as_str()) => HeaderValue::from_static("identity"),
+        _ => HeaderValue::from_static("gzip, br"),
+    }
+}
+
+/// Extension of the last segment of a request path, as written
+pub fn path_extension(path: &str) -> Option<&str> {
+    path.rsplit('/').next()?.rsplit_once('.').map(|(_, extension)| extension)
+}
+
+/// `body` failing with [`FetchError::TimedOut`] once `deadline` has passed
+///
+/// `timeout` is the limit the deadline was set from, reported in the error.
+pub fn with_deadline(
+    body: BoxStream<'static, Result<Bytes, FetchError>>,
+    deadline: tokio::time::Instant,
+    timeout: Duration,
+) -> BoxStream<'static, Result<Bytes, FetchError>> {
+    futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout_at(deadline, body.next()).await {
+            Ok(chunk) => chunk.map(|chunk| (chunk, Some(body))),
+            Err(_) => Some((Err(FetchError::TimedOut(timeout)), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Response received from upstream, with the body left unread
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Bytes, FetchError>>,
+}
+
+/// Source of upstream responses
+///
+/// Retries, circuit breaking and fallbacks are meant to be layered on as
+/// decorators that wrap another fetcher.
+#[async_trait]
+pub trait UpstreamFetcher: Send + Sync {
+    /// Send a GET request for `url` with the given request headers
+    async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError>;
+}
+
+/// Fetcher backed by a pooled reqwest client
+pub struct ReqwestFetcher {
+    client: reqwest::Client,
+}
+
+impl ReqwestFetcher {
+    pub fn new(config: &Config) -> Self {
+        // The timeout of each request is applied by the proxy, this only backs it up,
+        // and credentials are added per request since routes may lead to other servers
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.upstream.max_timeout()))
+            .user_agent(format!("akkoproxy/{}", env!("CARGO_PKG_VERSION")))
+            .pool_max_idle_per_host(10)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .redirect(reqwest::redirect::Policy::none())
+            // Compressed bodies are decoded on the fly and lose their
+            // Content-Encoding and Content-Length headers
+            .gzip(true)
+            .brotli(true)
+            .build()
+            .expect("Failed to create HTTP client");
+        debug!("HTTP client configured: timeout={}s, user_agent=akkoproxy/{}, redirect_policy=none",
+               config.upstream.max_timeout(), env!("CARGO_PKG_VERSION"));
+
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl UpstreamFetcher for ReqwestFetcher {
+    async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+        let response = self.client.get(url).headers(headers).send().await?;
+
+        Ok(UpstreamResponse {
+            status: response.status(),
+            headers: response.headers().clone(),
+            body: futures::stream::try_unfold(response, |mut response| async move {
+                let chunk = response.chunk().await?;
+                Ok(chunk.map(|chunk| (chunk, response)))
+            })
+            .boxed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_encoding_by_path() {
+        for path in ["/media/a.jpg", "/media/b.WEBP", "/proxy/sig/url/clip.mp4"] {
+            assert_eq!(accept_encoding(path), "identity", "{}", path);
+        }
+        for path in ["/media/data.json", "/media/drawing.svg", "/media/readme", "/media/v1.2/notes"] {
+            assert_eq!(accept_encoding(path), "gzip, br", "{}", path);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use axum::http::{header, HeaderValue};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Canned upstream response served by [`MockFetcher`]
+    #[derive(Clone)]
+    pub struct MockResponse {
+        pub status: StatusCode,
+        pub headers: HeaderMap,
+        pub body: Bytes,
+        /// Body bytes sent before the connection drops, and how many more transfers drop
+        drop_after: Option<(usize, Arc<AtomicUsize>)>,
+        /// Bytes per body chunk, the whole body in one chunk when unset
+        chunk_size: Option<usize>,
+    }
+
+    impl MockResponse {
+        pub fn ok(content_type: &'static str, body: impl Into<Bytes>) -> Self {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            Self {
+                status: StatusCode::OK,
+                headers,
+                body: body.into(),
+                drop_after: None,
+                chunk_size: None,
+            }
+        }
+
+        pub fn status(mut self, status: StatusCode) -> Self {
+            self.status = status;
+            self
+        }
+
+        pub fn header(mut self, name: header::HeaderName, value: &'static str) -> Self {
+            self.headers.insert(name, HeaderValue::from_static(value));
+            self
+        }
+
+        /// Drop the connection after `bytes` body bytes on the next `times` transfers
+        pub fn drop_after(mut self, bytes: usize, times: usize) -> Self {
+            self.drop_after = Some((bytes, Arc::new(AtomicUsize::new(times))));
+            self
+        }
+
+        /// Send the body in chunks of `bytes`
+        pub fn chunked(mut self, bytes: usize) -> Self {
+            self.chunk_size = Some(bytes);
+            self
+        }
+
+        /// Answer a `Range: bytes=<start>-` request the way a server with
+        /// `Accept-Ranges: bytes` would, honoring `If-Range`
+        fn ranged(mut self, request: &HeaderMap) -> Self {
+            let start = request
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+            let ranges = self.headers.get(header::ACCEPT_RANGES).is_some_and(|v| v == "bytes");
+            let current = request
+                .get(header::IF_RANGE)
+                .is_none_or(|etag| self.headers.get(header::ETAG) == Some(etag));
+            match start {
+                Some(start) if ranges && current && start < self.body.len() => {
+                    let range = format!("bytes {}-{}/{}", start, self.body.len() - 1, self.body.len());
+                    self.headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&range).unwrap());
+                    self.status = StatusCode::PARTIAL_CONTENT;
+                    self.body = self.body.slice(start..);
+                }
+                _ => {}
+            }
+            self
+        }
+    }
+
+    /// Test double answering by URL path, falling back to a default response
+    #[derive(Default)]
+    pub struct MockFetcher {
+        responses: Mutex<HashMap<String, MockResponse>>,
+        default: Option<MockResponse>,
+        requests: Mutex<Vec<(String, HeaderMap)>>,
+        delay: Mutex<Duration>,
+        completed: Arc<AtomicUsize>,
+    }
+
+    impl MockFetcher {
+        /// Serve the same response for every URL
+        pub fn always(response: MockResponse) -> Self {
+            Self {
+                default: Some(response),
+                ..Default::default()
+            }
+        }
+
+        /// Serve `response` for URLs whose path and query equal `path`
+        pub fn with(self, path: &str, response: MockResponse) -> Self {
+            self.responses.lock().unwrap().insert(path.to_string(), response);
+            self
+        }
+
+        /// Replace the response for `path` on a fetcher already in use
+        pub fn respond(&self, path: &str, response: MockResponse) {
+            self.responses.lock().unwrap().insert(path.to_string(), response);
+        }
+
+        /// Wait this long before answering each request
+        pub fn set_delay(&self, delay: Duration) {
+            *self.delay.lock().unwrap() = delay;
+        }
+
+        /// URLs requested so far, in order
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().iter().map(|(url, _)| url.clone()).collect()
+        }
+
+        /// Request headers sent so far, in order
+        pub fn request_headers(&self) -> Vec<HeaderMap> {
+            self.requests.lock().unwrap().iter().map(|(_, headers)| headers.clone()).collect()
+        }
+
+        /// Bodies read to their end so far
+        pub fn completed_bodies(&self) -> usize {
+            self.completed.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl UpstreamFetcher for MockFetcher {
+        async fn fetch(&self, url: &str, headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+            self.requests.lock().unwrap().push((url.to_string(), headers.clone()));
+            let delay = *self.delay.lock().unwrap();
+
+            // Answered with the response configured when the request arrived
+            let parsed = url::Url::parse(url).map_err(|e| FetchError::Mock(e.to_string()))?;
+            let key = match parsed.query() {
+                Some(query) => format!("{}?{}", parsed.path(), query),
+                None => parsed.path().to_string(),
+            };
+            let response = self
+                .responses
+                .lock()
+                .unwrap()
+                .get(&key)
+                .cloned()
+                .or_else(|| self.default.clone());
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let response = response.ok_or_else(|| FetchError::Mock(format!("No mock response for {}", key)))?;
+
+            let response = response.ranged(&headers);
+            let dropped = response.drop_after.as_ref().filter(|(_, times)| {
+                times.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok()
+            });
+            let body = match dropped {
+                Some((bytes, _)) => {
+                    let sent = response.body.slice(..(*bytes).min(response.body.len()));
+                    futures::stream::iter([Ok(sent), Err(FetchError::Mock("Connection reset".to_string()))]).boxed()
+                }
+                None => {
+                    let chunks: Vec<_> = match response.chunk_size {
+                        Some(size) => (0..response.body.len())
+                            .step_by(size)
+                            .map(|start| Ok(response.body.slice(start..(start + size).min(response.body.len()))))
+                            .collect(),
+                        None => vec![Ok(response.body)],
+                    };
+                    let completed = self.completed.clone();
+                    let end = futures::stream::once(async move {
+                        completed.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .filter_map(|_| async { None });
+                    futures::stream::iter(chunks).chain(end).boxed()
+                }
+            };
+            Ok(UpstreamResponse {
+                status: response.status,
+                headers: response.headers,
+                body,
+            })
+        }
+    }
+}
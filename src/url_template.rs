@@ -0,0 +1,156 @@
+use anyhow::{Context, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+
+/// Characters escaped in a rendered path segment: everything but RFC 3986 unreserved characters
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Path a template is rendered with when it is validated
+const SAMPLE_PATH: &str = "/media/sample.jpg";
+
+/// Part of a parsed upstream URL template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// The request path as received, still percent-encoded
+    Path,
+    /// The first N characters of the final path segment
+    Shard(usize),
+    /// The first 8 hex characters of the SHA-256 of the path
+    Hash8,
+}
+
+/// Upstream URL built from the request path, for media sharded across several backends
+///
+/// Supports `{path}`, `{shard:N}` and `{hash8}` placeholders, e.g.
+/// `https://{shard:1}.media.example.com{path}`. The query string left after the
+/// proxy's own parameters are removed is appended to the rendered URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UrlTemplate {
+    parts: Vec<Part>,
+}
+
+impl UrlTemplate {
+    /// Parse a template and check that it renders to a valid URL
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .with_context(|| format!("Unclosed placeholder in upstream URL template: {}", template))?;
+            let name = &rest[start + 1..start + end];
+            parts.push(match name.split_once(':') {
+                None if name == "path" => Part::Path,
+                None if name == "hash8" => Part::Hash8,
+                Some(("shard", len)) => match len.parse::<usize>() {
+                    Ok(len) if len > 0 => Part::Shard(len),
+                    _ => anyhow::bail!("Invalid shard length in upstream URL template: {{{}}}", name),
+                },
+                _ => anyhow::bail!("Unknown placeholder in upstream URL template: {{{}}}", name),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        if parts.iter().any(|part| matches!(part, Part::Literal(literal) if literal.contains('}'))) {
+            anyhow::bail!("Unmatched '}}' in upstream URL template: {}", template);
+        }
+
+        let parsed = Self { parts };
+        let sample = parsed.render(SAMPLE_PATH, "");
+        url::Url::parse(&sample)
+            .with_context(|| format!("Upstream URL template renders an invalid URL for {}: {}", SAMPLE_PATH, sample))?;
+        Ok(parsed)
+    }
+
+    /// Build the upstream URL for a percent-encoded request path and query string
+    pub fn render(&self, path: &str, query: &str) -> String {
+        let mut url = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => url.push_str(literal),
+                Part::Path => url.push_str(path),
+                Part::Shard(len) => {
+                    let segment = path.rsplit('/').next().unwrap_or_default();
+                    let decoded = percent_decode_str(segment).decode_utf8_lossy();
+                    let shard: String = decoded.chars().take(*len).collect();
+                    url.extend(utf8_percent_encode(&shard, SEGMENT));
+                }
+                Part::Hash8 => url.push_str(&path_hash(path)),
+            }
+        }
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(query);
+        }
+        url
+    }
+}
+
+/// First 8 hex characters of the SHA-256 of a request path, as rendered by `{hash8}`
+pub fn path_hash(path: &str) -> String {
+    Sha256::digest(path.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_placeholders() {
+        let template = UrlTemplate::parse("https://{shard:1}.media.example.com{path}").unwrap();
+        assert_eq!(template.render("/media/abc.jpg", ""), "https://a.media.example.com/media/abc.jpg");
+        assert_eq!(template.render("/media/b/xyz.png", "v=2"), "https://x.media.example.com/media/b/xyz.png?v=2");
+
+        let template = UrlTemplate::parse("https://cdn.example.com/{shard:2}/{hash8}{path}").unwrap();
+        let rendered = template.render("/media/abc.jpg", "");
+        assert_eq!(rendered, template.render("/media/abc.jpg", ""));
+        assert!(rendered.starts_with("https://cdn.example.com/ab/"));
+        let hash = &rendered["https://cdn.example.com/ab/".len()..][..8];
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        assert_ne!(hash, &template.render("/media/abd.jpg", "")["https://cdn.example.com/ab/".len()..][..8]);
+
+        // Shards shorter than requested use the whole segment
+        assert!(template.render("/media/a", "").starts_with("https://cdn.example.com/a/"));
+    }
+
+    #[test]
+    fn test_render_unicode_filenames() {
+        let template = UrlTemplate::parse("https://media.example.com/{shard:1}{path}").unwrap();
+
+        // Multi-byte characters are sharded whole and escaped again
+        assert_eq!(
+            template.render("/media/%E6%97%A5%E6%9C%AC.png", ""),
+            "https://media.example.com/%E6%97%A5/media/%E6%97%A5%E6%9C%AC.png",
+        );
+        assert_eq!(
+            template.render("/media/%C3%A9t%C3%A9.jpg", ""),
+            "https://media.example.com/%C3%A9/media/%C3%A9t%C3%A9.jpg",
+        );
+        // Reserved characters in the shard are escaped
+        assert_eq!(template.render("/media/%3Fq.jpg", ""), "https://media.example.com/%3F/media/%3Fq.jpg");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in [
+            "https://{shard:0}.example.com{path}",
+            "https://{shard:x}.example.com{path}",
+            "https://{host}{path}",
+            "https://example.com{path",
+            "https://example.com}{path}",
+            "{path}",
+        ] {
+            assert!(UrlTemplate::parse(template).is_err(), "{} should be rejected", template);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Shared helpers for the end-to-end tests
+//!
+//! [`MockUpstream`] is a real HTTP server standing in for Akkoma, answering with
+//! responses each test programs per path. [`TestProxy`] boots the proxy's own
+//! router and state on an ephemeral port in front of it, so requests take the
+//! same path through the network stack as in production.
+//!
+//! ```ignore
+//! let upstream = MockUpstream::start().await;
+//! upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(64, 48)));
+//! let proxy = TestProxy::start(upstream.config()).await;
+//!
+//! proxy.get("/media/a.jpg").accept("image/webp").send().await
+//!     .assert_status(200)
+//!     .assert_header("content-type", "image/webp");
+//! upstream.expect("/media/a.jpg").times(1).verify();
+//! ```
+#![allow(dead_code)]
+
+use akkoproxy::config::Config;
+use akkoproxy::listener::{self, ListenerSource};
+use akkoproxy::proxy::{router, AppState};
+use akkoproxy::serve::{serve, serve_all};
+use axum::body::Body;
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Response the mock upstream sends for a path
+#[derive(Clone)]
+pub struct Reply {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    /// Wait before sending the response headers
+    delay: Duration,
+    /// Send the body in chunks of this size, pausing in between
+    slow_body: Option<(usize, Duration)>,
+    /// Send this many zero bytes instead of `body`, produced as they are sent
+    zeros: Option<usize>,
+}
+
+/// Chunk the bodies of [`Reply::zeros`] are sent from
+static ZEROS: [u8; 64 * 1024] = [0; 64 * 1024];
+
+impl Reply {
+    /// 200 with `body` of type `content_type`
+    pub fn ok(content_type: &str, body: impl Into<Bytes>) -> Self {
+        Self {
+            status: StatusCode::OK,
+            headers: Vec::new(),
+            body: body.into(),
+            delay: Duration::ZERO,
+            slow_body: None,
+            zeros: None,
+        }
+        .header("content-type", content_type)
+    }
+
+    /// 200 with `len` zero bytes of type `content_type`, taking no memory however large
+    pub fn zeros(content_type: &str, len: usize) -> Self {
+        Self {
+            zeros: Some(len),
+            ..Self::ok(content_type, Bytes::new())
+        }
+    }
+
+    /// A plain text response with `status`
+    pub fn error(status: u16, body: &str) -> Self {
+        Self::ok("text/plain", body.to_string()).status(status)
+    }
+
+    /// 302 to `location`
+    pub fn redirect(location: &str) -> Self {
+        Self::ok("text/plain", "").status(302).header("location", location)
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = StatusCode::from_u16(status).expect("valid status code");
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        let name = HeaderName::try_from(name).expect("valid header name");
+        self.headers.push((name, HeaderValue::from_str(value).expect("valid header value")));
+        self
+    }
+
+    /// Wait `delay` before answering
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Send the body `chunk` bytes at a time, pausing `interval` before each chunk after the first
+    pub fn slow_body(mut self, chunk: usize, interval: Duration) -> Self {
+        self.slow_body = Some((chunk.max(1), interval));
+        self
+    }
+
+    async fn into_response(self) -> Response {
+        tokio::time::sleep(self.delay).await;
+        let body = match (self.zeros, self.slow_body) {
+            (Some(len), _) => {
+                let stream = futures::stream::unfold(len, |left| async move {
+                    let chunk = left.min(ZEROS.len());
+                    (chunk > 0).then(|| (Ok::<_, std::io::Error>(Bytes::from_static(&ZEROS[..chunk])), left - chunk))
+                });
+                Body::from_stream(stream)
+            }
+            (None, None) => Body::from(self.body),
+            (None, Some((chunk, interval))) => {
+                let chunks: Vec<Bytes> = self.body.chunks(chunk).map(Bytes::copy_from_slice).collect();
+                let stream = futures::stream::unfold((chunks.into_iter(), true), move |(mut chunks, first)| async move {
+                    let chunk = chunks.next()?;
+                    if !first {
+                        tokio::time::sleep(interval).await;
+                    }
+                    Some((Ok::<_, std::io::Error>(chunk), (chunks, false)))
+                });
+                Body::from_stream(stream)
+            }
+        };
+        let mut response = (self.status, body).into_response();
+        for (name, value) in self.headers {
+            response.headers_mut().append(name, value);
+        }
+        response
+    }
+}
+
+/// A request the mock upstream received
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct UpstreamState {
+    routes: Mutex<HashMap<String, Reply>>,
+    requests: Mutex<Vec<Recorded>>,
+    /// Requests being answered, and the most there ever were at once
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+/// HTTP server answering each path with the [`Reply`] programmed for it, 404 otherwise
+///
+/// Routes match on the path alone, whatever the query string. They can be
+/// changed while the server runs; requests get the reply set when they arrive.
+pub struct MockUpstream {
+    url: String,
+    state: Arc<UpstreamState>,
+}
+
+impl MockUpstream {
+    /// Start a server on an ephemeral local port
+    pub async fn start() -> Self {
+        let state = Arc::new(UpstreamState::default());
+        let handler_state = state.clone();
+        let app = Router::new().fallback(move |request: Request| {
+            let state = handler_state.clone();
+            async move {
+                let path = request.uri().path().to_string();
+                state.requests.lock().unwrap().push(Recorded {
+                    path: path.clone(),
+                    query: request.uri().query().map(str::to_string),
+                    headers: request.headers().clone(),
+                });
+                let reply = state.routes.lock().unwrap().get(&path).cloned();
+                let in_flight = state.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                state.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                let response = match reply {
+                    Some(reply) => reply.into_response().await,
+                    None => (StatusCode::NOT_FOUND, "no route programmed").into_response(),
+                };
+                state.in_flight.fetch_sub(1, Ordering::SeqCst);
+                response
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        Self { url, state }
+    }
+
+    /// Answer requests for `path` with `reply` from now on
+    pub fn route(&self, path: &str, reply: Reply) -> &Self {
+        self.state.routes.lock().unwrap().insert(path.to_string(), reply);
+        self
+    }
+
+    /// Base URL to configure as `upstream.url`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Default configuration pointed at this upstream
+    pub fn config(&self) -> Config {
+        let mut config = Config::with_upstream(self.url.clone());
+        config.cache.pressure_sample_interval = 0;
+        config
+    }
+
+    /// Every request received so far, in order
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Most requests answered at once so far, until their response headers were sent
+    pub fn max_concurrent(&self) -> usize {
+        self.state.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Number of requests received for `path`
+    pub fn hits(&self, path: &str) -> usize {
+        self.state.requests.lock().unwrap().iter().filter(|r| r.path == path).count()
+    }
+
+    /// Start describing the requests expected for `path`
+    pub fn expect(&self, path: &str) -> Expectation<'_> {
+        Expectation {
+            upstream: self,
+            path: path.to_string(),
+            times: None,
+            query: None,
+            headers: Vec::new(),
+        }
+    }
+}
+
+/// Requests [`MockUpstream`] should have received for a path, checked by [`Expectation::verify`]
+pub struct Expectation<'a> {
+    upstream: &'a MockUpstream,
+    path: String,
+    times: Option<usize>,
+    query: Option<Option<String>>,
+    headers: Vec<(String, String)>,
+}
+
+impl Expectation<'_> {
+    /// Exactly `times` requests
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// Every request carried this query string, `None` for none at all
+    pub fn query(mut self, query: Option<&str>) -> Self {
+        self.query = Some(query.map(str::to_string));
+        self
+    }
+
+    /// Every request carried this header value
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Panic with the requests received if any expectation is not met
+    pub fn verify(self) {
+        let requests: Vec<_> = self.upstream.requests().into_iter().filter(|r| r.path == self.path).collect();
+        if let Some(times) = self.times {
+            assert_eq!(requests.len(), times, "requests for {}: {:?}", self.path, requests);
+        }
+        for request in &requests {
+            if let Some(query) = &self.query {
+                assert_eq!(&request.query, query, "query of a request for {}", self.path);
+            }
+            for (name, value) in &self.headers {
+                let actual = request.headers.get(name.as_str()).and_then(|v| v.to_str().ok());
+                assert_eq!(actual, Some(value.as_str()), "{} of a request for {}", name, self.path);
+            }
+        }
+    }
+}
+
+/// The proxy served over HTTP on an ephemeral local port
+pub struct TestProxy {
+    pub state: AppState,
+    url: String,
+    client: reqwest::Client,
+}
+
+impl TestProxy {
+    /// Validate `config` and serve the proxy with it
+    pub async fn start(config: Config) -> Self {
+        config.validate().expect("valid test configuration");
+        let state = AppState::new(config);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener, router(state.clone()), None, state.metrics.clone(), std::future::pending()));
+        Self { state, url, client: client() }
+    }
+
+    /// Validate `config` and serve the proxy on every address of `server.bind`
+    ///
+    /// Returns one handle per listener, all sharing the same state.
+    pub async fn start_all(config: Config) -> Vec<Self> {
+        config.validate().expect("valid test configuration");
+        let listeners: Vec<_> = listener::listen(&config.server, ListenerSource::Bind)
+            .unwrap()
+            .into_iter()
+            .map(|listener| tokio::net::TcpListener::from_std(listener).unwrap())
+            .collect();
+        let state = AppState::new(config);
+        let urls: Vec<_> = listeners.iter().map(|listener| format!("http://{}", listener.local_addr().unwrap())).collect();
+        tokio::spawn(serve_all(listeners, router(state.clone()), None, state.metrics.clone(), std::future::pending()));
+        urls.into_iter().map(|url| Self { state: state.clone(), url, client: client() }).collect()
+    }
+
+    /// Base URL the proxy is served on
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Start building a GET request for `path_and_query`
+    pub fn get(&self, path_and_query: &str) -> ProxyRequest {
+        ProxyRequest {
+            request: self.client.get(format!("{}{}", self.url, path_and_query)),
+        }
+    }
+}
+
+/// Client of the proxy, leaving redirects for the test to look at
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+}
+
+/// A request to [`TestProxy`] being built
+pub struct ProxyRequest {
+    request: reqwest::RequestBuilder,
+}
+
+impl ProxyRequest {
+    pub fn accept(self, accept: &str) -> Self {
+        self.header("accept", accept)
+    }
+
+    pub fn header(self, name: &str, value: &str) -> Self {
+        Self {
+            request: self.request.header(name, value),
+        }
+    }
+
+    /// Send the request and read the whole response
+    pub async fn send(self) -> ProxyResponse {
+        let response = self.request.send().await.expect("proxy reachable");
+        let status = response.status().as_u16();
+        let headers = response.headers().clone();
+        let body = response.bytes().await.expect("complete response body");
+        ProxyResponse { status, headers, body }
+    }
+
+    /// Send the request, leaving the response body to be read as it arrives
+    pub async fn send_streaming(self) -> reqwest::Response {
+        self.request.send().await.expect("proxy reachable")
+    }
+}
+
+/// A response of [`TestProxy`], read in full
+#[derive(Debug)]
+pub struct ProxyResponse {
+    pub status: u16,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: Bytes,
+}
+
+impl ProxyResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// Value of `X-Cache-Status`, e.g. `MISS` or `HIT`
+    pub fn cache_status(&self) -> Option<&str> {
+        self.header("x-cache-status")
+    }
+
+    pub fn assert_status(&self, status: u16) -> &Self {
+        assert_eq!(self.status, status, "status of {:?}", self);
+        self
+    }
+
+    pub fn assert_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(self.header(name), Some(value), "{} of {:?}", name, self.headers);
+        self
+    }
+
+    pub fn assert_no_header(&self, name: &str) -> &Self {
+        assert_eq!(self.header(name), None, "{} of {:?}", name, self.headers);
+        self
+    }
+
+    pub fn assert_cache_status(&self, cache_status: &str) -> &Self {
+        self.assert_header("x-cache-status", cache_status)
+    }
+}
+
+/// A `width`×`height` JPEG with enough detail not to compress away
+pub fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let image = image::RgbImage::from_fn(width, height, |x, y| {
+        let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) % 256;
+        image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, noise as u8])
+    });
+    let mut bytes = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, 90)
+        .encode_image(&image)
+        .unwrap();
+    bytes
+}
+
+/// Resident memory of the test process, `None` where `/proc` is not available
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.trim_start_matches("VmRSS:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Dimensions of an image the image crate can decode
+pub fn dimensions(bytes: &[u8]) -> (u32, u32) {
+    let image = image::load_from_memory(bytes).expect("decodable image");
+    (image.width(), image.height())
+}
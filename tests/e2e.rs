@@ -0,0 +1,333 @@
+//! End-to-end tests of the proxy against a real HTTP upstream
+//!
+//! Helpers live in `tests/common`; see there for how to program the upstream.
+
+mod common;
+
+use akkoproxy::config::{CdnMode, InvalidationConfig};
+use akkoproxy::invalidation::InvalidationTarget;
+use akkoproxy::metrics::Metrics;
+use common::{dimensions, jpeg, resident_bytes, MockUpstream, Reply, TestProxy};
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_image_converted_and_cached() {
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(64, 48)));
+    let proxy = TestProxy::start(upstream.config()).await;
+
+    let response = proxy.get("/media/a.jpg").accept("image/webp,*/*").send().await;
+    response
+        .assert_status(200)
+        .assert_header("content-type", "image/webp")
+        .assert_cache_status("MISS");
+    assert_eq!(dimensions(&response.body), (64, 48));
+
+    // The second request is served from the cache without asking upstream
+    let again = proxy.get("/media/a.jpg").accept("image/webp,*/*").send().await;
+    again.assert_status(200).assert_cache_status("HIT");
+    assert_eq!(again.body, response.body);
+    upstream.expect("/media/a.jpg").times(1).query(None).verify();
+
+    // Clients without a preference get the original bytes
+    let original = proxy.get("/media/a.jpg").accept("*/*").send().await;
+    original.assert_status(200).assert_header("content-type", "image/jpeg");
+    assert_eq!(original.body.as_ref(), jpeg(64, 48));
+}
+
+#[tokio::test]
+async fn test_non_success_passed_through() {
+    let upstream = MockUpstream::start().await;
+    upstream
+        .route("/media/gone.jpg", Reply::error(404, "gone"))
+        .route("/media/moved.jpg", Reply::redirect("https://elsewhere.example/media/moved.jpg"))
+        .route("/media/broken.jpg", Reply::error(500, "oops"));
+    let proxy = TestProxy::start(upstream.config()).await;
+
+    let response = proxy.get("/media/gone.jpg").accept("image/avif,*/*").send().await;
+    response.assert_status(404);
+    assert_eq!(response.body.as_ref(), b"gone");
+
+    proxy
+        .get("/media/moved.jpg")
+        .send()
+        .await
+        .assert_status(302)
+        .assert_header("location", "https://elsewhere.example/media/moved.jpg");
+
+    proxy.get("/media/broken.jpg").send().await.assert_status(500);
+}
+
+#[tokio::test]
+async fn test_preserve_upstream_headers() {
+    let upstream = MockUpstream::start().await;
+    upstream.route(
+        "/media/a.txt",
+        Reply::ok("text/plain", "hello").header("x-upstream-note", "kept"),
+    );
+
+    let proxy = TestProxy::start(upstream.config()).await;
+    proxy
+        .get("/media/a.txt")
+        .send()
+        .await
+        .assert_status(200)
+        .assert_header("x-upstream-note", "kept");
+
+    let mut config = upstream.config();
+    config.server.preserve_upstream_headers = false;
+    let proxy = TestProxy::start(config).await;
+    let response = proxy.get("/media/a.txt").send().await;
+    response.assert_status(200).assert_no_header("x-upstream-note");
+    assert_eq!(response.body.as_ref(), b"hello");
+}
+
+#[tokio::test]
+async fn test_cloudflare_free_format_query() {
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(64, 48)));
+    let mut config = upstream.config();
+    config.server.cdn.mode = CdnMode::CloudflareFree;
+    let proxy = TestProxy::start(config).await;
+
+    // The format comes from the query, which upstream never sees
+    proxy
+        .get("/media/a.jpg?format=webp&v=2")
+        .accept("*/*")
+        .send()
+        .await
+        .assert_status(200)
+        .assert_header("content-type", "image/webp")
+        .assert_header("vary", "Accept");
+    proxy
+        .get("/media/a.jpg?v=2")
+        .accept("*/*")
+        .send()
+        .await
+        .assert_status(200)
+        .assert_header("content-type", "image/jpeg");
+    upstream.expect("/media/a.jpg").times(2).query(Some("v=2")).verify();
+}
+
+#[tokio::test]
+async fn test_oversized_image_served_unconverted() {
+    let upstream = MockUpstream::start().await;
+    let large = jpeg(256, 256);
+    upstream.route("/media/large.jpg", Reply::ok("image/jpeg", large.clone()));
+    let mut config = upstream.config();
+    config.image.max_convert_input_size = Some(large.len() as u64 - 1);
+    let proxy = TestProxy::start(config).await;
+
+    let response = proxy.get("/media/large.jpg").accept("image/webp,*/*").send().await;
+    response.assert_status(200).assert_header("content-type", "image/jpeg");
+    assert_eq!(response.body.as_ref(), large);
+}
+
+#[tokio::test]
+async fn test_stalled_upstream_body_times_out() {
+    let upstream = MockUpstream::start().await;
+    upstream.route(
+        "/media/slow.bin",
+        Reply::ok("application/octet-stream", vec![1u8; 64]).slow_body(16, Duration::from_secs(3)),
+    );
+    let mut config = upstream.config();
+    config.upstream.body_idle_timeout = Some(1);
+    let proxy = TestProxy::start(config).await;
+
+    proxy.get("/media/slow.bin").send().await.assert_status(504);
+}
+
+#[tokio::test]
+async fn test_timeout_override_for_slow_prefix() {
+    use akkoproxy::config::TimeoutOverride;
+
+    let upstream = MockUpstream::start().await;
+    let slow = || Reply::ok("video/mp4", vec![7u8; 64]).delay(Duration::from_millis(1500));
+    upstream.route("/media/archive/a.mp4", slow());
+    upstream.route("/media/b.mp4", slow());
+    let mut config = upstream.config();
+    config.upstream.timeout = 1;
+    config.upstream.timeout_overrides = vec![TimeoutOverride {
+        prefix: "/media/archive/".to_string(),
+        timeout: 3,
+        body_retries: None,
+    }];
+    let proxy = TestProxy::start(config).await;
+
+    let response = proxy.get("/media/archive/a.mp4").send().await;
+    response.assert_status(200);
+    assert_eq!(response.body.len(), 64);
+
+    // Elsewhere the global timeout applies, and the error names it
+    let response = proxy.get("/media/b.mp4").accept("application/json").send().await;
+    response.assert_status(504);
+    let error: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(error["code"], "upstream_timeout");
+    assert_eq!(error["error"], "Upstream did not respond within 1s");
+}
+
+#[tokio::test]
+async fn test_purge_shared_with_peers() {
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(16, 16)));
+    let mut config = upstream.config();
+    config.server.admin_token = Some("secret".to_string());
+    let peer = TestProxy::start(config.clone()).await;
+    config.cache.invalidation = Some(InvalidationConfig {
+        peers: vec![peer.url().to_string()],
+        node_id: Some("a".to_string()),
+        token: None,
+        timeout: 5,
+    });
+    let proxy = TestProxy::start(config).await;
+
+    for replica in [&proxy, &peer] {
+        replica.get("/media/a.jpg").send().await.assert_cache_status("MISS");
+    }
+    let state = &proxy.state;
+    assert_eq!(state.invalidator.purge(&state.cache, InvalidationTarget::Path("/media/a.jpg".to_string())).await, 1);
+    assert_eq!(Metrics::get(&state.metrics.invalidations_published), 1);
+    assert_eq!(Metrics::get(&peer.state.metrics.invalidations_received), 1);
+    peer.get("/media/a.jpg").send().await.assert_cache_status("MISS");
+
+    // An unreachable peer leaves the purge local
+    let mut config = upstream.config();
+    config.cache.invalidation = Some(InvalidationConfig {
+        peers: vec!["http://127.0.0.1:1".to_string()],
+        node_id: None,
+        token: Some("secret".to_string()),
+        timeout: 1,
+    });
+    let isolated = TestProxy::start(config).await;
+    isolated.get("/media/a.jpg").send().await;
+    let state = &isolated.state;
+    assert_eq!(state.invalidator.purge(&state.cache, InvalidationTarget::Path("/media/a.jpg".to_string())).await, 1);
+    assert_eq!(Metrics::get(&state.metrics.invalidation_publish_failures), 1);
+}
+
+#[tokio::test]
+async fn test_concurrent_fetches_limited_per_host() {
+    let upstream = MockUpstream::start().await;
+    for i in 0..8 {
+        upstream.route(&format!("/media/{}.txt", i), Reply::ok("text/plain", "hello").delay(Duration::from_millis(100)));
+    }
+    let mut config = upstream.config();
+    config.upstream.max_concurrent_per_host = Some(2);
+    let proxy = TestProxy::start(config).await;
+
+    let misses: Vec<_> = (0..8).map(|i| proxy.get(&format!("/media/{}.txt", i)).send()).collect();
+    for response in futures::future::join_all(misses).await {
+        response.assert_status(200).assert_cache_status("MISS");
+    }
+    assert_eq!(upstream.max_concurrent(), 2);
+
+    // Fetches that cannot get a connection in time fail instead of queueing forever
+    upstream.route("/media/slow.txt", Reply::ok("text/plain", "slow").delay(Duration::from_secs(3)));
+    let mut config = upstream.config();
+    config.upstream.max_concurrent_per_host = Some(1);
+    config.upstream.host_queue_timeout = 1;
+    let proxy = TestProxy::start(config).await;
+    let (slow, queued) = tokio::join!(proxy.get("/media/slow.txt").send(), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        proxy.get("/media/0.txt").send().await
+    });
+    slow.assert_status(200);
+    queued.assert_status(503);
+    assert_eq!(Metrics::get(&proxy.state.metrics.upstream_host_queue_timeouts), 1);
+}
+
+#[tokio::test]
+async fn test_large_body_streamed_without_buffering() {
+    const SIZE: usize = 256 * 1024 * 1024;
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/clip.mp4", Reply::zeros("video/mp4", SIZE));
+    let mut config = upstream.config();
+    config.cache.max_item_size = 1024 * 1024;
+    let proxy = TestProxy::start(config).await;
+
+    let before = resident_bytes();
+    let mut response = proxy.get("/media/clip.mp4").send_streaming().await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "video/mp4");
+    assert_eq!(response.headers()["x-cache-status"], "MISS; streamed");
+
+    // Memory is sampled every 8 MB received, it must not follow the body
+    let mut received = 0;
+    let mut peak = before;
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        if (received + chunk.len()) >> 23 != received >> 23 {
+            peak = peak.max(resident_bytes());
+        }
+        received += chunk.len();
+    }
+    assert_eq!(received, SIZE);
+    if let (Some(before), Some(peak)) = (before, peak) {
+        let growth = peak.saturating_sub(before);
+        assert!(growth < SIZE as u64 / 8, "resident memory grew by {} bytes", growth);
+    }
+    assert_eq!(Metrics::get(&proxy.state.metrics.streamed_responses), 1);
+
+    // Nothing was cached, the next request streams from upstream again
+    let again = proxy.get("/media/clip.mp4").send_streaming().await;
+    assert_eq!(again.headers()["x-cache-status"], "MISS; streamed");
+    drop(again);
+    upstream.expect("/media/clip.mp4").times(2).verify();
+}
+
+#[tokio::test]
+async fn test_unreachable_otlp_collector_degrades_telemetry() {
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(64, 48)));
+    let mut config = upstream.config();
+    // Nothing listens on port 1
+    config.telemetry.otlp_endpoint = Some("http://127.0.0.1:1".to_string());
+    let proxy = TestProxy::start(config).await;
+
+    proxy
+        .get("/media/a.jpg")
+        .accept("image/webp,*/*")
+        .send()
+        .await
+        .assert_status(200)
+        .assert_header("content-type", "image/webp");
+
+    // The first export fails in the background while requests are served
+    let mut health = String::new();
+    for _ in 0..100 {
+        health = String::from_utf8_lossy(&proxy.get("/health").send().await.body).into_owned();
+        if health.contains("telemetry_degraded: 1") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(health.starts_with("OK\n"), "{}", health);
+    assert!(health.contains("telemetry_degraded: 1"), "{}", health);
+    assert!(health.contains("telemetry_sink_up{sink=\"otlp\"}: 0"), "{}", health);
+
+    let metrics = proxy.get("/metrics").send().await;
+    let metrics = String::from_utf8_lossy(&metrics.body);
+    assert!(metrics.contains("telemetry_degraded 1\n"), "{}", metrics);
+    assert!(metrics.contains("telemetry_sink_up{sink=\"otlp\"} 0\n"), "{}", metrics);
+
+    // Shutting down with the collector still away neither hangs nor panics
+    tokio::time::timeout(Duration::from_secs(10), proxy.state.flush_telemetry()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_listeners_share_one_cache() {
+    let upstream = MockUpstream::start().await;
+    upstream.route("/media/a.jpg", Reply::ok("image/jpeg", jpeg(64, 48)));
+    let mut config = upstream.config();
+    config.server.bind = "127.0.0.1:0, 127.0.0.1:0".parse().unwrap();
+    let proxies = TestProxy::start_all(config).await;
+    assert_eq!(proxies.len(), 2);
+    assert_ne!(proxies[0].url(), proxies[1].url());
+
+    for proxy in &proxies {
+        proxy.get("/health").send().await.assert_status(200);
+    }
+    // What one listener cached the other serves
+    proxies[0].get("/media/a.jpg").accept("image/webp").send().await.assert_status(200).assert_cache_status("MISS");
+    proxies[1].get("/media/a.jpg").accept("image/webp").send().await.assert_status(200).assert_cache_status("HIT");
+    upstream.expect("/media/a.jpg").times(1).verify();
+}
@@ -0,0 +1,58 @@
+use akkoproxy::build_router;
+use akkoproxy::config::Config;
+use axum::body::Body;
+use axum::http::{header, Request, StatusCode};
+use axum::routing::get;
+use axum::Router;
+use tower::ServiceExt;
+
+/// Serve a fixed text file from a local upstream and return its base URL
+async fn spawn_upstream() -> String {
+    let app = Router::new().route(
+        "/media/hello.txt",
+        get(|| async { ([(header::CONTENT_TYPE, "text/plain")], "hello from upstream") }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    format!("http://{}", addr)
+}
+
+#[tokio::test]
+async fn test_router_nested_under_prefix() {
+    let config = Config::with_upstream(spawn_upstream().await);
+    config.validate().unwrap();
+    let (proxy, state) = build_router(config);
+
+    let app = Router::new()
+        .route("/", get(|| async { "parent root" }))
+        .nest("/mediaproxy", proxy);
+
+    let request = Request::builder()
+        .uri("/mediaproxy/media/hello.txt")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("x-cache-status").unwrap(), "MISS");
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(body.as_ref(), b"hello from upstream");
+
+    // The shared state sees the same cache as the router
+    let request = Request::builder()
+        .uri("/mediaproxy/media/hello.txt")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers().get("x-cache-status").unwrap(), "HIT");
+    assert_eq!(state.metrics.formats.snapshot().served["text/plain"].count, 2);
+
+    // The parent keeps its own root
+    let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+    let body = axum::body::to_bytes(app.oneshot(request).await.unwrap().into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(body.as_ref(), b"parent root");
+}
@@ -0,0 +1,190 @@
+//! Fixture images run through the whole proxy and compared against stored goldens
+//!
+//! Every file in `tests/golden/fixtures` is requested with each Accept header and
+//! query in the matrix below. Status, content type, dimensions and a perceptual
+//! hash of the decoded output are compared, not bytes, so encoder updates that
+//! change output imperceptibly do not fail the run.
+//!
+//! Run with `cargo test --features golden-tests --test golden`. After an intended
+//! change, set `AKKOPROXY_REGENERATE_GOLDENS=1` to rewrite `tests/golden/goldens.json`.
+#![cfg(feature = "golden-tests")]
+
+use akkoproxy::config::Config;
+use akkoproxy::proxy::{router, AppState};
+use akkoproxy::upstream::{FetchError, UpstreamFetcher, UpstreamResponse};
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower::ServiceExt;
+
+const ACCEPTS: [(&str, &str); 3] = [
+    ("avif", "image/avif,image/webp,*/*"),
+    ("webp", "image/webp,*/*"),
+    ("any", "*/*"),
+];
+
+const QUERIES: [(&str, &str); 2] = [("plain", ""), ("static", "?static=1")];
+
+/// Most differing bits between the golden and actual perceptual hash
+const MAX_HASH_DISTANCE: u32 = 6;
+
+/// Expected outcome of one request
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Golden {
+    status: u16,
+    content_type: Option<String>,
+    /// Dimensions and hash are `null` for output the image crate cannot decode, such as AVIF
+    width: Option<u32>,
+    height: Option<u32>,
+    dhash: Option<String>,
+}
+
+/// Upstream serving the fixture directory under `/media/`
+struct FixtureFetcher {
+    dir: PathBuf,
+}
+
+#[async_trait]
+impl UpstreamFetcher for FixtureFetcher {
+    async fn fetch(&self, url: &str, _headers: HeaderMap) -> Result<UpstreamResponse, FetchError> {
+        let path = url::Url::parse(url).unwrap().path().to_string();
+        let file = path.strip_prefix("/media/").map(|name| self.dir.join(name));
+        let (status, content_type, body) = match file.and_then(|file| std::fs::read(&file).ok().map(|body| (file, body))) {
+            Some((file, body)) => (StatusCode::OK, fixture_content_type(&file), body),
+            None => (StatusCode::NOT_FOUND, "text/plain", b"not found".to_vec()),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Ok(UpstreamResponse {
+            status,
+            headers,
+            body: futures::stream::once(async move { Ok(Bytes::from(body)) }).boxed(),
+        })
+    }
+}
+
+fn fixture_content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("jpg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Difference hash: one bit per horizontally adjacent pair of a 9×8 grayscale thumbnail
+fn dhash(image: &image::DynamicImage) -> u64 {
+    let thumbnail = image.grayscale().resize_exact(9, 8, image::imageops::FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash = hash << 1 | u64::from(thumbnail.get_pixel(x, y)[0] < thumbnail.get_pixel(x + 1, y)[0]);
+        }
+    }
+    hash
+}
+
+async fn run(state: &AppState, uri: &str, accept: &str) -> Golden {
+    let request = Request::builder().uri(uri).header(header::ACCEPT, accept).body(Body::empty()).unwrap();
+    let response = router(state.clone()).oneshot(request).await.unwrap();
+    let status = response.status().as_u16();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    let decoded = image::load_from_memory(&body).ok();
+    Golden {
+        status,
+        content_type,
+        width: decoded.as_ref().map(|image| image.width()),
+        height: decoded.as_ref().map(|image| image.height()),
+        dhash: decoded.as_ref().map(|image| format!("{:016x}", dhash(image))),
+    }
+}
+
+/// Differences between a golden and the actual outcome, empty if they match
+fn compare(expected: &Golden, actual: &Golden) -> Vec<String> {
+    let mut differences = Vec::new();
+    if expected.status != actual.status {
+        differences.push(format!("status {} != {}", actual.status, expected.status));
+    }
+    if expected.content_type != actual.content_type {
+        differences.push(format!("content type {:?} != {:?}", actual.content_type, expected.content_type));
+    }
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        differences.push(format!(
+            "dimensions {:?}x{:?} != {:?}x{:?}",
+            actual.width, actual.height, expected.width, expected.height
+        ));
+    }
+    match (&expected.dhash, &actual.dhash) {
+        (Some(expected), Some(actual)) => {
+            let distance = (u64::from_str_radix(expected, 16).unwrap() ^ u64::from_str_radix(actual, 16).unwrap()).count_ones();
+            if distance > MAX_HASH_DISTANCE {
+                differences.push(format!("perceptual hash {} is {} bits from {}", actual, distance, expected));
+            }
+        }
+        (None, None) => {}
+        (expected, actual) => differences.push(format!("perceptual hash {:?} != {:?}", actual, expected)),
+    }
+    differences
+}
+
+#[tokio::test]
+async fn test_fixture_corpus_matches_goldens() {
+    let dir = golden_dir();
+    let fetcher = Arc::new(FixtureFetcher { dir: dir.join("fixtures") });
+    let mut fixtures: Vec<String> = std::fs::read_dir(&fetcher.dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "No fixtures in {}", fetcher.dir.display());
+
+    let mut actual = BTreeMap::new();
+    for fixture in &fixtures {
+        for (accept_name, accept) in ACCEPTS {
+            for (query_name, query) in QUERIES {
+                // A fresh state per case, so every request goes through conversion
+                let state = AppState::deterministic(Config::with_upstream("http://fixtures.test".to_string()), fetcher.clone());
+                let outcome = run(&state, &format!("/media/{}{}", fixture, query), accept).await;
+                actual.insert(format!("{} accept={} query={}", fixture, accept_name, query_name), outcome);
+            }
+        }
+    }
+
+    let goldens_path = dir.join("goldens.json");
+    if std::env::var_os("AKKOPROXY_REGENERATE_GOLDENS").is_some() {
+        std::fs::write(&goldens_path, serde_json::to_string_pretty(&actual).unwrap() + "\n").unwrap();
+        return;
+    }
+
+    let goldens: BTreeMap<String, Golden> = serde_json::from_slice(&std::fs::read(&goldens_path).unwrap()).unwrap();
+    let mut failures = Vec::new();
+    for (case, outcome) in &actual {
+        match goldens.get(case) {
+            Some(golden) => failures.extend(compare(golden, outcome).into_iter().map(|d| format!("{}: {}", case, d))),
+            None => failures.push(format!("{}: no golden", case)),
+        }
+    }
+    failures.extend(goldens.keys().filter(|case| !actual.contains_key(*case)).map(|case| format!("{}: no such case", case)));
+    assert!(
+        failures.is_empty(),
+        "Output differs from the goldens (set AKKOPROXY_REGENERATE_GOLDENS=1 to accept it):\n{}",
+        failures.join("\n")
+    );
+}